@@ -21,6 +21,7 @@ use ambiq_hal::{self as hal, prelude::*};
 use chrono::NaiveDate;
 use core::cell::RefCell;
 use core::fmt::Write as _;
+use core::mem::MaybeUninit;
 use core::panic::PanicInfo;
 use core::sync::atomic::{AtomicI32, Ordering};
 #[allow(unused_imports)]
@@ -34,32 +35,97 @@ use embedded_hal::{
         delay::DelayMs,
         i2c::{Read, Write},
     },
+    digital::v2 as digital,
     spi,
 };
 use git_version::git_version;
 use hal::spi::{Freq, Spi};
 use hal::{i2c, pac::interrupt};
 
-use sfy::log::log;
+use sfy::cmd::Command;
+use sfy::crash::CrashRecord;
+use sfy::log::{event, Event, ResetReason};
 use sfy::note::Notecarrier;
+use sfy::scheduler::{Action, Devices, Scheduler};
+use sfy::uplink::Uplink;
+use sfy::watchdog::{Task, WATCHDOG};
 use sfy::waves::Waves;
 #[cfg(feature = "storage")]
 use sfy::{
     storage::{SdSpiSpeed, Storage},
     STORAGEQ,
 };
-use sfy::{Imu, Location, SharedState, State, NOTEQ};
+use sfy::{Location, SharedState, State, NOTEQ};
 
 mod log;
 
-/// This static is used to transfer ownership of the IMU subsystem to the interrupt handler.
+/// The IMU subsystem is owned by the `RTC` interrupt handler. It is only accessed outside the
+/// handler while the `RTC` interrupt is masked (see [`Tasks::reset_imu`]).
 type I = hal::i2c::Iom3;
 type E = <I as embedded_hal::blocking::i2c::Write>::Error;
 static mut IMU: Option<sfy::Imu<E, I>> = None;
 
+/// The Notecard is on its own bus, owned by the main loop.
+type NoteI2c = hal::i2c::Iom4;
+
+/// The storage of the main loop, set while the main loop is not using it so that batched packages
+/// can be written before the device is reset by the watchdog, a panic or a hard fault.
+static mut STORE: Option<*mut dyn Store> = None;
+
+/// Write batched packages to the storage, if the main loop is not using it.
+fn flush_store() {
+    if let Some(store) = free(|_| unsafe { STORE.take() }) {
+        warn!("Writing batched packages before reset..");
+        unsafe { (*store).flush() };
+    }
+}
+
 pub static COUNT: AtomicI32 = AtomicI32::new(0);
 defmt::timestamp!("{=i32}", COUNT.load(Ordering::Relaxed));
 
+/// Crash record, kept in RAM that is not initialized at start-up so that it survives a reset.
+#[link_section = ".uninit.CRASH"]
+static mut CRASH: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+
+/// The crash record. Any content is valid, the record is checked at boot.
+fn crash() -> &'static mut CrashRecord {
+    unsafe { &mut *CRASH.as_mut_ptr() }
+}
+
+/// Read and clear the hardware reset cause.
+fn reset_cause() -> u32 {
+    unsafe {
+        let rstgen = &*hal::pac::RSTGEN::ptr();
+        let stat = rstgen.stat.read().bits();
+        rstgen.clrstat.write(|w| w.bits(1));
+        stat
+    }
+}
+
+/// Watchdog interrupt period (seconds), the device is reset after two periods without being fed.
+const WDT_PERIOD: u32 = 10;
+
+/// Start the hardware watchdog: clocked at 1 Hz, interrupt after `WDT_PERIOD` and reset after two
+/// periods.
+fn start_watchdog() {
+    unsafe {
+        let wdt = &*hal::pac::WDT::ptr();
+        wdt.cfg.write(|w| {
+            w.bits(
+                (3 << 24) // CLKSEL: 1 Hz
+                    | (WDT_PERIOD << 16) // INTVAL
+                    | ((2 * WDT_PERIOD) << 8) // RESVAL
+                    | 0b111, // RESEN | INTEN | WDTEN
+            )
+        });
+        wdt.intclr.write(|w| w.bits(1));
+        wdt.inten.write(|w| w.bits(1));
+        wdt.rstrt.write(|w| w.bits(0xb2));
+
+        cortex_m::peripheral::NVIC::unmask(hal::pac::Interrupt::WDT);
+    }
+}
+
 /// The STATE contains the Real-Time-Clock which needs to be shared, as well as up-to-date
 /// longitude and latitude.
 pub static STATE: Mutex<RefCell<Option<SharedState<hal::rtc::Rtc>>>> =
@@ -82,6 +148,8 @@ fn main() -> ! {
         halc::am_bsp_low_power_init();
     }
 
+    let boot = crash().boot(reset_cause());
+
     let mut dp = hal::pac::Peripherals::take().unwrap();
     let core = hal::pac::CorePeripherals::take().unwrap();
     let mut delay = hal::delay::Delay::new(core.SYST, &mut dp.CLKGEN);
@@ -100,6 +168,7 @@ fn main() -> ! {
         git_version!(),
         sfy::note::BUOYSN
     );
+    info!("{}", defmt::Display2Format(&boot));
 
     info!("Setting up IOM and RTC.");
     delay.delay_ms(1_000u32);
@@ -115,6 +184,7 @@ fn main() -> ! {
     rtc.enable_alarm();
 
     let mut location = Location::new();
+    let mut power = sfy::power::PowerManager::new();
 
     info!("Giving subsystems a couple of seconds to boot..");
     delay.delay_ms(5_000u32);
@@ -142,20 +212,20 @@ fn main() -> ! {
                 SdSpiSpeed::Low => spi.set_freq(Freq::F100kHz),
                 SdSpiSpeed::High => spi.set_freq(Freq::F12mHz),
             },
+            git_version!(),
+            unsafe { &mut sfy::storage::writer::BATCH },
         );
+        storage.set_boot(&boot);
+
+        // Packages are stored from the main loop, after the STATE has been set up.
+        storage.set_timer(|| STATE.now().timestamp_millis());
 
         storage
             .acquire()
             .inspect_err(|e| {
                 defmt::error!("Failed to setup storage: {}", e);
 
-                let mut msg = heapless::String::<256>::new();
-                write!(&mut msg, "storage setup err: {:?}", e)
-                    .inspect_err(|e| {
-                        defmt::error!("failed to format storage-err: {:?}", defmt::Debug2Format(e))
-                    })
-                    .ok();
-                log(&msg);
+                event(Event::StorageError { error: e.code() });
             })
             .ok();
 
@@ -179,12 +249,13 @@ fn main() -> ! {
 
     info!("Send startup-message over cellular.");
 
-    let mut w = heapless::String::<100>::new();
+    let mut w = heapless::String::<384>::new();
     w.push_str("SFY (v").unwrap();
     w.push_str(git_version!()).unwrap();
     w.push_str(") (sn: ").unwrap();
     w.push_str(sfy::note::BUOYSN).unwrap();
-    w.push_str(") started up.").unwrap();
+    w.push_str(") started up. ").unwrap();
+    write!(&mut w, "{}", boot).ok();
     info!("{}", w);
 
     note.hub()
@@ -192,6 +263,12 @@ fn main() -> ! {
         .and_then(|r| r.wait(&mut delay))
         .ok(); // this will fail if more than 100 notes is added.
 
+    event(Event::Startup);
+    event(boot.event());
+    if let Some(e) = boot.watchdog_event() {
+        event(e);
+    }
+
     // Move state into globally available variables and set reference to NOTE for
     // logging on panic and hard resets.
     //
@@ -201,9 +278,7 @@ fn main() -> ! {
 
         STATE.borrow(cs).replace(Some(SharedState {
             rtc,
-            position_time: 0,
-            lon: 0.0,
-            lat: 0.0,
+            positions: sfy::gps::PositionHistory::new(),
         }));
     });
 
@@ -218,6 +293,7 @@ fn main() -> ! {
         (now.timestamp_millis() / 1000).try_into().unwrap_or(0),
         Ordering::Relaxed,
     );
+    sfy::log::set_time((now.timestamp_millis() / 1000).try_into().unwrap_or(0));
     info!(
         "Now: {} ms, position_time: {}, lat: {}, lon: {}",
         now.timestamp_millis(),
@@ -228,17 +304,14 @@ fn main() -> ! {
 
     info!("Setting up IMU..");
     let mut waves = Waves::new(i2c3).unwrap();
-    waves
-        .take_buf(now.timestamp_millis(), position_time, lon, lat)
-        .unwrap(); // set timestamp.
+    waves.take_buf(now.timestamp_millis()).unwrap(); // set timestamp.
 
     info!("Enable IMU.");
     waves.enable_fifo(&mut delay).unwrap();
 
     let imu = sfy::Imu::new(waves, imu_p);
 
-    // Move IMU into temporary variable for moving it into the `RTC` interrupt
-    // routine, _before_ we enable interrupts.
+    // Move IMU into the `RTC` interrupt routine, _before_ we enable interrupts.
     free(|_| {
         unsafe { IMU = Some(imu) };
     });
@@ -248,100 +321,49 @@ fn main() -> ! {
         cortex_m::interrupt::enable();
     }
 
-    info!("Entering main loop");
-    const GOOD_TRIES: u32 = 15;
+    info!("Starting watchdog.");
+    WATCHDOG.register(Task::Imu);
+    WATCHDOG.register(Task::Notecard);
+    WATCHDOG.register(Task::Location);
+    #[cfg(feature = "storage")]
+    WATCHDOG.register(Task::Storage);
+    start_watchdog();
 
-    let mut last: i64 = 0;
-    let mut good_tries: u32 = GOOD_TRIES;
-    let mut sd_good: bool = true; // Do not spam with log messags.
+    info!("Entering main loop");
+    let mut scheduler = Scheduler::new();
 
     loop {
         let now = STATE.now().timestamp_millis();
 
-        #[cfg(feature = "storage")]
-        let drdy = storage_manager.storage_queue.ready() || imu_queue.ready();
-
-        #[cfg(not(feature = "storage"))]
-        let drdy = imu_queue.ready();
-
-        if (drdy && ((now - last) > 500)) || ((now - last) > 5000) {
-            defmt::debug!("iteration, now: {}, drdy: {}", now, drdy);
-
-            sfy::log::drain_log(&mut note, &mut delay)
-                .inspect_err(|e| defmt::error!("drain log: {:?}", e))
-                .ok();
-
-            led.toggle().unwrap();
-
-            let l = location.check_retrieve(&STATE, &mut delay, &mut note);
+        free(|_| unsafe { STORE = None });
+
+        let action = scheduler.step(
+            now,
+            &mut Tasks {
+                note: &mut note,
+                delay: &mut delay,
+                led: &mut led,
+                location: &mut location,
+                power: &mut power,
+                imu_queue: &mut imu_queue,
+                #[cfg(feature = "storage")]
+                storage: &mut storage_manager,
+                #[cfg(not(feature = "storage"))]
+                storage: &mut (),
+            },
+        );
 
+        if let Action::Reset(reason) = action {
+            // Batched packages are lost on reset.
             #[cfg(feature = "storage")]
-            match storage_manager.drain_queue(&mut note, &mut delay) {
-                Err(e) => {
-                    error!("Failed to write to SD card: {:?}", e);
-
-                    if sd_good {
-                        let mut msg = heapless::String::<256>::new();
-                        write!(&mut msg, "storage-err-l: {:?}", e)
-                            .inspect_err(|e| {
-                                defmt::error!(
-                                    "failed to format storage-err: {:?}",
-                                    defmt::Debug2Format(e)
-                                )
-                            })
-                            .ok();
-                        log(&msg);
-                    }
-
-                    sd_good = false;
-                }
-                Ok(Some(_)) => {
-                    sd_good = true;
-                }
-                _ => {}
-            };
-
-            let nd = note.drain_queue(&mut imu_queue, &mut delay);
-            let ns = note.check_and_sync(&mut delay);
-
-            match (l, nd, ns) {
-                (Ok(_), Ok(_), Ok(_)) => good_tries = GOOD_TRIES,
-                (l, dq, cs) => {
-                    error!(
-                        "Fatal error occured during main loop: location: {:?}, note/drain_queue: {:?}, note/check_and_sync: {:?}. Tries left: {}",
-                        l,
-                        dq,
-                        cs,
-                        good_tries
-                    );
-
-                    // Notecard might be in WrongState.
-                    delay.delay_ms(100u16);
-                    note.reset(&mut delay).ok();
-                    delay.delay_ms(100u16);
-
-                    let mut msg = heapless::String::<512>::new();
-                    write!(&mut msg, "Fatal error in main loop: location: {:?}, note/drain_queue: {:?}, note/check_and_sync: {:?}. Tries left: {}", l, dq, cs, good_tries)
-                        .inspect_err(|e| defmt::error!("failed to format error: {:?}", defmt::Debug2Format(e)))
-                        .ok();
-
-                    warn!("Trying to send log message..");
-                    note.hub()
-                        .log(&mut delay, &msg, false, false)
-                        .and_then(|f| f.wait(&mut delay))
-                        .ok();
-
-                    if good_tries == 0 {
-                        error!("No more tries left, attempting to reset devices and restart.");
-                        reset(&mut note, &mut delay);
-                    } else {
-                        good_tries -= 1;
-                    }
-                }
-            };
-            last = now;
+            Store::flush(&mut storage_manager);
+
+            reset(reason, &mut note, &mut delay);
         }
 
+        #[cfg(feature = "storage")]
+        free(|_| unsafe { STORE = Some(&mut storage_manager as &mut dyn Store as *mut _) });
+
         #[cfg(not(feature = "deploy"))]
         delay.delay_ms(1000u16);
 
@@ -349,14 +371,186 @@ fn main() -> ! {
         asm::wfi(); // doesn't work very well with RTT + probe
 
         // defmt::flush();
+    }
+}
+
+/// The tasks of the main loop, see [`sfy::scheduler`].
+struct Tasks<'a, L: digital::ToggleableOutputPin, S: Store> {
+    note: &'a mut Notecarrier<NoteI2c>,
+    delay: &'a mut hal::delay::Delay,
+    led: &'a mut L,
+    location: &'a mut Location,
+    power: &'a mut sfy::power::PowerManager,
+    imu_queue: &'a mut heapless::spsc::Consumer<'static, sfy::axl::AxlPacket, { sfy::NOTEQ_SZ }>,
+    storage: &'a mut S,
+}
+
+impl<'a, L: digital::ToggleableOutputPin, S: Store> Devices for Tasks<'a, L, S> {
+    type Error = <Notecarrier<NoteI2c> as Uplink>::Error;
+
+    fn data_ready(&mut self) -> bool {
+        self.storage.ready() || self.imu_queue.ready()
+    }
+
+    fn drain_log(&mut self) {
+        self.storage.drain_log(self.note, self.delay);
+    }
+
+    fn heartbeat(&mut self) {
+        self.led.toggle().ok();
+    }
+
+    fn event(&mut self, e: Event) {
+        event(e);
+    }
+
+    fn checkin(&mut self, task: Task) {
+        WATCHDOG.checkin(task);
+    }
+
+    fn check_config(&mut self, now: i64) {
+        self.note
+            .check_config(now, self.delay)
+            .inspect_err(|e| defmt::error!("check config: {:?}", e))
+            .ok();
+    }
+
+    fn check_power(&mut self, now: i64) {
+        self.power
+            .check(now, self.note, self.delay)
+            .inspect_err(|e| defmt::error!("check power: {:?}", e))
+            .ok();
+    }
+
+    fn check_command(&mut self) -> Option<Command> {
+        sfy::cmd::check_command(self.note, self.delay)
+            .inspect_err(|e| defmt::error!("check command: {:?}", e))
+            .ok()
+            .flatten()
+    }
+
+    fn reset_imu(&mut self, now: i64) {
+        // The IMU is owned by the `RTC` interrupt, keep it masked while resetting so that the
+        // handler does not run in the middle of the (slow) reset.
+        cortex_m::peripheral::NVIC::mask(hal::pac::Interrupt::RTC);
+
+        if let Some(imu) = unsafe { IMU.as_mut() } {
+            let r = imu.reset(now, self.delay);
+            warn!("IMU reset: {:?}", r);
+        }
+
+        unsafe { cortex_m::peripheral::NVIC::unmask(hal::pac::Interrupt::RTC) };
+    }
+
+    fn location(&mut self) -> Result<(), Self::Error> {
+        self.location.check_retrieve(&STATE, self.delay, self.note)
+    }
+
+    fn store(&mut self) -> Result<Option<u32>, u8> {
+        self.storage.store(self.note, self.delay)
+    }
+
+    fn drain_queue(&mut self) -> Result<(), Self::Error> {
+        self.note
+            .drain_queue(self.imu_queue, self.delay)
+            .map(|_| ())
+    }
+
+    fn sync(&mut self) -> Result<(), Self::Error> {
+        self.note.check_and_sync(self.delay)
+    }
+
+    fn recover(&mut self, msg: &str) {
+        // Notecard might be in WrongState.
+        self.delay.delay_ms(100u16);
+        self.note.reset(self.delay).ok();
+        self.delay.delay_ms(100u16);
+
+        warn!("Trying to send log message..");
+        self.note
+            .hub()
+            .log(self.delay, msg, false, false)
+            .and_then(|f| f.wait(self.delay))
+            .ok();
+    }
+
+    fn spool_log(&mut self) {
+        self.storage.spool_log();
+    }
+}
+
+/// The SD-card storage of the main loop, `()` without the `storage` feature.
+trait Store {
+    fn ready(&self) -> bool {
+        false
+    }
+
+    fn drain_log(&mut self, note: &mut Notecarrier<NoteI2c>, delay: &mut hal::delay::Delay) {
+        sfy::log::drain_log(note, delay)
+            .inspect_err(|e| defmt::error!("drain log: {:?}", e))
+            .ok();
+    }
+
+    fn store(
+        &mut self,
+        _note: &mut Notecarrier<NoteI2c>,
+        _delay: &mut hal::delay::Delay,
+    ) -> Result<Option<u32>, u8> {
+        Ok(None)
+    }
+
+    fn spool_log(&mut self) {}
 
-        // TODO:
-        // * Set up and feed watchdog.
+    fn flush(&mut self) {}
+}
+
+impl Store for () {}
+
+#[cfg(feature = "storage")]
+impl<SPI: spi::Transfer<u8, Error = SE>, CS: digital::OutputPin, SE: core::fmt::Debug> Store
+    for sfy::StorageManager<Storage<sfy::storage::SdCard<SPI, CS>>>
+{
+    fn ready(&self) -> bool {
+        self.storage_queue.ready()
+    }
+
+    fn drain_log(&mut self, note: &mut Notecarrier<NoteI2c>, delay: &mut hal::delay::Delay) {
+        sfy::StorageManager::drain_log(self, note, delay)
+            .inspect_err(|e| defmt::error!("drain log: {:?}", e))
+            .ok();
+    }
+
+    fn store(
+        &mut self,
+        note: &mut Notecarrier<NoteI2c>,
+        delay: &mut hal::delay::Delay,
+    ) -> Result<Option<u32>, u8> {
+        self.drain_queue(note, delay).map_err(|e| {
+            error!("Failed to write to SD card: {:?}", e);
+            e.code()
+        })
+    }
+
+    fn spool_log(&mut self) {
+        sfy::StorageManager::spool_log(self)
+            .inspect_err(|e| defmt::error!("spool log: {:?}", e))
+            .ok();
+    }
+
+    fn flush(&mut self) {
+        sfy::StorageManager::flush(self)
+            .inspect_err(|e| defmt::error!("flush: {:?}", e))
+            .ok();
     }
 }
 
-fn reset<I: Read + Write>(note: &mut Notecarrier<I>, delay: &mut impl DelayMs<u16>) -> ! {
+fn reset<I: Read + Write>(
+    reason: ResetReason,
+    note: &mut Notecarrier<I>,
+    delay: &mut impl DelayMs<u16>,
+) -> ! {
     cortex_m::interrupt::disable();
+    crash().reset(reason);
 
     warn!("Resetting device!");
 
@@ -376,8 +570,6 @@ fn reset<I: Read + Write>(note: &mut Notecarrier<I>, delay: &mut impl DelayMs<u1
 #[allow(non_snake_case)]
 #[interrupt]
 fn RTC() {
-    #[allow(non_upper_case_globals)]
-    static mut imu: Option<Imu<E, I>> = None;
     static mut GOOD_TRIES: u16 = 5;
 
     // FIFO size of IMU is 512 samples (uncompressed), sample rate at IMU is 208 Hz. So we
@@ -390,55 +582,70 @@ fn RTC() {
             .write(|w| w.alm().set_bit());
     }
 
-    if let Some(imu) = imu {
-        let (now, position_time, lon, lat) = free(|cs| {
-            let state = STATE.borrow(cs).borrow();
-            let state = state.as_ref().unwrap();
-
-            let now = state.rtc.now().timestamp_millis();
-            let position_time = state.position_time;
-            let lon = state.lon;
-            let lat = state.lat;
-
-            (now, position_time, lon, lat)
-        });
+    if let Some(imu) = unsafe { IMU.as_mut() } {
+        let now = STATE.now().timestamp_millis();
 
         COUNT.store((now / 1000).try_into().unwrap_or(0), Ordering::Relaxed);
+        sfy::log::set_time((now / 1000).try_into().unwrap_or(0));
+
+        let mut delay = hal::delay::FlashDelay;
 
         // XXX: This is the most time-critical part of the program.
         //
         // It seems that the IMU I2C communication sometimes fails with a NAK, causing a module
         // reset, which again might cause a HardFault.
-        match imu.check_retrieve(now, position_time, lon, lat) {
+        match imu
+            .check_schedule(now, &STATE, &mut delay)
+            .and_then(|_| imu.check_retrieve(now, &STATE))
+        {
             Ok(_) => {
                 *GOOD_TRIES = 5;
             }
             Err(e) => {
                 error!("IMU ISR failed: {:?}, resetting IMU..", e);
 
-                let mut delay = hal::delay::FlashDelay;
-
-                let r = imu.reset(now, position_time, lon, lat, &mut delay);
+                let r = imu.reset(now, &mut delay);
                 warn!("IMU reset: {:?}", r);
 
-                let mut msg = heapless::String::<256>::new();
-                write!(&mut msg, "IMU failure: {:?}, reset: {:?}", e, r)
-                    .inspect_err(|e| {
-                        defmt::error!("failed to format IMU failure: {:?}", defmt::Debug2Format(e))
-                    })
-                    .ok();
-                log(&msg);
+                event(Event::ImuFailure {
+                    error: e.code(),
+                    tries: *GOOD_TRIES,
+                });
 
                 if *GOOD_TRIES == 0 {
+                    event(Event::Reset {
+                        reason: ResetReason::Imu,
+                    });
                     panic!("IMU has failed repeatedly: {:?}, resetting system.", e);
                 }
 
                 *GOOD_TRIES -= 1;
             }
         }
-    } else {
-        unsafe {
-            imu.replace(IMU.take().unwrap());
+
+        WATCHDOG.checkin(Task::Imu);
+    }
+}
+
+/// Feeds the hardware watchdog as long as all tasks are alive. Otherwise the late task is recorded
+/// and the watchdog resets the device at the end of the next period.
+#[cfg(not(feature = "host-tests"))]
+#[allow(non_snake_case)]
+#[interrupt]
+fn WDT() {
+    let wdt = unsafe { &*hal::pac::WDT::ptr() };
+    wdt.intclr.write(|w| unsafe { w.bits(1) });
+
+    match WATCHDOG.tick(WDT_PERIOD) {
+        Ok(()) => wdt.rstrt.write(|w| unsafe { w.bits(0xb2) }),
+        Err(task) => {
+            error!(
+                "watchdog: task {:?} has not checked in for {} s, resetting.",
+                task,
+                WATCHDOG.age(task)
+            );
+            crash().watchdog(task);
+            flush_store();
         }
     }
 }
@@ -450,6 +657,8 @@ unsafe fn HardFault(ef: &ExceptionFrame) -> ! {
         "hard fault exception: {:#?}. resetting system.",
         defmt::Debug2Format(ef)
     );
+    crash().hard_fault(ef.pc(), ef.lr());
+    flush_store();
     cortex_m::peripheral::SCB::sys_reset()
 }
 
@@ -458,12 +667,17 @@ unsafe fn HardFault(ef: &ExceptionFrame) -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     defmt::error!("panic: {}", defmt::Debug2Format(info));
-    log("panic reset.");
+    event(Event::Reset {
+        reason: ResetReason::Panic,
+    });
     let mut msg = heapless::String::<256>::new();
     write!(&mut msg, "panic: {}", info)
         .inspect_err(|e| defmt::error!("failed to format panic: {:?}", defmt::Debug2Format(e)))
         .ok();
-    log(&msg);
+    crash().panic(&msg);
+    sfy::log::log(&msg);
+
+    flush_store();
 
     let mut delay = hal::delay::FlashDelay;
 
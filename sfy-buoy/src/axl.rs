@@ -1,6 +1,9 @@
 use defmt::{write, Format, Formatter};
 use half::f16;
 use heapless::Vec;
+use micromath::F32Ext;
+
+use crate::gps::{PacketPosition, PositionHistory};
 
 pub const SAMPLE_SZ: usize = 3;
 pub const AXL_SZ: usize = SAMPLE_SZ * 1024;
@@ -25,11 +28,20 @@ pub struct AxlPacket {
     pub storage_id: Option<u32>,
     pub storage_version: Option<u32>,
 
-    /// Time of position in seconds.
+    /// Time of position in seconds. The position is interpolated to the middle of the package
+    /// (see [`crate::gps`]).
     pub position_time: u32,
     pub lon: f64,
     pub lat: f64,
 
+    /// Age and quality of the fix the position is based on. Not stored to the SD-card.
+    #[serde(skip)]
+    pub fix: Option<PacketPosition>,
+
+    /// ID of the burst the package was recorded in, if recording in bursts (see
+    /// [`crate::burst`]). Stored to the SD-card from storage version 3.
+    pub burst: Option<u32>,
+
     /// Frequency of data.
     pub freq: f32,
 
@@ -69,6 +81,33 @@ impl Format for AxlPacket {
 }
 
 impl AxlPacket {
+    /// Time of the middle sample of the package (ms).
+    pub fn mid_time(&self) -> i64 {
+        let n = (self.data.len() / SAMPLE_SZ) as f32;
+
+        if self.freq > 0. {
+            self.timestamp + (n / 2. / self.freq * 1000.) as i64
+        } else {
+            self.timestamp
+        }
+    }
+
+    /// Set position to the position at the middle of the package.
+    pub fn set_position(&mut self, positions: &PositionHistory) {
+        self.set_fix(positions.position_at(self.mid_time()));
+    }
+
+    /// Set the position fix of the package.
+    pub fn set_fix(&mut self, fix: Option<PacketPosition>) {
+        self.fix = fix;
+
+        if let Some(p) = self.fix {
+            self.position_time = p.time;
+            self.lat = p.lat;
+            self.lon = p.lon;
+        }
+    }
+
     pub fn base64(&self) -> Vec<u8, AXL_OUTN> {
         let mut b64: Vec<_, AXL_OUTN> = Vec::new();
         b64.resize_default(AXL_OUTN).unwrap();
@@ -97,6 +136,77 @@ pub struct AxlPacketMeta {
     pub position_time: u32,
     pub lon: f64,
     pub lat: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fix_age: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hdop: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sats: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u32>,
+}
+
+/// Summary of an `AxlPacket`, sent instead of the raw data in low-power mode (see
+/// [`crate::power::PowerMode::Summary`]).
+#[derive(serde::Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct AxlSummary {
+    pub timestamp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u32>,
+    pub freq: f32,
+    pub samples: u32,
+    pub position_time: u32,
+    pub lon: f64,
+    pub lat: f64,
+
+    /// Standard deviation of each axis (m/s^2).
+    pub std: [f32; SAMPLE_SZ],
+
+    /// Maximum absolute deviation from mean of each axis (m/s^2).
+    pub max: [f32; SAMPLE_SZ],
+}
+
+impl AxlPacket {
+    pub fn summary(&self) -> AxlSummary {
+        let n = self.data.len() / SAMPLE_SZ;
+        let mut mean = [0f32; SAMPLE_SZ];
+        let mut std = [0f32; SAMPLE_SZ];
+        let mut max = [0f32; SAMPLE_SZ];
+
+        if n > 0 {
+            for s in self.data.chunks_exact(SAMPLE_SZ) {
+                for (m, v) in mean.iter_mut().zip(s) {
+                    *m += v.to_f32();
+                }
+            }
+            mean.iter_mut().for_each(|m| *m /= n as f32);
+
+            for s in self.data.chunks_exact(SAMPLE_SZ) {
+                for i in 0..SAMPLE_SZ {
+                    let d = s[i].to_f32() - mean[i];
+                    std[i] += d * d;
+                    max[i] = max[i].max(F32Ext::abs(d));
+                }
+            }
+            std.iter_mut()
+                .for_each(|s| *s = F32Ext::sqrt(*s / n as f32));
+        }
+
+        AxlSummary {
+            timestamp: self.timestamp,
+            storage_id: self.storage_id,
+            burst: self.burst,
+            freq: self.freq,
+            samples: n as u32,
+            position_time: self.position_time,
+            lon: self.lon,
+            lat: self.lat,
+            std,
+            max,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -115,6 +225,8 @@ mod tests {
             offset: 0,
             storage_id: Some(0),
             storage_version: Some(STORAGE_VERSION),
+            fix: None,
+            burst: None,
             data: (0..3072)
                 .map(|v| f16::from_f32(v as f32))
                 .collect::<Vec<_, { AXL_SZ }>>(),
@@ -124,6 +236,38 @@ mod tests {
         println!("{}", core::str::from_utf8(&b64).unwrap());
     }
 
+    #[test]
+    fn summary() {
+        let p = AxlPacket {
+            timestamp: 0,
+            position_time: 0,
+            lat: 0.0,
+            lon: 0.0,
+            freq: 52.0,
+            offset: 0,
+            storage_id: Some(0),
+            storage_version: Some(STORAGE_VERSION),
+            fix: None,
+            burst: None,
+            data: (0..1024)
+                .flat_map(|i| {
+                    let z = if i % 2 == 0 { 1.0 } else { -1.0 };
+                    [
+                        f16::from_f32(0.5),
+                        f16::from_f32(0.),
+                        f16::from_f32(9.81 + z),
+                    ]
+                })
+                .collect::<Vec<_, { AXL_SZ }>>(),
+        };
+
+        let s = p.summary();
+        assert_eq!(s.samples, 1024);
+        assert_eq!(s.std[0], 0.0);
+        assert!((s.std[2] - 1.0).abs() < 0.01);
+        assert!((s.max[2] - 1.0).abs() < 0.01);
+    }
+
     #[test]
     fn postcard_size() {
         let p = AxlPacket {
@@ -135,6 +279,8 @@ mod tests {
             offset: 0,
             storage_id: Some(1489),
             storage_version: Some(STORAGE_VERSION),
+            fix: None,
+            burst: None,
             data: (6..3078)
                 .map(|v| f16::from_f32(v as f32))
                 .collect::<Vec<_, { AXL_SZ }>>(),
@@ -150,4 +296,52 @@ mod tests {
         // This does not include the additional size used by COBS.
         // assert!(AXL_POSTCARD_SZ >= AxlPacket::POSTCARD_MAX_SIZE);
     }
+
+    #[test]
+    fn position_at_mid_time() {
+        use crate::uplink::Position;
+
+        let mut p = AxlPacket {
+            timestamp: 1_000_000,
+            position_time: 0,
+            lat: 0.0,
+            lon: 0.0,
+            freq: 52.0,
+            offset: 0,
+            storage_id: None,
+            storage_version: None,
+            fix: None,
+            burst: None,
+            data: (0..AXL_SZ).map(|_| f16::from_f32(0.)).collect(),
+        };
+
+        assert_eq!(p.mid_time(), 1_009_846);
+
+        let mut h = PositionHistory::new();
+        p.set_position(&h);
+        assert_eq!((p.position_time, p.lat, p.fix), (0, 0.0, None));
+
+        for (time, lat) in [(940, 60.0), (1000, 60.01)] {
+            h.push(Position {
+                lat,
+                lon: 5.0,
+                time,
+                hdop: None,
+                sats: Some(7),
+            });
+        }
+
+        p.set_position(&h);
+        assert_eq!(p.position_time, 1009);
+        assert!((p.lat - 60.0115).abs() < 1e-9);
+        assert_eq!(p.lon, 5.0);
+        assert_eq!(p.fix.unwrap().age, 9);
+        assert_eq!(p.fix.unwrap().sats, Some(7));
+
+        // The fix is not stored.
+        let mut v: Vec<_, { AXL_POSTCARD_SZ }> = postcard::to_vec_cobs(&p).unwrap();
+        let d: AxlPacket = postcard::from_bytes_cobs(&mut v).unwrap();
+        assert_eq!(d.fix, None);
+        assert_eq!(d.position_time, 1009);
+    }
 }
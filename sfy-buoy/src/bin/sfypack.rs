@@ -1,13 +1,28 @@
 use argh::FromArgs;
 use chrono::NaiveDateTime;
 use serde_json as json;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
 
-use sfy::axl;
+use sfy::{
+    axl,
+    log::LogRecord,
+    storage::{
+        self, format,
+        manifest::{self, Manifest},
+        time_index::{self, TimeEntry},
+        Record,
+    },
+};
 
 #[derive(FromArgs)]
-/// Load and print Axl package from binary collection.
+/// Load and print Axl packages (and other records) from binary collection (any storage version),
+/// the collections in the time index (`TIME.I3`), or the boots in the deployment manifest
+/// (`MANIFEST.M1`). With `--salvage` packages are recovered from damaged collections, a directory
+/// of files or a disk image of a card.
 struct SfyPack {
     #[argh(positional, description = "file name")]
     file: PathBuf,
@@ -20,15 +35,57 @@ struct SfyPack {
 
     #[argh(switch, description = "simulate a note.add event")]
     note: bool,
+
+    #[argh(
+        option,
+        description = "start of time window (UTC, seconds), only for the time index"
+    )]
+    start: Option<i64>,
+
+    #[argh(
+        option,
+        description = "end of time window (UTC, seconds, inclusive), only for the time index"
+    )]
+    end: Option<i64>,
+
+    #[argh(
+        option,
+        description = "recover packages from raw files or a disk image into new collections in this directory"
+    )]
+    salvage: Option<PathBuf>,
 }
 
 fn main() -> anyhow::Result<()> {
     let pck: SfyPack = argh::from_env();
+
+    if pck.salvage.is_some() {
+        return salvage(pck);
+    }
+
+    if TimeIndex::is_time_index(&pck.file) {
+        return time_index(pck);
+    }
+
+    if Manifests::is_manifest(&pck.file) {
+        return manifests(pck);
+    }
+
     eprintln!("Loading collection from: {:?}", pck.file);
 
     let c = Collection::from_file(&pck.file)?;
     eprintln!("Loaded {} packages.", c.len());
 
+    if !c.records.is_empty() {
+        eprintln!("Loaded {} other records.", c.records.len());
+    }
+
+    if let Some(h) = &c.header {
+        eprintln!(
+            "Collection {} (storage version: {}) from {}, firmware: {}",
+            h.collection, h.version, h.buoy, h.firmware
+        );
+    }
+
     if pck.list {
         for p in c.iter() {
             let ts = NaiveDateTime::from_timestamp(
@@ -39,6 +96,11 @@ fn main() -> anyhow::Result<()> {
         }
 
         eprintln!("Listed {} packages.", c.len());
+
+        for (id, r) in &c.records {
+            eprint!("record {}: ", id);
+            list_record(r);
+        }
     }
 
     match (pck.json, pck.note) {
@@ -56,6 +118,156 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+fn list_log(r: &LogRecord) {
+    match r {
+        LogRecord::Event(e) => {
+            let ts = NaiveDateTime::from_timestamp(e.time as i64, 0);
+            eprintln!("{:?}: {:?} ({:?})", ts, e.event, e.event.severity());
+        }
+        LogRecord::Message(m) => eprintln!("message: {}", m),
+    }
+}
+
+fn list_record(r: &Record) {
+    match r {
+        Record::Axl(p) => eprintln!("{:?}", p),
+        Record::Track(p) => {
+            let ts = NaiveDateTime::from_timestamp(p.time as i64, 0);
+            eprintln!("{:?}: {:?}", ts, p);
+        }
+        Record::Log(r) => list_log(r),
+    }
+}
+
+fn manifests(pck: SfyPack) -> anyhow::Result<()> {
+    eprintln!("Loading manifest from: {:?}", pck.file);
+
+    let m = Manifests::from_file(&pck.file)?;
+    eprintln!("Loaded {} boots.", m.len());
+
+    for b in m.iter() {
+        let ts = NaiveDateTime::from_timestamp(b.time as i64, 0);
+        eprintln!(
+            "{:?}: {} ({}), firmware: {}, first ID: {}, boot: {}, reset reason: {}, cause: {:#x}",
+            ts,
+            b.buoy,
+            b.product,
+            b.firmware,
+            b.first_id,
+            b.boot_count,
+            b.reset_reason,
+            b.reset_cause
+        );
+
+        if pck.list {
+            eprintln!("  storage version: {}, {:?}", b.storage_version, b.sampling);
+        }
+    }
+
+    if pck.json {
+        println!("{}", json::to_string_pretty(&m.boots).unwrap());
+    }
+
+    if pck.note {
+        eprintln!("--note is not supported for manifest files");
+    }
+
+    Ok(())
+}
+
+fn time_index(pck: SfyPack) -> anyhow::Result<()> {
+    eprintln!("Loading time index from: {:?}", pck.file);
+
+    let t = TimeIndex::from_file(&pck.file)?;
+    eprintln!("Loaded {} collections.", t.len());
+
+    let start = pck.start.map_or(i64::MIN, |s| s * 1000);
+    let end = pck.end.map_or(i64::MAX, |e| e * 1000 + 999);
+
+    let entries = t
+        .iter()
+        .filter(|e| e.overlaps(start, end))
+        .collect::<Vec<_>>();
+
+    if pck.list {
+        for e in &entries {
+            eprintln!(
+                "{}: {:?} -> {:?}: {} packages ({} bytes), flags: {:#010b}{}",
+                e.collection,
+                NaiveDateTime::from_timestamp(e.first / 1000, 0),
+                NaiveDateTime::from_timestamp(e.last / 1000, 0),
+                e.count,
+                e.size,
+                e.flags,
+                if e.is_deleted() { " (deleted)" } else { "" }
+            );
+        }
+    }
+
+    let mut w = time_index::Window::new(start, end);
+    entries.iter().for_each(|e| w.push(e));
+
+    match (w.first, w.last) {
+        (Some(first), Some(last)) => eprintln!(
+            "Time window is in collections: {} -> {} (IDs: {} -> {}).",
+            first.collection,
+            last.collection,
+            first.first_id(),
+            last.end_id() - 1
+        ),
+        _ => eprintln!("No collections in time window."),
+    }
+
+    if pck.json {
+        println!("{}", json::to_string_pretty(&entries).unwrap());
+    }
+
+    if pck.note {
+        eprintln!("--note is not supported for the time index");
+    }
+
+    Ok(())
+}
+
+fn salvage(pck: SfyPack) -> anyhow::Result<()> {
+    let out = pck.salvage.as_ref().unwrap();
+
+    let files = if pck.file.is_dir() {
+        let mut files = std::fs::read_dir(&pck.file)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        files.retain(|f| f.is_file());
+        files.sort();
+        files
+    } else {
+        vec![pck.file.clone()]
+    };
+
+    let mut s = Salvage::new();
+
+    for f in &files {
+        eprintln!("Scanning: {:?}", f);
+        s.scan_file(f)?;
+    }
+
+    eprintln!(
+        "Recovered {} packages ({} found, including duplicates).",
+        s.len(),
+        s.found
+    );
+
+    if pck.list {
+        for p in s.values() {
+            eprintln!("{:?}", p);
+        }
+    }
+
+    let n = s.write(out)?;
+    eprintln!("Wrote {} collections to: {:?}", n, out);
+
+    Ok(())
+}
+
 /// Simulated note event
 #[derive(serde::Serialize)]
 pub struct AxlNote {
@@ -76,6 +288,7 @@ impl AxlNote {
             position_time: pck.position_time,
             lon: pck.lon,
             lat: pck.lat,
+            ..Default::default()
         };
 
         let payload = String::from_utf8(b64.as_slice().to_vec()).unwrap();
@@ -85,28 +298,50 @@ impl AxlNote {
 }
 
 struct Collection {
+    pub header: Option<format::CollectionHeader>,
     pub pcks: Vec<axl::AxlPacket>,
+
+    /// Records that are not packages, by storage ID.
+    pub records: Vec<(u32, Record)>,
 }
 
 impl Collection {
     pub fn from_file(p: impl AsRef<Path>) -> anyhow::Result<Collection> {
         let p = p.as_ref();
-        let mut b = std::fs::read(p)?;
+        let b = std::fs::read(p)?;
+
+        // Version 1 and 2 collections have no header, use the file extension.
+        let version = p
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(|e| e.parse().ok())
+            .unwrap_or(2);
+
+        Collection::from_bytes(b, version)
+    }
 
-        if (b.len() % axl::AXL_POSTCARD_SZ) != 0 {
+    pub fn from_bytes(mut b: Vec<u8>, version: u32) -> anyhow::Result<Collection> {
+        if b.starts_with(format::HEADER_MAGIC) {
+            return Collection::from_records(&b);
+        }
+
+        let sz = format::slot_sz(version);
+
+        if (b.len() % sz) != 0 {
             eprintln!("Warning, collection consists of non-integer number of packages.");
         }
 
-        let n = b.len() / axl::AXL_POSTCARD_SZ;
+        let n = b.len() / sz;
 
         eprintln!(
-            "Parsing {} bytes of packages into {} packages..",
+            "Parsing {} bytes of packages into {} packages (storage version: {})..",
             b.len(),
-            n
+            n,
+            version
         );
         let pcks = b
-            .chunks_exact_mut(axl::AXL_POSTCARD_SZ)
-            .filter_map(|p| match postcard::from_bytes_cobs(p) {
+            .chunks_exact_mut(sz)
+            .filter_map(|p| match format::decode_legacy(version, p) {
                 Ok(p) => Some(p),
                 Err(e) => {
                     eprintln!("failed to parse package: {:?}", e);
@@ -115,7 +350,44 @@ impl Collection {
             })
             .collect::<Vec<_>>();
 
-        Ok(Collection { pcks })
+        Ok(Collection {
+            header: None,
+            pcks,
+            records: Vec::new(),
+        })
+    }
+
+    /// Parse collection with header and records (storage version 3 and later).
+    fn from_records(b: &[u8]) -> anyhow::Result<Collection> {
+        let header = format::CollectionHeader::decode(b)
+            .map_err(|e| anyhow::anyhow!("invalid collection header: {:?}", e))?;
+
+        let mut end = format::HEADER_SZ;
+        let mut pcks = Vec::new();
+        let mut records = Vec::new();
+
+        for (h, r) in format::records(&b[format::HEADER_SZ..]) {
+            end += h.size() as usize;
+
+            match r {
+                Record::Axl(p) => pcks.push(p),
+                r => records.push((h.id, r)),
+            }
+        }
+
+        if end < b.len() {
+            eprintln!(
+                "failed to parse record at offset {}, ignoring the last {} bytes.",
+                end,
+                b.len() - end
+            );
+        }
+
+        Ok(Collection {
+            header: Some(header),
+            pcks,
+            records,
+        })
     }
 }
 
@@ -127,6 +399,301 @@ impl Deref for Collection {
     }
 }
 
+struct TimeIndex {
+    pub entries: Vec<TimeEntry>,
+}
+
+impl TimeIndex {
+    pub fn is_time_index(p: impl AsRef<Path>) -> bool {
+        p.as_ref()
+            .file_name()
+            .map(|f| f.to_string_lossy().to_uppercase() == time_index::TIME_INDEX)
+            .unwrap_or(false)
+    }
+
+    pub fn from_file(p: impl AsRef<Path>) -> anyhow::Result<TimeIndex> {
+        let b = std::fs::read(p.as_ref())?;
+        Ok(TimeIndex::from_bytes(&b))
+    }
+
+    /// Parse entries, skipping entries that are torn.
+    pub fn from_bytes(b: &[u8]) -> TimeIndex {
+        let entries = b
+            .chunks_exact(time_index::ENTRY_SZ)
+            .filter_map(TimeEntry::decode)
+            .collect::<Vec<_>>();
+
+        TimeIndex { entries }
+    }
+}
+
+impl Deref for TimeIndex {
+    type Target = Vec<TimeEntry>;
+
+    fn deref(&self) -> &Vec<TimeEntry> {
+        &self.entries
+    }
+}
+
+struct Manifests {
+    pub boots: Vec<Manifest>,
+}
+
+impl Manifests {
+    pub fn is_manifest(p: impl AsRef<Path>) -> bool {
+        p.as_ref()
+            .file_name()
+            .map(|f| f.to_string_lossy().to_uppercase() == manifest::MANIFEST_FNAME)
+            .unwrap_or(false)
+    }
+
+    pub fn from_file(p: impl AsRef<Path>) -> anyhow::Result<Manifests> {
+        let b = std::fs::read(p.as_ref())?;
+        Ok(Manifests::from_bytes(b))
+    }
+
+    /// Parse COBS separated records, skipping records that fail to parse.
+    pub fn from_bytes(mut b: Vec<u8>) -> Manifests {
+        let boots = b
+            .split_inclusive_mut(|c| *c == 0)
+            .filter_map(|r| match postcard::from_bytes_cobs(r) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    eprintln!("failed to parse manifest record: {:?}", e);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Manifests { boots }
+    }
+}
+
+impl Deref for Manifests {
+    type Target = Vec<Manifest>;
+
+    fn deref(&self) -> &Vec<Manifest> {
+        &self.boots
+    }
+}
+
+/// Packages recovered by scanning raw bytes for version 3 records and version 1 and 2 packages
+/// (COBS frames), regardless of the file system. Packages are deduplicated by storage ID and
+/// timestamp. Records that are not packages are not recovered.
+struct Salvage {
+    pub pcks: BTreeMap<(u32, i64), axl::AxlPacket>,
+
+    /// Collection headers that were found, by collection.
+    pub headers: BTreeMap<u32, format::CollectionHeader>,
+
+    /// Packages found, including duplicates.
+    pub found: usize,
+}
+
+/// Bytes scanned at the time, consecutive chunks overlap by the size of the largest package.
+const SALVAGE_CHUNK: usize = 64 * 1024 * 1024;
+
+/// Files start at a cluster, so version 1 and 2 slots start at a block.
+const SALVAGE_BLOCK: usize = 512;
+
+/// COBS frames shorter than this are not considered packages.
+const SALVAGE_MIN_FRAME: usize = 64;
+
+impl Salvage {
+    pub fn new() -> Salvage {
+        Salvage {
+            pcks: BTreeMap::new(),
+            headers: BTreeMap::new(),
+            found: 0,
+        }
+    }
+
+    /// Scan a file (or disk image) in chunks.
+    pub fn scan_file(&mut self, p: impl AsRef<Path>) -> anyhow::Result<()> {
+        let overlap = format::RECORD_MAX_SZ.max(format::slot_sz(2));
+        let mut f = File::open(p.as_ref())?;
+        let mut b = Vec::with_capacity(SALVAGE_CHUNK + overlap);
+
+        loop {
+            (&mut f)
+                .take((SALVAGE_CHUNK + overlap - b.len()) as u64)
+                .read_to_end(&mut b)?;
+
+            if b.len() < SALVAGE_CHUNK + overlap {
+                self.scan(&b);
+                return Ok(());
+            }
+
+            // Packages starting in the overlap are found in the next chunk.
+            self.scan_to(&b, SALVAGE_CHUNK);
+            b.drain(..SALVAGE_CHUNK);
+        }
+    }
+
+    /// Scan bytes for collection headers, version 3 records and version 1 and 2 packages. The
+    /// bytes must start at a block.
+    pub fn scan(&mut self, b: &[u8]) {
+        self.scan_to(b, b.len())
+    }
+
+    /// Scan for packages starting before `end`.
+    fn scan_to(&mut self, b: &[u8], end: usize) {
+        let mut i = 0;
+        while let Some(o) = b[i..end].windows(2).position(|w| {
+            w == format::RECORD_MAGIC
+                || w == format::PACKAGE_RECORD_MAGIC
+                || w == &format::HEADER_MAGIC[..2]
+        }) {
+            i += o;
+
+            if let Ok(h) = format::CollectionHeader::decode(&b[i..]) {
+                self.headers.entry(h.collection).or_insert(h);
+                i += format::HEADER_SZ;
+            } else if let Ok((h, _)) = format::verify_record(&b[i..]) {
+                if let Ok((_, Record::Axl(mut p))) = format::decode_record(&b[i..]) {
+                    p.storage_id = Some(h.id);
+                    self.push(p);
+                }
+                i += h.size() as usize;
+            } else {
+                i += 1;
+            }
+
+            if i >= end {
+                break;
+            }
+        }
+
+        // Version 1 and 2 packages are COBS frames at the start of a slot, followed by zeros.
+        for start in (0..end).step_by(SALVAGE_BLOCK) {
+            let slot = &b[start..b.len().min(start + format::slot_sz(2))];
+
+            let frame = match slot.iter().position(|c| *c == 0) {
+                Some(n) if n >= SALVAGE_MIN_FRAME => &slot[..=n],
+                _ => continue,
+            };
+
+            for version in [2, 1] {
+                match format::decode_legacy(version, &mut frame.to_vec()) {
+                    Ok(p) if plausible(&p) => {
+                        self.push(p);
+                        break;
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, p: axl::AxlPacket) {
+        if let Some(id) = p.storage_id {
+            self.found += 1;
+            self.pcks.entry((id, p.timestamp)).or_insert(p);
+        }
+    }
+
+    /// Write the recovered packages to new version 3 collections and indexes in `dir`, existing
+    /// files are not overwritten. Packages with the same ID (but different timestamps) can not be
+    /// stored in the same collection, only the first is kept. Returns the number of collections
+    /// written.
+    pub fn write(&self, dir: impl AsRef<Path>) -> anyhow::Result<usize> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+
+        let buoy = self
+            .headers
+            .values()
+            .next()
+            .map(|h| h.buoy.as_str())
+            .unwrap_or("unknown");
+
+        let mut collections: BTreeMap<u32, Vec<&axl::AxlPacket>> = BTreeMap::new();
+        for ((id, _), p) in &self.pcks {
+            let c = collections
+                .entry(id / storage::COLLECTION_SIZE)
+                .or_default();
+
+            if c.last().is_some_and(|l| l.storage_id == Some(*id)) {
+                eprintln!(
+                    "Package {} exists with different timestamps, keeping the first.",
+                    id
+                );
+                continue;
+            }
+
+            c.push(p);
+        }
+
+        let mut buf = [0u8; format::RECORD_MAX_SZ];
+
+        for (c, pcks) in &collections {
+            let header = match self.headers.get(c) {
+                Some(h) => h.clone(),
+                None => format::CollectionHeader::new(*c, buoy, "salvaged"),
+            };
+
+            let mut b = header
+                .encode()
+                .map_err(|e| anyhow::anyhow!("{:?}", e))?
+                .to_vec();
+
+            // Entries for missing packages are left invalid.
+            let mut index = vec![0xff; storage::COLLECTION_SIZE as usize * format::INDEX_ENTRY_SZ];
+            let mut end = 0;
+
+            for p in pcks {
+                let id = p.storage_id.unwrap();
+                let e = format::IndexEntry {
+                    id,
+                    offset: b.len() as u32,
+                };
+
+                let position = format::IndexEntry::position(id) as usize;
+                index[position..(position + format::INDEX_ENTRY_SZ)].copy_from_slice(&e.encode());
+                end = position + format::INDEX_ENTRY_SZ;
+
+                let r = format::encode_record(id, *p, &mut buf)
+                    .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+                b.extend_from_slice(r);
+            }
+
+            for (f, b) in [
+                (storage::collection_fname(*c), &b[..]),
+                (storage::index_fname(*c), &index[..end]),
+            ] {
+                File::options()
+                    .write(true)
+                    .create_new(true)
+                    .open(dir.join(f.as_str()))?
+                    .write_all(b)?;
+            }
+        }
+
+        Ok(collections.len())
+    }
+}
+
+impl Deref for Salvage {
+    type Target = BTreeMap<(u32, i64), axl::AxlPacket>;
+
+    fn deref(&self) -> &BTreeMap<(u32, i64), axl::AxlPacket> {
+        &self.pcks
+    }
+}
+
+/// Packages decoded from random bytes are unlikely to have a storage ID, a timestamp after the
+/// RTC is reset at boot (2020-01-01) and whole samples.
+fn plausible(p: &axl::AxlPacket) -> bool {
+    p.storage_id.is_some()
+        && (1_577_836_800_000..4_102_444_800_000).contains(&p.timestamp)
+        && !p.data.is_empty()
+        && p.data.len() % axl::SAMPLE_SZ == 0
+        && p.freq.is_finite()
+        && p.freq > 0.0
+        && p.lat.abs() <= 90.0
+        && p.lon.abs() <= 180.0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -138,9 +705,192 @@ mod tests {
 
         let c = Collection::from_file("tests/data/74.1").unwrap();
         println!("packages: {}", c.pcks.len());
+        assert!(c.header.is_none());
+        assert!(c.pcks.iter().all(|p| p.storage_version == Some(1)));
 
         // for p in c.pcks {
         //     println!("Package: {:?}", p);
         // }
     }
+
+    #[test]
+    fn read_records() {
+        use half::f16;
+        use sfy::storage::STORAGE_VERSION;
+
+        let mut b = format::CollectionHeader::new(3, "cain", "v0.1.0")
+            .encode()
+            .unwrap()
+            .to_vec();
+
+        let mut buf = [0u8; format::RECORD_MAX_SZ];
+        for id in 300..303 {
+            let p = axl::AxlPacket {
+                timestamp: 1_700_000_000_000 + id as i64 * 20_000,
+                position_time: 0,
+                lat: 60.0,
+                lon: 5.0,
+                freq: 52.0,
+                offset: 0,
+                storage_id: Some(id),
+                storage_version: Some(STORAGE_VERSION),
+                fix: None,
+                burst: None,
+                data: (0..300).map(|v| f16::from_f32(v as f32)).collect(),
+            };
+            b.extend_from_slice(format::encode_record(id, &p, &mut buf).unwrap());
+        }
+
+        let m = Record::Log(LogRecord::Message("low battery".into()));
+        b.extend_from_slice(format::encode_record(303, &m, &mut buf).unwrap());
+
+        // Torn write of the last record.
+        let torn = format::encode_record(304, &m, &mut buf).unwrap();
+        b.extend_from_slice(&torn[..torn.len() - 4]);
+
+        let c = Collection::from_bytes(b, 3).unwrap();
+        assert_eq!(c.header.as_ref().unwrap().buoy, "cain");
+        assert_eq!(c.len(), 3);
+        assert_eq!(c[1].storage_id, Some(301));
+        assert_eq!(c.records, [(303, m)]);
+    }
+
+    #[test]
+    fn salvage_image() {
+        use half::f16;
+
+        let mut buf = [0u8; format::RECORD_MAX_SZ];
+        let pck = |id: u32| axl::AxlPacket {
+            timestamp: 1_700_000_000_000 + id as i64 * 20_000,
+            position_time: 0,
+            lat: 60.0,
+            lon: 5.0,
+            freq: 52.0,
+            offset: 0,
+            storage_id: Some(id),
+            storage_version: Some(3),
+            fix: None,
+            burst: None,
+            data: (0..300).map(|v| f16::from_f32(v as f32)).collect(),
+        };
+
+        // Garbage, a version 1 collection, a version 3 collection with a stale copy of some of its
+        // records in a free cluster and a log record, and a torn record.
+        let mut b: Vec<u8> = (0..10_240u32).map(|v| (v * 7 % 251) as u8).collect();
+        let legacy = std::fs::read("tests/data/74.1").unwrap();
+        b.extend_from_slice(&legacy);
+        b.extend_from_slice(
+            &format::CollectionHeader::new(3, "cain", "v0.1.0")
+                .encode()
+                .unwrap(),
+        );
+        for id in (300..310).chain(302..305) {
+            b.extend_from_slice(format::encode_record(id, &pck(id), &mut buf).unwrap());
+        }
+        let m = Record::Log(LogRecord::Message("low battery".into()));
+        b.extend_from_slice(format::encode_record(310, &m, &mut buf).unwrap());
+        let torn = format::encode_record(310, &pck(310), &mut buf).unwrap();
+        b.extend_from_slice(&torn[..torn.len() - 10]);
+
+        let dir = std::env::temp_dir().join(format!("sfypack-salvage-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("card.img"), &b).unwrap();
+
+        let mut s = Salvage::new();
+        s.scan_file(dir.join("card.img")).unwrap();
+
+        let legacy = Collection::from_bytes(legacy, 1).unwrap();
+        assert!(legacy
+            .iter()
+            .all(|p| s.get(&(p.storage_id.unwrap(), p.timestamp)) == Some(p)));
+        assert_eq!(s.len(), legacy.len() + 10);
+        assert_eq!(s.found, legacy.len() + 13);
+        assert_eq!(s.headers[&3].buoy, "cain");
+
+        assert_eq!(s.write(&dir).unwrap(), 1 + legacy.len() / 100 + 1);
+
+        let c = Collection::from_file(dir.join("3.3")).unwrap();
+        assert_eq!(c.header.as_ref().unwrap().buoy, "cain");
+        assert_eq!(c.len(), 10);
+        assert_eq!(c[9], pck(309));
+
+        let index = std::fs::read(dir.join("3.I3")).unwrap();
+        let e = format::IndexEntry::decode(&index[format::IndexEntry::position(305) as usize..])
+            .unwrap();
+        assert_eq!(e.id, 305);
+
+        // Existing collections are not overwritten.
+        assert!(s.write(&dir).is_err());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn read_time_index() {
+        assert!(TimeIndex::is_time_index("TIME.I3"));
+        assert!(!TimeIndex::is_time_index("12.I3"));
+
+        let mut b = Vec::new();
+        for c in 3..6 {
+            let e = TimeEntry {
+                collection: c,
+                first: c as i64 * 2_000_000,
+                last: c as i64 * 2_000_000 + 1_980_000,
+                size: 620_000,
+                count: 100,
+                flags: TimeEntry::EVENT,
+            };
+            b.extend_from_slice(&e.encode());
+        }
+
+        let mut torn = b.clone();
+        torn[30] ^= 1;
+
+        let t = TimeIndex::from_bytes(&b);
+        assert_eq!(t.len(), 3);
+        assert_eq!(t[2].end_id(), 600);
+        assert_eq!(TimeIndex::from_bytes(&torn).len(), 2);
+    }
+
+    #[test]
+    fn read_manifest() {
+        use sfy::crash::{BootReport, ResetCause};
+
+        assert!(Manifests::is_manifest("MANIFEST.M1"));
+        assert!(!Manifests::is_manifest("SPOOL.Q2"));
+
+        let boot = BootReport {
+            boot_count: 1,
+            reset_cause: ResetCause(0x2),
+            reason: None,
+            pc: 0,
+            lr: 0,
+            task: None,
+            msg: heapless::String::new(),
+        };
+
+        let mut b = Vec::new();
+        let mut boots = Vec::new();
+        for (first_id, time) in [(0, 1_700_000_000), (1200, 1_700_100_000)] {
+            let mut m = Manifest::new(&boot, "v0.1.0");
+            m.first_id = first_id;
+            m.time = time;
+
+            let e: heapless::Vec<u8, { manifest::MANIFEST_POSTCARD_SZ }> =
+                postcard::to_vec_cobs(&m).unwrap();
+            b.extend_from_slice(&e);
+            boots.push(m);
+        }
+
+        // Torn record at the end.
+        b.extend_from_slice(&[1, 2, 3]);
+
+        let m = Manifests::from_bytes(b);
+        assert_eq!(m.boots, boots);
+
+        let j = json::to_string(&m.boots).unwrap();
+        assert!(j.contains("\"first_id\":1200"));
+        assert!(j.contains("\"imu_freq\":208.0"));
+    }
 }
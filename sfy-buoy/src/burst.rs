@@ -0,0 +1,203 @@
+//! Duty-cycled measurements (bursts).
+//!
+//! By default the IMU records continuously. With a burst schedule (see [`crate::config`]) the buoy
+//! records a window of `length` every `period`, aligned to UTC: a burst starts when the time is a
+//! multiple of the period (e.g. 20 minutes at the start of every hour). Between bursts the IMU is
+//! powered down.
+//!
+//! The IMU is started [`WARMUP`] seconds before the burst, so that the fusion and FIR filters
+//! have converged when the burst starts. Samples from the warm-up are discarded. Packages recorded
+//! during a burst are tagged with the burst ID, which is the start time of the burst (UTC,
+//! seconds).
+//!
+//! Until the time has been retrieved the buoy records continuously, since the bursts can not be
+//! aligned.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Default time between bursts (minutes), continuous recording.
+pub const BURST_PERIOD: u32 = 0;
+
+/// Default length of bursts (minutes).
+pub const BURST_LENGTH: u32 = 20;
+
+/// Time the IMU is started before a burst (seconds).
+pub const WARMUP: u32 = 60;
+
+/// Times before this (2020-01-01, seconds) are not valid, and bursts are not scheduled.
+pub const VALID_TIME: u32 = 1_577_836_800;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Schedule {
+    /// Time between the start of bursts (minutes), `0` for continuous recording.
+    pub period: u16,
+
+    /// Length of bursts (minutes).
+    pub length: u16,
+}
+
+/// The phase of the schedule at a given time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Phase {
+    /// Recording continuously.
+    Continuous,
+
+    /// Between bursts, the IMU is off.
+    Off,
+
+    /// The IMU is warming up for the burst starting at `burst`.
+    Warmup { burst: u32 },
+
+    /// Recording the burst starting at `burst`.
+    Record { burst: u32 },
+}
+
+impl Phase {
+    /// The IMU is running.
+    pub const fn active(&self) -> bool {
+        !matches!(self, Phase::Off)
+    }
+
+    /// Packages are recorded.
+    pub const fn records(&self) -> bool {
+        matches!(self, Phase::Continuous | Phase::Record { .. })
+    }
+
+    /// The ID of the burst being recorded.
+    pub const fn burst(&self) -> Option<u32> {
+        match self {
+            Phase::Record { burst } => Some(*burst),
+            _ => None,
+        }
+    }
+}
+
+impl Schedule {
+    pub const CONTINUOUS: Schedule = Schedule {
+        period: 0,
+        length: 0,
+    };
+
+    pub const fn is_continuous(&self) -> bool {
+        self.period == 0
+    }
+
+    /// Period and length packed in a `u32`, see [`Schedule::from_bits`].
+    pub const fn to_bits(&self) -> u32 {
+        (self.period as u32) << 16 | self.length as u32
+    }
+
+    pub const fn from_bits(v: u32) -> Schedule {
+        Schedule {
+            period: (v >> 16) as u16,
+            length: v as u16,
+        }
+    }
+
+    /// The phase at `now` (UTC, seconds).
+    pub fn phase(&self, now: u32) -> Phase {
+        if self.is_continuous() || now < VALID_TIME {
+            return Phase::Continuous;
+        }
+
+        let period = self.period as u32 * 60;
+        let start = now - now % period;
+
+        if now - start < self.length as u32 * 60 {
+            Phase::Record { burst: start }
+        } else if start + period - now <= WARMUP {
+            Phase::Warmup {
+                burst: start + period,
+            }
+        } else {
+            Phase::Off
+        }
+    }
+}
+
+/// The active schedule (see [`Schedule::to_bits`]). Set by the main loop when the configuration
+/// changes, and read by the IMU interrupt.
+static SCHEDULE: AtomicU32 = AtomicU32::new(Schedule::CONTINUOUS.to_bits());
+
+/// The active schedule.
+pub fn schedule() -> Schedule {
+    Schedule::from_bits(SCHEDULE.load(Ordering::Acquire))
+}
+
+pub fn set_schedule(s: Schedule) {
+    SCHEDULE.store(s.to_bits(), Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2022-06-01T00:00:00Z
+    const T0: u32 = 1_654_041_600;
+
+    #[test]
+    fn hourly_bursts() {
+        let s = Schedule {
+            period: 60,
+            length: 20,
+        };
+
+        assert_eq!(s.phase(T0), Phase::Record { burst: T0 });
+        assert_eq!(s.phase(T0 + 1199), Phase::Record { burst: T0 });
+        assert_eq!(s.phase(T0 + 1200), Phase::Off);
+        assert_eq!(s.phase(T0 + 3600 - 61), Phase::Off);
+        assert_eq!(s.phase(T0 + 3600 - 60), Phase::Warmup { burst: T0 + 3600 });
+        assert_eq!(s.phase(T0 + 3600), Phase::Record { burst: T0 + 3600 });
+
+        assert!(!s.phase(T0 + 2000).active());
+        assert!(s.phase(T0 + 3590).active());
+        assert!(!s.phase(T0 + 3590).records());
+        assert_eq!(s.phase(T0 + 3700).burst(), Some(T0 + 3600));
+    }
+
+    #[test]
+    fn short_gap() {
+        // The gap is shorter than the warm-up, the IMU is never turned off.
+        let s = Schedule {
+            period: 10,
+            length: 9,
+        };
+
+        for t in (T0..(T0 + 3600)).step_by(10) {
+            assert!(s.phase(t).active());
+        }
+    }
+
+    #[test]
+    fn continuous() {
+        assert_eq!(Schedule::CONTINUOUS.phase(T0), Phase::Continuous);
+
+        // No time yet.
+        let s = Schedule {
+            period: 60,
+            length: 10,
+        };
+        assert_eq!(s.phase(1200), Phase::Continuous);
+        assert_eq!(s.phase(1200).burst(), None);
+        assert!(s.phase(1200).records());
+    }
+
+    #[test]
+    fn schedule_bits() {
+        assert_eq!(Schedule::from_bits(0), Schedule::CONTINUOUS);
+
+        for s in [
+            Schedule::CONTINUOUS,
+            Schedule {
+                period: 24 * 60,
+                length: 60,
+            },
+            Schedule {
+                period: u16::MAX,
+                length: u16::MAX,
+            },
+        ] {
+            assert_eq!(Schedule::from_bits(s.to_bits()), s);
+        }
+    }
+}
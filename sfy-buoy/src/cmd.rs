@@ -0,0 +1,300 @@
+//! Remote commands.
+//!
+//! Commands are added to the inbound `cmd.qi` notefile on Notehub (see `sfydata ctrl cmd` in
+//! `sfy-processing`) and received by the Notecard on the next inbound sync. The main loop polls
+//! one command at the time, and every command is acknowledged with a note in `cmd.qo`.
+//!
+//! A command note has the body:
+//!
+//! ```json
+//! { "id": 12, "cmd": "resend", "start": 1000, "end": 1200 }
+//! ```
+//!
+//! where `id` is chosen by the sender and returned in the acknowledgement. Available commands:
+//!
+//! * `reboot`: reboot the buoy.
+//! * `reset-imu`: reset the IMU and filters.
+//! * `resend`: resend the stored packages with storage IDs `start` to `end` (inclusive).
+//! * `hub-mode`: set `mode` to `periodic` or `continuous`.
+//! * `gps-period`: set the GPS period to `value` seconds.
+//! * `flush`: sync the Notecard queues to Notehub now.
+//! * `start`: start recording.
+//! * `stop`: stop recording.
+//!
+//! Successfully executed commands are returned from [`check_command`] after they have been
+//! acknowledged, so that the firmware can carry out the parts that the library cannot (e.g.
+//! `reboot` and `reset-imu`, see [`crate::scheduler`]). A note that is not a valid command is
+//! rejected with `invalid-note`.
+
+use core::sync::atomic::Ordering;
+use embedded_hal::blocking::{
+    delay::DelayMs,
+    i2c::{Read, Write},
+};
+use heapless::String;
+
+use crate::config::ConfigField;
+use crate::note::Notecarrier;
+use blues_notecard::NoteError;
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum HubMode {
+    Periodic,
+    Continuous,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum Command {
+    Reboot,
+    ResetImu,
+    Resend { start: u32, end: u32 },
+    HubMode(HubMode),
+    GpsPeriod(u32),
+    Flush,
+    StartRecording,
+    StopRecording,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum CommandError {
+    Unknown,
+    MissingArgument,
+    InvalidArgument,
+    Failed,
+
+    /// The note in `cmd.qi` is not a valid command note.
+    InvalidNote,
+}
+
+impl CommandError {
+    pub const fn as_str(&self) -> &'static str {
+        use CommandError::*;
+
+        match self {
+            Unknown => "unknown-command",
+            MissingArgument => "missing-argument",
+            InvalidArgument => "invalid-argument",
+            Failed => "failed",
+            InvalidNote => "invalid-note",
+        }
+    }
+}
+
+/// Body of a note in `cmd.qi`.
+#[derive(Debug, Default, Clone, PartialEq, serde::Deserialize, defmt::Format)]
+pub struct CommandNote {
+    #[serde(default)]
+    pub id: Option<u32>,
+
+    pub cmd: String<16>,
+
+    #[serde(default)]
+    pub start: Option<u32>,
+
+    #[serde(default)]
+    pub end: Option<u32>,
+
+    #[serde(default)]
+    pub value: Option<u32>,
+
+    #[serde(default)]
+    pub mode: Option<String<16>>,
+}
+
+/// Body of a note in `cmd.qo`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, defmt::Format)]
+pub struct CommandAck {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
+    pub cmd: String<16>,
+    pub ok: bool,
+    pub result: &'static str,
+}
+
+impl TryFrom<&CommandNote> for Command {
+    type Error = CommandError;
+
+    fn try_from(n: &CommandNote) -> Result<Command, CommandError> {
+        use Command::*;
+        use CommandError::*;
+
+        match n.cmd.as_str() {
+            "reboot" => Ok(Reboot),
+            "reset-imu" => Ok(ResetImu),
+            "resend" => match (n.start, n.end) {
+                (Some(start), Some(end)) if start <= end => Ok(Resend { start, end }),
+                (Some(_), Some(_)) => Err(InvalidArgument),
+                _ => Err(MissingArgument),
+            },
+            "hub-mode" => match n.mode.as_ref().map(|m| m.as_str()) {
+                Some("periodic") => Ok(Command::HubMode(self::HubMode::Periodic)),
+                Some("continuous") => Ok(Command::HubMode(self::HubMode::Continuous)),
+                Some(_) => Err(InvalidArgument),
+                None => Err(MissingArgument),
+            },
+            "gps-period" => match n.value {
+                Some(v) if ConfigField::GpsPeriod.range().contains(&v) => Ok(GpsPeriod(v)),
+                Some(_) => Err(InvalidArgument),
+                None => Err(MissingArgument),
+            },
+            "flush" => Ok(Flush),
+            "start" => Ok(StartRecording),
+            "stop" => Ok(StopRecording),
+            _ => Err(Unknown),
+        }
+    }
+}
+
+impl CommandAck {
+    pub fn new(note: &CommandNote, result: Result<(), CommandError>) -> CommandAck {
+        CommandAck {
+            id: note.id,
+            cmd: note.cmd.clone(),
+            ok: result.is_ok(),
+            result: match result {
+                Ok(_) => "ok",
+                Err(e) => e.as_str(),
+            },
+        }
+    }
+}
+
+/// Receive, execute and acknowledge one command (if any). The command is returned if it was
+/// executed successfully.
+pub fn check_command<I2C: Read + Write>(
+    note: &mut Notecarrier<I2C>,
+    delay: &mut impl DelayMs<u16>,
+) -> Result<Option<Command>, NoteError> {
+    let n = match note.read_command(delay)? {
+        Some(Ok(n)) => n,
+        Some(Err(e)) => {
+            note.ack_command(&CommandAck::new(&CommandNote::default(), Err(e)), delay)?;
+            return Ok(None);
+        }
+        None => return Ok(None),
+    };
+
+    defmt::info!("Received command: {:?}", n);
+
+    let cmd = Command::try_from(&n);
+    let result = match cmd {
+        Ok(cmd) => execute(note, cmd, delay),
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
+        defmt::error!("Command {:?} failed: {:?}", n, e);
+    }
+
+    note.ack_command(&CommandAck::new(&n, result), delay)?;
+
+    match (cmd, result) {
+        (Ok(cmd), Ok(_)) => Ok(Some(cmd)),
+        _ => Ok(None),
+    }
+}
+
+fn execute<I2C: Read + Write>(
+    note: &mut Notecarrier<I2C>,
+    cmd: Command,
+    delay: &mut impl DelayMs<u16>,
+) -> Result<(), CommandError> {
+    use Command::*;
+
+    let r = match cmd {
+        Reboot | ResetImu => Ok(()), // handled by firmware after acknowledgement.
+        Resend { start, end } => note.write_request(delay, start, end),
+        Command::HubMode(mode) => {
+            let mut o = note.overrides();
+            o.continuous = Some(mode == self::HubMode::Continuous);
+            note.set_overrides(o, delay)
+        }
+        GpsPeriod(period) => {
+            let mut o = note.overrides();
+            o.gps_period = Some(period);
+            note.set_overrides(o, delay)
+        }
+        Flush => note
+            .hub()
+            .sync(delay, false)
+            .and_then(|r| r.wait(delay))
+            .map(|_| ()),
+        StartRecording => {
+            crate::RECORDING.store(true, Ordering::Release);
+            Ok(())
+        }
+        StopRecording => {
+            crate::RECORDING.store(false, Ordering::Release);
+            Ok(())
+        }
+    };
+
+    r.inspect_err(|e| defmt::error!("Failed to execute command: {:?}: {:?}", cmd, e))
+        .map_err(|_| CommandError::Failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<Command, CommandError> {
+        let n: CommandNote = serde_json::from_str(s).unwrap();
+        Command::try_from(&n)
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(parse(r#"{"cmd": "reboot"}"#), Ok(Command::Reboot));
+        assert_eq!(
+            parse(r#"{"id": 3, "cmd": "reset-imu"}"#),
+            Ok(Command::ResetImu)
+        );
+        assert_eq!(
+            parse(r#"{"cmd": "resend", "start": 10, "end": 20}"#),
+            Ok(Command::Resend { start: 10, end: 20 })
+        );
+        assert_eq!(
+            parse(r#"{"cmd": "hub-mode", "mode": "continuous"}"#),
+            Ok(Command::HubMode(HubMode::Continuous))
+        );
+        assert_eq!(
+            parse(r#"{"cmd": "gps-period", "value": 600}"#),
+            Ok(Command::GpsPeriod(600))
+        );
+        assert_eq!(parse(r#"{"cmd": "flush"}"#), Ok(Command::Flush));
+        assert_eq!(parse(r#"{"cmd": "start"}"#), Ok(Command::StartRecording));
+        assert_eq!(parse(r#"{"cmd": "stop"}"#), Ok(Command::StopRecording));
+    }
+
+    #[test]
+    fn parse_bad_commands() {
+        assert_eq!(parse(r#"{"cmd": "explode"}"#), Err(CommandError::Unknown));
+        assert_eq!(
+            parse(r#"{"cmd": "resend", "start": 10}"#),
+            Err(CommandError::MissingArgument)
+        );
+        assert_eq!(
+            parse(r#"{"cmd": "resend", "start": 20, "end": 10}"#),
+            Err(CommandError::InvalidArgument)
+        );
+        assert_eq!(
+            parse(r#"{"cmd": "hub-mode", "mode": "sometimes"}"#),
+            Err(CommandError::InvalidArgument)
+        );
+        assert_eq!(
+            parse(r#"{"cmd": "gps-period", "value": 1}"#),
+            Err(CommandError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn ack() {
+        let n: CommandNote = serde_json::from_str(r#"{"id": 7, "cmd": "explode"}"#).unwrap();
+        let a = CommandAck::new(&n, Command::try_from(&n).map(|_| ()));
+
+        assert_eq!(
+            serde_json::to_string(&a).unwrap(),
+            r#"{"id":7,"cmd":"explode","ok":false,"result":"unknown-command"}"#
+        );
+    }
+}
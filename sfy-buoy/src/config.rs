@@ -0,0 +1,586 @@
+//! Run-time configuration of the buoy.
+//!
+//! The configuration can be changed remotely by setting environment variables on Notehub (for
+//! the device, fleet or project). The Notecard caches the variables, and they are read with
+//! `env.get` at boot and then every [`CONFIG_CHECK_INTERVAL`]. The variables are validated as a
+//! whole: if any of them is invalid the current configuration is kept. The active configuration
+//! is reported back in `config.qo` whenever it changes.
+//!
+//! Environment variables (all values are strings on Notehub):
+//!
+//! * `sfy_gps_period`: GPS sampling period in seconds (see [`note::GPS_PERIOD`]).
+//! * `sfy_sync_storage`: Initiate sync when Notecard storage is above this percentage (see
+//!   [`note::NOTECARD_STORAGE_INIT_SYNC`]).
+//! * `sfy_sync_outbound`: Max time between outbound syncs in minutes (see
+//!   [`note::NOTECARD_OUTBOUND_PERIOD`]).
+//! * `sfy_storage_max`: Do not add more notes when Notecard storage is above this percentage (see
+//!   [`note::NOTECARD_STORAGE_MAX`]).
+//! * `sfy_location_interval`: Interval in seconds between retrieving location and time from the
+//!   Notecard (see [`LOCATION_INTERVAL`]).
+//! * `sfy_power_periodic`, `sfy_power_summary`, `sfy_power_survival`: Voltage thresholds in mV
+//!   for the power modes (see [`crate::power`]). The thresholds must be decreasing.
+//! * `sfy_burst_period`, `sfy_burst_length`: Record bursts of `length` minutes every `period`
+//!   minutes, aligned to UTC (see [`crate::burst`]). The period must divide a day and be longer
+//!   than the length, `0` records continuously.
+//! * `sfy_retention_max`: Max space used by collections on the SD-card in MB, `0` uses most of the
+//!   card (see [`crate::retention`]).
+//! * `sfy_retention_protect`: Collections with any of these quality flags are not deleted when the
+//!   SD-card fills up (see [`crate::storage::time_index::TimeEntry`]).
+//!
+//! The configuration is built in layers: the defaults, the environment variables (unset variables
+//! keep their default, so removing a variable on Notehub reverts it), the [`Overrides`] set by
+//! remote commands, and finally the limits of the power mode (see [`Config::limited`]).
+
+use core::ops::RangeInclusive;
+use heapless::String;
+
+use crate::burst::{self, Schedule};
+use crate::note;
+use crate::power::{self, PowerMode};
+use crate::retention::{self, Policy};
+
+/// Interval between checking for updated environment variables (ms).
+pub const CONFIG_CHECK_INTERVAL: i64 = 10 * 60_000;
+
+/// Default interval between retrieving location and time from the Notecard (seconds).
+pub const LOCATION_INTERVAL: u32 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, defmt::Format)]
+pub struct Config {
+    /// GPS period (seconds).
+    pub gps_period: u32,
+
+    /// Initiate sync when Notecard storage is above this percentage.
+    pub sync_storage: u32,
+
+    /// Max time between outbound syncs (minutes).
+    pub sync_outbound: u32,
+
+    /// Stop adding notes when Notecard storage is above this percentage.
+    pub storage_max: u32,
+
+    /// Interval between retrieving location and time (seconds).
+    pub location_interval: u32,
+
+    /// Keep the modem continuously connected, rather than syncing periodically. This is
+    /// defaulted by the `continuous` feature, and can only be changed with a command (see
+    /// [`crate::cmd`]).
+    pub continuous: bool,
+
+    /// Below this voltage (mV) the modem is not kept continuously connected.
+    pub power_periodic: u32,
+
+    /// Below this voltage (mV) only summaries are sent.
+    pub power_summary: u32,
+
+    /// Below this voltage (mV) nothing is recorded.
+    pub power_survival: u32,
+
+    /// Time between bursts (minutes), `0` for continuous recording.
+    pub burst_period: u32,
+
+    /// Length of bursts (minutes).
+    pub burst_length: u32,
+
+    /// Max space used by collections on the SD-card (MB), `0` for no limit other than the card.
+    pub retention_max: u32,
+
+    /// Quality flags of collections that are not deleted when the SD-card fills up.
+    pub retention_protect: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            gps_period: note::GPS_PERIOD,
+            sync_storage: note::NOTECARD_STORAGE_INIT_SYNC,
+            sync_outbound: note::NOTECARD_OUTBOUND_PERIOD,
+            storage_max: note::NOTECARD_STORAGE_MAX,
+            location_interval: LOCATION_INTERVAL,
+            continuous: cfg!(feature = "continuous"),
+            power_periodic: power::POWER_PERIODIC,
+            power_summary: power::POWER_SUMMARY,
+            power_survival: power::POWER_SURVIVAL,
+            burst_period: burst::BURST_PERIOD,
+            burst_length: burst::BURST_LENGTH,
+            retention_max: retention::RETENTION_MAX,
+            retention_protect: retention::RETENTION_PROTECT,
+        }
+    }
+}
+
+/// Environment variables as returned by `env.get`. Unset or empty variables keep their default
+/// value.
+#[derive(Debug, Default, Clone, PartialEq, serde::Deserialize, defmt::Format)]
+pub struct EnvVars {
+    #[serde(default)]
+    pub sfy_gps_period: Option<String<12>>,
+
+    #[serde(default)]
+    pub sfy_sync_storage: Option<String<12>>,
+
+    #[serde(default)]
+    pub sfy_sync_outbound: Option<String<12>>,
+
+    #[serde(default)]
+    pub sfy_storage_max: Option<String<12>>,
+
+    #[serde(default)]
+    pub sfy_location_interval: Option<String<12>>,
+
+    #[serde(default)]
+    pub sfy_power_periodic: Option<String<12>>,
+
+    #[serde(default)]
+    pub sfy_power_summary: Option<String<12>>,
+
+    #[serde(default)]
+    pub sfy_power_survival: Option<String<12>>,
+
+    #[serde(default)]
+    pub sfy_burst_period: Option<String<12>>,
+
+    #[serde(default)]
+    pub sfy_burst_length: Option<String<12>>,
+
+    #[serde(default)]
+    pub sfy_retention_max: Option<String<12>>,
+
+    #[serde(default)]
+    pub sfy_retention_protect: Option<String<12>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum ConfigField {
+    GpsPeriod,
+    SyncStorage,
+    SyncOutbound,
+    StorageMax,
+    LocationInterval,
+    PowerPeriodic,
+    PowerSummary,
+    PowerSurvival,
+    BurstPeriod,
+    BurstLength,
+    RetentionMax,
+    RetentionProtect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum ConfigError {
+    /// The value could not be parsed as an integer.
+    Parse(ConfigField),
+
+    /// The value is outside the allowed range.
+    Range(ConfigField, u32),
+
+    /// `sfy_sync_storage` must be less than `sfy_storage_max`, otherwise the Notecard will never
+    /// be synced before it refuses new notes.
+    SyncAboveMax { sync_storage: u32, storage_max: u32 },
+
+    /// The power thresholds must be decreasing: `periodic > summary > survival`.
+    PowerThresholds {
+        periodic: u32,
+        summary: u32,
+        survival: u32,
+    },
+
+    /// The burst period must divide a day and be longer than the burst length.
+    Burst { period: u32, length: u32 },
+}
+
+impl ConfigField {
+    pub const fn name(&self) -> &'static str {
+        use ConfigField::*;
+
+        match self {
+            GpsPeriod => "sfy_gps_period",
+            SyncStorage => "sfy_sync_storage",
+            SyncOutbound => "sfy_sync_outbound",
+            StorageMax => "sfy_storage_max",
+            LocationInterval => "sfy_location_interval",
+            PowerPeriodic => "sfy_power_periodic",
+            PowerSummary => "sfy_power_summary",
+            PowerSurvival => "sfy_power_survival",
+            BurstPeriod => "sfy_burst_period",
+            BurstLength => "sfy_burst_length",
+            RetentionMax => "sfy_retention_max",
+            RetentionProtect => "sfy_retention_protect",
+        }
+    }
+
+    /// Allowed values.
+    pub const fn range(&self) -> RangeInclusive<u32> {
+        use ConfigField::*;
+
+        match self {
+            GpsPeriod => 10..=(24 * 3600),
+            SyncStorage => 1..=95,
+            SyncOutbound => 1..=(24 * 60),
+            StorageMax => 10..=95,
+            LocationInterval => 10..=(24 * 3600),
+            PowerPeriodic | PowerSummary | PowerSurvival => 2000..=6000,
+            BurstPeriod => 0..=(24 * 60),
+            BurstLength => 1..=(24 * 60),
+            RetentionMax => 0..=0xff_ffff,
+            RetentionProtect => 0..=0xff,
+        }
+    }
+
+    fn parse(&self, v: &Option<String<12>>) -> Result<Option<u32>, ConfigError> {
+        match v.as_ref().map(|v| v.trim()) {
+            None | Some("") => Ok(None),
+            Some(v) => {
+                let v = v.parse::<u32>().map_err(|_| ConfigError::Parse(*self))?;
+
+                if self.range().contains(&v) {
+                    Ok(Some(v))
+                } else {
+                    Err(ConfigError::Range(*self, v))
+                }
+            }
+        }
+    }
+}
+
+/// Configuration set by remote commands (see [`crate::cmd`]). The overrides are applied on top of
+/// the environment variables, and are kept when the variables are updated.
+#[derive(Debug, Default, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Overrides {
+    pub gps_period: Option<u32>,
+    pub continuous: Option<bool>,
+}
+
+impl Overrides {
+    pub fn apply(&self, config: Config) -> Config {
+        let mut c = config;
+
+        if let Some(v) = self.gps_period {
+            c.gps_period = v;
+        }
+
+        if let Some(v) = self.continuous {
+            c.continuous = v;
+        }
+
+        c
+    }
+}
+
+impl Config {
+    /// The configuration from the environment variables, variables that are not set take their
+    /// default value.
+    pub fn from_vars(vars: &EnvVars) -> Result<Config, ConfigError> {
+        Config::default().with_vars(vars)
+    }
+
+    /// Returns a new configuration with the environment variables applied on top of this
+    /// configuration. The environment variables are validated, and if any of them fail the whole
+    /// set is rejected.
+    pub fn with_vars(&self, vars: &EnvVars) -> Result<Config, ConfigError> {
+        use ConfigField::*;
+
+        let mut c = *self;
+
+        if let Some(v) = GpsPeriod.parse(&vars.sfy_gps_period)? {
+            c.gps_period = v;
+        }
+
+        if let Some(v) = SyncStorage.parse(&vars.sfy_sync_storage)? {
+            c.sync_storage = v;
+        }
+
+        if let Some(v) = SyncOutbound.parse(&vars.sfy_sync_outbound)? {
+            c.sync_outbound = v;
+        }
+
+        if let Some(v) = StorageMax.parse(&vars.sfy_storage_max)? {
+            c.storage_max = v;
+        }
+
+        if let Some(v) = LocationInterval.parse(&vars.sfy_location_interval)? {
+            c.location_interval = v;
+        }
+
+        if let Some(v) = PowerPeriodic.parse(&vars.sfy_power_periodic)? {
+            c.power_periodic = v;
+        }
+
+        if let Some(v) = PowerSummary.parse(&vars.sfy_power_summary)? {
+            c.power_summary = v;
+        }
+
+        if let Some(v) = PowerSurvival.parse(&vars.sfy_power_survival)? {
+            c.power_survival = v;
+        }
+
+        if let Some(v) = BurstPeriod.parse(&vars.sfy_burst_period)? {
+            c.burst_period = v;
+        }
+
+        if let Some(v) = BurstLength.parse(&vars.sfy_burst_length)? {
+            c.burst_length = v;
+        }
+
+        if let Some(v) = RetentionMax.parse(&vars.sfy_retention_max)? {
+            c.retention_max = v;
+        }
+
+        if let Some(v) = RetentionProtect.parse(&vars.sfy_retention_protect)? {
+            c.retention_protect = v;
+        }
+
+        if c.sync_storage >= c.storage_max {
+            return Err(ConfigError::SyncAboveMax {
+                sync_storage: c.sync_storage,
+                storage_max: c.storage_max,
+            });
+        }
+
+        if c.power_periodic <= c.power_summary || c.power_summary <= c.power_survival {
+            return Err(ConfigError::PowerThresholds {
+                periodic: c.power_periodic,
+                summary: c.power_summary,
+                survival: c.power_survival,
+            });
+        }
+
+        if c.burst_period > 0
+            && ((24 * 60) % c.burst_period != 0 || c.burst_length >= c.burst_period)
+        {
+            return Err(ConfigError::Burst {
+                period: c.burst_period,
+                length: c.burst_length,
+            });
+        }
+
+        Ok(c)
+    }
+
+    /// The configuration limited by a power mode (see [`crate::power`]).
+    pub fn limited(&self, mode: PowerMode) -> Config {
+        let mut c = *self;
+
+        if mode > PowerMode::Continuous {
+            c.continuous = false;
+        }
+
+        if mode >= PowerMode::Summary {
+            c.sync_outbound = c.sync_outbound.max(power::SUMMARY_SYNC_OUTBOUND);
+        }
+
+        if mode >= PowerMode::Survival {
+            c.gps_period = c.gps_period.max(power::SURVIVAL_GPS_PERIOD);
+            c.sync_outbound = c.sync_outbound.max(power::SURVIVAL_SYNC_OUTBOUND);
+        }
+
+        c
+    }
+
+    /// The GPS period has changed, the Notecard location mode needs to be updated.
+    pub fn location_mode_changed(&self, old: &Config) -> bool {
+        self.gps_period != old.gps_period || self.continuous != old.continuous
+    }
+
+    /// The hub settings (e.g. outbound period) have changed, `hub.set` needs to be re-issued.
+    pub fn hub_changed(&self, old: &Config) -> bool {
+        self.sync_outbound != old.sync_outbound || self.continuous != old.continuous
+    }
+
+    /// The burst schedule (see [`crate::burst`]).
+    pub fn schedule(&self) -> Schedule {
+        Schedule {
+            period: self.burst_period as u16,
+            length: self.burst_length as u16,
+        }
+    }
+
+    /// The retention policy of the SD-card (see [`crate::retention`]).
+    pub fn retention(&self) -> Policy {
+        Policy {
+            max: self.retention_max,
+            protect: self.retention_protect as u8,
+        }
+    }
+
+    /// Location interval in ms.
+    pub fn location_interval_ms(&self) -> i64 {
+        self.location_interval as i64 * 1000
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(s: &str) -> EnvVars {
+        serde_json::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn defaults() {
+        let c = Config::default();
+        assert_eq!(c.gps_period, note::GPS_PERIOD);
+        assert_eq!(c.sync_storage, note::NOTECARD_STORAGE_INIT_SYNC);
+        assert_eq!(c.storage_max, 75);
+        assert_eq!(c.sync_outbound, 20);
+        assert_eq!(c.location_interval_ms(), 60_000);
+
+        // No variables set, nothing changes.
+        assert_eq!(c.with_vars(&EnvVars::default()), Ok(c));
+    }
+
+    #[test]
+    fn parse_env_get_body() {
+        let v = vars(r#"{"sfy_gps_period": "600", "sfy_sync_outbound": " 60 ", "other": "x"}"#);
+
+        let c = Config::default().with_vars(&v).unwrap();
+        assert_eq!(c.gps_period, 600);
+        assert_eq!(c.sync_outbound, 60);
+        assert_eq!(c.sync_storage, note::NOTECARD_STORAGE_INIT_SYNC);
+
+        assert!(c.location_mode_changed(&Config::default()));
+        assert!(c.hub_changed(&Config::default()));
+    }
+
+    #[test]
+    fn unset_reverts_to_default() {
+        let v = vars(r#"{"sfy_gps_period": "600", "sfy_burst_period": "60"}"#);
+        let c = Config::from_vars(&v).unwrap();
+        assert_eq!(c.gps_period, 600);
+        assert_eq!(c.burst_period, 60);
+
+        let v = vars(r#"{"sfy_gps_period": "600"}"#);
+        let c = Config::from_vars(&v).unwrap();
+        assert_eq!(c.gps_period, 600);
+        assert!(c.schedule().is_continuous());
+
+        assert_eq!(
+            Config::from_vars(&EnvVars::default()),
+            Ok(Config::default())
+        );
+    }
+
+    #[test]
+    fn overrides() {
+        let o = Overrides {
+            gps_period: Some(300),
+            continuous: Some(true),
+        };
+
+        let v = vars(r#"{"sfy_gps_period": "600", "sfy_sync_outbound": "60"}"#);
+        let c = o.apply(Config::from_vars(&v).unwrap());
+        assert_eq!(c.gps_period, 300);
+        assert!(c.continuous);
+        assert_eq!(c.sync_outbound, 60);
+
+        // The power mode still limits the overrides.
+        let l = c.limited(PowerMode::Survival);
+        assert!(!l.continuous);
+        assert_eq!(l.gps_period, power::SURVIVAL_GPS_PERIOD.max(300));
+
+        assert_eq!(Overrides::default().apply(c), c);
+    }
+
+    #[test]
+    fn empty_is_unset() {
+        let v = vars(r#"{"sfy_gps_period": ""}"#);
+        assert_eq!(Config::default().with_vars(&v), Ok(Config::default()));
+    }
+
+    #[test]
+    fn reject_invalid() {
+        let v = vars(r#"{"sfy_gps_period": "600", "sfy_location_interval": "sixty"}"#);
+        assert_eq!(
+            Config::default().with_vars(&v),
+            Err(ConfigError::Parse(ConfigField::LocationInterval))
+        );
+
+        let v = vars(r#"{"sfy_gps_period": "1"}"#);
+        assert_eq!(
+            Config::default().with_vars(&v),
+            Err(ConfigError::Range(ConfigField::GpsPeriod, 1))
+        );
+
+        let v = vars(r#"{"sfy_sync_storage": "80"}"#);
+        assert_eq!(
+            Config::default().with_vars(&v),
+            Err(ConfigError::SyncAboveMax {
+                sync_storage: 80,
+                storage_max: 75
+            })
+        );
+
+        let v = vars(r#"{"sfy_sync_storage": "80", "sfy_storage_max": "90"}"#);
+        assert!(Config::default().with_vars(&v).is_ok());
+
+        let v = vars(r#"{"sfy_power_summary": "3700"}"#);
+        assert_eq!(
+            Config::default().with_vars(&v),
+            Err(ConfigError::PowerThresholds {
+                periodic: 3600,
+                summary: 3700,
+                survival: 3200
+            })
+        );
+    }
+
+    #[test]
+    fn burst_schedule() {
+        assert!(Config::default().schedule().is_continuous());
+
+        let v = vars(r#"{"sfy_burst_period": "60", "sfy_burst_length": "20"}"#);
+        let c = Config::default().with_vars(&v).unwrap();
+        assert_eq!(
+            c.schedule(),
+            Schedule {
+                period: 60,
+                length: 20
+            }
+        );
+
+        // Back to continuous.
+        let v = vars(r#"{"sfy_burst_period": "0"}"#);
+        assert!(c.with_vars(&v).unwrap().schedule().is_continuous());
+
+        // Not aligned to the day.
+        let v = vars(r#"{"sfy_burst_period": "50"}"#);
+        assert_eq!(
+            Config::default().with_vars(&v),
+            Err(ConfigError::Burst {
+                period: 50,
+                length: 20
+            })
+        );
+
+        let v = vars(r#"{"sfy_burst_period": "30", "sfy_burst_length": "30"}"#);
+        assert!(Config::default().with_vars(&v).is_err());
+    }
+
+    #[test]
+    fn retention_policy() {
+        assert_eq!(
+            Config::default().retention(),
+            Policy {
+                max: retention::RETENTION_MAX,
+                protect: retention::RETENTION_PROTECT as u8
+            }
+        );
+
+        let v = vars(r#"{"sfy_retention_max": "16000", "sfy_retention_protect": "12"}"#);
+        let c = Config::default().with_vars(&v).unwrap();
+        assert_eq!(
+            c.retention(),
+            Policy {
+                max: 16000,
+                protect: 0b1100
+            }
+        );
+
+        let v = vars(r#"{"sfy_retention_protect": "256"}"#);
+        assert_eq!(
+            Config::default().with_vars(&v),
+            Err(ConfigError::Range(ConfigField::RetentionProtect, 256))
+        );
+    }
+}
@@ -0,0 +1,375 @@
+//! Crash and reset records kept across reboots.
+//!
+//! The [`CrashRecord`] is placed by the firmware in RAM that is not initialized at start-up, so
+//! it survives a software or watchdog reset, but not a loss of power. Before the firmware resets
+//! the device it records the [`ResetReason`], and for panics and hard faults the message or the
+//! exception frame. The watchdog interrupt records the task that stopped checking in. At start-up
+//! the record of the previous boot is taken out as a [`BootReport`] together with the hardware
+//! reset cause, and reported in the startup message and as
+//! [`Event::Boot`](crate::log::Event::Boot).
+//!
+//! The record is protected by a magic number and a checksum. A record that does not match (e.g.
+//! after power-on, or if the bootloader has used the memory) is cleared, and the boot counter
+//! starts over.
+
+use core::fmt;
+use heapless::String;
+
+use crate::log::{Event, ResetReason};
+use crate::watchdog::Task;
+
+pub const CRASH_MAGIC: u32 = 0x5346_5943;
+
+/// Max length of panic message kept.
+pub const CRASH_MSG_SZ: usize = 128;
+
+#[repr(C)]
+pub struct CrashRecord {
+    magic: u32,
+
+    /// Number of boots since the record was cleared.
+    boot_count: u32,
+
+    /// [`ResetReason`] of the last reset initiated by the firmware, `0` if none.
+    reason: u32,
+
+    /// Program counter and link register of hard fault.
+    pc: u32,
+    lr: u32,
+
+    /// [`Task`] that caused a watchdog reset, `0` if none.
+    task: u32,
+
+    msg_len: u32,
+    msg: [u8; CRASH_MSG_SZ],
+
+    checksum: u32,
+}
+
+/// Report of the previous boot.
+#[derive(Debug, Clone, PartialEq, defmt::Format)]
+pub struct BootReport {
+    /// Number of this boot since the record was cleared (`1` is the first boot).
+    pub boot_count: u32,
+
+    /// Hardware reset cause, see [`ResetCause`].
+    pub reset_cause: ResetCause,
+
+    /// Reason of the reset, if initiated by the firmware.
+    pub reason: Option<ResetReason>,
+
+    /// Exception frame of hard fault (`0` otherwise).
+    pub pc: u32,
+    pub lr: u32,
+
+    /// Task that caused a watchdog reset.
+    pub task: Option<Task>,
+
+    /// Panic message (empty otherwise).
+    pub msg: String<CRASH_MSG_SZ>,
+}
+
+impl Default for CrashRecord {
+    fn default() -> Self {
+        CrashRecord::new()
+    }
+}
+
+impl CrashRecord {
+    pub const fn new() -> CrashRecord {
+        CrashRecord {
+            magic: 0,
+            boot_count: 0,
+            reason: 0,
+            pc: 0,
+            lr: 0,
+            task: 0,
+            msg_len: 0,
+            msg: [0; CRASH_MSG_SZ],
+            checksum: 0,
+        }
+    }
+
+    /// FNV-1a of all fields except the checksum.
+    fn calculate_checksum(&self) -> u32 {
+        let words = [
+            self.magic,
+            self.boot_count,
+            self.reason,
+            self.pc,
+            self.lr,
+            self.task,
+            self.msg_len,
+        ];
+
+        words
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .chain(self.msg.iter().copied())
+            .fold(0x811c_9dc5, |h, b| (h ^ b as u32).wrapping_mul(0x0100_0193))
+    }
+
+    fn seal(&mut self) {
+        self.checksum = self.calculate_checksum();
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.magic == CRASH_MAGIC
+            && self.msg_len as usize <= CRASH_MSG_SZ
+            && self.checksum == self.calculate_checksum()
+    }
+
+    /// Take out the report of the previous boot and start a new one. Should be called once at
+    /// start-up, `reset_cause` is the hardware reset cause register.
+    pub fn boot(&mut self, reset_cause: u32) -> BootReport {
+        if !self.is_valid() {
+            *self = CrashRecord::new();
+            self.magic = CRASH_MAGIC;
+        }
+
+        self.boot_count = self.boot_count.wrapping_add(1);
+
+        let mut msg = String::new();
+        if let Ok(m) = core::str::from_utf8(&self.msg[..self.msg_len as usize]) {
+            msg.push_str(m).ok();
+        }
+
+        let report = BootReport {
+            boot_count: self.boot_count,
+            reset_cause: ResetCause(reset_cause),
+            reason: reason_from_code(self.reason),
+            pc: self.pc,
+            lr: self.lr,
+            task: Task::from_code(self.task),
+            msg,
+        };
+
+        self.reason = 0;
+        self.pc = 0;
+        self.lr = 0;
+        self.task = 0;
+        self.msg_len = 0;
+        self.msg = [0; CRASH_MSG_SZ];
+        self.seal();
+
+        report
+    }
+
+    /// Record a reset initiated by the firmware.
+    pub fn reset(&mut self, reason: ResetReason) {
+        self.reason = reason as u32;
+        self.seal();
+    }
+
+    /// Record a panic, the message is truncated to [`CRASH_MSG_SZ`].
+    pub fn panic(&mut self, msg: &str) {
+        let mut n = msg.len().min(CRASH_MSG_SZ);
+        while !msg.is_char_boundary(n) {
+            n -= 1;
+        }
+
+        self.msg[..n].copy_from_slice(&msg.as_bytes()[..n]);
+        self.msg_len = n as u32;
+        self.reset(ResetReason::Panic);
+    }
+
+    /// Record a hard fault.
+    pub fn hard_fault(&mut self, pc: u32, lr: u32) {
+        self.pc = pc;
+        self.lr = lr;
+        self.reset(ResetReason::HardFault);
+    }
+
+    /// Record a watchdog reset caused by `task`. Only the first culprit is kept.
+    pub fn watchdog(&mut self, task: Task) {
+        if self.reason != ResetReason::Watchdog as u32 {
+            self.task = task as u32;
+            self.reset(ResetReason::Watchdog);
+        }
+    }
+}
+
+fn reason_from_code(code: u32) -> Option<ResetReason> {
+    use ResetReason::*;
+
+    [Command, MainLoop, Imu, Panic, HardFault, Watchdog]
+        .into_iter()
+        .find(|r| *r as u32 == code)
+}
+
+impl BootReport {
+    /// Event reporting this boot.
+    pub fn event(&self) -> Event {
+        Event::Boot {
+            count: self.boot_count,
+            reason: self.reason.map(|r| r as u8).unwrap_or(0),
+            cause: self.reset_cause.0,
+        }
+    }
+
+    /// Event reporting the culprit of a watchdog reset.
+    pub fn watchdog_event(&self) -> Option<Event> {
+        self.task
+            .map(|task| Event::WatchdogReset { task: task as u8 })
+    }
+}
+
+impl fmt::Display for BootReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "boot: {}, reset cause: {}, last reset: ",
+            self.boot_count, self.reset_cause
+        )?;
+
+        match self.reason {
+            None => write!(f, "unknown"),
+            Some(ResetReason::HardFault) => {
+                write!(
+                    f,
+                    "hard fault (pc: {:#010x}, lr: {:#010x})",
+                    self.pc, self.lr
+                )
+            }
+            Some(ResetReason::Panic) => write!(f, "panic ({})", self.msg),
+            Some(ResetReason::Watchdog) => match self.task {
+                Some(t) => write!(f, "watchdog (task: {:?})", t),
+                None => write!(f, "watchdog"),
+            },
+            Some(r) => write!(f, "{:?}", r),
+        }
+    }
+}
+
+/// Hardware reset cause, the `STAT` register of the Apollo3 reset generator (`RSTGEN`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ResetCause(pub u32);
+
+impl ResetCause {
+    const NAMES: [&'static str; 7] = ["EXT", "POR", "BOD", "SWPOR", "SWPOI", "DBG", "WDT"];
+}
+
+impl fmt::Display for ResetCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)?;
+
+        let mut names = Self::NAMES
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.0 & (1 << i) != 0)
+            .map(|(_, n)| n);
+
+        if let Some(n) = names.next() {
+            write!(f, " ({}", n)?;
+            for n in names {
+                write!(f, "|{}", n)?;
+            }
+            write!(f, ")")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn boot_counter() {
+        // Uninitialized memory.
+        let mut c = CrashRecord::new();
+        c.msg_len = 1_000;
+        assert!(!c.is_valid());
+
+        let r = c.boot(0b10);
+        assert!(c.is_valid());
+        assert_eq!(r.boot_count, 1);
+        assert_eq!(r.reason, None);
+        assert_eq!(
+            r.to_string(),
+            "boot: 1, reset cause: 0x2 (POR), last reset: unknown"
+        );
+
+        // Unexpected reset (e.g. watchdog).
+        let r = c.boot(0b100_0000);
+        assert_eq!(r.boot_count, 2);
+        assert_eq!(r.reason, None);
+        assert_eq!(
+            r.event(),
+            Event::Boot {
+                count: 2,
+                reason: 0,
+                cause: 64
+            }
+        );
+
+        c.reset(ResetReason::Command);
+        let r = c.boot(0b1_0000);
+        assert_eq!(r.boot_count, 3);
+        assert_eq!(r.reason, Some(ResetReason::Command));
+        assert_eq!(
+            r.to_string(),
+            "boot: 3, reset cause: 0x10 (SWPOI), last reset: Command"
+        );
+
+        // Corrupted record starts over.
+        c.boot_count = 100;
+        assert_eq!(c.boot(0).boot_count, 1);
+    }
+
+    #[test]
+    fn panic_and_hard_fault() {
+        let mut c = CrashRecord::new();
+        c.boot(0);
+
+        c.panic("panicked at 'IMU has failed repeatedly', src/main.rs:442:21");
+        let r = c.boot(0b1_0001);
+        assert_eq!(r.reason, Some(ResetReason::Panic));
+        assert_eq!(
+            r.msg,
+            "panicked at 'IMU has failed repeatedly', src/main.rs:442:21"
+        );
+        assert!(r.to_string().ends_with(
+            "last reset: panic (panicked at 'IMU has failed repeatedly', src/main.rs:442:21)"
+        ));
+        assert!(r.to_string().contains("(EXT|SWPOI)"));
+
+        // Cleared for the next boot.
+        let r = c.boot(0);
+        assert_eq!(r.reason, None);
+        assert!(r.msg.is_empty());
+
+        // Long message is truncated at a char boundary.
+        let long = "æ".repeat(100);
+        c.panic(&long);
+        assert_eq!(c.boot(0).msg.len(), 128);
+
+        c.hard_fault(0x0001_2345, 0xffff_fff9);
+        let r = c.boot(0);
+        assert_eq!((r.pc, r.lr), (0x0001_2345, 0xffff_fff9));
+        assert!(r
+            .to_string()
+            .ends_with("hard fault (pc: 0x00012345, lr: 0xfffffff9)"));
+    }
+
+    #[test]
+    fn watchdog() {
+        let mut c = CrashRecord::new();
+        c.boot(0);
+
+        c.watchdog(Task::Notecard);
+        c.watchdog(Task::Imu);
+
+        let r = c.boot(0b100_0000);
+        assert_eq!(r.reason, Some(ResetReason::Watchdog));
+        assert_eq!(r.task, Some(Task::Notecard));
+        assert_eq!(r.watchdog_event(), Some(Event::WatchdogReset { task: 3 }));
+        assert!(r
+            .to_string()
+            .ends_with("(WDT), last reset: watchdog (task: Notecard)"));
+
+        assert_eq!(c.boot(0).watchdog_event(), None);
+    }
+}
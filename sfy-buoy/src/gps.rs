@@ -0,0 +1,296 @@
+//! History of position fixes, and interpolation of the position of data-packages.
+//!
+//! A data-package covers about 20 seconds of samples, while the GPS is only sampled every
+//! [`GPS_PERIOD`](crate::note::GPS_PERIOD) seconds (or less often). The most recent fixes are
+//! kept in a small ring buffer, and each package is given the position at its mid-time:
+//! interpolated between the surrounding fixes, or extrapolated from the two latest fixes when
+//! the package is newer than the latest fix (which it usually is when it is cut). The time to the
+//! nearest fix used is kept as the fix age.
+//!
+//! New fixes are also queued in [`TRACKQ`] as [`TrackPoint`]s, so that they can be stored as
+//! records among the packages (see [`crate::storage::record`]).
+
+use heapless::{mpmc::Q8, Deque};
+
+use crate::uplink::Position;
+
+/// Number of fixes kept.
+pub const HISTORY_SZ: usize = 8;
+
+/// Maximum time to extrapolate beyond the latest fix (seconds). Beyond this the latest position
+/// is used as is.
+pub const MAX_EXTRAPOLATE: u32 = 5 * 60;
+
+/// Maximum time between two fixes for interpolating or extrapolating between them (seconds).
+pub const MAX_FIX_GAP: u32 = 30 * 60;
+
+/// New fixes waiting to be stored.
+pub static TRACKQ: Q8<TrackPoint> = Q8::new();
+
+/// A fix in the track, stored as a record among the packages.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize, defmt::Format)]
+pub struct TrackPoint {
+    /// Time of fix (UTC, seconds).
+    pub time: u32,
+    pub lat: f64,
+    pub lon: f64,
+    pub hdop: Option<f32>,
+    pub sats: Option<u8>,
+}
+
+impl From<&Position> for TrackPoint {
+    fn from(p: &Position) -> TrackPoint {
+        TrackPoint {
+            time: p.time,
+            lat: p.lat,
+            lon: p.lon,
+            hdop: p.hdop,
+            sats: p.sats,
+        }
+    }
+}
+
+/// Queue a new fix for the track.
+pub fn queue_track(p: &Position) {
+    TRACKQ
+        .enqueue(p.into())
+        .inspect_err(|p| defmt::error!("Track queue full, discarding fix: {:?}", p))
+        .ok();
+}
+
+/// Position of a data-package, see [`PositionHistory::position_at`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, defmt::Format)]
+pub struct PacketPosition {
+    pub lat: f64,
+    pub lon: f64,
+
+    /// Time of position (UTC, seconds).
+    pub time: u32,
+
+    /// Time from `time` to the nearest fix used (seconds).
+    pub age: u32,
+
+    /// Horizontal dilution of precision of the nearest fix used.
+    pub hdop: Option<f32>,
+
+    /// Number of satellites of the nearest fix used.
+    pub sats: Option<u8>,
+}
+
+#[derive(Clone, Default)]
+pub struct PositionHistory {
+    fixes: Deque<Position, HISTORY_SZ>,
+}
+
+impl PositionHistory {
+    pub fn new() -> PositionHistory {
+        PositionHistory {
+            fixes: Deque::new(),
+        }
+    }
+
+    /// Add a fix. Fixes that are not newer than the latest fix are ignored (the Notecard keeps
+    /// returning the last fix until a new one is available).
+    pub fn push(&mut self, fix: Position) -> bool {
+        if self.latest().map(|l| fix.time <= l.time).unwrap_or(false) {
+            return false;
+        }
+
+        if self.fixes.is_full() {
+            self.fixes.pop_front();
+        }
+
+        self.fixes.push_back(fix).ok();
+
+        true
+    }
+
+    pub fn latest(&self) -> Option<&Position> {
+        self.fixes.back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fixes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fixes.is_empty()
+    }
+
+    /// Fixes, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &Position> {
+        self.fixes.iter()
+    }
+
+    /// Position at `time` (UTC, ms).
+    pub fn position_at(&self, time: i64) -> Option<PacketPosition> {
+        let t = (time / 1000).max(0) as u32;
+
+        let mut before: Option<&Position> = None;
+        let mut after: Option<&Position> = None;
+        let mut prev: Option<&Position> = None;
+
+        for f in self.fixes.iter() {
+            if f.time <= t {
+                prev = before;
+                before = Some(f);
+            } else {
+                after = Some(f);
+                break;
+            }
+        }
+
+        let (a, b) = match (before, after) {
+            (Some(a), Some(b)) => (a, b),
+
+            // Newer than latest fix: extrapolate from the two latest.
+            (Some(b), None) => match prev {
+                Some(a) if (t - b.time) <= MAX_EXTRAPOLATE => (a, b),
+                _ => return Some(PacketPosition::at(b, t)),
+            },
+
+            // Older than all fixes.
+            (None, Some(b)) => return Some(PacketPosition::at(b, t)),
+            (None, None) => return None,
+        };
+
+        let nearest = if t.abs_diff(a.time) < t.abs_diff(b.time) {
+            a
+        } else {
+            b
+        };
+
+        if b.time - a.time > MAX_FIX_GAP {
+            return Some(PacketPosition::at(nearest, t));
+        }
+
+        let w = (t as f64 - a.time as f64) / (b.time - a.time) as f64;
+
+        Some(PacketPosition {
+            lat: a.lat + w * (b.lat - a.lat),
+            lon: a.lon + w * (b.lon - a.lon),
+            time: t,
+            ..PacketPosition::at(nearest, t)
+        })
+    }
+}
+
+impl PacketPosition {
+    /// Position of fix, used at `time`.
+    fn at(fix: &Position, time: u32) -> PacketPosition {
+        PacketPosition {
+            lat: fix.lat,
+            lon: fix.lon,
+            time,
+            age: time.abs_diff(fix.time),
+            hdop: fix.hdop,
+            sats: fix.sats,
+        }
+    }
+}
+
+/// Parse the number of satellites from the Notecard GPS status, e.g.: `GPS updated (58 sec, 41dB
+/// SNR, 9 sats) {gps-active}`.
+pub fn parse_sats(status: &str) -> Option<u8> {
+    let s = status.split(" sats").next()?;
+    let n = s.rsplit(|c: char| !c.is_ascii_digit()).next()?;
+
+    if n.len() == s.len() {
+        return None;
+    }
+
+    n.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fix(time: u32, lat: f64, lon: f64) -> Position {
+        Position {
+            lat,
+            lon,
+            time,
+            hdop: Some(1.2),
+            sats: Some(9),
+        }
+    }
+
+    #[test]
+    fn ring_buffer() {
+        let mut h = PositionHistory::new();
+        assert!(h.position_at(1000).is_none());
+
+        for i in 0..20 {
+            assert!(h.push(fix(100 + i * 60, 60., 5.)));
+        }
+        assert_eq!(h.len(), HISTORY_SZ);
+        assert_eq!(h.iter().next().unwrap().time, 100 + 12 * 60);
+
+        // Repeated fix is ignored.
+        assert!(!h.push(fix(100 + 19 * 60, 61., 5.)));
+        assert_eq!(h.latest().unwrap().lat, 60.);
+    }
+
+    #[test]
+    fn interpolate() {
+        let mut h = PositionHistory::new();
+        h.push(fix(1000, 60.0, 5.0));
+        h.push(fix(1060, 60.001, 5.002));
+
+        let p = h.position_at(1_030_500).unwrap();
+        assert_eq!(p.time, 1030);
+        assert!((p.lat - 60.0005).abs() < 1e-9);
+        assert!((p.lon - 5.001).abs() < 1e-9);
+        assert_eq!(p.age, 30);
+        assert_eq!(p.sats, Some(9));
+
+        // Extrapolate from the latest two fixes.
+        let p = h.position_at(1_090_000).unwrap();
+        assert!((p.lat - 60.0015).abs() < 1e-9);
+        assert!((p.lon - 5.003).abs() < 1e-9);
+        assert_eq!(p.age, 30);
+
+        // Too far ahead.
+        let p = h
+            .position_at((1060 + MAX_EXTRAPOLATE as i64 + 1) * 1000)
+            .unwrap();
+        assert_eq!(p.lat, 60.001);
+        assert_eq!(p.age, MAX_EXTRAPOLATE + 1);
+
+        // Before first fix.
+        let p = h.position_at(900_000).unwrap();
+        assert_eq!(p.lat, 60.0);
+        assert_eq!(p.age, 100);
+
+        // Fixes too far apart.
+        h.push(fix(1060 + MAX_FIX_GAP + 10, 61.0, 6.0));
+        let p = h.position_at(1_070_000).unwrap();
+        assert_eq!(p.lat, 60.001);
+        assert_eq!(p.age, 10);
+    }
+
+    #[test]
+    fn track_point() {
+        let p = TrackPoint::from(&fix(1_700_000_000, 60.1, 5.2));
+
+        let mut b: heapless::Vec<u8, 64> = postcard::to_vec_cobs(&p).unwrap();
+        assert_eq!(*b.last().unwrap(), 0);
+        let d: TrackPoint = postcard::from_bytes_cobs(&mut b).unwrap();
+        assert_eq!(d, p);
+    }
+
+    #[test]
+    fn sats_from_status() {
+        assert_eq!(
+            parse_sats("GPS updated (58 sec, 41dB SNR, 9 sats) {gps-active} {gps}"),
+            Some(9)
+        );
+        assert_eq!(
+            parse_sats("GPS search (111 sec, 32/33 dB SNR, 0/1 sats) {gps-active}"),
+            Some(1)
+        );
+        assert_eq!(parse_sats("{gps-inactive}"), None);
+        assert_eq!(parse_sats(""), None);
+    }
+}
@@ -13,33 +13,41 @@ extern crate test;
 #[allow(unused_imports)]
 use defmt::{debug, error, info, trace, warn};
 
-use blues_notecard as notecard;
 use chrono::NaiveDateTime;
 use core::cell::RefCell;
 use core::fmt::Debug;
 use core::ops::DerefMut;
+use core::sync::atomic::{AtomicBool, Ordering};
 use cortex_m::interrupt::{free, Mutex};
-use embedded_hal::{
-    blocking::{
-        delay::DelayMs,
-        i2c::{Read, Write, WriteRead},
-        spi::Transfer,
-    },
-    digital::v2::OutputPin,
+use embedded_hal::blocking::{
+    delay::DelayMs,
+    i2c::{Write, WriteRead},
 };
 use rtcc::DateTimeAccess;
 
 pub mod axl;
+pub mod burst;
+pub mod cmd;
+pub mod config;
+pub mod crash;
 pub mod fir;
+pub mod gps;
 pub mod log;
 pub mod note;
+pub mod power;
+pub mod request;
+pub mod retention;
+pub mod scheduler;
 #[cfg(feature = "storage")]
 pub mod storage;
+pub mod uplink;
+pub mod watchdog;
 pub mod waves;
 
 use axl::AxlPacket;
 #[cfg(feature = "storage")]
-use storage::Storage;
+use storage::PacketStore;
+use uplink::Uplink;
 
 pub const STORAGEQ_SZ: usize = 12;
 
@@ -63,11 +71,15 @@ pub static mut STORAGEQ: heapless::spsc::Queue<AxlPacket, STORAGEQ_SZ> =
 
 pub static mut NOTEQ: heapless::spsc::Queue<AxlPacket, NOTEQ_SZ> = heapless::spsc::Queue::new();
 
+/// Packages are only queued when recording. The IMU is still drained when not recording to
+/// prevent FIFO overruns, but the data is discarded. See [`cmd::Command::StopRecording`].
+pub static RECORDING: AtomicBool = AtomicBool::new(true);
+
 pub struct SharedState<D: DateTimeAccess> {
     pub rtc: D,
-    pub position_time: u32,
-    pub lon: f64,
-    pub lat: f64,
+
+    /// Recent position fixes, used to position data-packages.
+    pub positions: gps::PositionHistory,
 }
 
 pub trait State {
@@ -75,6 +87,10 @@ pub trait State {
 
     /// Returns now, posistion_time, lat, lon.
     fn get(&self) -> (NaiveDateTime, u32, f64, f64);
+
+    /// Returns the position at `time` (UTC, ms), see [`gps::PositionHistory::position_at`]. The
+    /// position history is only borrowed while the position is computed.
+    fn position_at(&self, time: i64) -> Option<gps::PacketPosition>;
 }
 
 impl<D: DateTimeAccess> SharedState<D> {
@@ -85,14 +101,13 @@ impl<D: DateTimeAccess> SharedState<D> {
     }
 
     fn get(&mut self) -> (NaiveDateTime, u32, f64, f64) {
-        (
-            self.rtc
-                .datetime()
-                .unwrap_or(NaiveDateTime::from_timestamp(0, 0)),
-            self.position_time,
-            self.lat,
-            self.lon,
-        )
+        let (position_time, lat, lon) = self
+            .positions
+            .latest()
+            .map(|p| (p.time, p.lat, p.lon))
+            .unwrap_or((0, 0.0, 0.0));
+
+        (self.now(), position_time, lat, lon)
     }
 }
 
@@ -114,6 +129,14 @@ impl<D: DateTimeAccess> State for Mutex<RefCell<Option<SharedState<D>>>> {
             state.get()
         })
     }
+
+    fn position_at(&self, time: i64) -> Option<gps::PacketPosition> {
+        free(|cs| {
+            let state = self.borrow(cs).borrow();
+
+            state.as_ref().unwrap().positions.position_at(time)
+        })
+    }
 }
 
 #[derive(Clone)]
@@ -143,31 +166,27 @@ impl Location {
         }
     }
 
-    pub fn check_retrieve<T: Read + Write, D: DateTimeAccess>(
+    pub fn check_retrieve<U: Uplink, D: DateTimeAccess>(
         &mut self,
         state: &Mutex<RefCell<Option<SharedState<D>>>>,
         delay: &mut impl DelayMs<u16>,
-        note: &mut note::Notecarrier<T>,
-    ) -> Result<(), notecard::NoteError> {
-        use notecard::card::res::{Location, Time};
+        uplink: &mut U,
+    ) -> Result<(), U::Error> {
         use LocationState::*;
 
-        const LOCATION_DIFF: i64 = 1 * 60_000; // ms
+        let location_diff = uplink.location_interval_ms();
 
         let now = state.now().timestamp_millis();
         defmt::trace!("now: {}", now);
 
         match self.state {
-            Retrieved(t) | Trying(t) if (now - t) > LOCATION_DIFF => {
-                let gps = note.card().location(delay)?.wait(delay)?;
-                let tm = note.card().time(delay)?.wait(delay);
+            Retrieved(t) | Trying(t) if (now - t) > location_diff => {
+                let gps = uplink.location(delay)?;
+                let tm = uplink.time(delay);
 
                 info!("Location: {:?}, Time: {:?}", gps, tm);
 
-                if let Ok(Time {
-                    time: Some(time), ..
-                }) = tm
-                {
+                if let Ok(Some(time)) = tm {
                     info!("Got time, setting RTC.");
                     self.time = time;
 
@@ -182,30 +201,24 @@ impl Location {
                     });
                 }
 
-                if let Location {
-                    lat: Some(lat),
-                    lon: Some(lon),
-                    time: Some(position_time),
-                    ..
-                } = gps
-                {
+                if let Some(fix) = gps {
                     info!("Got location, setting position.");
 
-                    self.lat = lat;
-                    self.lon = lon;
-                    self.position_time = position_time;
+                    self.lat = fix.lat;
+                    self.lon = fix.lon;
+                    self.position_time = fix.time;
 
                     free(|cs| {
                         let mut state = state.borrow(cs).borrow_mut();
                         let state: &mut _ = state.deref_mut().as_mut().unwrap();
 
-                        state.position_time = position_time;
-                        state.lat = lat;
-                        state.lon = lon;
+                        if state.positions.push(fix) {
+                            gps::queue_track(&fix);
+                        }
                     });
                 }
 
-                if let (Ok(Time { time: Some(_), .. }), Location { lat: Some(_), .. }) = (tm, gps) {
+                if let (Ok(Some(_)), Some(_)) = (tm, gps) {
                     info!("Both time and location retrieved.");
                     free(|cs| {
                         let mut state = state.borrow(cs).borrow_mut();
@@ -227,9 +240,13 @@ pub struct Imu<E: Debug + defmt::Format, I: Write<Error = E> + WriteRead<Error =
     pub queue: heapless::spsc::Producer<'static, AxlPacket, IMUQ_SZ>,
     waves: waves::Waves<I>,
     last_read: i64,
+
+    /// Phase of the burst schedule, see [`burst`].
+    phase: burst::Phase,
 }
 
 impl<E: Debug + defmt::Format, I: Write<Error = E> + WriteRead<Error = E>> Imu<E, I> {
+    /// The IMU FIFO must be enabled.
     pub fn new(
         waves: waves::Waves<I>,
         queue: heapless::spsc::Producer<'static, AxlPacket, IMUQ_SZ>,
@@ -238,36 +255,86 @@ impl<E: Debug + defmt::Format, I: Write<Error = E> + WriteRead<Error = E>> Imu<E
             queue,
             waves,
             last_read: 0,
+            phase: burst::Phase::Continuous,
+        }
+    }
+
+    /// Follow the burst schedule (see [`burst`]): the remaining samples are pushed as a package
+    /// when a burst ends, the IMU is powered down between bursts and powered up again before the
+    /// next burst. Samples from the warm-up are discarded. In survival mode (see [`power`]) the
+    /// IMU is powered down. Returns `true` if the IMU is running.
+    pub fn check_schedule(
+        &mut self,
+        now: i64,
+        state: &impl State,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<bool, waves::ImuError<E>> {
+        let phase = if power::mode().records() {
+            burst::schedule().phase((now / 1000).try_into().unwrap_or(0))
+        } else {
+            burst::Phase::Off
+        };
+
+        if phase == self.phase {
+            return Ok(phase.active());
         }
+
+        debug!("burst phase: {:?} -> {:?}", self.phase, phase);
+
+        if self.phase.records() {
+            let mut pck = self.waves.take_buf(now)?;
+
+            if !pck.data.is_empty() {
+                pck.burst = self.phase.burst();
+                self.push(pck, state);
+            }
+        } else if self.phase.active() {
+            self.waves.take_buf(now)?;
+        }
+
+        match (self.phase.active(), phase.active()) {
+            (true, false) => self.waves.power_down()?,
+            (false, true) => {
+                self.waves.power_up(now, delay)?;
+                self.last_read = now;
+            }
+            _ => (),
+        }
+
+        self.phase = phase;
+
+        Ok(phase.active())
     }
 
     /// Read samples and check for full buffers. Return number of sample pairs consumed from IMU.
+    /// Full packages are positioned using the position history in `state`.
     pub fn check_retrieve(
         &mut self,
         now: i64,
-        position_time: u32,
-        lon: f64,
-        lat: f64,
+        state: &impl State,
     ) -> Result<u32, waves::ImuError<E>> {
         trace!("Polling IMU.. (now: {})", now,);
 
+        if !self.phase.active() {
+            trace!("IMU is powered down between bursts.");
+            return Ok(0);
+        }
+
         let mut samples = self.waves.read_and_filter()?;
 
         if self.waves.is_full() {
             trace!("waves buffer is full, pushing to queue..");
-            let pck = self.waves.take_buf(now, position_time, lon, lat)?;
+            let mut pck = self.waves.take_buf(now)?;
 
             trace!("collect remaining samples, to avoid overrun.");
             samples += self.waves.read_and_filter()?;
 
-            self.queue
-                .enqueue(pck)
-                .inspect_err(|pck| {
-                    error!("queue is full, discarding data: {}", pck.data.len());
-
-                    log::log("Queue is full: discarding package.");
-                })
-                .ok();
+            if self.phase.records() {
+                pck.burst = self.phase.burst();
+                self.push(pck, state);
+            } else {
+                trace!("warming up, discarding package.");
+            }
         }
 
         if samples == 0 {
@@ -285,57 +352,110 @@ impl<E: Debug + defmt::Format, I: Write<Error = E> + WriteRead<Error = E>> Imu<E
         Ok(samples)
     }
 
+    /// Position and queue package, if recording.
+    fn push(&mut self, mut pck: AxlPacket, state: &impl State) {
+        pck.set_fix(state.position_at(pck.mid_time()));
+
+        if RECORDING.load(Ordering::Acquire) && power::mode().records() {
+            power::ACTIVITY.imu.fetch_add(1, Ordering::Relaxed);
+
+            self.queue
+                .enqueue(pck)
+                .inspect_err(|pck| {
+                    error!("queue is full, discarding data: {}", pck.data.len());
+
+                    log::event(log::Event::QueueFull {
+                        queue: log::QueueId::Imu,
+                    });
+                })
+                .ok();
+        } else {
+            trace!("not recording, discarding package.");
+        }
+    }
+
     pub fn reset(
         &mut self,
         now: i64,
-        position_time: u32,
-        lon: f64,
-        lat: f64,
         delay: &mut impl DelayMs<u16>,
     ) -> Result<(), waves::ImuError<E>> {
         self.waves.reset(delay)?;
-        self.waves.take_buf(now, position_time, lon, lat)?; // buf is empty, this sets time and offset.
+        self.waves.take_buf(now)?; // buf is empty, this sets time and offset.
         self.waves.enable_fifo(delay)?;
         self.last_read = now; // prevent TooFewSamples to be triggered.
 
+        if !self.phase.active() {
+            self.waves.power_down()?;
+        }
+
         Ok(())
     }
 }
 
+/// Stores queued packages and serves data-requests from a [`PacketStore`] (the SD-card on the
+/// buoy).
 #[cfg(feature = "storage")]
-pub struct StorageManager<Spi: Transfer<u8>, CS: OutputPin>
-where
-    <Spi as Transfer<u8>>::Error: Debug,
-{
-    storage: Storage<Spi, CS>,
+pub struct StorageManager<S: PacketStore> {
+    storage: S,
     pub storage_queue: heapless::spsc::Consumer<'static, AxlPacket, STORAGEQ_SZ>,
     pub note_queue: heapless::spsc::Producer<'static, AxlPacket, NOTEQ_SZ>,
+
+    /// Queued data-requests, loaded from the notecard on first use.
+    requests: Option<request::RequestQueue>,
+
+    /// Fix from [`gps::TRACKQ`] that failed to be stored, retried before the queue.
+    track: Option<gps::TrackPoint>,
 }
 
+/// Maximum number of requested packages read from the SD-card per pass.
+#[cfg(feature = "storage")]
+pub const REQUEST_BATCH: usize = 100;
+
 #[cfg(feature = "storage")]
-impl<Spi: Transfer<u8>, CS: OutputPin> StorageManager<Spi, CS>
-where
-    <Spi as Transfer<u8>>::Error: Debug,
-{
+impl<S: PacketStore> StorageManager<S> {
     pub fn new(
-        storage: Storage<Spi, CS>,
+        storage: S,
         storage_queue: heapless::spsc::Consumer<'static, AxlPacket, STORAGEQ_SZ>,
         note_queue: heapless::spsc::Producer<'static, AxlPacket, NOTEQ_SZ>,
-    ) -> StorageManager<Spi, CS> {
+    ) -> StorageManager<S> {
         StorageManager {
             storage,
             storage_queue,
             note_queue,
+            requests: None,
+            track: None,
         }
     }
 
-    pub fn drain_queue<I2C: Read + Write>(
+    /// State of the store.
+    pub fn health(&self) -> storage::Health {
+        self.storage.health()
+    }
+
+    /// Write all batched packages, before the device is reset.
+    pub fn flush(&mut self) -> Result<(), storage::StorageErr> {
+        self.storage.flush(true)
+    }
+
+    /// Store a record that is not queued as a package, it can be requested like the packages.
+    pub fn store_record(&mut self, r: &mut storage::Record) -> Result<u32, storage::StorageErr> {
+        self.storage
+            .store(r)
+            .inspect_err(|err| defmt::error!("Failed to save record: {}", err))
+    }
+
+    pub fn drain_queue<U: Uplink>(
         &mut self,
-        note: &mut note::Notecarrier<I2C>,
+        note: &mut U,
         delay: &mut impl DelayMs<u16>,
     ) -> Result<Option<u32>, storage::StorageErr> {
         let mut e: Result<Option<u32>, storage::StorageErr> = Ok(None);
 
+        // Keep packages that are queued for sending when old collections are deleted.
+        if let Some(requests) = &self.requests {
+            self.storage.set_protected(requests.pending());
+        }
+
         while let Some(mut pck) = self.storage_queue.dequeue() {
             defmt::debug!(
                 "Storing package: {:?} (queue length: {})",
@@ -348,106 +468,418 @@ where
                 .inspect_err(|err| {
                     defmt::error!("Failed to save package: {}", err);
                 })
+                .inspect(|_| {
+                    power::ACTIVITY.stored.fetch_add(1, Ordering::Relaxed);
+                })
                 .map(|id| Some(id));
 
             self.note_queue
                 .enqueue(pck)
                 .inspect_err(|pck| {
                     defmt::error!("queue is full, discarding data: {}", pck.data.len());
+
+                    log::event(log::Event::QueueFull {
+                        queue: log::QueueId::Uplink,
+                    });
                 })
                 .ok();
         }
 
-        // Send additional requested packages from SD-card.
-        if let Some(next_id) = self.storage.next_id() {
-            if let Ok((
-                Some(note::StorageIdInfo { sent_id }),
-                Some(note::RequestData {
-                    request_start: Some(request_start),
-                    request_end: Some(request_end),
-                }),
-            )) = note.read_storage_info(delay)
-            {
-                let sent_id = sent_id.unwrap_or(request_start);
-                let request_end = request_end.min(next_id.saturating_sub(1));
-
-                if sent_id < request_end {
-                    defmt::info!("Request, sending range: {} -> {}", sent_id, request_end);
-                    for id in (sent_id..=request_end).take(100) {
-                        let pck = self.storage.get(id);
-
-                        defmt::debug!("Sending stored package: {:?}", pck);
-
-                        match pck {
-                            Ok(pck) => {
-                                match self.note_queue.enqueue(pck) {
-                                    Ok(_) => {
-                                        // Update range of sent packages.
-                                        note.write_storage_info(
-                                            delay,
-                                            Some(id),
-                                            if id >= request_end { true } else { false },
-                                        )
-                                        .inspect_err(|e| {
-                                            defmt::error!("Failed to set storageinfo: {:?}", e)
-                                        })
-                                        .ok();
-                                    }
-                                    Err(_) => {
-                                        defmt::trace!(
-                                            "Notecard queue is full, not adding more packages."
-                                        );
-                                        break;
-                                    } // queue is full.
-                                }
-                            }
-                            Err(storage::StorageErr::GenericSdMmmcErr(
-                                embedded_sdmmc::Error::FileNotFound,
-                            )) => {
-                                let new_id = ((id / storage::COLLECTION_SIZE) + 1)
-                                    * storage::COLLECTION_SIZE;
-
-                                defmt::debug!(
-                                    "File does not exist, advancing range by full collection: {} -> {}.",
-                                    id, new_id
-                                );
-
-                                note.write_storage_info(
-                                    delay,
-                                    Some(new_id),
-                                    if new_id >= request_end { true } else { false },
-                                )
-                                .inspect_err(|e| {
-                                    defmt::error!("Failed to set storageinfo: {:?}", e)
-                                })
-                                .ok();
-
-                                break;
-                            }
-                            Err(e) => {
-                                defmt::error!(
-                                    "Failed to read from SD-card: {:?}, clearing request.",
-                                    e
-                                );
-                                note.write_storage_info(delay, None, true)
-                                    .inspect_err(|e| {
-                                        defmt::error!("Failed to set storageinfo: {:?}", e)
-                                    })
-                                    .ok();
-                                return Err(e);
-                            }
-                        }
+        // GPS fixes are stored as records among the packages.
+        while let Some(p) = self.track.take().or_else(|| gps::TRACKQ.dequeue()) {
+            if self.store_record(&mut storage::Record::Track(p)).is_err() {
+                self.track = Some(p);
+                break;
+            }
+        }
+
+        // Write batched records that have waited too long for the batch to fill up.
+        if let Err(err) = self.storage.flush(false) {
+            defmt::error!("Failed to write batched records: {}", err);
+            e = Err(err);
+        }
+
+        // Send additional requested packages from SD-card (not in low-power modes).
+        if let Some(next_id) = self.storage.next_id().filter(|_| power::mode().sends_raw()) {
+            self.receive_requests(note, delay);
+            self.serve_request(note, delay, next_id)?;
+        }
+
+        e
+    }
+
+    /// Send queued log records over the uplink. Spooled records are forwarded first, and while
+    /// there are spooled records, or if the uplink fails, new records are added to the spool so
+    /// that they are sent in order.
+    pub fn drain_log<U: Uplink>(
+        &mut self,
+        note: &mut U,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), storage::StorageErr> {
+        self.drain_log_from(log::queue(), note, delay)
+    }
+
+    /// Send the log records in `log` over the uplink, see [`StorageManager::drain_log`].
+    pub fn drain_log_from<U: Uplink, const E: usize, const M: usize>(
+        &mut self,
+        log: &log::LogQueue<E, M>,
+        note: &mut U,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), storage::StorageErr> {
+        let forwarded = self
+            .storage
+            .forward_spool(note, delay)
+            .inspect_err(|e| defmt::error!("Failed to forward spooled log: {}", e))
+            .unwrap_or(true);
+
+        if forwarded && log.drain_log(note, delay).is_ok() {
+            return Ok(());
+        }
+
+        self.spool_log_from(log)
+    }
+
+    /// Move all queued log records to the spool on the SD-card, e.g. before a reset.
+    pub fn spool_log(&mut self) -> Result<(), storage::StorageErr> {
+        self.spool_log_from(log::queue())
+    }
+
+    fn spool_log_from<const E: usize, const M: usize>(
+        &mut self,
+        log: &log::LogQueue<E, M>,
+    ) -> Result<(), storage::StorageErr> {
+        log.drain(|r| self.storage.spool(r))
+            .inspect_err(|e| defmt::error!("Failed to spool log: {}", e))
+    }
+
+    /// Load the request queue (if not loaded), and take one new request from the notecard.
+    fn receive_requests<U: Uplink>(&mut self, note: &mut U, delay: &mut impl DelayMs<u16>) {
+        let requests = match &mut self.requests {
+            Some(requests) => requests,
+            None => match note.read_request_queue(delay) {
+                Ok(requests) => self.requests.insert(requests.unwrap_or_default()),
+                Err(e) => {
+                    defmt::error!("Failed to read request queue: {:?}", e);
+                    return;
+                }
+            },
+        };
+
+        if requests.len() >= request::MAX_REQUESTS {
+            return;
+        }
+
+        match note.take_request(delay) {
+            Ok(Some(r)) => {
+                defmt::info!("New data-request: {:?}", r);
+
+                if let Err(err) = requests.push(r) {
+                    defmt::error!("Invalid data-request: {:?}", err);
+
+                    log::event(log::Event::RequestRejected { error: err as u8 });
+                    return;
+                }
+
+                note.write_request_queue(delay, requests)
+                    .inspect_err(|e| defmt::error!("Failed to write request queue: {:?}", e))
+                    .ok();
+            }
+            Ok(None) => (),
+            Err(e) => defmt::error!("Failed to read data-request: {:?}", e),
+        }
+    }
+
+    /// Queue packages for the current data-request.
+    fn serve_request<U: Uplink>(
+        &mut self,
+        note: &mut U,
+        delay: &mut impl DelayMs<u16>,
+        next_id: u32,
+    ) -> Result<(), storage::StorageErr> {
+        let requests = match &mut self.requests {
+            Some(requests) => requests,
+            None => return Ok(()),
+        };
+
+        let r = match requests.current() {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+
+        if !r.is_resolved() {
+            let (start, end) = (
+                r.start_time.unwrap_or(0) as i64 * 1000,
+                r.end_time.unwrap_or(0) as i64 * 1000 + 999,
+            );
+
+            match self.storage.find_by_time(start, end) {
+                Ok(Some((first, last))) => {
+                    defmt::info!("Request, time window resolved: {} -> {}", first, last);
+                    r.resolve(first, last);
+                }
+                Ok(None) => {
+                    defmt::info!("Request, no packages in time window.");
+                    r.resolve_empty();
+                }
+                Err(err) => {
+                    defmt::error!("Failed to search SD-card: {:?}, dropping request.", err);
+                    requests.pop();
+                    note.write_request_queue(delay, requests).ok();
+                    return Err(err);
+                }
+            }
+        }
+
+        let mut result = Ok(());
+
+        if let Some(id) = r.next_stored(next_id) {
+            defmt::info!("Request, sending range: {} -> {:?}", id, r.end);
+        }
+
+        for _ in 0..REQUEST_BATCH {
+            let id = match r.next_stored(next_id) {
+                Some(id) => id,
+                None => break,
+            };
+
+            if !self.note_queue.ready() {
+                defmt::trace!("Notecard queue is full, not adding more packages.");
+                break;
+            }
+
+            match self.storage.get_record(id) {
+                Ok(storage::Record::Axl(pck)) => {
+                    defmt::debug!("Sending stored package: {:?}", pck);
+                    self.note_queue.enqueue(pck).ok();
+                    r.advance_sent(id);
+                }
+                Ok(rec) => {
+                    defmt::debug!("Sending stored record: {} (tag: {})", id, rec.tag());
+
+                    if let Err(err) = rec.send(note, delay) {
+                        defmt::error!("Failed to send stored record: {:?}, retrying later.", err);
+                        break;
                     }
-                } else {
-                    // Request done, clearing.
-                    defmt::info!("Request complete, deleting request.");
-                    note.write_storage_info(delay, None, true)
-                        .inspect_err(|e| defmt::error!("Failed to set storageinfo: {:?}", e))
-                        .ok();
+                    r.advance_sent(id);
+                }
+                Err(err) if err.is_not_found() => {
+                    let new_id = self.storage.skip_missing(id);
+
+                    defmt::debug!(
+                        "Package does not exist, advancing range: {} -> {}.",
+                        id,
+                        new_id
+                    );
+
+                    r.advance_not_found(id, new_id);
+                }
+                Err(storage::StorageErr::ReadPackageError) => {
+                    defmt::error!("Failed to de-serialize package: {}, skipping.", id);
+                    r.advance_not_found(id, id + 1);
+                }
+                Err(err) => {
+                    defmt::error!("Failed to read from SD-card: {:?}, dropping request.", err);
+                    r.next = r.end.map(|end| end + 1);
+                    result = Err(err);
+                    break;
                 }
             }
         }
 
-        e
+        let mut info = note::StorageIdInfo {
+            sent_id: r.next.map(|n| n.saturating_sub(1)),
+            sent: r.sent,
+            remaining: 0,
+            not_found: r.not_found,
+            queued: 0,
+        };
+
+        if r.is_complete() {
+            defmt::info!(
+                "Request complete (sent: {}, not found: {}), removing request.",
+                r.sent,
+                r.not_found
+            );
+
+            if let Some(r) = requests.pop() {
+                note.report_request(delay, &r)
+                    .inspect_err(|e| defmt::error!("Failed to report request: {:?}", e))
+                    .ok();
+            }
+        }
+
+        info.remaining = requests.remaining();
+        info.queued = requests.len() as u32;
+
+        note.write_request_queue(delay, requests)
+            .inspect_err(|e| defmt::error!("Failed to write request queue: {:?}", e))
+            .ok();
+        note.write_storage_info(delay, info)
+            .inspect_err(|e| defmt::error!("Failed to set storageinfo: {:?}", e))
+            .ok();
+
+        result
+    }
+}
+
+#[cfg(all(test, feature = "storage"))]
+mod tests {
+    use super::*;
+    use note::emulator::{NoDelay, NotecardEmulator};
+    use note::Notecarrier;
+    use serde_json::json;
+    use storage::mem::MemStore;
+
+    type Manager = StorageManager<MemStore>;
+
+    /// Storage manager over a store in RAM, with the Notecard emulator as uplink.
+    fn manager() -> (
+        NotecardEmulator,
+        Notecarrier<NotecardEmulator>,
+        Manager,
+        heapless::spsc::Producer<'static, AxlPacket, STORAGEQ_SZ>,
+        heapless::spsc::Consumer<'static, AxlPacket, NOTEQ_SZ>,
+    ) {
+        let card = NotecardEmulator::new();
+        let note = Notecarrier::new(card.clone(), &mut NoDelay).unwrap();
+
+        let sq = Box::leak(Box::new(heapless::spsc::Queue::new()));
+        let nq = Box::leak(Box::new(heapless::spsc::Queue::new()));
+        let (sp, sc) = sq.split();
+        let (np, nc) = nq.split();
+
+        let m = StorageManager::new(MemStore::new(200), sc, np);
+
+        (card, note, m, sp, nc)
+    }
+
+    fn package(timestamp: i64) -> AxlPacket {
+        AxlPacket {
+            timestamp,
+            position_time: 0,
+            lat: 0.0,
+            lon: 0.0,
+            freq: 52.0,
+            offset: 0,
+            storage_id: None,
+            storage_version: None,
+            fix: None,
+            burst: None,
+            data: (0..axl::AXL_SZ)
+                .map(|v| half::f16::from_f32(v as f32))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn drain_queue() {
+        let (_card, mut note, mut m, mut sp, mut nc) = manager();
+
+        assert_eq!(m.drain_queue(&mut note, &mut NoDelay).unwrap(), None);
+
+        for i in 0..3 {
+            sp.enqueue(package(1_700_000_000_000 + i * 20_000)).unwrap();
+        }
+
+        assert_eq!(m.drain_queue(&mut note, &mut NoDelay).unwrap(), Some(2));
+        assert!(m.storage_queue.is_empty());
+
+        // Stored packages are queued for the uplink with their storage ID.
+        for id in 0..3 {
+            let pck = nc.dequeue().unwrap();
+            assert_eq!(pck.storage_id, Some(id));
+            assert_eq!(m.storage.get(id).unwrap(), pck);
+        }
+        assert!(nc.dequeue().is_none());
+
+        // Packages are stored when the uplink queue is full.
+        while m.note_queue.ready() {
+            m.note_queue.enqueue(package(0)).unwrap();
+        }
+        sp.enqueue(package(1_700_000_060_000)).unwrap();
+
+        assert_eq!(m.drain_queue(&mut note, &mut NoDelay).unwrap(), Some(3));
+        assert_eq!(m.storage.get(3).unwrap().timestamp, 1_700_000_060_000);
+        assert_eq!(nc.len(), NOTEQ_SZ - 1);
+    }
+
+    #[test]
+    fn serve_request() {
+        let (card, mut note, mut m, mut sp, mut nc) = manager();
+
+        for i in 0..5 {
+            sp.enqueue(package(1_700_000_000_000 + i * 20_000)).unwrap();
+        }
+        m.drain_queue(&mut note, &mut NoDelay).unwrap();
+        while nc.dequeue().is_some() {}
+
+        let mut r = storage::Record::Log(log::LogRecord::Message("stored message".into()));
+        assert_eq!(m.store_record(&mut r).unwrap(), 5);
+
+        // Requested packages are queued for the uplink, other records are sent directly.
+        card.add_inbound("request.qi", json!({ "start": 2, "end": 5 }));
+        assert_eq!(m.drain_queue(&mut note, &mut NoDelay).unwrap(), None);
+
+        let ids: Vec<_> = core::iter::from_fn(|| nc.dequeue())
+            .map(|p| p.storage_id)
+            .collect();
+        assert_eq!(ids, [Some(2), Some(3), Some(4)]);
+        assert_eq!(card.state().log, ["stored message"]);
+
+        // The completed request is reported.
+        assert_eq!(card.queue("storage.qo").len(), 1);
+        assert!(card.db("storage.dbx", "storage-info").is_some());
+
+        // Time windows are resolved to storage IDs.
+        card.add_inbound(
+            "request.qi",
+            json!({ "start_time": 1_700_000_020, "end_time": 1_700_000_040 }),
+        );
+        m.drain_queue(&mut note, &mut NoDelay).unwrap();
+
+        let ids: Vec<_> = core::iter::from_fn(|| nc.dequeue())
+            .map(|p| p.storage_id)
+            .collect();
+        assert_eq!(ids, [Some(1), Some(2)]);
+        assert_eq!(card.queue("storage.qo").len(), 2);
+
+        // The part of a range that has not been stored yet is served when it is stored.
+        card.add_inbound("request.qi", json!({ "start": 4, "end": 6 }));
+        m.drain_queue(&mut note, &mut NoDelay).unwrap();
+
+        let ids: Vec<_> = core::iter::from_fn(|| nc.dequeue())
+            .map(|p| p.storage_id)
+            .collect();
+        assert_eq!(ids, [Some(4)]);
+        assert_eq!(card.queue("storage.qo").len(), 2);
+
+        sp.enqueue(package(1_700_000_100_000)).unwrap();
+        m.drain_queue(&mut note, &mut NoDelay).unwrap();
+
+        let ids: Vec<_> = core::iter::from_fn(|| nc.dequeue())
+            .map(|p| p.storage_id)
+            .collect();
+        assert_eq!(ids, [Some(6), Some(6)]);
+        assert_eq!(card.queue("storage.qo").len(), 3);
+    }
+
+    #[test]
+    fn drain_log() {
+        let (card, mut note, mut m, _sp, _nc) = manager();
+
+        // There is no spool in RAM, records are kept in the queue while the uplink fails.
+        let q = log::LogQueue::<4, 4>::new();
+        q.log("drain_log test message".into());
+
+        card.state().fail_next = usize::MAX;
+        assert!(matches!(
+            m.drain_log_from(&q, &mut note, &mut NoDelay),
+            Err(storage::StorageErr::Unsupported)
+        ));
+        assert!(card.state().log.is_empty());
+
+        card.state().fail_next = 0;
+        m.drain_log_from(&q, &mut note, &mut NoDelay).unwrap();
+        assert_eq!(card.state().log, ["drain_log test message"]);
     }
 }
@@ -1,14 +1,378 @@
-use blues_notecard::NoteError;
+//! Log messages and events sent back over the uplink.
+//!
+//! Free-form text messages ([`log`]) are kept for messages that can not be classified (e.g.
+//! panics). Everything else should be logged as an [`Event`]: an event has a code, a severity, a
+//! timestamp and up to three numeric arguments. Events are sent as templated notes to `event.qo`
+//! (see [`EventNote`]), so that they can be queried and alerted on at the server
+//! (`sfy-processing/sfy/logevent.py` decodes them).
+//!
+//! Events are queued in a larger queue than messages. If a queue is full, the record is dropped
+//! and counted, and an [`Event::EventsDropped`] is sent with the next events.
+//!
+//! With the `storage` feature, records that can not be sent because the uplink is unavailable are
+//! spooled to the SD-card and forwarded when the uplink recovers (see [`crate::storage::spool`]).
+
+use core::sync::atomic::{AtomicU32, Ordering};
 use embedded_hal::blocking::{
     delay::DelayMs,
     i2c::{Read, Write},
 };
-use heapless::{mpmc::Q4 as Queue, String};
+use heapless::{
+    mpmc::{MpMcQueue, Q2},
+    String,
+};
 
 use crate::note::Notecarrier;
+use crate::power::PowerMode;
+use crate::uplink::Uplink;
+
+/// Queued events and log messages to be sent back over notecard.
+static LOG: Log = LogQueue::new();
+
+/// The queue of events and log messages, see [`event`] and [`log`].
+pub type Log = LogQueue<16, 4>;
+
+/// Current time (UTC, seconds), used to timestamp events. Updated by the RTC interrupt.
+static TIME: AtomicU32 = AtomicU32::new(0);
+
+/// Set the time used to timestamp events.
+pub fn set_time(time: u32) {
+    TIME.store(time, Ordering::Relaxed);
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub enum Severity {
+    Debug = 0,
+    Info = 1,
+    Warn = 2,
+    Error = 3,
+}
+
+/// Queue that a package was discarded from.
+#[repr(u8)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, defmt::Format,
+)]
+pub enum QueueId {
+    /// The queue filled by the IMU.
+    Imu = 1,
+
+    /// The queue for the uplink, filled from storage.
+    Uplink = 2,
+}
+
+/// Reason for a reset initiated by the firmware.
+#[repr(u8)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, defmt::Format,
+)]
+pub enum ResetReason {
+    /// Reboot command (see [`crate::cmd::Command::Reboot`]).
+    Command = 1,
+
+    /// The main loop failed repeatedly.
+    MainLoop = 2,
+
+    /// The IMU failed repeatedly.
+    Imu = 3,
+
+    Panic = 4,
+    HardFault = 5,
+
+    /// A task stopped checking in with the watchdog (see [`crate::watchdog`]).
+    Watchdog = 6,
+}
+
+/// A log event. The code and arguments of every event are fixed, new events must get a new code.
+/// New events must also be added at the end, since the variant index is used in the records on
+/// the SD-card (see [`LogRecord`]).
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize, defmt::Format)]
+pub enum Event {
+    /// `1`: The firmware started.
+    Startup,
+
+    /// `2`: The firmware is resetting the device.
+    Reset { reason: ResetReason },
+
+    /// `3`: Reading the IMU failed, the IMU was reset. `error`: see
+    /// [`ImuError::code`](crate::waves::ImuError::code).
+    ImuFailure { error: u8, tries: u16 },
+
+    /// `4`: A package was discarded because a queue was full.
+    QueueFull { queue: QueueId },
+
+    /// `5`: Storage (SD-card) failed. `error`: see `StorageErr::code`.
+    StorageError { error: u8 },
+
+    /// `6`: The main loop failed: location, drain queue and sync.
+    MainLoopError {
+        location: bool,
+        drain: bool,
+        sync: bool,
+        tries: u16,
+    },
+
+    /// `7`: Power mode changed, `mv`: supply voltage.
+    PowerMode {
+        from: PowerMode,
+        to: PowerMode,
+        mv: u32,
+    },
+
+    /// `8`: A data-request was rejected, `error`: [`RequestError`](crate::request::RequestError).
+    RequestRejected { error: u8 },
+
+    /// `9`: Invalid configuration, the current configuration is kept.
+    ConfigRejected,
+
+    /// `10`: Events or log messages were dropped because their queue was full.
+    EventsDropped { n: u32 },
+
+    /// `11`: The firmware started after a reset. `reason`: [`ResetReason`] if the reset was
+    /// initiated by the firmware (`0` otherwise), `cause`: hardware reset cause (see
+    /// [`ResetCause`](crate::crash::ResetCause)).
+    Boot { count: u32, reason: u8, cause: u32 },
+
+    /// `12`: The watchdog reset the device because `task` stopped checking in, see
+    /// [`Task`](crate::watchdog::Task).
+    WatchdogReset { task: u8 },
+
+    /// `13`: The SD-card was full, collections `first` to `last` (inclusive) were deleted (`n`
+    /// collections, protected collections in between are kept). See [`crate::retention`].
+    CollectionsDeleted { first: u32, last: u32, n: u32 },
+}
+
+impl Event {
+    pub const fn code(&self) -> u16 {
+        use Event::*;
+
+        match self {
+            Startup => 1,
+            Reset { .. } => 2,
+            ImuFailure { .. } => 3,
+            QueueFull { .. } => 4,
+            StorageError { .. } => 5,
+            MainLoopError { .. } => 6,
+            PowerMode { .. } => 7,
+            RequestRejected { .. } => 8,
+            ConfigRejected => 9,
+            EventsDropped { .. } => 10,
+            Boot { .. } => 11,
+            WatchdogReset { .. } => 12,
+            CollectionsDeleted { .. } => 13,
+        }
+    }
+
+    pub const fn severity(&self) -> Severity {
+        use Event::*;
+
+        match self {
+            Startup | PowerMode { .. } | Boot { .. } => Severity::Info,
+            Reset { .. }
+            | RequestRejected { .. }
+            | ConfigRejected
+            | EventsDropped { .. }
+            | CollectionsDeleted { .. } => Severity::Warn,
+            ImuFailure { .. }
+            | QueueFull { .. }
+            | StorageError { .. }
+            | MainLoopError { .. }
+            | WatchdogReset { .. } => Severity::Error,
+        }
+    }
+
+    /// Numeric arguments.
+    pub const fn args(&self) -> [u32; 3] {
+        use Event::*;
+
+        match *self {
+            Startup | ConfigRejected => [0; 3],
+            Reset { reason } => [reason as u32, 0, 0],
+            ImuFailure { error, tries } => [error as u32, tries as u32, 0],
+            QueueFull { queue } => [queue as u32, 0, 0],
+            StorageError { error } => [error as u32, 0, 0],
+            MainLoopError {
+                location,
+                drain,
+                sync,
+                tries,
+            } => [
+                location as u32 | ((drain as u32) << 1) | ((sync as u32) << 2),
+                tries as u32,
+                0,
+            ],
+            PowerMode { from, to, mv } => [from as u32, to as u32, mv],
+            RequestRejected { error } => [error as u32, 0, 0],
+            EventsDropped { n } => [n, 0, 0],
+            Boot {
+                count,
+                reason,
+                cause,
+            } => [count, reason as u32, cause],
+            WatchdogReset { task } => [task as u32, 0, 0],
+            CollectionsDeleted { first, last, n } => [first, last, n],
+        }
+    }
+}
+
+/// A queued event.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize, defmt::Format)]
+pub struct LogEvent {
+    /// Time of event (UTC, seconds).
+    pub time: u32,
+    pub event: Event,
+}
+
+/// A queued event or message, as it is spooled to the SD-card when the uplink is unavailable
+/// (see [`crate::storage::spool`]).
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[allow(clippy::large_enum_variant)]
+pub enum LogRecord {
+    Event(LogEvent),
+    Message(String<256>),
+}
+
+impl LogRecord {
+    /// Send record over the uplink.
+    pub fn send<U: Uplink>(
+        &self,
+        uplink: &mut U,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), U::Error> {
+        match self {
+            LogRecord::Event(e) => uplink.send_event(e, delay),
+            LogRecord::Message(msg) => uplink.send_log(msg.as_str(), delay),
+        }
+    }
+}
+
+/// Body of a note in `event.qo`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct EventNote {
+    pub code: u16,
+    pub severity: u8,
+    pub time: u32,
+    pub a: u32,
+    pub b: u32,
+    pub c: u32,
+}
+
+impl From<&LogEvent> for EventNote {
+    fn from(e: &LogEvent) -> EventNote {
+        let [a, b, c] = e.event.args();
+
+        EventNote {
+            code: e.event.code(),
+            severity: e.event.severity() as u8,
+            time: e.time,
+            a,
+            b,
+            c,
+        }
+    }
+}
+
+/// Queues of events and log messages. A record that fails to be passed on is held as the head
+/// of the log, and passed on first the next time, so that records are kept in order.
+pub struct LogQueue<const E: usize, const M: usize> {
+    events: MpMcQueue<LogEvent, E>,
+    messages: MpMcQueue<String<256>, M>,
 
-/// Log message queue for messages to be sent back over notecard.
-static LOGQ: Queue<String<256>> = Queue::new();
+    /// Record that failed to be passed on (only one is held at the time).
+    head: Q2<LogRecord>,
+
+    /// Number of records dropped because the queue was full.
+    dropped: AtomicU32,
+}
+
+impl<const E: usize, const M: usize> LogQueue<E, M> {
+    pub const fn new() -> Self {
+        LogQueue {
+            events: MpMcQueue::new(),
+            messages: MpMcQueue::new(),
+            head: Q2::new(),
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    pub fn event(&self, e: LogEvent) {
+        if self.events.enqueue(e).is_err() {
+            #[cfg(not(test))]
+            defmt::error!("event queue full, dropping: {:?}", e);
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn log(&self, msg: String<256>) {
+        if self.messages.enqueue(msg).is_err() {
+            #[cfg(not(test))]
+            defmt::error!("failed to queue message, dropping.");
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// See [`drain`].
+    pub fn drain<R>(&self, mut f: impl FnMut(&LogRecord) -> Result<(), R>) -> Result<(), R> {
+        let n = self.dropped.swap(0, Ordering::Relaxed);
+        if n > 0 {
+            let r = LogRecord::Event(LogEvent {
+                time: TIME.load(Ordering::Relaxed),
+                event: Event::EventsDropped { n },
+            });
+
+            f(&r).inspect_err(|_| {
+                self.dropped.fetch_add(n, Ordering::Relaxed);
+            })?;
+        }
+
+        let head = core::iter::from_fn(|| self.head.dequeue());
+        let events = core::iter::from_fn(|| self.events.dequeue()).map(LogRecord::Event);
+        let messages = core::iter::from_fn(|| self.messages.dequeue()).map(LogRecord::Message);
+
+        for r in head.chain(events).chain(messages) {
+            if let Err(err) = f(&r) {
+                // Keep it as the head, to be passed on first next time.
+                if self.head.enqueue(r).is_err() {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// See [`drain_log`].
+    pub fn drain_log<U: Uplink>(
+        &self,
+        uplink: &mut U,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), U::Error> {
+        self.drain(|r| {
+            #[cfg(not(test))]
+            defmt::info!("logging: {:?}", defmt::Debug2Format(r));
+
+            r.send(uplink, delay)
+        })
+    }
+}
+
+/// The queue that [`event`] and [`log`] add to.
+pub fn queue() -> &'static Log {
+    &LOG
+}
+
+/// Queue an event to be sent over the uplink.
+pub fn event(event: Event) {
+    #[cfg(not(test))]
+    defmt::debug!("eventq: {:?}", event);
+
+    LOG.event(LogEvent {
+        time: TIME.load(Ordering::Relaxed),
+        event,
+    });
+}
 
 #[allow(unused)]
 pub fn log(msg: &str) {
@@ -17,19 +381,20 @@ pub fn log(msg: &str) {
     let mut s = String::new();
     s.push_str(msg).ok();
 
-    LOGQ.enqueue(s)
-        .inspect_err(|e| {
-            #[cfg(not(test))]
-            defmt::error!("failed to queue message: {:?}", e)
-        })
-        .ok();
+    LOG.log(s);
 }
 
-pub fn drain_log<I: Read + Write>(
-    note: &mut Notecarrier<I>,
-    delay: &mut impl DelayMs<u16>,
-) -> Result<(), NoteError> {
-    note.drain_log(&LOGQ, delay)
+/// Send queued events and log messages over the uplink. A record that fails to send is kept,
+/// and sent first the next time.
+pub fn drain_log<U: Uplink>(uplink: &mut U, delay: &mut impl DelayMs<u16>) -> Result<(), U::Error> {
+    LOG.drain_log(uplink, delay)
+}
+
+/// Pass queued events and log messages to `f`, oldest events first, then messages. The number
+/// of dropped records is passed first as an [`Event::EventsDropped`]. If `f` fails draining
+/// stops, and the record is passed first the next time.
+pub fn drain<E>(f: impl FnMut(&LogRecord) -> Result<(), E>) -> Result<(), E> {
+    LOG.drain(f)
 }
 
 /// Tries to send the remaining queue to notecard in case of panic or HardFault. Must be wrapped
@@ -82,6 +447,104 @@ mod tests {
         write!(&mut s, "test: {}", "rrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrr").ok();
     }
 
+    #[test]
+    fn event_args() {
+        let e = Event::MainLoopError {
+            location: true,
+            drain: false,
+            sync: true,
+            tries: 3,
+        };
+        assert_eq!(e.code(), 6);
+        assert_eq!(e.args(), [0b101, 3, 0]);
+        assert_eq!(e.severity(), Severity::Error);
+
+        let e = Event::PowerMode {
+            from: PowerMode::Periodic,
+            to: PowerMode::Summary,
+            mv: 3390,
+        };
+        assert_eq!(e.args(), [1, 2, 3390]);
+
+        let n = EventNote::from(&LogEvent {
+            time: 1000,
+            event: e,
+        });
+        assert_eq!(
+            serde_json::to_string(&n).unwrap(),
+            r#"{"code":7,"severity":1,"time":1000,"a":1,"b":2,"c":3390}"#
+        );
+    }
+
+    #[test]
+    fn events_dropped() {
+        let q = LogQueue::<16, 4>::new();
+
+        for _ in 0..20 {
+            q.event(LogEvent {
+                time: 1234,
+                event: Event::Startup,
+            });
+        }
+        for _ in 0..5 {
+            q.log("asdf".into());
+        }
+
+        let mut records = std::vec::Vec::new();
+        q.drain(|r| {
+            records.push(r.clone());
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+
+        assert_eq!(records.len(), 1 + 16 + 4);
+        assert!(matches!(
+            records[0],
+            LogRecord::Event(LogEvent {
+                event: Event::EventsDropped { n: 5 },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn drain_keeps_order() {
+        let q = LogQueue::<16, 4>::new();
+
+        for time in 0..3 {
+            q.event(LogEvent {
+                time,
+                event: Event::Startup,
+            });
+        }
+        q.log("asdf".into());
+
+        let mut sent = std::vec::Vec::new();
+        let mut send = |r: &LogRecord| {
+            if sent.len() == 1 {
+                sent.push(None);
+                Err(())
+            } else {
+                sent.push(Some(r.clone()));
+                Ok(())
+            }
+        };
+
+        assert!(q.drain(&mut send).is_err());
+        q.drain(&mut send).unwrap();
+
+        let times: std::vec::Vec<_> = sent
+            .iter()
+            .flatten()
+            .map(|r| match r {
+                LogRecord::Event(e) => Some(e.time),
+                LogRecord::Message(_) => None,
+            })
+            .collect();
+        assert_eq!(times, [Some(0), Some(1), Some(2), None]);
+        assert_eq!(q.dropped.load(Ordering::Relaxed), 0);
+    }
+
     #[test]
     fn exhaust_queue() {
         for _ in 0..256 {
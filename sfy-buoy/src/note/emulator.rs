@@ -0,0 +1,736 @@
+//! A software Notecard for testing [`Notecarrier`](super::Notecarrier) on the host.
+//!
+//! The emulator speaks the Notecard JSON request/response protocol over the Notecard I2C framing
+//! (see [`NotecardEmulator`]). It models notefiles (queues and databases), templates, environment
+//! variables, storage use (`card.status`), sync status, supply voltage, location and time. Only
+//! the requests used by the firmware are implemented, and the responses are modelled on the
+//! responses of a real Notecard.
+//!
+//! The emulator is a handle to shared state, so that a test can keep a clone to inspect or modify
+//! the Notecard while the `Notecarrier` owns the bus.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::rc::Rc;
+
+use embedded_hal::blocking::{
+    delay::DelayMs,
+    i2c::{Read, Write},
+};
+use serde_json::{json, Map, Value};
+
+/// I2C address of the Notecard.
+pub const NOTECARD_ADDR: u8 = 0x17;
+
+/// Storage used per note in outbound queues (percent).
+pub const STORAGE_PER_NOTE: usize = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    pub body: Option<Value>,
+    pub payload: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmulatorError {
+    /// The transaction was not addressed to the Notecard.
+    Address(u8),
+
+    /// Injected bus error (see [`State::fail_next`]).
+    Bus,
+}
+
+#[derive(Debug, Default)]
+pub struct State {
+    /// Queue notefiles (`*.qo`, `*.qi`).
+    pub queues: BTreeMap<String, VecDeque<Note>>,
+
+    /// Database notefiles (`*.db`, `*.dbx`).
+    pub dbs: BTreeMap<String, BTreeMap<String, Note>>,
+
+    /// Outbound notes that have been synced to Notehub.
+    pub synced: BTreeMap<String, Vec<Note>>,
+
+    /// Templates by notefile.
+    pub templates: BTreeMap<String, Value>,
+
+    /// Environment variables.
+    pub env: BTreeMap<String, String>,
+
+    /// Messages logged with `hub.log`.
+    pub log: Vec<String>,
+
+    /// Every request received, in order.
+    pub requests: Vec<Value>,
+
+    /// Arguments of the last `hub.set`.
+    pub hub: Map<String, Value>,
+
+    /// Arguments of the last `card.location.mode`.
+    pub location_mode: Map<String, Value>,
+
+    /// Storage used in addition to outbound notes (percent).
+    pub storage_base: usize,
+
+    /// Sync in progress, completes after this many `hub.sync.status` requests.
+    pub sync_pending: Option<usize>,
+
+    /// Number of `hub.sync.status` requests a sync takes.
+    pub sync_polls: usize,
+
+    /// Number of completed syncs.
+    pub syncs: usize,
+
+    /// Current time (UTC, seconds), `None` if the Notecard has not got time yet.
+    pub time: Option<u32>,
+
+    /// Supply voltage (V).
+    pub voltage: f32,
+
+    /// Last GPS fix: lat, lon, time.
+    pub location: Option<(f64, f64, u32)>,
+
+    /// Fail the next `n` bus transactions.
+    pub fail_next: usize,
+
+    input: Vec<u8>,
+    output: VecDeque<u8>,
+    read_request: usize,
+}
+
+#[derive(Clone, Default)]
+pub struct NotecardEmulator(pub Rc<RefCell<State>>);
+
+impl NotecardEmulator {
+    pub fn new() -> NotecardEmulator {
+        let n = NotecardEmulator::default();
+        n.state().sync_polls = 2;
+        n.state().voltage = 4.1;
+        n
+    }
+
+    pub fn state(&self) -> std::cell::RefMut<'_, State> {
+        self.0.borrow_mut()
+    }
+
+    /// Add a note to an inbound queue, as if synced from Notehub.
+    pub fn add_inbound(&self, file: &str, body: Value) {
+        self.state()
+            .queues
+            .entry(file.into())
+            .or_default()
+            .push_back(Note {
+                body: Some(body),
+                payload: None,
+            });
+    }
+
+    /// Notes in a queue notefile (not including synced notes).
+    pub fn queue(&self, file: &str) -> Vec<Note> {
+        self.state()
+            .queues
+            .get(file)
+            .map(|q| q.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Note in a database notefile.
+    pub fn db(&self, file: &str, id: &str) -> Option<Note> {
+        self.state()
+            .dbs
+            .get(file)
+            .and_then(|db| db.get(id))
+            .cloned()
+    }
+
+    /// Names of the requests received, in order.
+    pub fn request_names(&self) -> Vec<String> {
+        self.state()
+            .requests
+            .iter()
+            .filter_map(|r| r["req"].as_str().map(String::from))
+            .collect()
+    }
+
+    /// Process a single request line and return the response line (without newline).
+    pub fn request(&self, line: &[u8]) -> Option<String> {
+        self.state().request(line)
+    }
+}
+
+impl State {
+    /// Percentage of storage in use.
+    pub fn storage(&self) -> usize {
+        let notes: usize = self
+            .queues
+            .iter()
+            .filter(|(f, _)| f.ends_with(".qo"))
+            .map(|(_, q)| q.len())
+            .sum();
+
+        (self.storage_base + notes * STORAGE_PER_NOTE).min(100)
+    }
+
+    /// Complete any pending sync: move outbound notes to `synced`.
+    pub fn sync(&mut self) {
+        self.sync_pending = None;
+        self.syncs += 1;
+
+        let files: Vec<String> = self
+            .queues
+            .keys()
+            .filter(|f| f.ends_with(".qo"))
+            .cloned()
+            .collect();
+
+        for f in files {
+            let notes = self.queues.remove(&f).unwrap_or_default();
+            self.synced.entry(f).or_default().extend(notes);
+        }
+    }
+
+    fn request(&mut self, line: &[u8]) -> Option<String> {
+        let line = std::str::from_utf8(line).ok()?.trim();
+
+        if line.is_empty() {
+            return None;
+        }
+
+        let req: Value = match serde_json::from_str(line) {
+            Ok(req) => req,
+            Err(e) => return Some(err(&format!("invalid json: {} {{io}}", e)).to_string()),
+        };
+
+        self.requests.push(req.clone());
+
+        let name = req["req"].as_str().unwrap_or("").to_string();
+        let res = self.handle(&name, &req);
+
+        Some(res.to_string())
+    }
+
+    fn handle(&mut self, name: &str, req: &Value) -> Value {
+        match name {
+            "card.version" => json!({
+                "body": {
+                    "org": "Blues Wireless",
+                    "product": "Notecard",
+                    "version": "notecard-3.5.1",
+                    "ver_major": 3,
+                    "ver_minor": 5,
+                    "ver_patch": 1,
+                    "ver_build": 15154,
+                    "built": "Oct 19 2022 10:00:00"
+                },
+                "version": "notecard-3.5.1.15154",
+                "device": "dev:000000000000000",
+                "name": "Blues Wireless Notecard",
+                "sku": "NOTE-WBEX-500",
+                "board": "1.11",
+                "api": 3
+            }),
+            "card.status" => json!({
+                "status": "{normal}",
+                "usb": true,
+                "storage": self.storage(),
+                "time": self.time.unwrap_or(0),
+                "connected": true
+            }),
+            "card.wireless" => json!({
+                "status": "{modem-on}",
+                "count": 3,
+                "net": { "rat": "lte", "bars": 3 }
+            }),
+            "card.voltage" => json!({
+                "usb": false,
+                "mode": "normal",
+                "value": self.voltage,
+                "hours": 120,
+                "vmin": 3.2,
+                "vmax": self.voltage,
+                "vavg": self.voltage
+            }),
+            "card.time" => match self.time {
+                Some(time) => json!({
+                    "time": time,
+                    "area": "Oslo",
+                    "zone": "CET,Europe/Oslo",
+                    "minutes": 60,
+                    "lat": 59.9,
+                    "lon": 10.7,
+                    "country": "NO"
+                }),
+                None => err("time is not yet set {no-time}"),
+            },
+            "card.location" => match self.location {
+                Some((lat, lon, time)) => json!({
+                    "status": "GPS updated (58 sec, 41dB SNR, 9 sats) {gps-active} {gps-signal} {gps-sats} {gps}",
+                    "mode": "periodic",
+                    "lat": lat,
+                    "lon": lon,
+                    "time": time,
+                    "max": 25
+                }),
+                None => json!({
+                    "status": "GPS search (111 sec, 32/33 dB SNR, 0/1 sats) {gps-active} {gps-signal} {gps-sats}",
+                    "mode": "periodic"
+                }),
+            },
+            "card.location.mode" => {
+                self.location_mode = args(req);
+                let mut res = Map::new();
+                res.insert(
+                    "mode".into(),
+                    req.get("mode").cloned().unwrap_or(json!("periodic")),
+                );
+                if let Some(s) = req.get("seconds") {
+                    res.insert("seconds".into(), s.clone());
+                }
+                Value::Object(res)
+            }
+            "card.location.track" => json!({
+                "start": true,
+                "heartbeat": true,
+                "hours": req.get("hours").cloned().unwrap_or(json!(1)),
+                "file": "_track.qo"
+            }),
+            "hub.set" => {
+                self.hub = args(req);
+                json!({})
+            }
+            "hub.sync" => {
+                if self.sync_pending.is_none() {
+                    self.sync_pending = Some(self.sync_polls);
+                }
+                json!({})
+            }
+            "hub.sync.status" => match self.sync_pending {
+                Some(0) => {
+                    self.sync();
+                    json!({
+                        "status": "completed {sync-end}",
+                        "time": self.time.unwrap_or(0),
+                        "completed": 1
+                    })
+                }
+                Some(n) => {
+                    self.sync_pending = Some(n - 1);
+                    json!({
+                        "status": "starting communications {wait-module} {connecting}",
+                        "requested": 1,
+                        "sync": true
+                    })
+                }
+                None => json!({
+                    "status": "completed {sync-end}",
+                    "time": self.time.unwrap_or(0),
+                    "completed": 30
+                }),
+            },
+            "hub.log" => {
+                self.log
+                    .push(req["text"].as_str().unwrap_or_default().to_string());
+                json!({})
+            }
+            "note.template" => {
+                let file = req["file"].as_str().unwrap_or_default().to_string();
+                match req.get("body") {
+                    Some(body) => {
+                        let bytes = body.as_object().map(|b| b.len() * 4).unwrap_or(0);
+                        self.templates.insert(file, body.clone());
+                        json!({ "bytes": bytes })
+                    }
+                    None => err("no template body {template-incompatible}"),
+                }
+            }
+            "note.add" => self.note_add(req),
+            "note.get" => self.note_get(req),
+            "note.update" => {
+                let file = req["file"].as_str().unwrap_or_default().to_string();
+                let id = req["note"].as_str().unwrap_or_default().to_string();
+                self.dbs.entry(file).or_default().insert(
+                    id,
+                    Note {
+                        body: req.get("body").cloned(),
+                        payload: req["payload"].as_str().map(String::from),
+                    },
+                );
+                json!({})
+            }
+            "note.delete" => {
+                let file = req["file"].as_str().unwrap_or_default();
+                let id = req["note"].as_str().unwrap_or_default();
+                match self.dbs.get_mut(file).and_then(|db| db.remove(id)) {
+                    Some(_) => json!({}),
+                    None => err(&format!("note not found: {} {{note-noexist}}", id)),
+                }
+            }
+            "env.get" => match req["name"].as_str() {
+                Some(name) => json!({
+                    "text": self.env.get(name).cloned().unwrap_or_default(),
+                    "time": self.time.unwrap_or(0),
+                }),
+                None => json!({
+                    "body": self.env,
+                    "time": self.time.unwrap_or(0),
+                }),
+            },
+            _ => err(&format!("unknown request: {} {{not-supported}}", name)),
+        }
+    }
+
+    fn note_add(&mut self, req: &Value) -> Value {
+        let file = req["file"].as_str().unwrap_or("data.qo").to_string();
+
+        if !file.ends_with(".qo") && !file.ends_with(".qos") {
+            return err(&format!(
+                "notes can only be added to outbound queues: {}",
+                file
+            ));
+        }
+
+        if let (Some(template), Some(body)) = (self.templates.get(&file), req.get("body")) {
+            let template = template.as_object().unwrap();
+            for (k, v) in body.as_object().into_iter().flatten() {
+                match template.get(k) {
+                    None => {
+                        return err(&format!(
+                            "field {} is not in template {{template-incompatible}}",
+                            k
+                        ))
+                    }
+                    Some(t) if t.is_number() != v.is_number() => {
+                        return err(&format!(
+                            "field {} does not match template type {{template-incompatible}}",
+                            k
+                        ))
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let q = self.queues.entry(file).or_default();
+        q.push_back(Note {
+            body: req.get("body").cloned(),
+            payload: req["payload"].as_str().map(String::from),
+        });
+
+        let total = q.len();
+
+        if req["sync"].as_bool() == Some(true) {
+            self.sync_pending.get_or_insert(self.sync_polls);
+        }
+
+        json!({ "total": total })
+    }
+
+    fn note_get(&mut self, req: &Value) -> Value {
+        let file = req["file"].as_str().unwrap_or_default().to_string();
+        let delete = req["delete"].as_bool().unwrap_or(false);
+
+        let note = if file.ends_with(".qi") || file.ends_with(".qis") {
+            let q = self.queues.entry(file).or_default();
+            let n = if delete {
+                q.pop_front()
+            } else {
+                q.front().cloned()
+            };
+
+            match n {
+                Some(n) => (String::from("1"), n),
+                None => return err("no notes available in queue {note-noexist}"),
+            }
+        } else {
+            let id = req["note"].as_str().unwrap_or_default().to_string();
+            let db = self.dbs.entry(file).or_default();
+            let n = if delete {
+                db.remove(&id)
+            } else {
+                db.get(&id).cloned()
+            };
+
+            match n {
+                Some(n) => (id, n),
+                None => return err(&format!("note not found: {} {{note-noexist}}", id)),
+            }
+        };
+
+        let mut res = Map::new();
+        res.insert("note".into(), json!(note.0));
+        if let Some(body) = note.1.body {
+            res.insert("body".into(), body);
+        }
+        if let Some(payload) = note.1.payload {
+            res.insert("payload".into(), json!(payload));
+        }
+        res.insert("time".into(), json!(self.time.unwrap_or(0)));
+
+        Value::Object(res)
+    }
+
+    /// Write bytes to the Notecard (I2C framing: length byte followed by data, or a zero length
+    /// byte followed by the number of bytes requested for the next read).
+    fn write(&mut self, bytes: &[u8]) {
+        match bytes {
+            [] => (), // ping
+            [0, n, ..] => self.read_request = *n as usize,
+            [0] => self.read_request = 0,
+            [n, data @ ..] => {
+                let n = (*n as usize).min(data.len());
+                self.input.extend_from_slice(&data[..n]);
+                self.process_input();
+            }
+        }
+    }
+
+    /// Read bytes from the Notecard: available bytes remaining, bytes returned, data.
+    fn read(&mut self, buf: &mut [u8]) {
+        buf.fill(0);
+
+        if buf.len() < 2 {
+            return;
+        }
+
+        let n = self.read_request.min(self.output.len()).min(buf.len() - 2);
+
+        for (b, o) in buf[2..(2 + n)].iter_mut().zip(self.output.drain(..n)) {
+            *b = o;
+        }
+
+        buf[0] = self.output.len().min(255) as u8;
+        buf[1] = n as u8;
+
+        self.read_request = 0;
+    }
+
+    /// Bytes written can be used as serial-port input as well.
+    pub fn serial_write(&mut self, bytes: &[u8]) {
+        self.input.extend_from_slice(bytes);
+        self.process_input();
+    }
+
+    /// Take bytes from the response output (serial-port).
+    pub fn serial_read(&mut self) -> Option<u8> {
+        self.output.pop_front()
+    }
+
+    fn process_input(&mut self) {
+        while let Some(i) = self.input.iter().position(|c| *c == b'\n') {
+            let line: Vec<u8> = self.input.drain(..=i).collect();
+
+            if let Some(res) = self.request(&line) {
+                self.output.extend(res.as_bytes());
+                self.output.extend(b"\r\n");
+            }
+        }
+    }
+}
+
+fn err(msg: &str) -> Value {
+    json!({ "err": msg })
+}
+
+fn args(req: &Value) -> Map<String, Value> {
+    let mut a = req.as_object().cloned().unwrap_or_default();
+    a.remove("req");
+    a
+}
+
+impl Write for NotecardEmulator {
+    type Error = EmulatorError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        let mut s = self.state();
+
+        if address != NOTECARD_ADDR {
+            return Err(EmulatorError::Address(address));
+        }
+
+        if s.fail_next > 0 {
+            s.fail_next -= 1;
+            return Err(EmulatorError::Bus);
+        }
+
+        s.write(bytes);
+        Ok(())
+    }
+}
+
+impl Read for NotecardEmulator {
+    type Error = EmulatorError;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let mut s = self.state();
+
+        if address != NOTECARD_ADDR {
+            return Err(EmulatorError::Address(address));
+        }
+
+        if s.fail_next > 0 {
+            s.fail_next -= 1;
+            return Err(EmulatorError::Bus);
+        }
+
+        s.read(buffer);
+        Ok(())
+    }
+}
+
+/// Perform a request using the Notecard I2C protocol, like `notecard-rs` does: the request is
+/// written in small chunks, then the available bytes are polled and read in chunks.
+pub fn i2c_request<E, I: Read<Error = E> + Write<Error = E>>(
+    bus: &mut I,
+    req: &[u8],
+) -> Result<Vec<u8>, E> {
+    for chunk in req.chunks(10) {
+        let mut c = vec![chunk.len() as u8];
+        c.extend_from_slice(chunk);
+        bus.write(NOTECARD_ADDR, &c)?;
+    }
+
+    let mut res = Vec::new();
+    let mut buf = [0u8; 32];
+
+    for _ in 0..100 {
+        // query available
+        bus.write(NOTECARD_ADDR, &[0, 0])?;
+        bus.read(NOTECARD_ADDR, &mut buf[..2])?;
+        let mut available = buf[0] as usize;
+
+        while available > 0 {
+            let sz = available.min(buf.len() - 2);
+            bus.write(NOTECARD_ADDR, &[0, sz as u8])?;
+            bus.read(NOTECARD_ADDR, &mut buf[..(sz + 2)])?;
+            res.extend_from_slice(&buf[2..(2 + buf[1] as usize)]);
+            available = buf[0] as usize;
+        }
+
+        if res.ends_with(b"\n") {
+            break;
+        }
+    }
+
+    Ok(res)
+}
+
+/// The emulator connected to a serial port (see [`super::serial`]).
+#[derive(Clone, Default)]
+pub struct EmulatedSerial(pub NotecardEmulator);
+
+impl NotecardEmulator {
+    pub fn serial(&self) -> EmulatedSerial {
+        EmulatedSerial(self.clone())
+    }
+
+    /// Output bytes on the serial port that are not part of a response (noise).
+    pub fn inject_output(&self, bytes: &[u8]) {
+        self.state().output.extend(bytes);
+    }
+}
+
+impl embedded_hal::serial::Read<u8> for EmulatedSerial {
+    type Error = EmulatorError;
+
+    fn read(&mut self) -> nb::Result<u8, EmulatorError> {
+        let mut s = self.0.state();
+
+        if s.fail_next > 0 {
+            s.fail_next -= 1;
+            return Err(nb::Error::Other(EmulatorError::Bus));
+        }
+
+        s.serial_read().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl embedded_hal::blocking::serial::Write<u8> for EmulatedSerial {
+    type Error = EmulatorError;
+
+    fn bwrite_all(&mut self, buffer: &[u8]) -> Result<(), EmulatorError> {
+        self.0.state().serial_write(buffer);
+        Ok(())
+    }
+
+    fn bflush(&mut self) -> Result<(), EmulatorError> {
+        Ok(())
+    }
+}
+
+/// A delay that does not wait.
+pub struct NoDelay;
+
+impl DelayMs<u16> for NoDelay {
+    fn delay_ms(&mut self, _ms: u16) {}
+}
+
+impl DelayMs<u32> for NoDelay {
+    fn delay_ms(&mut self, _ms: u32) {}
+}
+
+impl DelayMs<u8> for NoDelay {
+    fn delay_ms(&mut self, _ms: u8) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn i2c_framing() {
+        let mut n = NotecardEmulator::new();
+
+        n.write(NOTECARD_ADDR, &[]).unwrap(); // ping
+        let res = i2c_request(&mut n, b"{\"req\":\"card.status\"}\n").unwrap();
+
+        assert!(res.ends_with(b"\r\n"));
+        let res: Value = serde_json::from_slice(&res).unwrap();
+        assert_eq!(res["storage"], 0);
+
+        assert_eq!(n.write(0x6a, &[]), Err(EmulatorError::Address(0x6a)));
+    }
+
+    #[test]
+    fn queues_and_sync() {
+        let n = NotecardEmulator::new();
+
+        let r = n.request(br#"{"req":"note.add","file":"axl.qo","body":{"a":1}}"#);
+        assert_eq!(r.unwrap(), r#"{"total":1}"#);
+        assert_eq!(n.state().storage(), STORAGE_PER_NOTE);
+
+        n.request(br#"{"req":"hub.sync"}"#);
+        assert!(n
+            .request(br#"{"req":"hub.sync.status"}"#)
+            .unwrap()
+            .contains("requested"));
+        n.request(br#"{"req":"hub.sync.status"}"#);
+        assert!(n
+            .request(br#"{"req":"hub.sync.status"}"#)
+            .unwrap()
+            .contains("completed"));
+
+        assert_eq!(n.state().storage(), 0);
+        assert_eq!(n.state().synced["axl.qo"].len(), 1);
+
+        n.add_inbound("cmd.qi", json!({"cmd": "reboot"}));
+        let r = n.request(br#"{"req":"note.get","file":"cmd.qi","delete":true}"#);
+        assert!(r.unwrap().contains("reboot"));
+        let r = n.request(br#"{"req":"note.get","file":"cmd.qi","delete":true}"#);
+        assert!(r.unwrap().contains("note-noexist"));
+    }
+
+    #[test]
+    fn templates() {
+        let n = NotecardEmulator::new();
+        n.request(br#"{"req":"note.template","file":"axl.qo","body":{"a":14}}"#);
+
+        let r = n.request(br#"{"req":"note.add","file":"axl.qo","body":{"b":1}}"#);
+        assert!(r.unwrap().contains("template-incompatible"));
+
+        let r = n.request(br#"{"req":"note.add","file":"axl.qo","body":{"a":"x"}}"#);
+        assert!(r.unwrap().contains("template-incompatible"));
+
+        let r = n.request(br#"{"req":"note.add","file":"axl.qo","body":{"a":2}}"#);
+        assert_eq!(r.unwrap(), r#"{"total":1}"#);
+    }
+}
@@ -0,0 +1,1194 @@
+use crate::axl::{AxlPacket, AxlPacketMeta, AxlSummary, AXL_OUTN};
+use crate::cmd::{CommandAck, CommandError, CommandNote};
+use crate::config::{Config, EnvVars, Overrides, CONFIG_CHECK_INTERVAL};
+use crate::gps;
+use crate::log::{EventNote, LogEvent};
+use crate::power::{self, PowerMode, PowerReport};
+use crate::request::{DataRequest, RequestError, RequestQueue};
+use crate::uplink::{self, Position, Uplink};
+use blues_notecard::{self as notecard, NoteError, Notecard, NotecardConfig};
+use core::ops::{Deref, DerefMut};
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::blocking::i2c::{Read, Write};
+
+use crate::NOTEQ_SZ;
+
+#[cfg(test)]
+pub mod emulator;
+pub mod serial;
+
+pub const BUOYSN: &str = const { option_env!("BUOYSN").unwrap_or("cain") };
+pub const BUOYPR: &str = env!("BUOYPR", "Specify notehub project");
+
+/// GPS is sampled at this interval (seconds) when movement is detected by the accelerometer on the
+/// modem. When below 300 seconds the GPS is not turned off when the buoy is moving. For experiment
+/// drifting in fjords and similar 10 minutes is sufficient. However, for experiments on beaches a
+/// higher sample rate is useful.
+// pub const GPS_PERIOD: u32 =  10 * 60;
+pub const GPS_PERIOD: u32 = 60;
+
+/// Initialize sync when storage use is above this percentage.
+pub const NOTECARD_STORAGE_INIT_SYNC: u32 = 50;
+
+/// Do not add more notes when storage use is above this percentage, wait for sync to complete.
+pub const NOTECARD_STORAGE_MAX: u32 = 75;
+
+/// Max time between out-going syncs (minutes).
+pub const NOTECARD_OUTBOUND_PERIOD: u32 = 20;
+
+pub struct Notecarrier<I2C: Read + Write> {
+    note: Notecard<I2C>,
+
+    /// Active configuration, see [`crate::config`].
+    pub config: Config,
+
+    /// Configuration from the environment variables, before the overrides are applied.
+    env_config: Config,
+
+    /// Configuration set by remote commands.
+    overrides: Overrides,
+
+    /// Time of last check for updated configuration (ms).
+    last_config_check: Option<i64>,
+}
+
+/// Progress of data-requests (see [`crate::request`]).
+#[derive(serde::Serialize, serde::Deserialize, Default, defmt::Format, PartialEq)]
+pub struct StorageIdInfo {
+    /// Last sent storage ID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sent_id: Option<u32>,
+
+    /// Packages sent for the current request.
+    #[serde(default)]
+    pub sent: u32,
+
+    /// Packages remaining in all queued requests.
+    #[serde(default)]
+    pub remaining: u32,
+
+    /// Packages not found on the SD-card for the current request.
+    #[serde(default)]
+    pub not_found: u32,
+
+    /// Number of queued requests.
+    #[serde(default)]
+    pub queued: u32,
+}
+
+/// Legacy single range request, see [`crate::request::DataRequest`].
+#[derive(serde::Serialize, serde::Deserialize, Default, defmt::Format, PartialEq)]
+pub struct RequestData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_start: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_end: Option<u32>,
+}
+
+impl<I2C: Read + Write> Notecarrier<I2C> {
+    pub fn new(i2c: I2C, delay: &mut impl DelayMs<u16>) -> Result<Notecarrier<I2C>, NoteError> {
+        let mut note = Notecard::new_with_config(
+            i2c,
+            NotecardConfig {
+                chunk_delay: 5,
+                segment_delay: 20,
+                ..Default::default()
+            },
+        );
+        note.initialize(delay)?;
+
+        let mut n = Notecarrier {
+            note,
+            config: Config::default(),
+            env_config: Config::default(),
+            overrides: Overrides::default(),
+            last_config_check: None,
+        };
+
+        match n.read_config(delay) {
+            Ok(config) => {
+                n.env_config = config;
+                n.config = config;
+            }
+            Err(e) => defmt::error!("Failed to read configuration, using defaults: {:?}", e),
+        }
+        defmt::info!("Configuration: {:?}", n.config);
+        crate::burst::set_schedule(n.config.schedule());
+        crate::retention::set_policy(n.config.retention());
+
+        n.hub_and_location_mode(delay)?;
+
+        n.note
+            .card()
+            .location_track(delay, true, true, false, Some(1), None)?
+            .wait(delay)?;
+
+        let version = n.note.card().version(delay)?.wait(delay)?;
+        defmt::info!("Notecard version: {:?}", version);
+
+        n.setup_templates(delay)?;
+        n.report_config(delay)
+            .inspect_err(|e| defmt::error!("Failed to report configuration: {:?}", e))
+            .ok();
+
+        defmt::info!("initializing initial sync ..");
+        n.note.hub().sync(delay, false)?.wait(delay)?;
+
+        Ok(n)
+    }
+
+    fn hub_set(&mut self, delay: &mut impl DelayMs<u16>) -> Result<(), NoteError> {
+        let config = self.active_config();
+
+        self.note
+            .hub()
+            .set(
+                delay,
+                Some(BUOYPR),
+                None,
+                if config.continuous {
+                    Some(notecard::hub::req::HubMode::Continuous)
+                } else {
+                    Some(notecard::hub::req::HubMode::Periodic)
+                },
+                Some(BUOYSN),
+                Some(config.sync_outbound), // max time between out-going sync in minutes.
+                None,
+                None,
+                None,
+                None,
+                Some(false),
+                None,
+            )?
+            .wait(delay)?;
+
+        Ok(())
+    }
+
+    fn location_mode(&mut self, delay: &mut impl DelayMs<u16>) -> Result<(), NoteError> {
+        let config = self.active_config();
+
+        if config.continuous {
+            // Location mode is not supported when in continuous mode.
+            self.note
+                .card()
+                .location_mode(
+                    delay,
+                    Some("off"),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                )?
+                .wait(delay)?;
+        } else {
+            self.note
+                .card()
+                .location_mode(
+                    delay,
+                    Some("periodic"),
+                    Some(config.gps_period),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                )?
+                .wait(delay)?;
+        }
+
+        Ok(())
+    }
+
+    /// Set hub and location mode in the order required by the Notecard: location mode must be
+    /// turned off before switching to continuous mode.
+    fn hub_and_location_mode(&mut self, delay: &mut impl DelayMs<u16>) -> Result<(), NoteError> {
+        if self.active_config().continuous {
+            self.location_mode(delay)?;
+            self.hub_set(delay)?;
+        } else {
+            self.hub_set(delay)?;
+            self.location_mode(delay)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read environment variables and return the resulting configuration, starting from the
+    /// defaults. Invalid variables are logged and the current configuration from the environment
+    /// variables is returned.
+    pub fn read_config(&mut self, delay: &mut impl DelayMs<u16>) -> Result<Config, NoteError> {
+        let vars: EnvVars = self
+            .note
+            .env()
+            .get(delay, None)?
+            .wait(delay)?
+            .body
+            .unwrap_or_default();
+
+        defmt::debug!("Environment variables: {:?}", vars);
+
+        Ok(Config::from_vars(&vars)
+            .inspect_err(|e| {
+                defmt::error!("Invalid configuration, keeping current: {:?}", e);
+
+                crate::log::event(crate::log::Event::ConfigRejected);
+            })
+            .unwrap_or(self.env_config))
+    }
+
+    /// Check for updated environment variables every `CONFIG_CHECK_INTERVAL` and apply the new
+    /// configuration. Returns `true` if the configuration was changed.
+    pub fn check_config(
+        &mut self,
+        now: i64,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<bool, NoteError> {
+        match self.last_config_check {
+            Some(last) if (now - last) < CONFIG_CHECK_INTERVAL => return Ok(false),
+            _ => self.last_config_check = Some(now),
+        }
+
+        self.env_config = self.read_config(delay)?;
+        let config = self.overrides.apply(self.env_config);
+
+        if config == self.config {
+            return Ok(false);
+        }
+
+        self.apply_config(config, delay)?;
+
+        Ok(true)
+    }
+
+    /// Apply a new configuration, update the Notecard where necessary and report it back.
+    pub fn apply_config(
+        &mut self,
+        config: Config,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), NoteError> {
+        let old = self.active_config();
+        self.config = config;
+        defmt::info!("New configuration: {:?} (was: {:?})", self.config, old);
+        crate::burst::set_schedule(self.config.schedule());
+        crate::retention::set_policy(self.config.retention());
+
+        self.update_modes(&old, delay)?;
+        self.report_config(delay)
+    }
+
+    /// Set the configuration overrides of the remote commands, they are kept when the
+    /// environment variables are updated.
+    pub fn set_overrides(
+        &mut self,
+        overrides: Overrides,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), NoteError> {
+        self.overrides = overrides;
+        self.apply_config(overrides.apply(self.env_config), delay)
+    }
+
+    /// Configuration overrides of the remote commands.
+    pub fn overrides(&self) -> Overrides {
+        self.overrides
+    }
+
+    /// The configuration limited by the current power mode (see [`crate::power`]).
+    pub fn active_config(&self) -> Config {
+        self.config.limited(power::mode())
+    }
+
+    /// Update hub and location mode if they differ from the previously active configuration.
+    fn update_modes(
+        &mut self,
+        old: &Config,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), NoteError> {
+        let config = self.active_config();
+
+        match (config.hub_changed(old), config.location_mode_changed(old)) {
+            (true, _) => self.hub_and_location_mode(delay),
+            (false, true) => self.location_mode(delay),
+            (false, false) => Ok(()),
+        }
+    }
+
+    /// The power mode has changed from `previous`, update the Notecard.
+    pub fn set_power_mode(
+        &mut self,
+        previous: PowerMode,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), NoteError> {
+        let old = self.config.limited(previous);
+        self.update_modes(&old, delay)
+    }
+
+    /// Supply voltage (V).
+    pub fn voltage(&mut self, delay: &mut impl DelayMs<u16>) -> Result<f32, NoteError> {
+        Ok(self.note.card().voltage(delay)?.wait(delay)?.value)
+    }
+
+    /// Report a power mode transition to `power.qo`.
+    pub fn report_power(
+        &mut self,
+        report: &PowerReport,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), NoteError> {
+        self.note
+            .note()
+            .add(delay, Some("power.qo"), None, Some(report), None, false)?
+            .wait(delay)?;
+
+        Ok(())
+    }
+
+    /// Report the active configuration to `config.qo`.
+    pub fn report_config(&mut self, delay: &mut impl DelayMs<u16>) -> Result<(), NoteError> {
+        self.note
+            .note()
+            .add(
+                delay,
+                Some("config.qo"),
+                None,
+                Some(self.config),
+                None,
+                false,
+            )?
+            .wait(delay)?;
+
+        Ok(())
+    }
+
+    /// Initiate sync and wait for it to complete (or time out).
+    pub fn sync_and_wait(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+        timeout_ms: u16,
+    ) -> Result<bool, NoteError> {
+        defmt::info!("sync..");
+        self.note.hub().sync(delay, true)?.wait(delay)?;
+
+        for _ in 0..(timeout_ms / 1000) {
+            delay.delay_ms(1000u16);
+            defmt::debug!("querying sync status..");
+            let status = self.note.hub().sync_status(delay)?.wait(delay);
+            defmt::debug!("status: {:?}", status);
+
+            if let Ok(status) = status {
+                if status.completed.is_some() {
+                    defmt::info!("successful sync.");
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Set up note templates for sensor data and other messages, this will save space and
+    /// bandwidth.
+    fn setup_templates(&mut self, delay: &mut impl DelayMs<u16>) -> Result<(), NoteError> {
+        defmt::debug!("setting up templates..");
+
+        #[derive(serde::Serialize, Default)]
+        struct AxlPacketMetaTemplate {
+            timestamp: u32,
+            offset: u32,
+            length: u32,
+            freq: f32,
+            storage_id: u32,
+            position_time: u32,
+            lon: f32,
+            lat: f32,
+            fix_age: u32,
+            hdop: f32,
+            sats: u8,
+            burst: u32,
+        }
+
+        let meta_template = AxlPacketMetaTemplate {
+            timestamp: 18,
+            offset: 14,
+            length: 14,
+            freq: 14.1,
+            storage_id: 14,
+            position_time: 14,
+            lon: 18.1,
+            lat: 18.1,
+            fix_age: 14,
+            hdop: 12.1,
+            sats: 11,
+            burst: 14,
+        };
+
+        defmt::debug!("setting up template for AxlPacketMeta");
+        self.note()
+            .template(
+                delay,
+                Some("axl.qo"),
+                Some(meta_template),
+                Some(AXL_OUTN as u32),
+            )?
+            .wait(delay)?;
+
+        defmt::debug!("setting up template for EventNote");
+        self.note()
+            .template(
+                delay,
+                Some("event.qo"),
+                Some(EventNote {
+                    code: 12,
+                    severity: 11,
+                    time: 14,
+                    a: 14,
+                    b: 14,
+                    c: 14,
+                }),
+                None,
+            )?
+            .wait(delay)?;
+
+        Ok(())
+    }
+
+    pub fn send(
+        &mut self,
+        pck: &AxlPacket,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<usize, NoteError> {
+        let b64 = pck.base64();
+
+        let meta = AxlPacketMeta {
+            timestamp: pck.timestamp,
+            offset: pck.offset as u32,
+            length: b64.len() as u32,
+            freq: pck.freq,
+            storage_id: pck.storage_id,
+            position_time: pck.position_time,
+            lon: pck.lon,
+            lat: pck.lat,
+            fix_age: pck.fix.map(|f| f.age),
+            hdop: pck.fix.and_then(|f| f.hdop),
+            sats: pck.fix.and_then(|f| f.sats),
+            burst: pck.burst,
+        };
+
+        let r = self
+            .note
+            .note()
+            .add(
+                delay,
+                Some("axl.qo"),
+                None,
+                Some(meta),
+                Some(core::str::from_utf8(&b64).unwrap()),
+                self.active_config().continuous,
+            )?
+            .wait(delay)?;
+
+        defmt::info!(
+            "Sent data package: {}, bytes: {} (note: {:?})",
+            pck.storage_id,
+            b64.len(),
+            r
+        );
+
+        Ok(b64.len())
+    }
+
+    /// Send a summary of the package instead of the raw data (see [`crate::power`]).
+    pub fn send_summary(
+        &mut self,
+        pck: &AxlPacket,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<usize, NoteError> {
+        let summary = pck.summary();
+
+        self.note
+            .note()
+            .add(delay, Some("summary.qo"), None, Some(summary), None, false)?
+            .wait(delay)?;
+
+        defmt::info!("Sent summary of data package: {}", pck.storage_id);
+
+        Ok(core::mem::size_of::<AxlSummary>())
+    }
+
+    /// Take the next data request. The legacy `request-data` note in `storage.db` is taken
+    /// first (and deleted), then requests are taken from the `request.qi` queue. The note is
+    /// removed from the queue by the Notecard, a body that does not decode as a [`DataRequest`]
+    /// is rejected with [`log::Event::RequestRejected`](crate::log::Event::RequestRejected).
+    pub fn take_request(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<Option<DataRequest>, NoteError> {
+        let d: Option<RequestData> = self
+            .note
+            .note()
+            .get(delay, "storage.db", "request-data", false, false)?
+            .wait(delay)
+            .map(|r| r.body)
+            .unwrap_or(None);
+
+        if let Some(RequestData {
+            request_start,
+            request_end,
+        }) = d
+        {
+            defmt::info!(
+                "Taking data-request from storage.db: {:?} -> {:?}",
+                request_start,
+                request_end
+            );
+            self.note
+                .note()
+                .delete(delay, "storage.db", "request-data")
+                .and_then(|r| r.wait(delay))
+                .inspect_err(|e| defmt::error!("Failed to delete request-data: {:?}", e))
+                .ok();
+
+            if let (Some(start), Some(end)) = (request_start, request_end) {
+                return Ok(Some(DataRequest::ids(start, end)));
+            }
+        }
+
+        match self
+            .note
+            .note()
+            .get::<DataRequest>(delay, "request.qi", "", true, false)?
+            .wait(delay)
+        {
+            Ok(r) => Ok(r.body),
+            Err(NoteError::NotecardErr(e)) if e.contains("{note-noexist}") => Ok(None),
+            Err(NoteError::DeserError(e)) => {
+                defmt::error!("Invalid data-request note: {}", e.as_str());
+                crate::log::event(crate::log::Event::RequestRejected {
+                    error: RequestError::Invalid as u8,
+                });
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Request a range of stored packages to be sent. The request is queued after any other
+    /// requests.
+    pub fn write_request(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+        start: u32,
+        end: u32,
+    ) -> Result<(), NoteError> {
+        defmt::info!("Requesting stored packages: {} -> {}", start, end);
+
+        self.note
+            .note()
+            .update(
+                delay,
+                "storage.db",
+                "request-data",
+                Some(RequestData {
+                    request_start: Some(start),
+                    request_end: Some(end),
+                }),
+                None,
+                false,
+            )?
+            .wait(delay)?;
+
+        Ok(())
+    }
+
+    /// Take the next command from `cmd.qi` (if any). The note is removed from the queue by the
+    /// Notecard, a body that does not decode as a [`CommandNote`] is returned as
+    /// [`CommandError::InvalidNote`] so that it can be rejected.
+    pub fn read_command(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<Option<Result<CommandNote, CommandError>>, NoteError> {
+        match self
+            .note
+            .note()
+            .get::<CommandNote>(delay, "cmd.qi", "", true, false)?
+            .wait(delay)
+        {
+            Ok(r) => Ok(r.body.map(Ok)),
+            Err(NoteError::NotecardErr(e)) if e.contains("{note-noexist}") => Ok(None),
+            Err(NoteError::DeserError(e)) => {
+                defmt::error!("Invalid command note: {}", e.as_str());
+                Ok(Some(Err(CommandError::InvalidNote)))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Acknowledge a command in `cmd.qo`.
+    pub fn ack_command(
+        &mut self,
+        ack: &CommandAck,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), NoteError> {
+        self.note
+            .note()
+            .add(delay, Some("cmd.qo"), None, Some(ack), None, false)?
+            .wait(delay)?;
+
+        Ok(())
+    }
+
+    /// Read the queue of data-requests being served.
+    pub fn read_request_queue(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<Option<RequestQueue>, NoteError> {
+        Ok(self
+            .note
+            .note()
+            .get(delay, "storage.dbx", "request-queue", false, false)?
+            .wait(delay)
+            .map(|r| r.body)
+            .unwrap_or(None))
+    }
+
+    pub fn write_request_queue(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+        queue: &RequestQueue,
+    ) -> Result<(), NoteError> {
+        self.note
+            .note()
+            .update(
+                delay,
+                "storage.dbx",
+                "request-queue",
+                Some(queue),
+                None,
+                false,
+            )?
+            .wait(delay)?;
+
+        Ok(())
+    }
+
+    pub fn read_storage_info(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<Option<StorageIdInfo>, NoteError> {
+        Ok(self
+            .note
+            .note()
+            .get(delay, "storage.dbx", "storage-info", false, false)?
+            .wait(delay)
+            .map(|r| r.body)
+            .unwrap_or(None))
+    }
+
+    /// Update progress of data-requests.
+    pub fn write_storage_info(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+        info: StorageIdInfo,
+    ) -> Result<(), NoteError> {
+        let current_info = self.read_storage_info(delay).ok().flatten();
+
+        if Some(&info) != current_info.as_ref() {
+            defmt::trace!("Updating storage-info: {:?}", info);
+            self.note
+                .note()
+                .delete(delay, "storage.dbx", "storage-info")
+                .and_then(|r| r.wait(delay))
+                .inspect_err(|e| defmt::error!("Failed to delete storage-info: {:?}", e))
+                .ok();
+
+            self.note
+                .note()
+                .update(
+                    delay,
+                    "storage.dbx",
+                    "storage-info",
+                    Some(info),
+                    None,
+                    false,
+                )?
+                .wait(delay)?;
+        }
+
+        Ok(())
+    }
+
+    /// Report a completed data-request to `storage.qo`.
+    pub fn report_request(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+        request: &DataRequest,
+    ) -> Result<(), NoteError> {
+        self.note
+            .note()
+            .add(delay, Some("storage.qo"), None, Some(request), None, false)?
+            .wait(delay)?;
+
+        Ok(())
+    }
+
+    /// Send queued packages to the notecard.
+    pub fn drain_queue(
+        &mut self,
+        queue: &mut heapless::spsc::Consumer<'static, AxlPacket, NOTEQ_SZ>,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<usize, NoteError> {
+        // Sending packages takes a long time (16-17 seconds). Only 1 package is sent at a time
+        // before running main-loop again and letting other tasks run. The main-loop will keep
+        // going immediately again if there are more data in the queue.
+        uplink::drain_queue(self, queue, delay)
+    }
+
+    /// Notecard has room for more notes.
+    fn has_storage(&mut self, delay: &mut impl DelayMs<u16>) -> Result<bool, NoteError> {
+        // #[cfg(not(feature = "continuous"))]
+        // {
+        //     let sync_status = self.note.hub().sync_status(delay)?.wait(delay)?;
+
+        //     if sync_status.requested.is_some() {
+        //         defmt::warn!(
+        //             "notecard is syncing, not sending any data-packages until done: queue sz: {}",
+        //             queue.len()
+        //         );
+        //         return Ok(sz);
+        //     }
+        // }
+
+        let status = self.note.card().status(delay)?.wait(delay)?;
+
+        if status.storage > self.config.storage_max as usize {
+            // wait until notecard has synced.
+            defmt::warn!(
+                "notecard is more than {}% full, not adding more notes until sync is done.",
+                self.config.storage_max
+            );
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Check if notecard is filling up, and initiate sync in that case.
+    pub fn check_and_sync(&mut self, delay: &mut impl DelayMs<u16>) -> Result<(), NoteError> {
+        let status = self.note.card().status(delay)?.wait(delay)?;
+        defmt::trace!("card.status: {}", status);
+
+        let sync_status = self.note.hub().sync_status(delay)?.wait(delay)?;
+        defmt::trace!("hub.sync_status: {}", sync_status);
+
+        #[cfg(debug_assertions)]
+        {
+            let wireless = self.note.card().wireless(delay).and_then(|r| r.wait(delay));
+            defmt::trace!("card.wireless: {}", wireless);
+        }
+
+        if status.storage > self.config.sync_storage as usize {
+            if sync_status.requested.is_none() {
+                defmt::warn!(
+                    "notecard is more than {}% full, initiating sync.",
+                    self.config.sync_storage
+                );
+                self.note.hub().sync(delay, false)?.wait(delay)?;
+            }
+            defmt::info!(
+                "notecard is filling up ({}%): sync status: {:?}",
+                status.storage,
+                sync_status
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl<I2C: Read + Write> Uplink for Notecarrier<I2C> {
+    type Error = NoteError;
+
+    fn ready(&mut self, delay: &mut impl DelayMs<u16>) -> Result<bool, NoteError> {
+        self.has_storage(delay)
+    }
+
+    fn send_packet(
+        &mut self,
+        pck: &AxlPacket,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<usize, NoteError> {
+        if power::mode().sends_raw() {
+            self.send(pck, delay)
+        } else {
+            self.send_summary(pck, delay)
+        }
+    }
+
+    fn send_log(&mut self, msg: &str, delay: &mut impl DelayMs<u16>) -> Result<(), NoteError> {
+        self.note.hub().log(delay, msg, false, false)?.wait(delay)?;
+
+        Ok(())
+    }
+
+    fn send_event(
+        &mut self,
+        event: &LogEvent,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), NoteError> {
+        self.note
+            .note()
+            .add(
+                delay,
+                Some("event.qo"),
+                None,
+                Some(EventNote::from(event)),
+                None,
+                false,
+            )?
+            .wait(delay)?;
+
+        Ok(())
+    }
+
+    fn time(&mut self, delay: &mut impl DelayMs<u16>) -> Result<Option<u32>, NoteError> {
+        Ok(self
+            .note
+            .card()
+            .time(delay)?
+            .wait(delay)
+            .ok()
+            .and_then(|t| t.time))
+    }
+
+    fn location(&mut self, delay: &mut impl DelayMs<u16>) -> Result<Option<Position>, NoteError> {
+        let gps = self.note.card().location(delay)?.wait(delay)?;
+
+        match gps {
+            notecard::card::res::Location {
+                lat: Some(lat),
+                lon: Some(lon),
+                time: Some(time),
+                status,
+                ..
+            } => Ok(Some(Position {
+                lat,
+                lon,
+                time,
+                hdop: None, // `dop` is not exposed by notecard-rs.
+                sats: gps::parse_sats(&status),
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    fn location_interval_ms(&self) -> i64 {
+        self.config.location_interval_ms()
+    }
+
+    fn take_request(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<Option<DataRequest>, NoteError> {
+        Notecarrier::take_request(self, delay)
+    }
+
+    fn read_request_queue(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<Option<RequestQueue>, NoteError> {
+        Notecarrier::read_request_queue(self, delay)
+    }
+
+    fn write_request_queue(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+        queue: &RequestQueue,
+    ) -> Result<(), NoteError> {
+        Notecarrier::write_request_queue(self, delay, queue)
+    }
+
+    fn report_request(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+        request: &DataRequest,
+    ) -> Result<(), NoteError> {
+        Notecarrier::report_request(self, delay, request)
+    }
+
+    fn write_storage_info(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+        info: StorageIdInfo,
+    ) -> Result<(), NoteError> {
+        Notecarrier::write_storage_info(self, delay, info)
+    }
+
+    fn sync(&mut self, delay: &mut impl DelayMs<u16>) -> Result<(), NoteError> {
+        self.note.hub().sync(delay, false)?.wait(delay)?;
+
+        Ok(())
+    }
+
+    fn check_and_sync(&mut self, delay: &mut impl DelayMs<u16>) -> Result<(), NoteError> {
+        Notecarrier::check_and_sync(self, delay)
+    }
+}
+
+impl<I2C: Read + Write> Deref for Notecarrier<I2C> {
+    type Target = Notecard<I2C>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.note
+    }
+}
+
+impl<I2C: Read + Write> DerefMut for Notecarrier<I2C> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.note
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::emulator::{NoDelay, NotecardEmulator};
+    use super::*;
+    use crate::axl::AXL_SZ;
+    use half::f16;
+    use serde_json::json;
+
+    fn notecarrier() -> (NotecardEmulator, Notecarrier<NotecardEmulator>) {
+        let card = NotecardEmulator::new();
+        let note = Notecarrier::new(card.clone(), &mut NoDelay).unwrap();
+        (card, note)
+    }
+
+    fn package(storage_id: u32) -> AxlPacket {
+        AxlPacket {
+            timestamp: 1000,
+            position_time: 0,
+            lat: 0.0,
+            lon: 0.0,
+            freq: 52.0,
+            offset: 0,
+            storage_id: Some(storage_id),
+            storage_version: None,
+            fix: None,
+            burst: None,
+            data: (0..AXL_SZ).map(|v| f16::from_f32(v as f32)).collect(),
+        }
+    }
+
+    #[test]
+    fn setup() {
+        let (card, note) = notecarrier();
+
+        assert_eq!(note.config, Config::default());
+
+        let s = card.state();
+        assert_eq!(s.hub["mode"], "periodic");
+        assert_eq!(s.location_mode["mode"], "periodic");
+        assert_eq!(s.location_mode["seconds"], GPS_PERIOD);
+        assert!(s.templates.contains_key("axl.qo"));
+        assert_eq!(s.queues["config.qo"].len(), 1);
+        assert_eq!(s.sync_pending, Some(s.sync_polls));
+    }
+
+    #[test]
+    fn setup_with_env() {
+        let card = NotecardEmulator::new();
+        card.state()
+            .env
+            .insert("sfy_gps_period".into(), "600".into());
+
+        let note = Notecarrier::new(card.clone(), &mut NoDelay).unwrap();
+        assert_eq!(note.config.gps_period, 600);
+        assert_eq!(card.state().location_mode["seconds"], 600);
+    }
+
+    #[test]
+    fn send_and_sync() {
+        let (card, mut note) = notecarrier();
+        note.sync_and_wait(&mut NoDelay, 10_000).unwrap();
+        assert!(card.queue("config.qo").is_empty());
+
+        let q = Box::leak(Box::new(heapless::spsc::Queue::<AxlPacket, NOTEQ_SZ>::new()));
+        let (mut p, mut c) = q.split();
+        p.enqueue(package(3)).unwrap();
+
+        assert!(note.drain_queue(&mut c, &mut NoDelay).unwrap() > 0);
+        assert!(c.is_empty());
+
+        let sent = card.queue("axl.qo");
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].body.as_ref().unwrap()["storage_id"], 3);
+        assert!(sent[0].payload.is_some());
+
+        // Card full: packages are kept in queue.
+        card.state().storage_base = 80;
+        p.enqueue(package(4)).unwrap();
+        assert_eq!(note.drain_queue(&mut c, &mut NoDelay).unwrap(), 0);
+        assert_eq!(c.len(), 1);
+
+        // Above sync limit: sync is initiated.
+        note.check_and_sync(&mut NoDelay).unwrap();
+        assert!(card.state().sync_pending.is_some());
+        assert!(card.request_names().contains(&"hub.sync".to_string()));
+    }
+
+    #[test]
+    fn config_update() {
+        let (card, mut note) = notecarrier();
+
+        assert!(!note.check_config(0, &mut NoDelay).unwrap());
+
+        card.state()
+            .env
+            .insert("sfy_sync_outbound".into(), "60".into());
+        assert!(!note.check_config(1000, &mut NoDelay).unwrap());
+        assert!(note
+            .check_config(CONFIG_CHECK_INTERVAL, &mut NoDelay)
+            .unwrap());
+
+        assert_eq!(note.config.sync_outbound, 60);
+        assert_eq!(card.state().hub["outbound"], 60);
+        assert_eq!(card.queue("config.qo").len(), 2);
+
+        // Invalid variables are rejected as a whole.
+        card.state().env.insert("sfy_gps_period".into(), "1".into());
+        assert!(!note
+            .check_config(2 * CONFIG_CHECK_INTERVAL, &mut NoDelay)
+            .unwrap());
+        assert_eq!(note.config.gps_period, GPS_PERIOD);
+
+        // Removing the variables reverts to the defaults.
+        card.state().env.clear();
+        assert!(note
+            .check_config(3 * CONFIG_CHECK_INTERVAL, &mut NoDelay)
+            .unwrap());
+        assert_eq!(note.config, Config::default());
+        assert_eq!(card.state().hub["outbound"], NOTECARD_OUTBOUND_PERIOD);
+    }
+
+    #[test]
+    fn commands() {
+        let (card, mut note) = notecarrier();
+
+        card.add_inbound(
+            "cmd.qi",
+            json!({ "id": 1, "cmd": "gps-period", "value": 300 }),
+        );
+        card.add_inbound("cmd.qi", json!({ "id": 2, "cmd": "explode" }));
+        card.add_inbound("cmd.qi", json!({ "id": 3, "cmd": 12 }));
+
+        assert_eq!(
+            crate::cmd::check_command(&mut note, &mut NoDelay).unwrap(),
+            Some(crate::cmd::Command::GpsPeriod(300))
+        );
+        assert_eq!(card.state().location_mode["seconds"], 300);
+
+        // The command is kept when the environment variables are updated.
+        card.state()
+            .env
+            .insert("sfy_gps_period".into(), "600".into());
+        card.state()
+            .env
+            .insert("sfy_sync_outbound".into(), "60".into());
+        assert!(note.check_config(0, &mut NoDelay).unwrap());
+        assert_eq!(note.config.gps_period, 300);
+        assert_eq!(note.config.sync_outbound, 60);
+
+        for _ in 0..3 {
+            assert_eq!(
+                crate::cmd::check_command(&mut note, &mut NoDelay).unwrap(),
+                None
+            );
+        }
+        assert!(card.queue("cmd.qi").is_empty());
+
+        let acks = card.queue("cmd.qo");
+        assert_eq!(acks.len(), 3);
+        assert_eq!(acks[0].body.as_ref().unwrap()["ok"], true);
+        assert_eq!(acks[1].body.as_ref().unwrap()["result"], "unknown-command");
+        assert_eq!(acks[2].body.as_ref().unwrap()["ok"], false);
+        assert_eq!(acks[2].body.as_ref().unwrap()["result"], "invalid-note");
+
+        // Bus errors are returned, not taken as an empty queue.
+        card.add_inbound("cmd.qi", json!({ "id": 4, "cmd": "flush" }));
+        card.state().fail_next = 1;
+        assert!(crate::cmd::check_command(&mut note, &mut NoDelay).is_err());
+    }
+
+    #[test]
+    fn requests() {
+        let (card, mut note) = notecarrier();
+
+        assert_eq!(note.take_request(&mut NoDelay).unwrap(), None);
+
+        note.write_request(&mut NoDelay, 10, 20).unwrap();
+        card.add_inbound("request.qi", json!({ "start_time": 100, "end_time": 200 }));
+
+        assert_eq!(
+            note.take_request(&mut NoDelay).unwrap(),
+            Some(DataRequest::ids(10, 20))
+        );
+        assert!(card.db("storage.db", "request-data").is_none());
+        assert_eq!(
+            note.take_request(&mut NoDelay).unwrap(),
+            Some(DataRequest::time(100, 200))
+        );
+        assert_eq!(note.take_request(&mut NoDelay).unwrap(), None);
+
+        // Invalid notes are rejected, bus errors are returned.
+        card.add_inbound("request.qi", json!({ "start": "first" }));
+        assert_eq!(note.take_request(&mut NoDelay).unwrap(), None);
+
+        card.add_inbound("request.qi", json!({ "start": 0, "end": 5 }));
+        card.state().fail_next = 1;
+        assert!(note.take_request(&mut NoDelay).is_err());
+        assert_eq!(
+            note.take_request(&mut NoDelay).unwrap(),
+            Some(DataRequest::ids(0, 5))
+        );
+
+        let mut q = RequestQueue::new();
+        q.push(DataRequest::ids(0, 5)).unwrap();
+        note.write_request_queue(&mut NoDelay, &q).unwrap();
+        assert_eq!(note.read_request_queue(&mut NoDelay).unwrap(), Some(q));
+    }
+
+    #[test]
+    fn bus_errors() {
+        let (card, mut note) = notecarrier();
+
+        card.state().fail_next = 1;
+        assert!(note.check_and_sync(&mut NoDelay).is_err());
+        assert!(note.check_and_sync(&mut NoDelay).is_ok());
+    }
+
+    #[test]
+    fn read_transmitted_data_package() {
+        use std::fs;
+
+        let sent_data = (0..3072)
+            .map(|v| f16::from_f32(v as f32))
+            .collect::<heapless::Vec<_, { AXL_SZ }>>();
+
+        let length: usize = 8192;
+        let b64 = fs::read("tests/data/transmitted_payload.txt").unwrap();
+
+        let b64 = &b64[..length];
+
+        // this test assumes host platform is little endian
+
+        let mut buf = Vec::with_capacity(3072 * 2);
+        buf.resize(3072 * 2, 0);
+        let _data_bytes = base64::decode_config_slice(b64, base64::STANDARD, &mut buf).unwrap();
+        let data_values = bytemuck::cast_slice::<_, half::f16>(&buf);
+
+        assert_eq!(sent_data, data_values);
+    }
+}
@@ -0,0 +1,350 @@
+//! Notecard on a serial port (UART).
+//!
+//! `notecard-rs` speaks the Notecard I2C protocol. [`SerialTransport`] implements the I2C traits
+//! on top of a serial port, so that a [`Notecarrier`](super::Notecarrier) can be used with a
+//! Notecard connected to a UART (9600 baud by default). On the serial port requests and responses
+//! are newline-delimited JSON:
+//!
+//! * I2C writes of request data are forwarded to the serial port.
+//! * Responses are buffered until a complete line has been received, only then is the line
+//!   reported as available to the I2C reader (like the Notecard does on I2C).
+//!
+//! A response that is not complete within [`SerialTransport::timeout`] is discarded and the read
+//! fails. Before the next request the transport resynchronizes: a newline is sent to make the
+//! Notecard discard any partial request, and any input that does not belong to the new request
+//! (late responses, noise) is discarded.
+
+use embedded_hal::{
+    blocking::{
+        delay::DelayMs,
+        i2c::{Read, Write},
+        serial,
+    },
+    serial::Read as SerialRead,
+};
+use heapless::Vec;
+
+use super::Notecarrier;
+
+/// Size of receive buffer, responses longer than this are discarded.
+pub const RX_SZ: usize = 1024;
+
+/// Default time to wait for a response (ms).
+pub const RESPONSE_TIMEOUT: u16 = 5_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum SerialError {
+    /// Error reading or writing the serial port.
+    Serial,
+
+    /// No complete response within timeout.
+    Timeout,
+
+    /// The response did not fit in the receive buffer.
+    Overflow,
+}
+
+pub struct SerialTransport<S, D> {
+    serial: S,
+    delay: D,
+
+    /// Received bytes.
+    rx: Vec<u8, RX_SZ>,
+
+    /// Bytes requested for the next read (I2C `[0, n]` write).
+    requested: usize,
+
+    /// A request is being written (the newline has not been written yet).
+    in_request: bool,
+
+    /// A request has been sent, waiting for the response.
+    awaiting: bool,
+
+    /// The last response was incomplete or corrupt, resynchronize before next request.
+    desync: bool,
+
+    /// Time to wait for a response (ms).
+    pub timeout: u16,
+}
+
+/// A [`Notecarrier`] with the Notecard on a serial port.
+pub type SerialNotecarrier<S, D> = Notecarrier<SerialTransport<S, D>>;
+
+impl<E, S, D> SerialTransport<S, D>
+where
+    S: SerialRead<u8, Error = E> + serial::Write<u8, Error = E>,
+    D: DelayMs<u16>,
+{
+    pub fn new(serial: S, delay: D) -> SerialTransport<S, D> {
+        SerialTransport {
+            serial,
+            delay,
+            rx: Vec::new(),
+            requested: 0,
+            in_request: false,
+            awaiting: false,
+            desync: true,
+            timeout: RESPONSE_TIMEOUT,
+        }
+    }
+
+    pub fn free(self) -> (S, D) {
+        (self.serial, self.delay)
+    }
+
+    /// Read all bytes available on the serial port into the receive buffer.
+    fn poll(&mut self) -> Result<(), SerialError> {
+        loop {
+            match self.serial.read() {
+                Ok(b) => {
+                    if self.rx.push(b).is_err() {
+                        defmt::error!("notecard serial: response too long, discarding.");
+                        self.rx.clear();
+                        self.desync = true;
+                        self.awaiting = false;
+                        return Err(SerialError::Overflow);
+                    }
+                }
+                Err(nb::Error::WouldBlock) => return Ok(()),
+                Err(nb::Error::Other(_)) => {
+                    self.desync = true;
+                    return Err(SerialError::Serial);
+                }
+            }
+        }
+    }
+
+    /// Length of the first complete line in the receive buffer (including line ending).
+    fn line(&self) -> Option<usize> {
+        self.rx.iter().position(|b| *b == b'\n').map(|i| i + 1)
+    }
+
+    /// Wait for a complete response line.
+    fn wait_response(&mut self) -> Result<(), SerialError> {
+        for _ in 0..self.timeout {
+            self.poll()?;
+
+            if self.line().is_some() {
+                return Ok(());
+            }
+
+            self.delay.delay_ms(1u16);
+        }
+
+        defmt::error!(
+            "notecard serial: no response within {} ms, discarding: {} bytes.",
+            self.timeout,
+            self.rx.len()
+        );
+        self.rx.clear();
+        self.awaiting = false;
+        self.desync = true;
+
+        Err(SerialError::Timeout)
+    }
+
+    /// Make the Notecard discard any partial request, and discard any unread input.
+    pub fn resync(&mut self) -> Result<(), SerialError> {
+        defmt::debug!("notecard serial: resync");
+
+        self.serial
+            .bwrite_all(b"\n")
+            .and_then(|_| self.serial.bflush())
+            .map_err(|_| SerialError::Serial)?;
+        self.delay.delay_ms(20u16);
+
+        while self.serial.read().is_ok() {}
+        self.rx.clear();
+        self.desync = false;
+
+        Ok(())
+    }
+}
+
+impl<E, S, D> Write for SerialTransport<S, D>
+where
+    S: SerialRead<u8, Error = E> + serial::Write<u8, Error = E>,
+    D: DelayMs<u16>,
+{
+    type Error = SerialError;
+
+    fn write(&mut self, _address: u8, bytes: &[u8]) -> Result<(), SerialError> {
+        match bytes {
+            [] => Ok(()), // ping
+            [0] => Ok(()),
+            [0, n, ..] => {
+                self.requested = *n as usize;
+                Ok(())
+            }
+            [n, data @ ..] => {
+                let data = &data[..(*n as usize).min(data.len())];
+
+                if !self.in_request {
+                    // New request: anything received now does not belong to it.
+                    self.poll().ok();
+
+                    if self.desync || !self.rx.is_empty() {
+                        self.resync()?;
+                    }
+                }
+
+                self.serial
+                    .bwrite_all(data)
+                    .and_then(|_| self.serial.bflush())
+                    .map_err(|_| SerialError::Serial)?;
+
+                self.in_request = !data.ends_with(b"\n");
+                self.awaiting = !self.in_request;
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<E, S, D> Read for SerialTransport<S, D>
+where
+    S: SerialRead<u8, Error = E> + serial::Write<u8, Error = E>,
+    D: DelayMs<u16>,
+{
+    type Error = SerialError;
+
+    /// Returns `[available, returned, data..]`.
+    fn read(&mut self, _address: u8, buffer: &mut [u8]) -> Result<(), SerialError> {
+        buffer.fill(0);
+
+        if buffer.len() < 2 {
+            return Ok(());
+        }
+
+        if self.awaiting && self.line().is_none() {
+            self.wait_response()?;
+        } else {
+            self.poll()?;
+        }
+
+        let available = self.line().unwrap_or(0);
+        let n = self.requested.min(available).min(buffer.len() - 2);
+
+        buffer[2..(2 + n)].copy_from_slice(&self.rx[..n]);
+        self.rx.rotate_left(n);
+        self.rx.truncate(self.rx.len() - n);
+
+        buffer[0] = (available - n).min(u8::MAX as usize) as u8;
+        buffer[1] = n as u8;
+
+        if n > 0 && n == available {
+            self.awaiting = false;
+        }
+
+        self.requested = 0;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::note::emulator::{i2c_request, EmulatedSerial, NoDelay, NotecardEmulator};
+    use serde_json::Value;
+
+    fn transport(n: &NotecardEmulator) -> SerialTransport<EmulatedSerial, NoDelay> {
+        SerialTransport::new(n.serial(), NoDelay)
+    }
+
+    #[test]
+    fn request_response() {
+        let n = NotecardEmulator::new();
+        let mut t = transport(&n);
+
+        for _ in 0..3 {
+            let res = i2c_request(&mut t, b"{\"req\":\"card.status\"}\n").unwrap();
+            let res: Value = serde_json::from_slice(&res).unwrap();
+            assert_eq!(res["storage"], 0);
+        }
+
+        // Long response, read in many chunks.
+        let res = i2c_request(&mut t, b"{\"req\":\"card.version\"}\n").unwrap();
+        assert!(res.len() > 255);
+        let res: Value = serde_json::from_slice(&res).unwrap();
+        assert_eq!(res["body"]["product"], "Notecard");
+
+        // Only one resync at start.
+        assert_eq!(n.request_names().len(), 4);
+    }
+
+    #[test]
+    fn noise_is_discarded() {
+        let n = NotecardEmulator::new();
+        let mut t = transport(&n);
+
+        n.inject_output(b"{\"garbage\r\n\x00\xff");
+
+        let res = i2c_request(&mut t, b"{\"req\":\"card.status\"}\n").unwrap();
+        let res: Value = serde_json::from_slice(&res).unwrap();
+        assert_eq!(res["storage"], 0);
+
+        // Late response from an earlier request.
+        i2c_request(&mut t, b"{\"req\":\"card.status\"}\n").unwrap();
+        n.inject_output(b"{\"total\":1}\r\n");
+
+        let res = i2c_request(&mut t, b"{\"req\":\"card.wireless\"}\n").unwrap();
+        let res: Value = serde_json::from_slice(&res).unwrap();
+        assert_eq!(res["status"], "{modem-on}");
+    }
+
+    #[test]
+    fn timeout_and_resync() {
+        let n = NotecardEmulator::new();
+        let mut t = transport(&n);
+        t.timeout = 10;
+
+        // Incomplete request: no response.
+        assert_eq!(
+            i2c_request(&mut t, b"{\"req\":\"card.sta"),
+            Ok(std::vec::Vec::new())
+        );
+
+        // The Notecard has a partial response in progress.
+        t.in_request = false;
+        t.awaiting = true;
+        n.inject_output(b"{\"sto");
+        let mut buf = [0u8; 2];
+        t.write(0x17, &[0, 0]).unwrap();
+        assert_eq!(t.read(0x17, &mut buf), Err(SerialError::Timeout));
+
+        // Partial request is terminated by resync and discarded by the Notecard.
+        let res = i2c_request(&mut t, b"{\"req\":\"card.status\"}\n").unwrap();
+        let res: Value = serde_json::from_slice(&res).unwrap();
+        assert_eq!(res["storage"], 0);
+    }
+
+    #[test]
+    fn serial_errors() {
+        let n = NotecardEmulator::new();
+        let mut t = transport(&n);
+
+        // Error while waiting for the response.
+        t.write(0x17, b"\x16{\"req\":\"card.status\"}\n").unwrap();
+        n.state().fail_next = 1;
+        t.write(0x17, &[0, 0]).unwrap();
+        assert_eq!(t.read(0x17, &mut [0u8; 2]), Err(SerialError::Serial));
+
+        // Error before a request is recovered by resync.
+        n.state().fail_next = 1;
+
+        let res = i2c_request(&mut t, b"{\"req\":\"card.status\"}\n").unwrap();
+        let res: Value = serde_json::from_slice(&res).unwrap();
+        assert_eq!(res["storage"], 0);
+    }
+
+    #[test]
+    fn notecarrier() {
+        let n = NotecardEmulator::new();
+        let note: SerialNotecarrier<_, _> = Notecarrier::new(transport(&n), &mut NoDelay).unwrap();
+
+        assert_eq!(note.config, crate::config::Config::default());
+        assert!(n.state().templates.contains_key("axl.qo"));
+    }
+}
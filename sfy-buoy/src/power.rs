@@ -0,0 +1,410 @@
+//! Battery-aware power management.
+//!
+//! The supply voltage is read from the Notecard (`card.voltage`) every [`POWER_CHECK_INTERVAL`],
+//! and the buoy is moved between power modes depending on configurable voltage thresholds (see
+//! [`crate::config`]):
+//!
+//! * [`PowerMode::Continuous`]: raw data is recorded and sent, the modem is kept connected (only
+//!   if continuous mode is configured).
+//! * [`PowerMode::Periodic`]: raw data is recorded and sent, the modem syncs periodically.
+//! * [`PowerMode::Summary`]: raw data is recorded to the SD-card, but only a summary of every
+//!   package is sent (`summary.qo`). Data-requests are not served, and syncs are less frequent.
+//! * [`PowerMode::Survival`]: nothing is recorded and the IMU is powered down, only the GPS is
+//!   sampled (rarely) and synced.
+//!
+//! The buoy moves to a more restrictive mode as soon as the voltage drops below the threshold,
+//! but only moves back when the voltage is [`HYSTERESIS`] above the threshold, so that it does
+//! not flip between modes as the voltage recovers with reduced load. The activity of the IMU and
+//! the SD-card is counted between the checks (see [`ACTIVITY`]): if they were idle the voltage is
+//! read without their load, and [`IDLE_HYSTERESIS`] is required instead. Mode transitions are
+//! logged and reported to `power.qo` together with the activity (IMU packages, packages stored
+//! and sent, and the batches written to the SD-card) since the previous check.
+
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use embedded_hal::blocking::{
+    delay::DelayMs,
+    i2c::{Read, Write},
+};
+
+use crate::config::Config;
+use crate::note::Notecarrier;
+use blues_notecard::NoteError;
+
+/// Interval between checking the supply voltage (ms).
+pub const POWER_CHECK_INTERVAL: i64 = 5 * 60_000;
+
+/// Margin above threshold required before moving to a less restrictive mode (mV).
+pub const HYSTERESIS: u32 = 100;
+
+/// Margin above threshold required before moving to a less restrictive mode when the IMU and the
+/// SD-card have been idle since the previous check (mV).
+pub const IDLE_HYSTERESIS: u32 = 250;
+
+/// Default threshold (mV) below which continuous mode is not used.
+pub const POWER_PERIODIC: u32 = 3600;
+
+/// Default threshold (mV) below which only summaries are sent.
+pub const POWER_SUMMARY: u32 = 3400;
+
+/// Default threshold (mV) below which nothing is recorded.
+pub const POWER_SURVIVAL: u32 = 3200;
+
+/// GPS period in survival mode (seconds).
+pub const SURVIVAL_GPS_PERIOD: u32 = 3600;
+
+/// Max time between outbound syncs in survival mode (minutes).
+pub const SURVIVAL_SYNC_OUTBOUND: u32 = 12 * 60;
+
+/// Max time between outbound syncs in summary mode (minutes).
+pub const SUMMARY_SYNC_OUTBOUND: u32 = 60;
+
+/// Power modes, from least to most restrictive.
+#[repr(u8)]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Serialize,
+    serde::Deserialize,
+    defmt::Format,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerMode {
+    Continuous = 0,
+    Periodic = 1,
+    Summary = 2,
+    Survival = 3,
+}
+
+/// The active power mode. Read by the IMU interrupt and the main loop.
+static MODE: AtomicU8 = AtomicU8::new(if cfg!(feature = "continuous") {
+    PowerMode::Continuous as u8
+} else {
+    PowerMode::Periodic as u8
+});
+
+/// The active power mode.
+pub fn mode() -> PowerMode {
+    PowerMode::from_u8(MODE.load(Ordering::Acquire))
+}
+
+fn set_mode(mode: PowerMode) {
+    MODE.store(mode as u8, Ordering::Release);
+}
+
+impl PowerMode {
+    const fn from_u8(v: u8) -> PowerMode {
+        use PowerMode::*;
+
+        match v {
+            0 => Continuous,
+            1 => Periodic,
+            2 => Summary,
+            _ => Survival,
+        }
+    }
+
+    pub const fn as_str(&self) -> &'static str {
+        use PowerMode::*;
+
+        match self {
+            Continuous => "continuous",
+            Periodic => "periodic",
+            Summary => "summary",
+            Survival => "survival",
+        }
+    }
+
+    /// IMU packages are recorded.
+    pub const fn records(&self) -> bool {
+        !matches!(self, PowerMode::Survival)
+    }
+
+    /// Raw data packages are sent (and data-requests served).
+    pub const fn sends_raw(&self) -> bool {
+        matches!(self, PowerMode::Continuous | PowerMode::Periodic)
+    }
+
+    /// The mode for a voltage without hysteresis.
+    fn classify(mv: u32, config: &Config) -> PowerMode {
+        use PowerMode::*;
+
+        if mv < config.power_survival {
+            Survival
+        } else if mv < config.power_summary {
+            Summary
+        } else if mv < config.power_periodic || !config.continuous {
+            Periodic
+        } else {
+            Continuous
+        }
+    }
+
+    /// The mode for a voltage (mV) given the current mode and the activity since the previous
+    /// check: more restrictive modes are entered immediately, less restrictive modes only when
+    /// the voltage is above the threshold with [`HYSTERESIS`] ([`IDLE_HYSTERESIS`] if the IMU and
+    /// the SD-card were idle).
+    pub fn for_voltage(
+        mv: u32,
+        current: PowerMode,
+        activity: &ActivityCount,
+        config: &Config,
+    ) -> PowerMode {
+        let mode = PowerMode::classify(mv, config);
+
+        let hysteresis = if activity.is_idle() {
+            IDLE_HYSTERESIS
+        } else {
+            HYSTERESIS
+        };
+
+        if mode >= current {
+            mode
+        } else {
+            PowerMode::classify(mv.saturating_sub(hysteresis), config).min(current)
+        }
+    }
+}
+
+/// Activity counters, incremented as packages pass through the firmware.
+pub struct Activity {
+    /// Packages recorded from the IMU.
+    pub imu: AtomicU32,
+
+    /// Packages stored to the SD-card.
+    pub stored: AtomicU32,
+
+    /// Packages (or summaries) sent over the uplink.
+    pub sent: AtomicU32,
+
+    /// Batches of packages written to the SD-card (see [`crate::storage::writer`]).
+    pub flushes: AtomicU32,
+
+    /// Longest time used writing a batch to the SD-card (ms).
+    pub write_max: AtomicU32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Serialize, defmt::Format)]
+pub struct ActivityCount {
+    pub imu: u32,
+    pub stored: u32,
+    pub sent: u32,
+    pub flushes: u32,
+    pub write_max: u32,
+}
+
+pub static ACTIVITY: Activity = Activity::new();
+
+impl Activity {
+    pub const fn new() -> Activity {
+        Activity {
+            imu: AtomicU32::new(0),
+            stored: AtomicU32::new(0),
+            sent: AtomicU32::new(0),
+            flushes: AtomicU32::new(0),
+            write_max: AtomicU32::new(0),
+        }
+    }
+
+    /// Take and reset the counters.
+    pub fn take(&self) -> ActivityCount {
+        ActivityCount {
+            imu: self.imu.swap(0, Ordering::AcqRel),
+            stored: self.stored.swap(0, Ordering::AcqRel),
+            sent: self.sent.swap(0, Ordering::AcqRel),
+            flushes: self.flushes.swap(0, Ordering::AcqRel),
+            write_max: self.write_max.swap(0, Ordering::AcqRel),
+        }
+    }
+}
+
+impl ActivityCount {
+    /// Nothing was recorded from the IMU or written to the SD-card.
+    pub const fn is_idle(&self) -> bool {
+        self.imu == 0 && self.stored == 0 && self.flushes == 0
+    }
+}
+
+/// Body of a note in `power.qo`.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, defmt::Format)]
+pub struct PowerReport {
+    pub mode: PowerMode,
+    pub previous: PowerMode,
+
+    /// Supply voltage (V).
+    pub voltage: f32,
+
+    /// Activity since the previous check.
+    pub activity: ActivityCount,
+}
+
+pub struct PowerManager {
+    last_check: Option<i64>,
+
+    /// Last voltage reading (V).
+    pub voltage: Option<f32>,
+}
+
+impl PowerManager {
+    pub fn new() -> PowerManager {
+        PowerManager {
+            last_check: None,
+            voltage: None,
+        }
+    }
+
+    /// Read the voltage every [`POWER_CHECK_INTERVAL`] and change power mode if necessary, taking
+    /// the activity since the previous check into account. Returns the new mode if it changed.
+    pub fn check<I2C: Read + Write>(
+        &mut self,
+        now: i64,
+        note: &mut Notecarrier<I2C>,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<Option<PowerMode>, NoteError> {
+        match self.last_check {
+            Some(last) if (now - last) < POWER_CHECK_INTERVAL => return Ok(None),
+            _ => self.last_check = Some(now),
+        }
+
+        let voltage = note.voltage(delay)?;
+        self.voltage = Some(voltage);
+
+        let activity = ACTIVITY.take();
+        let previous = mode();
+        let new =
+            PowerMode::for_voltage((voltage * 1000.) as u32, previous, &activity, &note.config);
+        defmt::debug!(
+            "Voltage: {} V, power mode: {:?}, activity: {:?}",
+            voltage,
+            previous,
+            activity
+        );
+
+        if new == previous {
+            return Ok(None);
+        }
+
+        defmt::warn!(
+            "Power mode: {:?} -> {:?} (voltage: {} V)",
+            previous,
+            new,
+            voltage
+        );
+
+        crate::log::event(crate::log::Event::PowerMode {
+            from: previous,
+            to: new,
+            mv: (voltage * 1000.) as u32,
+        });
+
+        set_mode(new);
+        note.set_power_mode(previous, delay)?;
+
+        let report = PowerReport {
+            mode: new,
+            previous,
+            voltage,
+            activity,
+        };
+
+        note.report_power(&report, delay)
+            .inspect_err(|e| defmt::error!("Failed to report power mode: {:?}", e))
+            .ok();
+
+        Ok(Some(new))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_with_hysteresis() {
+        use PowerMode::*;
+
+        let c = Config {
+            continuous: true,
+            ..Config::default()
+        };
+        let a = ActivityCount {
+            imu: 15,
+            stored: 15,
+            sent: 15,
+            flushes: 4,
+            write_max: 120,
+        };
+
+        assert_eq!(PowerMode::for_voltage(4100, Continuous, &a, &c), Continuous);
+        assert_eq!(PowerMode::for_voltage(3550, Continuous, &a, &c), Periodic);
+        assert_eq!(PowerMode::for_voltage(3100, Continuous, &a, &c), Survival);
+
+        // Recovering, but within hysteresis.
+        assert_eq!(PowerMode::for_voltage(3250, Survival, &a, &c), Survival);
+        assert_eq!(PowerMode::for_voltage(3350, Survival, &a, &c), Summary);
+        assert_eq!(PowerMode::for_voltage(3650, Summary, &a, &c), Periodic);
+        assert_eq!(PowerMode::for_voltage(3700, Summary, &a, &c), Continuous);
+
+        // Dropping slightly below threshold is immediate.
+        assert_eq!(PowerMode::for_voltage(3399, Periodic, &a, &c), Summary);
+
+        // The voltage read while the IMU and SD-card are idle must recover more.
+        let idle = ActivityCount::default();
+        assert_eq!(PowerMode::for_voltage(3350, Survival, &idle, &c), Survival);
+        assert_eq!(PowerMode::for_voltage(3450, Survival, &idle, &c), Summary);
+        assert_eq!(PowerMode::for_voltage(3700, Summary, &idle, &c), Periodic);
+        assert_eq!(PowerMode::for_voltage(3399, Periodic, &idle, &c), Summary);
+
+        // Continuous mode is only used when configured.
+        let c = Config {
+            continuous: false,
+            ..Config::default()
+        };
+        assert_eq!(PowerMode::for_voltage(4100, Continuous, &a, &c), Periodic);
+        assert_eq!(PowerMode::for_voltage(4100, Summary, &a, &c), Periodic);
+    }
+
+    #[test]
+    fn limited_config() {
+        let c = Config {
+            continuous: true,
+            ..Config::default()
+        };
+
+        assert_eq!(c.limited(PowerMode::Continuous), c);
+        assert!(!c.limited(PowerMode::Periodic).continuous);
+
+        let s = c.limited(PowerMode::Summary);
+        assert_eq!(s.sync_outbound, SUMMARY_SYNC_OUTBOUND);
+        assert_eq!(s.gps_period, c.gps_period);
+
+        let s = c.limited(PowerMode::Survival);
+        assert_eq!(s.gps_period, SURVIVAL_GPS_PERIOD);
+        assert_eq!(s.sync_outbound, SURVIVAL_SYNC_OUTBOUND);
+    }
+
+    #[test]
+    fn report_json() {
+        let r = PowerReport {
+            mode: PowerMode::Summary,
+            previous: PowerMode::Periodic,
+            voltage: 3.5,
+            activity: ActivityCount {
+                imu: 10,
+                stored: 10,
+                sent: 9,
+                flushes: 3,
+                write_max: 180,
+            },
+        };
+
+        assert_eq!(
+            serde_json::to_string(&r).unwrap(),
+            r#"{"mode":"summary","previous":"periodic","voltage":3.5,"activity":{"imu":10,"stored":10,"sent":9,"flushes":3,"write_max":180}}"#
+        );
+    }
+}
@@ -0,0 +1,430 @@
+//! Requests for stored data-packages to be re-sent.
+//!
+//! Requests are received as notes in the inbound `request.qi` notefile, or through the single
+//! `request-data` note in `storage.db` (see [`crate::note::RequestData`]). A request either
+//! specifies a range of storage IDs or a UTC time window:
+//!
+//! ```json
+//! { "start": 1000, "end": 1200, "priority": 1 }
+//! { "start_time": 1665360000, "end_time": 1665370800 }
+//! ```
+//!
+//! Time windows are resolved to storage IDs using the time index of the SD-card and bisecting the
+//! stored packages (see [`crate::storage::time_index`] and [`lower_bound`]) before they are
+//! served. Requests with higher priority are served first, and
+//! requests with equal priority are served in the order they were received. The queue is kept in
+//! `storage.dbx` so that it survives a reset, and progress is reported in `storage-info`.
+
+use heapless::Vec;
+
+/// Maximum number of queued requests.
+pub const MAX_REQUESTS: usize = 8;
+
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone, PartialEq, defmt::Format)]
+pub struct DataRequest {
+    /// First storage ID (inclusive).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<u32>,
+
+    /// Last storage ID (inclusive).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<u32>,
+
+    /// Start of time window (UTC, seconds).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<u32>,
+
+    /// End of time window (UTC, seconds, inclusive).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_time: Option<u32>,
+
+    /// Higher priority is served first.
+    #[serde(default)]
+    pub priority: u8,
+
+    /// Next storage ID to send.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next: Option<u32>,
+
+    /// Number of packages sent.
+    #[serde(default)]
+    pub sent: u32,
+
+    /// Number of packages that were not found on the SD-card.
+    #[serde(default)]
+    pub not_found: u32,
+
+    /// The time window was resolved, but no packages matched it.
+    #[serde(default, skip_serializing_if = "core::ops::Not::not")]
+    pub empty: bool,
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum RequestError {
+    /// Neither a storage ID range or a time window is specified, or the range is reversed.
+    Invalid = 1,
+
+    /// The request queue is full.
+    QueueFull = 2,
+}
+
+impl DataRequest {
+    pub fn ids(start: u32, end: u32) -> DataRequest {
+        DataRequest {
+            start: Some(start),
+            end: Some(end),
+            ..Default::default()
+        }
+    }
+
+    pub fn time(start_time: u32, end_time: u32) -> DataRequest {
+        DataRequest {
+            start_time: Some(start_time),
+            end_time: Some(end_time),
+            ..Default::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), RequestError> {
+        match (self.start, self.end, self.start_time, self.end_time) {
+            (Some(s), Some(e), _, _) if s <= e => Ok(()),
+            (None, None, Some(s), Some(e)) if s <= e => Ok(()),
+            _ => Err(RequestError::Invalid),
+        }
+    }
+
+    /// The storage ID range is known, or no packages matched the time window (time windows are
+    /// not resolved yet).
+    pub fn is_resolved(&self) -> bool {
+        self.empty || (self.start.is_some() && self.end.is_some())
+    }
+
+    /// Set the storage ID range for a time window.
+    pub fn resolve(&mut self, start: u32, end: u32) {
+        self.start = Some(start);
+        self.end = Some(end);
+    }
+
+    /// No packages matched the time window, the request is complete.
+    pub fn resolve_empty(&mut self) {
+        self.empty = true;
+    }
+
+    /// The next ID to send, or `None` if the request is not resolved or complete.
+    pub fn next_id(&self) -> Option<u32> {
+        match (self.start, self.end) {
+            _ if self.empty => None,
+            (Some(start), Some(end)) => {
+                let next = self.next.unwrap_or(start);
+                if next <= end {
+                    Some(next)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.is_resolved() && self.next_id().is_none()
+    }
+
+    /// Number of packages remaining (including packages that may turn out to be missing).
+    pub fn remaining(&self) -> u32 {
+        match (self.next_id(), self.end) {
+            (Some(next), Some(end)) => end - next + 1,
+            _ => 0,
+        }
+    }
+
+    /// Package was sent, advance to next.
+    pub fn advance_sent(&mut self, id: u32) {
+        self.sent += 1;
+        self.next = Some(id.saturating_add(1));
+    }
+
+    /// Packages up to (not including) `next` were not found.
+    pub fn advance_not_found(&mut self, id: u32, next: u32) {
+        let end = self.end.unwrap_or(id).saturating_add(1);
+        let next = next.max(id.saturating_add(1)).min(end);
+
+        self.not_found += next - id;
+        self.next = Some(next);
+    }
+
+    /// The next ID to send if it has been stored. The part of the range that has not been stored
+    /// yet is kept, and served when the packages are stored.
+    pub fn next_stored(&self, next_free_id: u32) -> Option<u32> {
+        self.next_id().filter(|id| *id < next_free_id)
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone, PartialEq, defmt::Format)]
+pub struct RequestQueue {
+    pub requests: Vec<DataRequest, MAX_REQUESTS>,
+}
+
+impl RequestQueue {
+    pub fn new() -> RequestQueue {
+        RequestQueue::default()
+    }
+
+    /// Queue request after any other requests with the same or higher priority.
+    pub fn push(&mut self, request: DataRequest) -> Result<(), RequestError> {
+        request.validate()?;
+
+        if self.requests.is_full() {
+            return Err(RequestError::QueueFull);
+        }
+
+        let i = self
+            .requests
+            .iter()
+            .position(|r| r.priority < request.priority)
+            .unwrap_or(self.requests.len());
+
+        self.requests
+            .insert(i, request)
+            .map_err(|_| RequestError::QueueFull)
+    }
+
+    /// The request currently being served.
+    pub fn current(&mut self) -> Option<&mut DataRequest> {
+        self.requests.first_mut()
+    }
+
+    /// Remove the current request.
+    pub fn pop(&mut self) -> Option<DataRequest> {
+        if self.requests.is_empty() {
+            None
+        } else {
+            Some(self.requests.remove(0))
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.requests.clear();
+    }
+
+    /// Remaining packages in all resolved requests.
+    pub fn remaining(&self) -> u32 {
+        self.requests.iter().map(|r| r.remaining()).sum()
+    }
+
+    /// Ranges of storage IDs (inclusive) that remain to be sent in resolved requests. These are
+    /// kept when old collections are deleted (see [`crate::retention`]).
+    pub fn pending(&self) -> Vec<(u32, u32), MAX_REQUESTS> {
+        self.requests
+            .iter()
+            .filter_map(|r| Some((r.next_id()?, r.end?)))
+            .collect()
+    }
+}
+
+/// Result of probing the storage for an ID.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Probe {
+    /// Package exists with timestamp (ms).
+    Found(i64),
+
+    /// Package does not exist, the next ID that may exist is `next`.
+    Missing { next: u32 },
+}
+
+/// Find the first storage ID in `[lo, hi)` with timestamp at or after `t` (ms), assuming that the
+/// timestamps of stored packages are increasing. Missing packages are skipped, so the returned ID
+/// may be the start of a gap before the first package matching. Returns `hi` if no package
+/// matches.
+pub fn lower_bound<E>(
+    mut lo: u32,
+    mut hi: u32,
+    t: i64,
+    mut probe: impl FnMut(u32) -> Result<Probe, E>,
+) -> Result<u32, E> {
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+
+        // Find first existing package at or after mid.
+        let mut m = mid;
+        let found = loop {
+            if m >= hi {
+                break None;
+            }
+
+            match probe(m)? {
+                Probe::Found(ts) => break Some((m, ts)),
+                Probe::Missing { next } => m = next.max(m + 1),
+            }
+        };
+
+        match found {
+            Some((m, ts)) if ts < t => lo = m + 1,
+            _ => hi = mid,
+        }
+    }
+
+    Ok(lo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_priority() {
+        let mut q = RequestQueue::new();
+
+        q.push(DataRequest::ids(0, 10)).unwrap();
+        q.push(DataRequest::ids(20, 30)).unwrap();
+        q.push(DataRequest {
+            priority: 2,
+            ..DataRequest::time(100, 200)
+        })
+        .unwrap();
+        q.push(DataRequest {
+            priority: 1,
+            ..DataRequest::ids(40, 50)
+        })
+        .unwrap();
+
+        assert_eq!(q.pop().unwrap().start_time, Some(100));
+        assert_eq!(q.pop().unwrap().start, Some(40));
+        assert_eq!(q.pop().unwrap().start, Some(0));
+        assert_eq!(q.pop().unwrap().start, Some(20));
+        assert!(q.pop().is_none());
+    }
+
+    #[test]
+    fn queue_full_and_invalid() {
+        let mut q = RequestQueue::new();
+
+        assert_eq!(q.push(DataRequest::ids(10, 0)), Err(RequestError::Invalid));
+        assert_eq!(q.push(DataRequest::default()), Err(RequestError::Invalid));
+
+        for i in 0..MAX_REQUESTS {
+            q.push(DataRequest::ids(i as u32, 100)).unwrap();
+        }
+
+        assert_eq!(q.push(DataRequest::ids(0, 1)), Err(RequestError::QueueFull));
+    }
+
+    #[test]
+    fn queue_pending() {
+        let mut q = RequestQueue::new();
+
+        q.push(DataRequest::ids(0, 10)).unwrap();
+        q.push(DataRequest::time(100, 200)).unwrap();
+        q.push(DataRequest::ids(20, 30)).unwrap();
+        q.current().unwrap().advance_sent(4);
+
+        assert_eq!(q.pending(), [(5, 10), (20, 30)]);
+    }
+
+    #[test]
+    fn progress() {
+        // Packages that have not been stored yet are kept in the request.
+        let r = DataRequest::ids(100, 309);
+        assert_eq!(r.next_stored(250), Some(100));
+        assert_eq!(r.next_stored(100), None);
+        assert_eq!(r.end, Some(309));
+        assert_eq!(r.remaining(), 210);
+
+        let mut r = DataRequest::ids(100, 249);
+        assert_eq!(r.remaining(), 150);
+
+        r.advance_sent(100);
+        assert_eq!(r.next_id(), Some(101));
+        assert_eq!(r.remaining(), 149);
+
+        // Rest of collection missing.
+        r.advance_not_found(101, 200);
+        assert_eq!(r.not_found, 99);
+        assert_eq!(r.next_id(), Some(200));
+
+        // Missing beyond end of request.
+        r.advance_not_found(200, 300);
+        assert_eq!(r.not_found, 149);
+        assert_eq!(r.sent, 1);
+        assert!(r.is_complete());
+        assert_eq!(r.remaining(), 0);
+
+        let mut r = DataRequest::time(100, 200);
+        r.resolve_empty();
+        assert!(r.is_resolved());
+        assert!(r.is_complete());
+        assert_eq!(r.sent, 0);
+        assert_eq!(r.remaining(), 0);
+        assert_eq!((r.start, r.end, r.next), (None, None, None));
+
+        let mut q = RequestQueue::new();
+        q.push(DataRequest::time(100, 200)).unwrap();
+        q.current().unwrap().resolve_empty();
+        assert!(q.pending().is_empty());
+
+        let s = serde_json::to_string(&q).unwrap();
+        assert!(s.contains(r#""empty":true"#));
+        assert_eq!(serde_json::from_str::<RequestQueue>(&s).unwrap(), q);
+    }
+
+    #[test]
+    fn request_json() {
+        let r: DataRequest =
+            serde_json::from_str(r#"{"start_time": 1665360000, "end_time": 1665370800}"#).unwrap();
+        assert_eq!(r, DataRequest::time(1665360000, 1665370800));
+        assert!(!r.is_resolved());
+        assert!(!r.is_complete());
+
+        let mut q = RequestQueue::new();
+        q.push(r).unwrap();
+        let s = serde_json::to_string(&q).unwrap();
+        let q2: RequestQueue = serde_json::from_str(&s).unwrap();
+        assert_eq!(q, q2);
+    }
+
+    /// Packages with timestamp `id * 10`, collections of 100 packages, with collections 2, 3 and
+    /// 7 missing and only 50 packages in collection 5.
+    fn probe(id: u32) -> Result<Probe, ()> {
+        let c = id / 100;
+        match c {
+            2 | 3 | 7 => Ok(Probe::Missing {
+                next: (c + 1) * 100,
+            }),
+            5 if id % 100 >= 50 => Ok(Probe::Missing {
+                next: (c + 1) * 100,
+            }),
+            _ => Ok(Probe::Found(id as i64 * 10)),
+        }
+    }
+
+    #[test]
+    fn bisect_time() {
+        assert_eq!(lower_bound(0, 1000, 0, probe), Ok(0));
+        assert_eq!(lower_bound(0, 1000, 15, probe), Ok(2));
+        assert_eq!(lower_bound(0, 1000, 1990, probe), Ok(199));
+
+        // In gap, the first existing is 400.
+        for t in [2500, 4000] {
+            let l = lower_bound(0, 1000, t, probe).unwrap();
+            assert!((200..=400).contains(&l));
+            assert!((l..400).all(|id| matches!(probe(id), Ok(Probe::Missing { .. }))));
+        }
+
+        assert_eq!(lower_bound(0, 1000, 4010, probe), Ok(401));
+        assert_eq!(lower_bound(0, 1000, 5490, probe), Ok(549));
+        let l = lower_bound(0, 1000, 5600, probe).unwrap();
+        assert!((550..=600).contains(&l));
+        assert!((l..600).all(|id| matches!(probe(id), Ok(Probe::Missing { .. }))));
+        assert_eq!(lower_bound(0, 1000, 9990, probe), Ok(999));
+        assert_eq!(lower_bound(0, 1000, 100_000, probe), Ok(1000));
+    }
+}
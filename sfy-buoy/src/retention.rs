@@ -0,0 +1,144 @@
+//! Retention of packages on the SD-card.
+//!
+//! When the collections use more than the limit of the policy (by default 95% of the card), or a
+//! write fails because the card is full, the oldest collections are deleted until the collections
+//! are below the limit. Collections with any of the protected quality flags (see
+//! [`TimeEntry`](crate::storage::time_index::TimeEntry)), by default events, and collections with
+//! packages in queued data-requests are kept. Protected collections are passed over for good:
+//! they are not deleted later if the policy changes.
+//!
+//! Every pass that deletes collections is logged with a
+//! [`CollectionsDeleted`](crate::log::Event::CollectionsDeleted) event.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Default max space used by collections (MB), `0` uses [`CARD_USE`] of the card.
+pub const RETENTION_MAX: u32 = 0;
+
+/// Default protected quality flags: events.
+pub const RETENTION_PROTECT: u32 = 1 << 2;
+
+/// Percentage of the card used by collections if the policy has no limit. The rest is left for
+/// track files, the log spool and the time index.
+pub const CARD_USE: u64 = 95;
+
+/// Max number of collections deleted in one pass.
+pub const MAX_DELETE: u32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Policy {
+    /// Max space used by collections (MB), `0` for no limit other than the card.
+    pub max: u32,
+
+    /// Collections with any of these quality flags are not deleted.
+    pub protect: u8,
+}
+
+impl Policy {
+    pub const DEFAULT: Policy = Policy {
+        max: RETENTION_MAX,
+        protect: RETENTION_PROTECT as u8,
+    };
+
+    /// Max and protected flags packed in a `u32`, see [`Policy::from_bits`].
+    pub const fn to_bits(&self) -> u32 {
+        self.max << 8 | self.protect as u32
+    }
+
+    pub const fn from_bits(v: u32) -> Policy {
+        Policy {
+            max: v >> 8,
+            protect: v as u8,
+        }
+    }
+
+    /// Max space used by collections (bytes) on a card of `card` bytes.
+    pub fn limit(&self, card: u64) -> u64 {
+        let card = card * CARD_USE / 100;
+
+        if self.max > 0 {
+            (self.max as u64 * 1024 * 1024).min(card)
+        } else {
+            card
+        }
+    }
+
+    /// The collection with IDs `first` to `end` (exclusive) and quality `flags` is kept.
+    /// `requested` are ranges of IDs (inclusive) in queued data-requests.
+    pub fn protects(&self, flags: u8, first: u32, end: u32, requested: &[(u32, u32)]) -> bool {
+        flags & self.protect != 0 || requested.iter().any(|(s, e)| *s < end && first <= *e)
+    }
+}
+
+/// The active policy (see [`Policy::to_bits`]). Set by the main loop when the configuration
+/// changes.
+static POLICY: AtomicU32 = AtomicU32::new(Policy::DEFAULT.to_bits());
+
+/// The active policy.
+pub fn policy() -> Policy {
+    Policy::from_bits(POLICY.load(Ordering::Acquire))
+}
+
+pub fn set_policy(p: Policy) {
+    POLICY.store(p.to_bits(), Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GB: u64 = 1024 * 1024 * 1024;
+
+    #[test]
+    fn limit() {
+        let p = Policy { max: 0, protect: 0 };
+        assert_eq!(p.limit(100 * GB), 95 * GB);
+
+        let p = Policy {
+            max: 1024,
+            protect: 0,
+        };
+        assert_eq!(p.limit(32 * GB), GB);
+        assert_eq!(p.limit(GB / 2), GB / 2 * 95 / 100);
+    }
+
+    #[test]
+    fn protects() {
+        let p = Policy {
+            max: 0,
+            protect: 1 << 2,
+        };
+
+        assert!(p.protects(0b101, 1200, 1300, &[]));
+        assert!(!p.protects(0b001, 1200, 1300, &[]));
+
+        assert!(p.protects(0, 1200, 1300, &[(0, 10), (1250, 1400)]));
+        assert!(p.protects(0, 1200, 1300, &[(1000, 1200)]));
+        assert!(!p.protects(0, 1200, 1300, &[(1000, 1199), (1300, 1400)]));
+    }
+
+    #[test]
+    fn policy_bits() {
+        assert_eq!(
+            Policy::DEFAULT,
+            Policy {
+                max: RETENTION_MAX,
+                protect: RETENTION_PROTECT as u8
+            }
+        );
+
+        for p in [
+            Policy::DEFAULT,
+            Policy {
+                max: 30_000,
+                protect: 0xff,
+            },
+            Policy {
+                max: u32::MAX >> 8,
+                protect: 0,
+            },
+        ] {
+            assert_eq!(Policy::from_bits(p.to_bits()), p);
+        }
+    }
+}
@@ -0,0 +1,641 @@
+//! Scheduling and error recovery of the firmware main loop.
+//!
+//! The main loop runs a pass over all tasks every [`PERIOD`] ms, or every [`DATA_PERIOD`] ms when
+//! new data is waiting. The tasks are performed by the [`Devices`] of the platform, and the
+//! [`Scheduler`] decides when to run them and how to recover from errors:
+//!
+//! * A pass fails if retrieving the location, draining the package queue or syncing fails. The
+//!   uplink is then reset and the error logged, and the scheduler backs off: the time to the next
+//!   pass is doubled for every failed pass, up to [`BACKOFF_MAX`] ([`Mode::BackingOff`]). After
+//!   [`GOOD_TRIES`] failed passes in a row the log is spooled and the device should be reset
+//!   ([`Action::Reset`]).
+//! * Storage errors are logged once until storage works again.
+//! * A reboot command resets the device, a reset-imu command resets the IMU from the main loop.
+//!
+//! The time is passed to [`Scheduler::step`], and events and watchdog check-ins go through the
+//! [`Devices`], so that the main loop can be simulated with virtual time on the host.
+
+use core::fmt::{Debug, Write as _};
+use heapless::String;
+
+use crate::cmd::Command;
+use crate::log::{Event, ResetReason};
+use crate::watchdog::Task;
+
+/// Time between passes (ms).
+pub const PERIOD: i64 = 5_000;
+
+/// Time between passes when there is new data (ms).
+pub const DATA_PERIOD: i64 = 500;
+
+/// Number of failed passes in a row before the device is reset.
+pub const GOOD_TRIES: u16 = 15;
+
+/// Maximum time between passes when backing off after failed passes (ms).
+pub const BACKOFF_MAX: i64 = 60_000;
+
+/// The tasks of the main loop. Errors should be logged by the implementation, unless they are
+/// returned.
+pub trait Devices {
+    type Error: Debug + defmt::Format;
+
+    /// There is new data to be stored or sent.
+    fn data_ready(&mut self) -> bool;
+
+    /// Send (or spool) queued log records.
+    fn drain_log(&mut self);
+
+    /// Signal that the main loop is running (e.g. toggle LED).
+    fn heartbeat(&mut self) {}
+
+    /// Queue an event to be sent over the uplink (see [`crate::log::event`]).
+    fn event(&mut self, event: Event);
+
+    /// Check in `task` with the watchdog (see [`crate::watchdog`]).
+    fn checkin(&mut self, task: Task);
+
+    /// Check for changes to the remote configuration.
+    fn check_config(&mut self, now: i64);
+
+    /// Check the supply voltage and update the power mode.
+    fn check_power(&mut self, now: i64);
+
+    /// Receive and execute one command, returns the command if it was executed.
+    fn check_command(&mut self) -> Option<Command>;
+
+    /// Reset the IMU (see [`Command::ResetImu`]).
+    fn reset_imu(&mut self, _now: i64) {}
+
+    /// Retrieve time and location.
+    fn location(&mut self) -> Result<(), Self::Error>;
+
+    /// Store queued packages. Returns the ID of the last stored package, or the
+    /// [code](crate::storage::StorageErr::code) of the error.
+    fn store(&mut self) -> Result<Option<u32>, u8> {
+        Ok(None)
+    }
+
+    /// Send queued packages to the uplink.
+    fn drain_queue(&mut self) -> Result<(), Self::Error>;
+
+    /// Sync the uplink if necessary.
+    fn sync(&mut self) -> Result<(), Self::Error>;
+
+    /// Reset the uplink after a failed pass, and try to send `msg` directly.
+    fn recover(&mut self, msg: &str);
+
+    /// Move queued log records to persistent storage (before a reset).
+    fn spool_log(&mut self) {}
+}
+
+/// What the main loop should do after a step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Action {
+    /// Nothing to do, wait for next step.
+    Idle,
+
+    /// A pass was run.
+    Ran,
+
+    /// Reset the device.
+    Reset(ResetReason),
+}
+
+/// State of the scheduler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Mode {
+    /// Passes run every [`PERIOD`], or [`DATA_PERIOD`] when there is new data.
+    Running,
+
+    /// The last pass failed, the next pass runs after `delay` ms.
+    BackingOff { delay: i64 },
+}
+
+impl Mode {
+    /// Mode after a failed pass.
+    fn back_off(self) -> Mode {
+        let delay = match self {
+            Mode::Running => 2 * PERIOD,
+            Mode::BackingOff { delay } => 2 * delay,
+        };
+
+        Mode::BackingOff {
+            delay: delay.min(BACKOFF_MAX),
+        }
+    }
+}
+
+pub struct Scheduler {
+    /// Time of last pass (ms).
+    last: i64,
+
+    mode: Mode,
+
+    /// Failed passes left before resetting.
+    good_tries: u16,
+
+    /// Storage is working (errors are only logged once).
+    storage_good: bool,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+impl Scheduler {
+    pub const fn new() -> Scheduler {
+        Scheduler {
+            last: 0,
+            mode: Mode::Running,
+            good_tries: GOOD_TRIES,
+            storage_good: true,
+        }
+    }
+
+    pub fn good_tries(&self) -> u16 {
+        self.good_tries
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Run a pass if it is due at `now` (ms).
+    pub fn step<D: Devices>(&mut self, now: i64, dev: &mut D) -> Action {
+        let due = match self.mode {
+            Mode::BackingOff { delay } => delay,
+            Mode::Running if dev.data_ready() => DATA_PERIOD,
+            Mode::Running => PERIOD,
+        };
+
+        if now - self.last <= due {
+            return Action::Idle;
+        }
+
+        defmt::debug!("iteration, now: {}, due: {}", now, due);
+
+        let action = self.pass(now, dev);
+        self.last = now;
+
+        action
+    }
+
+    fn pass<D: Devices>(&mut self, now: i64, dev: &mut D) -> Action {
+        dev.drain_log();
+        dev.heartbeat();
+        dev.check_config(now);
+        dev.check_power(now);
+
+        match dev.check_command() {
+            Some(Command::Reboot) => {
+                defmt::warn!("Reboot requested by command.");
+                dev.event(Event::Reset {
+                    reason: ResetReason::Command,
+                });
+
+                return Action::Reset(ResetReason::Command);
+            }
+            Some(Command::ResetImu) => {
+                defmt::warn!("IMU reset requested by command.");
+                dev.reset_imu(now);
+            }
+            _ => (),
+        }
+
+        let l = dev.location();
+        dev.checkin(Task::Location);
+
+        match dev.store() {
+            Err(error) => {
+                if self.storage_good {
+                    dev.event(Event::StorageError { error });
+                }
+
+                self.storage_good = false;
+            }
+            Ok(Some(_)) => self.storage_good = true,
+            Ok(None) => (),
+        }
+        dev.checkin(Task::Storage);
+
+        let nd = dev.drain_queue();
+        let ns = dev.sync();
+        dev.checkin(Task::Notecard);
+
+        match (l, nd, ns) {
+            (Ok(_), Ok(_), Ok(_)) => {
+                self.good_tries = GOOD_TRIES;
+                self.mode = Mode::Running;
+                Action::Ran
+            }
+            (l, dq, cs) => {
+                defmt::error!(
+                    "Fatal error occured during main loop: location: {:?}, note/drain_queue: {:?}, note/check_and_sync: {:?}. Tries left: {}",
+                    l,
+                    dq,
+                    cs,
+                    self.good_tries
+                );
+
+                let mut msg = String::<512>::new();
+                write!(&mut msg, "Fatal error in main loop: location: {:?}, note/drain_queue: {:?}, note/check_and_sync: {:?}. Tries left: {}", l, dq, cs, self.good_tries)
+                    .inspect_err(|e| defmt::error!("failed to format error: {:?}", defmt::Debug2Format(e)))
+                    .ok();
+
+                dev.event(Event::MainLoopError {
+                    location: l.is_err(),
+                    drain: dq.is_err(),
+                    sync: cs.is_err(),
+                    tries: self.good_tries,
+                });
+
+                // Uplink might be in a wrong state.
+                dev.recover(&msg);
+                self.mode = self.mode.back_off();
+
+                if self.good_tries == 0 {
+                    defmt::error!("No more tries left, attempting to reset devices and restart.");
+                    dev.event(Event::Reset {
+                        reason: ResetReason::MainLoop,
+                    });
+
+                    // The uplink is likely failing: keep the log, it is forwarded after the reset.
+                    dev.spool_log();
+
+                    Action::Reset(ResetReason::MainLoop)
+                } else {
+                    self.good_tries -= 1;
+                    Action::Ran
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::note::emulator::{NoDelay, NotecardEmulator};
+    use crate::uplink::Uplink;
+
+    /// Simulated devices, with injected failures.
+    #[derive(Default)]
+    struct Sim {
+        /// Virtual time (ms).
+        now: i64,
+
+        /// Packages waiting.
+        queued: usize,
+
+        /// Failures.
+        uplink_down: bool,
+        storage_down: bool,
+
+        /// Next command.
+        command: Option<Command>,
+
+        /// Counters.
+        passes: usize,
+        times: Vec<i64>,
+        stored: u32,
+        sent: usize,
+        recovered: Vec<std::string::String>,
+        spooled: usize,
+        imu_resets: usize,
+        events: Vec<Event>,
+        checkins: Vec<Task>,
+    }
+
+    impl Devices for Sim {
+        type Error = ();
+
+        fn data_ready(&mut self) -> bool {
+            self.queued > 0
+        }
+
+        fn drain_log(&mut self) {
+            self.passes += 1;
+            self.times.push(self.now);
+        }
+
+        fn event(&mut self, event: Event) {
+            self.events.push(event);
+        }
+
+        fn checkin(&mut self, task: Task) {
+            self.checkins.push(task);
+        }
+
+        fn check_config(&mut self, _now: i64) {}
+
+        fn check_power(&mut self, _now: i64) {}
+
+        fn check_command(&mut self) -> Option<Command> {
+            self.command.take()
+        }
+
+        fn reset_imu(&mut self, _now: i64) {
+            self.imu_resets += 1;
+        }
+
+        fn location(&mut self) -> Result<(), ()> {
+            if self.uplink_down {
+                Err(())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn store(&mut self) -> Result<Option<u32>, u8> {
+            if self.queued == 0 {
+                Ok(None)
+            } else if self.storage_down {
+                Err(5)
+            } else {
+                self.stored += self.queued as u32;
+                Ok(Some(self.stored))
+            }
+        }
+
+        fn drain_queue(&mut self) -> Result<(), ()> {
+            if self.uplink_down {
+                return Err(());
+            }
+
+            self.sent += self.queued;
+            self.queued = 0;
+            Ok(())
+        }
+
+        fn sync(&mut self) -> Result<(), ()> {
+            self.location()
+        }
+
+        fn recover(&mut self, msg: &str) {
+            self.recovered.push(msg.into());
+        }
+
+        fn spool_log(&mut self) {
+            self.spooled += 1;
+        }
+    }
+
+    /// Run the main loop for `duration` ms, with the loop waking up every second. Returns the
+    /// reset reason if reset.
+    fn run(s: &mut Scheduler, sim: &mut Sim, duration: i64) -> Option<ResetReason> {
+        let end = sim.now + duration;
+
+        while sim.now < end {
+            sim.now += 1_000;
+
+            if let Action::Reset(reason) = s.step(sim.now, sim) {
+                return Some(reason);
+            }
+        }
+
+        None
+    }
+
+    #[test]
+    fn timing() {
+        let mut s = Scheduler::new();
+        let mut sim = Sim::default();
+
+        run(&mut s, &mut sim, 60_000);
+        assert_eq!(sim.passes, 10);
+        assert_eq!(sim.checkins.len(), 30);
+        assert_eq!(
+            sim.checkins[..3],
+            [Task::Location, Task::Storage, Task::Notecard]
+        );
+
+        // New data is handled at the next wake-up.
+        sim.queued = 3;
+        assert_eq!(s.step(sim.now + 1_000, &mut sim), Action::Ran);
+        assert_eq!(sim.sent, 3);
+        assert_eq!(s.step(sim.now + 1_200, &mut sim), Action::Idle);
+    }
+
+    #[test]
+    fn uplink_failure_resets() {
+        let mut s = Scheduler::new();
+        let mut sim = Sim::default();
+        run(&mut s, &mut sim, 60_000);
+
+        // Uplink fails for a while, but recovers.
+        sim.uplink_down = true;
+        run(&mut s, &mut sim, 40_000);
+        assert_eq!(sim.recovered.len(), 3);
+        assert_eq!(s.good_tries(), GOOD_TRIES - 3);
+        assert!(sim.recovered[0].contains("Tries left: 15"));
+        assert!(matches!(
+            sim.events[0],
+            Event::MainLoopError {
+                location: true,
+                drain: true,
+                sync: true,
+                tries: 15
+            }
+        ));
+
+        sim.uplink_down = false;
+        run(&mut s, &mut sim, 70_000);
+        assert_eq!(s.good_tries(), GOOD_TRIES);
+
+        // Uplink fails permanently: reset after all tries are used.
+        sim.uplink_down = true;
+        sim.recovered.clear();
+        assert_eq!(
+            run(&mut s, &mut sim, 3_600_000),
+            Some(ResetReason::MainLoop)
+        );
+        assert_eq!(sim.recovered.len(), GOOD_TRIES as usize + 1);
+        assert_eq!(sim.spooled, 1);
+        assert_eq!(
+            sim.events.last(),
+            Some(&Event::Reset {
+                reason: ResetReason::MainLoop
+            })
+        );
+    }
+
+    #[test]
+    fn back_off() {
+        let mut s = Scheduler::new();
+        let mut sim = Sim::default();
+        run(&mut s, &mut sim, 10_000);
+
+        // The time between passes is doubled for every failed pass.
+        sim.uplink_down = true;
+        sim.times.clear();
+        run(&mut s, &mut sim, 300_000);
+        assert_eq!(s.mode(), Mode::BackingOff { delay: BACKOFF_MAX });
+
+        let gaps: Vec<i64> = sim.times.windows(2).map(|w| w[1] - w[0]).collect();
+        assert_eq!(gaps[..5], [11_000, 21_000, 41_000, 61_000, 61_000]);
+
+        // New data does not shorten the delay while backing off.
+        sim.queued = 1;
+        assert_eq!(s.step(sim.now + 1_000, &mut sim), Action::Idle);
+
+        // A good pass resumes normal operation.
+        sim.uplink_down = false;
+        run(&mut s, &mut sim, 70_000);
+        assert_eq!(s.mode(), Mode::Running);
+        assert_eq!(s.good_tries(), GOOD_TRIES);
+        assert_eq!(sim.queued, 0);
+    }
+
+    #[test]
+    fn storage_failure_is_not_fatal() {
+        let mut s = Scheduler::new();
+        let mut sim = Sim {
+            storage_down: true,
+            ..Default::default()
+        };
+
+        for _ in 0..20 {
+            sim.queued = 1;
+            assert_eq!(run(&mut s, &mut sim, 10_000), None);
+        }
+
+        assert_eq!(sim.sent, 20);
+        assert!(!s.storage_good);
+        assert_eq!(sim.events, [Event::StorageError { error: 5 }]);
+
+        sim.storage_down = false;
+        sim.queued = 1;
+        run(&mut s, &mut sim, 10_000);
+        assert!(s.storage_good);
+    }
+
+    #[test]
+    fn commands() {
+        let mut s = Scheduler::new();
+        let mut sim = Sim::default();
+        run(&mut s, &mut sim, 20_000);
+
+        sim.command = Some(Command::ResetImu);
+        assert_eq!(run(&mut s, &mut sim, 20_000), None);
+        assert_eq!(sim.imu_resets, 1);
+
+        sim.command = Some(Command::Reboot);
+        assert_eq!(run(&mut s, &mut sim, 20_000), Some(ResetReason::Command));
+        assert_eq!(
+            sim.events,
+            [Event::Reset {
+                reason: ResetReason::Command
+            }]
+        );
+    }
+
+    /// The tasks of the main loop over the Notecard emulator.
+    struct Carrier {
+        note: crate::note::Notecarrier<NotecardEmulator>,
+        queue: heapless::spsc::Consumer<'static, crate::axl::AxlPacket, { crate::NOTEQ_SZ }>,
+        events: Vec<Event>,
+        recovered: usize,
+    }
+
+    impl Devices for Carrier {
+        type Error = blues_notecard::NoteError;
+
+        fn data_ready(&mut self) -> bool {
+            self.queue.ready()
+        }
+
+        fn drain_log(&mut self) {}
+
+        fn event(&mut self, event: Event) {
+            self.events.push(event);
+        }
+
+        fn checkin(&mut self, _task: Task) {}
+
+        fn check_config(&mut self, now: i64) {
+            self.note.check_config(now, &mut NoDelay).ok();
+        }
+
+        fn check_power(&mut self, _now: i64) {}
+
+        fn check_command(&mut self) -> Option<Command> {
+            crate::cmd::check_command(&mut self.note, &mut NoDelay)
+                .ok()
+                .flatten()
+        }
+
+        fn location(&mut self) -> Result<(), Self::Error> {
+            self.note.location(&mut NoDelay).map(|_| ())
+        }
+
+        fn drain_queue(&mut self) -> Result<(), Self::Error> {
+            self.note
+                .drain_queue(&mut self.queue, &mut NoDelay)
+                .map(|_| ())
+        }
+
+        fn sync(&mut self) -> Result<(), Self::Error> {
+            self.note.check_and_sync(&mut NoDelay)
+        }
+
+        fn recover(&mut self, _msg: &str) {
+            self.recovered += 1;
+        }
+    }
+
+    #[test]
+    fn notecard_emulator() {
+        let card = NotecardEmulator::new();
+        let note = crate::note::Notecarrier::new(card.clone(), &mut NoDelay).unwrap();
+        let q = Box::leak(Box::new(heapless::spsc::Queue::new()));
+        let (_, queue) = q.split();
+
+        let mut dev = Carrier {
+            note,
+            queue,
+            events: Vec::new(),
+            recovered: 0,
+        };
+        let mut s = Scheduler::new();
+
+        assert_eq!(s.step(10_000, &mut dev), Action::Ran);
+        assert!(card.request_names().contains(&"hub.sync.status".into()));
+
+        // The Notecard stops responding: the pass fails and the scheduler backs off.
+        card.state().fail_next = usize::MAX;
+        assert_eq!(s.step(20_000, &mut dev), Action::Ran);
+        assert_eq!(dev.recovered, 1);
+        assert_eq!(s.mode(), Mode::BackingOff { delay: 2 * PERIOD });
+        assert!(matches!(
+            dev.events[0],
+            Event::MainLoopError {
+                location: true,
+                sync: true,
+                ..
+            }
+        ));
+        assert_eq!(s.step(26_000, &mut dev), Action::Idle);
+
+        // The Notecard recovers.
+        card.state().fail_next = 0;
+        assert_eq!(s.step(31_000, &mut dev), Action::Ran);
+        assert_eq!(s.mode(), Mode::Running);
+        assert_eq!(s.good_tries(), GOOD_TRIES);
+
+        // Reboot by command.
+        card.add_inbound("cmd.qi", serde_json::json!({ "id": 1, "cmd": "reboot" }));
+        assert_eq!(
+            s.step(40_000, &mut dev),
+            Action::Reset(ResetReason::Command)
+        );
+        assert_eq!(card.queue("cmd.qo")[0].body.as_ref().unwrap()["ok"], true);
+        assert_eq!(
+            dev.events.last(),
+            Some(&Event::Reset {
+                reason: ResetReason::Command
+            })
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! The card holding the FAT file system used by [`Storage`](super::Storage).
+//!
+//! On the buoy this is the SD-card on the SPI bus ([`SdCard`]). The storage only needs a
+//! [`BlockDevice`], so it can also run on a disk image on the host (see `image` in the tests).
+
+use core::fmt::Debug;
+use core::ops::DerefMut;
+use embedded_hal::{blocking::spi::Transfer, digital::v2::OutputPin};
+use embedded_sdmmc::{BlockDevice, BlockSpi, SdMmcError, SdMmcSpi};
+
+pub trait Card {
+    type Device<'a>: BlockDevice<Error = SdMmcError>
+    where
+        Self: 'a;
+
+    /// Initialize the card and acquire the block device.
+    fn init(&mut self) -> Result<Self::Device<'_>, SdMmcError>;
+
+    /// Acquire the block device of an initialized card.
+    fn acquire(&mut self) -> Result<Self::Device<'_>, SdMmcError>;
+
+    /// Size of the card (bytes).
+    fn size_bytes(device: &Self::Device<'_>) -> Result<u64, SdMmcError>;
+}
+
+pub enum SdSpiSpeed {
+    Low,
+    High,
+}
+
+/// SD-card on the SPI bus. The SPI bus is re-clocked using `reclock_cb` to a low speed while
+/// the card is initialized.
+pub struct SdCard<Spi: Transfer<u8>, CS: OutputPin>
+where
+    <Spi as Transfer<u8>>::Error: Debug,
+{
+    sd: SdMmcSpi<Spi, CS>,
+    reclock_cb: fn(&mut Spi, SdSpiSpeed) -> (),
+}
+
+impl<Spi: Transfer<u8>, CS: OutputPin> SdCard<Spi, CS>
+where
+    <Spi as Transfer<u8>>::Error: Debug,
+{
+    pub fn new(spi: Spi, cs: CS, reclock_cb: fn(&mut Spi, SdSpiSpeed) -> ()) -> SdCard<Spi, CS> {
+        SdCard {
+            sd: SdMmcSpi::new(spi, cs),
+            reclock_cb,
+        }
+    }
+}
+
+impl<Spi: Transfer<u8>, CS: OutputPin> Card for SdCard<Spi, CS>
+where
+    <Spi as Transfer<u8>>::Error: Debug,
+{
+    type Device<'a>
+        = BlockSpi<'a, Spi, CS>
+    where
+        Self: 'a;
+
+    fn init(&mut self) -> Result<Self::Device<'_>, SdMmcError> {
+        defmt::info!("Initializing SD-card (low-speed)..");
+        (self.reclock_cb)(self.sd.spi().deref_mut(), SdSpiSpeed::Low);
+
+        // XXX: This is slow if it fails (time-out), hopefully not too slow, but if so
+        // needs to only be attempted seldomly.
+        let mut block = self.sd.acquire()?;
+
+        defmt::debug!("Increasing SPI speed.");
+        (self.reclock_cb)(block.spi().deref_mut(), SdSpiSpeed::High);
+
+        Ok(block)
+    }
+
+    fn acquire(&mut self) -> Result<Self::Device<'_>, SdMmcError> {
+        self.sd.acquire()
+    }
+
+    fn size_bytes(device: &Self::Device<'_>) -> Result<u64, SdMmcError> {
+        device.card_size_bytes()
+    }
+}
@@ -0,0 +1,715 @@
+//! Log of records (mostly packages) on a SPI NOR flash.
+//!
+//! The flash is divided in sectors, the smallest unit that can be erased. The sectors are used as
+//! a ring: packages and other records are appended (see [`format`]) to the newest sector, and
+//! continue in the next sector when it is full. When the log reaches the oldest sector it is
+//! erased, and the packages in it are lost.
+//!
+//! Every sector starts with a header with a sequence number, the offset of the first record that
+//! starts in the sector and the ID of that record. The sequence numbers give the oldest and newest
+//! sector when the flash is mounted, the end of the log is found by reading the records in the
+//! newest sector. A record that is torn because the buoy is reset while it is written is skipped,
+//! and writing continues in the next sector. Packages are found by bisecting the sectors on the
+//! IDs in the headers, and reading the records from the first record in the sector.
+//!
+//! The flash holds a lot less than an SD-card: a 16 MiB flash holds about 2500 packages, or 14
+//! hours at 52 Hz.
+
+use core::cmp::min;
+use embedded_hal::{blocking::spi::Transfer, digital::v2::OutputPin};
+
+use super::format::{self, crc32, RecordHeader, RECORD_HEADER_SZ, RECORD_MAX_SZ};
+use super::{Health, PacketStore, Record, StorageErr, Tagged};
+use crate::request::{self, Probe};
+
+pub trait Flash {
+    /// Size of a sector, the smallest unit that can be erased (bytes).
+    const SECTOR_SZ: u32;
+
+    /// Size of the flash (bytes).
+    fn capacity(&self) -> u32;
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), StorageErr>;
+
+    /// Program `buf` at `addr`, the bytes must have been erased.
+    fn write(&mut self, addr: u32, buf: &[u8]) -> Result<(), StorageErr>;
+
+    /// Erase the sector starting at `addr`.
+    fn erase(&mut self, addr: u32) -> Result<(), StorageErr>;
+}
+
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_READ: u8 = 0x03;
+const CMD_READ_STATUS: u8 = 0x05;
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_SECTOR_ERASE: u8 = 0x20;
+const CMD_JEDEC_ID: u8 = 0x9f;
+
+/// Write or erase in progress.
+const STATUS_BUSY: u8 = 0x01;
+
+/// Bytes that can be programmed with one command.
+const PAGE_SZ: u32 = 256;
+
+/// Maximum number of status reads while waiting for a write or erase. A sector erase takes up to
+/// 400 ms, and a status read at least 16 us.
+const WAIT_MAX: u32 = 100_000;
+
+/// JEDEC compatible SPI NOR flash (e.g. Winbond W25Q128) with 4 KiB sectors, 256 byte pages and
+/// 24 bit addresses (at most 16 MiB).
+pub struct SpiFlash<Spi: Transfer<u8>, CS: OutputPin> {
+    spi: Spi,
+    cs: CS,
+    capacity: u32,
+}
+
+impl<Spi: Transfer<u8>, CS: OutputPin> SpiFlash<Spi, CS> {
+    /// Open the flash, the capacity is read from the JEDEC ID.
+    pub fn new(spi: Spi, cs: CS) -> Result<SpiFlash<Spi, CS>, StorageErr> {
+        let mut f = SpiFlash {
+            spi,
+            cs,
+            capacity: 0,
+        };
+
+        let mut id = [CMD_JEDEC_ID, 0, 0, 0];
+        f.command(&mut id, &mut [])?;
+
+        defmt::info!(
+            "SPI flash: manufacturer: {=u8:#x}, type: {=u8:#x}, capacity: 2^{} bytes",
+            id[1],
+            id[2],
+            id[3]
+        );
+
+        if !(16..=24).contains(&id[3]) {
+            defmt::error!("SPI flash not found or not supported.");
+            return Err(StorageErr::FlashError);
+        }
+        f.capacity = 1 << id[3];
+
+        Ok(f)
+    }
+
+    /// Send command with address, and transfer `buf` after it.
+    fn command(&mut self, cmd: &mut [u8], buf: &mut [u8]) -> Result<(), StorageErr> {
+        self.cs.set_low().map_err(|_| StorageErr::FlashError)?;
+
+        let r = self
+            .spi
+            .transfer(cmd)
+            .and_then(|_| self.spi.transfer(buf))
+            .map(|_| ());
+
+        self.cs.set_high().map_err(|_| StorageErr::FlashError)?;
+
+        r.map_err(|_| StorageErr::FlashError)
+    }
+
+    fn addressed(cmd: u8, addr: u32) -> [u8; 4] {
+        [cmd, (addr >> 16) as u8, (addr >> 8) as u8, addr as u8]
+    }
+
+    fn write_enable(&mut self) -> Result<(), StorageErr> {
+        self.command(&mut [CMD_WRITE_ENABLE], &mut [])
+    }
+
+    /// Wait for write or erase to complete, fails if the flash is still busy after [`WAIT_MAX`]
+    /// status reads.
+    fn wait(&mut self) -> Result<(), StorageErr> {
+        for _ in 0..WAIT_MAX {
+            let mut status = [CMD_READ_STATUS, 0];
+            self.command(&mut status, &mut [])?;
+
+            if status[1] & STATUS_BUSY == 0 {
+                return Ok(());
+            }
+        }
+
+        defmt::error!("Flash busy after {} status reads.", WAIT_MAX);
+        Err(StorageErr::FlashError)
+    }
+}
+
+impl<Spi: Transfer<u8>, CS: OutputPin> Flash for SpiFlash<Spi, CS> {
+    const SECTOR_SZ: u32 = 4096;
+
+    fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), StorageErr> {
+        self.command(&mut Self::addressed(CMD_READ, addr), buf)
+    }
+
+    fn write(&mut self, mut addr: u32, mut buf: &[u8]) -> Result<(), StorageErr> {
+        let mut page = [0u8; PAGE_SZ as usize];
+
+        // A page program wraps around at the end of the page.
+        while !buf.is_empty() {
+            let n = min(buf.len(), (PAGE_SZ - addr % PAGE_SZ) as usize);
+            page[..n].copy_from_slice(&buf[..n]);
+
+            self.write_enable()?;
+            self.command(&mut Self::addressed(CMD_PAGE_PROGRAM, addr), &mut page[..n])?;
+            self.wait()?;
+
+            addr += n as u32;
+            buf = &buf[n..];
+        }
+
+        Ok(())
+    }
+
+    fn erase(&mut self, addr: u32) -> Result<(), StorageErr> {
+        self.write_enable()?;
+        self.command(&mut Self::addressed(CMD_SECTOR_ERASE, addr), &mut [])?;
+        self.wait()
+    }
+}
+
+const SECTOR_MAGIC: &[u8; 4] = b"SFYF";
+
+/// Size of the header at the start of every sector.
+const SECTOR_HEADER_SZ: u32 = 20;
+
+/// No record starts in the sector.
+const NONE: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+struct SectorHeader {
+    /// Sequence number, increases by one for every sector that is started.
+    seq: u32,
+
+    /// Offset of the first record that starts in the sector, or [`NONE`] if the sector is filled
+    /// by a record that started in an earlier sector.
+    first: u32,
+
+    /// ID of the first record that starts in (or after) the sector.
+    id: u32,
+}
+
+impl SectorHeader {
+    fn encode(&self) -> [u8; SECTOR_HEADER_SZ as usize] {
+        let mut buf = [0u8; SECTOR_HEADER_SZ as usize];
+        buf[..4].copy_from_slice(SECTOR_MAGIC);
+        buf[4..8].copy_from_slice(&self.seq.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.first.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.id.to_le_bytes());
+
+        let crc = crc32(&buf[..16]);
+        buf[16..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Decode header, `None` if the sector is erased or the header is torn.
+    fn decode(buf: &[u8; SECTOR_HEADER_SZ as usize]) -> Option<SectorHeader> {
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..(i + 4)].try_into().unwrap());
+
+        (&buf[..4] == SECTOR_MAGIC && u32_at(16) == crc32(&buf[..16])).then(|| SectorHeader {
+            seq: u32_at(4),
+            first: u32_at(8),
+            id: u32_at(12),
+        })
+    }
+}
+
+pub struct FlashStore<F: Flash> {
+    flash: F,
+
+    /// The log has been read from the flash.
+    mounted: bool,
+
+    /// No sector has been started.
+    empty: bool,
+
+    /// Number of sectors.
+    sectors: u32,
+
+    /// Oldest sector.
+    tail: u32,
+
+    /// Sector being written.
+    head: u32,
+
+    /// Sequence number of the head sector.
+    seq: u32,
+
+    /// Address where the next record is written, the end of the head sector when it is full.
+    pos: u32,
+
+    next_id: u32,
+
+    /// ID of the oldest package.
+    oldest: u32,
+
+    /// The log has wrapped around, and the oldest sectors have been erased.
+    full: bool,
+}
+
+impl<F: Flash> FlashStore<F> {
+    /// Returns an un-mounted log on `flash`, it is mounted when it is first used.
+    pub fn new(flash: F) -> FlashStore<F> {
+        FlashStore {
+            flash,
+            mounted: false,
+            empty: true,
+            sectors: 0,
+            tail: 0,
+            head: 0,
+            seq: 0,
+            pos: 0,
+            next_id: 0,
+            oldest: 0,
+            full: false,
+        }
+    }
+
+    /// Find the oldest and newest sector, and the end of the log.
+    fn mount(&mut self) -> Result<(), StorageErr> {
+        self.sectors = self.flash.capacity() / F::SECTOR_SZ;
+
+        let mut tail: Option<(u32, SectorHeader)> = None;
+        let mut head: Option<(u32, SectorHeader)> = None;
+
+        for s in 0..self.sectors {
+            let h = match self.header(s)? {
+                Some(h) => h,
+                None => continue,
+            };
+
+            match tail {
+                Some((_, t)) if t.seq <= h.seq => (),
+                _ => tail = Some((s, h)),
+            }
+
+            match head {
+                Some((_, t)) if t.seq >= h.seq => (),
+                _ => head = Some((s, h)),
+            }
+        }
+
+        let ((tail, th), (head, hh)) = match tail.zip(head) {
+            Some(sectors) => sectors,
+            None => {
+                defmt::info!("Flash is empty.");
+                self.empty = true;
+                self.mounted = true;
+                return Ok(());
+            }
+        };
+
+        // Records that start in the head sector end in it.
+        let end = (head + 1) * F::SECTOR_SZ;
+        let mut next_id = hh.id;
+        let mut pos = end;
+
+        if hh.first != NONE {
+            pos = head * F::SECTOR_SZ + hh.first;
+
+            while let Some(h) = self.record_at(pos, end)? {
+                next_id = h.id + 1;
+                pos += h.size();
+            }
+
+            // A torn record can not be written over, continue in the next sector.
+            let mut b = [0u8; RECORD_HEADER_SZ];
+            let n = min(b.len(), (end - pos) as usize);
+            self.flash.read(pos, &mut b[..n])?;
+
+            if b[..n].iter().any(|b| *b != 0xff) {
+                defmt::warn!("Torn record at: {}, skipping to next sector.", pos);
+
+                if RecordHeader::decode(&b).map(|h| h.id) == Some(next_id) {
+                    next_id += 1;
+                }
+
+                pos = end;
+            }
+        }
+
+        self.empty = false;
+        self.tail = tail;
+        self.head = head;
+        self.seq = hh.seq;
+        self.pos = pos;
+        self.oldest = th.id;
+        self.full = self.used() == self.sectors;
+
+        // IDs of torn records are not re-used.
+        self.next_id = self.next_id.max(next_id);
+
+        defmt::info!(
+            "Flash mounted, sectors: {} -> {} of {}, packages: {} -> {}",
+            tail,
+            head,
+            self.sectors,
+            self.oldest,
+            self.next_id
+        );
+
+        self.mounted = true;
+
+        Ok(())
+    }
+
+    fn ensure_mounted(&mut self) -> Result<(), StorageErr> {
+        if self.mounted {
+            Ok(())
+        } else {
+            self.mount()
+        }
+    }
+
+    fn header(&mut self, sector: u32) -> Result<Option<SectorHeader>, StorageErr> {
+        let mut buf = [0u8; SECTOR_HEADER_SZ as usize];
+        self.flash.read(sector * F::SECTOR_SZ, &mut buf)?;
+
+        Ok(SectorHeader::decode(&buf))
+    }
+
+    /// Number of sectors in the log.
+    fn used(&self) -> u32 {
+        if self.empty {
+            0
+        } else {
+            (self.head + self.sectors - self.tail) % self.sectors + 1
+        }
+    }
+
+    /// The `n`th sector from the tail.
+    fn nth(&self, n: u32) -> u32 {
+        (self.tail + n) % self.sectors
+    }
+
+    /// Header of the valid record at `pos` ending before `end`.
+    fn record_at(&mut self, pos: u32, end: u32) -> Result<Option<RecordHeader>, StorageErr> {
+        let mut buf = [0u8; RECORD_MAX_SZ];
+
+        if end - pos < RECORD_HEADER_SZ as u32 {
+            return Ok(None);
+        }
+        self.flash.read(pos, &mut buf[..RECORD_HEADER_SZ])?;
+
+        let sz = match RecordHeader::decode(&buf) {
+            Some(h) if h.size() <= min(end - pos, RECORD_MAX_SZ as u32) => h.size() as usize,
+            _ => return Ok(None),
+        };
+        self.flash.read(pos, &mut buf[..sz])?;
+
+        Ok(format::verify_record(&buf[..sz]).ok().map(|(h, _)| h))
+    }
+
+    /// Position `n` bytes after `pos` in the log, skipping the sector headers.
+    fn advance(&self, pos: u32, n: u32) -> u32 {
+        let mut sector = pos / F::SECTOR_SZ;
+        let mut offset = pos % F::SECTOR_SZ + n;
+
+        while offset >= F::SECTOR_SZ {
+            offset -= F::SECTOR_SZ - SECTOR_HEADER_SZ;
+            sector = (sector + 1) % self.sectors;
+        }
+
+        sector * F::SECTOR_SZ + offset
+    }
+
+    /// Read from `pos` in the log, continuing in the next sectors.
+    fn read_log(&mut self, mut pos: u32, mut buf: &mut [u8]) -> Result<(), StorageErr> {
+        while !buf.is_empty() {
+            let n = min(buf.len(), (F::SECTOR_SZ - pos % F::SECTOR_SZ) as usize);
+            let (b, rest) = core::mem::take(&mut buf).split_at_mut(n);
+
+            self.flash.read(pos, b)?;
+
+            pos = self.advance(pos, n as u32);
+            buf = rest;
+        }
+
+        Ok(())
+    }
+
+    /// Erase and start the next sector, `cont` bytes of the record being written continue in it.
+    /// The oldest sector is erased if the log has wrapped around.
+    fn start_sector(&mut self, id: u32, cont: u32) -> Result<(), StorageErr> {
+        let (s, seq) = if self.empty {
+            (0, 0)
+        } else {
+            ((self.head + 1) % self.sectors, self.seq + 1)
+        };
+
+        if !self.empty && s == self.tail {
+            defmt::debug!("Flash is full, erasing oldest sector: {}", s);
+            self.tail = (self.tail + 1) % self.sectors;
+            self.full = true;
+        }
+
+        let h = SectorHeader {
+            seq,
+            first: if SECTOR_HEADER_SZ + cont < F::SECTOR_SZ {
+                SECTOR_HEADER_SZ + cont
+            } else {
+                NONE
+            },
+            id: if cont > 0 { id + 1 } else { id },
+        };
+
+        self.flash.erase(s * F::SECTOR_SZ)?;
+        self.flash.write(s * F::SECTOR_SZ, &h.encode())?;
+
+        if self.empty {
+            self.tail = s;
+            self.oldest = h.id;
+        }
+
+        self.empty = false;
+        self.head = s;
+        self.seq = seq;
+        self.pos = s * F::SECTOR_SZ + SECTOR_HEADER_SZ;
+
+        if self.full {
+            let tail = self.tail;
+            self.oldest = self.header(tail)?.map_or(h.id, |t| t.id);
+        }
+
+        Ok(())
+    }
+
+    /// Append record with `id` to the log.
+    fn append(&mut self, id: u32, record: &[u8]) -> Result<(), StorageErr> {
+        let mut r = record;
+
+        while !r.is_empty() {
+            let end = (self.head + 1) * F::SECTOR_SZ;
+
+            if self.empty || self.pos == end {
+                let cont = if r.len() < record.len() { r.len() } else { 0 };
+                self.start_sector(id, cont as u32)?;
+                continue;
+            }
+
+            let n = min(r.len(), (end - self.pos) as usize);
+            self.flash.write(self.pos, &r[..n])?;
+
+            self.pos += n as u32;
+            r = &r[n..];
+        }
+
+        Ok(())
+    }
+
+    /// Probe for record with `id`, used for finding records by time.
+    fn probe(&mut self, id: u32) -> Result<Probe, StorageErr> {
+        match self.get_record(id) {
+            Ok(r) => Ok(match r.timestamp() {
+                Some(t) => Probe::Found(t),
+                None => Probe::Missing { next: id + 1 },
+            }),
+            Err(StorageErr::ReadPackageError) => Ok(Probe::Missing { next: id + 1 }),
+            Err(e) if e.is_not_found() => Ok(Probe::Missing {
+                next: self.skip_missing(id),
+            }),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<F: Flash> PacketStore for FlashStore<F> {
+    fn store(&mut self, r: &mut impl Tagged) -> Result<u32, StorageErr> {
+        self.ensure_mounted()?;
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        r.set_id(id);
+
+        let mut buf = [0u8; RECORD_MAX_SZ];
+        let b = format::encode_record(id, r, &mut buf)?;
+
+        defmt::info!("Storing record id: {} to flash, size: {}", id, b.len());
+
+        // The log is mounted again after a failed write, so that a torn record is skipped.
+        self.append(id, b)
+            .inspect_err(|e| defmt::error!("Failed to write to flash: {:?}", e))
+            .inspect_err(|_| self.mounted = false)?;
+
+        Ok(id)
+    }
+
+    fn get_record(&mut self, id: u32) -> Result<Record, StorageErr> {
+        self.ensure_mounted()?;
+
+        if self.empty || id < self.oldest || id >= self.next_id {
+            return Err(StorageErr::NotFound);
+        }
+
+        // Find the last sector where the first record is at or before `id`.
+        let (mut lo, mut hi) = (0, self.used());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+
+            match self.header(self.nth(mid))? {
+                Some(h) if h.id <= id => lo = mid + 1,
+                _ => hi = mid,
+            }
+        }
+
+        let sector = self.nth(lo.checked_sub(1).ok_or(StorageErr::NotFound)?);
+        let mut pos = match self.header(sector)? {
+            Some(h) if h.first != NONE => sector * F::SECTOR_SZ + h.first,
+            _ => return Err(StorageErr::NotFound),
+        };
+
+        let mut buf = [0u8; RECORD_MAX_SZ];
+
+        loop {
+            self.read_log(pos, &mut buf[..RECORD_HEADER_SZ])?;
+
+            let h = match RecordHeader::decode(&buf) {
+                Some(h) if h.id <= id => h,
+                _ => return Err(StorageErr::NotFound),
+            };
+
+            if h.id == id {
+                let sz = h.size() as usize;
+                self.read_log(pos, &mut buf[..sz])?;
+
+                return format::decode_record(&buf[..sz]).map(|(_, r)| r);
+            }
+
+            pos = self.advance(pos, h.size());
+        }
+    }
+
+    fn next_id(&self) -> Option<u32> {
+        self.mounted.then_some(self.next_id)
+    }
+
+    fn find_by_time(&mut self, start: i64, end: i64) -> Result<Option<(u32, u32)>, StorageErr> {
+        self.ensure_mounted()?;
+
+        let (lo, hi) = (self.oldest, self.next_id);
+        let first = request::lower_bound(lo, hi, start, |id| self.probe(id))?;
+        let last = request::lower_bound(first, hi, end + 1, |id| self.probe(id))?;
+
+        if last > first {
+            Ok(Some((first, last - 1)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Packages older than the oldest sector have been erased.
+    fn skip_missing(&self, id: u32) -> u32 {
+        (id + 1).max(self.oldest)
+    }
+
+    fn health(&self) -> Health {
+        Health {
+            ready: self.mounted,
+            capacity: self.flash.capacity() as u64,
+            used: self.used() as u64 * F::SECTOR_SZ as u64,
+            full: self.full,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mem::MemFlash;
+    use super::*;
+    use crate::axl::{AxlPacket, AXL_SZ};
+    use half::f16;
+
+    fn pck(t: i64) -> AxlPacket {
+        AxlPacket {
+            timestamp: t * 1000,
+            position_time: t as u32,
+            lat: 60.0,
+            lon: 5.0,
+            freq: 52.0,
+            offset: 0,
+            storage_id: None,
+            storage_version: None,
+            fix: None,
+            burst: None,
+            data: (0..AXL_SZ)
+                .map(|v| f16::from_f32(v as f32))
+                .collect::<heapless::Vec<_, { AXL_SZ }>>(),
+        }
+    }
+
+    #[test]
+    fn sector_header() {
+        let h = SectorHeader {
+            seq: 7,
+            first: 100,
+            id: 12,
+        };
+        let mut b = h.encode();
+        assert_eq!(SectorHeader::decode(&b), Some(h));
+
+        b[8] = 0;
+        assert_eq!(SectorHeader::decode(&b), None);
+        assert_eq!(
+            SectorHeader::decode(&[0xff; SECTOR_HEADER_SZ as usize]),
+            None
+        );
+    }
+
+    #[test]
+    fn wrap_around() {
+        // Room for about 10 packages.
+        let flash = MemFlash::new(16 * 4096);
+        let mut s = FlashStore::new(flash.clone());
+
+        for i in 0..40 {
+            assert_eq!(s.store(&mut pck(1_700_000_000 + i)).unwrap(), i as u32);
+        }
+
+        let h = s.health();
+        assert!(h.full);
+        assert_eq!(h.used, h.capacity);
+        assert!(s.oldest > 25);
+
+        for id in s.oldest..40 {
+            assert_eq!(s.get(id).unwrap().storage_id, Some(id));
+        }
+        assert!(s.get(s.oldest - 1).unwrap_err().is_not_found());
+        assert_eq!(s.skip_missing(0), s.oldest);
+
+        // The log is found again.
+        let oldest = s.oldest;
+        let mut s = FlashStore::new(flash);
+        assert_eq!(s.get(39).unwrap().storage_id, Some(39));
+        assert_eq!((s.oldest, s.next_id(), s.full), (oldest, Some(40), true));
+        assert_eq!(s.store(&mut pck(1_700_000_040)).unwrap(), 40);
+    }
+
+    #[test]
+    fn torn_write() {
+        let flash = MemFlash::new(64 * 4096);
+        let mut s = FlashStore::new(flash.clone());
+
+        for i in 0..5 {
+            s.store(&mut pck(1_700_000_000 + i)).unwrap();
+        }
+
+        // The buoy is reset in the middle of writing a package.
+        flash.set_writes_left(Some(3000));
+        assert!(s.store(&mut pck(1_700_000_005)).is_err());
+        flash.set_writes_left(None);
+
+        let mut s = FlashStore::new(flash);
+        assert_eq!(s.store(&mut pck(1_700_000_006)).unwrap(), 6);
+
+        for id in [0, 4, 6] {
+            assert_eq!(s.get(id).unwrap().storage_id, Some(id));
+        }
+        assert!(s.get(5).is_err());
+        assert_eq!(
+            s.find_by_time(1_700_000_004_000, 1_700_000_010_000)
+                .unwrap(),
+            Some((4, 6))
+        );
+    }
+}
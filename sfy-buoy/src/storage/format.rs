@@ -0,0 +1,603 @@
+//! Layout of collection files.
+//!
+//! Version 3 collection files (`NNNNN.3`) start with a [`CollectionHeader`] of [`HEADER_SZ`]
+//! bytes:
+//!
+//! ```text
+//! | b"SFYC" | postcard header | zero padding | CRC32 (LE) |
+//! ```
+//!
+//! followed by variable length records:
+//!
+//! ```text
+//! | b"ST" | length (u32 LE) | id (u32 LE) | CRC32 of payload (u32 LE) | postcard payload |
+//! ```
+//!
+//! The payload is a tagged [`Record`] (see [`super::record`]), so that a collection can hold any
+//! type of data. Collections written before tagged records were introduced have records with the
+//! magic `b"SR"` and a package as the payload, these are read as [`Record::Axl`].
+//!
+//! A torn or corrupted record is detected by the length or the CRC not matching. Every record is
+//! also appended to an index file (`NNNNN.I3`) as an [`IndexEntry`] with the byte offset of the
+//! record in the collection, so that a package can be looked up without scanning the collection.
+//!
+//! Version 1 and 2 collections (`NNNNN.1` and `NNNNN.2`) are fixed slots of packages serialized
+//! with postcard and COBS, padded with zeros. Version 1 used 8 KB slots and fixed size integers
+//! (postcard before 1.0), version 2 uses [`AXL_POSTCARD_SZ`] slots. These are only read.
+
+use half::f16;
+use heapless::{String, Vec};
+use serde::Deserialize;
+
+use super::record::{Record, Tagged};
+use super::{StorageErr, COLLECTION_SIZE, STORAGE_VERSION};
+use crate::axl::{AxlPacket, AXL_POSTCARD_SZ, AXL_SZ, SAMPLE_SZ};
+
+/// Size of the collection header, including magic and CRC.
+pub const HEADER_SZ: usize = 128;
+pub const HEADER_MAGIC: &[u8; 4] = b"SFYC";
+
+/// Size of the header preceding every record.
+pub const RECORD_HEADER_SZ: usize = 14;
+pub const RECORD_MAGIC: &[u8; 2] = b"ST";
+
+/// Magic of records with an untagged package, only read.
+pub const PACKAGE_RECORD_MAGIC: &[u8; 2] = b"SR";
+
+/// Max size of a record, including the record header.
+pub const RECORD_MAX_SZ: usize = RECORD_HEADER_SZ + AXL_POSTCARD_SZ;
+
+/// Size of an entry in the index file.
+pub const INDEX_ENTRY_SZ: usize = 8;
+
+/// Size of a package slot in version 1 and 2 collections.
+pub const fn slot_sz(version: u32) -> usize {
+    match version {
+        1 => 8 * 1024,
+        _ => AXL_POSTCARD_SZ,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CollectionHeader {
+    /// Storage version of the collection.
+    pub version: u32,
+
+    /// Collection number.
+    pub collection: u32,
+
+    /// Serial number of the buoy (`BUOYSN`).
+    pub buoy: String<32>,
+
+    /// Firmware version that wrote the collection.
+    pub firmware: String<32>,
+
+    /// Number of packages in a collection.
+    pub collection_size: u32,
+
+    /// Number of values per sample.
+    pub sample_sz: u32,
+
+    /// Max number of values in a package.
+    pub package_sz: u32,
+}
+
+impl CollectionHeader {
+    pub fn new(collection: u32, buoy: &str, firmware: &str) -> CollectionHeader {
+        CollectionHeader {
+            version: STORAGE_VERSION,
+            collection,
+            buoy: truncate(buoy),
+            firmware: truncate(firmware),
+            collection_size: COLLECTION_SIZE,
+            sample_sz: SAMPLE_SZ as u32,
+            package_sz: AXL_SZ as u32,
+        }
+    }
+
+    pub fn encode(&self) -> Result<[u8; HEADER_SZ], StorageErr> {
+        let mut buf = [0u8; HEADER_SZ];
+        buf[..4].copy_from_slice(HEADER_MAGIC);
+
+        postcard::to_slice(self, &mut buf[4..(HEADER_SZ - 4)])
+            .map_err(|_| StorageErr::SerializationError)?;
+
+        let crc = crc32(&buf[..(HEADER_SZ - 4)]);
+        buf[(HEADER_SZ - 4)..].copy_from_slice(&crc.to_le_bytes());
+
+        Ok(buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<CollectionHeader, StorageErr> {
+        if buf.len() < HEADER_SZ || &buf[..4] != HEADER_MAGIC {
+            return Err(StorageErr::ReadPackageError);
+        }
+
+        let crc = read_u32(&buf[(HEADER_SZ - 4)..]);
+        if crc != crc32(&buf[..(HEADER_SZ - 4)]) {
+            return Err(StorageErr::ReadPackageError);
+        }
+
+        postcard::from_bytes(&buf[4..(HEADER_SZ - 4)]).map_err(|_| StorageErr::ReadPackageError)
+    }
+}
+
+pub(super) fn truncate<const N: usize>(s: &str) -> String<N> {
+    let mut t = String::new();
+    for c in s.chars() {
+        if t.push(c).is_err() {
+            break;
+        }
+    }
+    t
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct RecordHeader {
+    /// The payload is a tagged [`Record`], otherwise it is a package.
+    pub tagged: bool,
+
+    /// Length of payload.
+    pub len: u32,
+
+    /// Storage ID of record.
+    pub id: u32,
+
+    /// CRC32 of payload.
+    pub crc: u32,
+}
+
+impl RecordHeader {
+    pub fn encode(&self) -> [u8; RECORD_HEADER_SZ] {
+        let mut buf = [0u8; RECORD_HEADER_SZ];
+        buf[..2].copy_from_slice(if self.tagged {
+            RECORD_MAGIC
+        } else {
+            PACKAGE_RECORD_MAGIC
+        });
+        buf[2..6].copy_from_slice(&self.len.to_le_bytes());
+        buf[6..10].copy_from_slice(&self.id.to_le_bytes());
+        buf[10..14].copy_from_slice(&self.crc.to_le_bytes());
+        buf
+    }
+
+    /// Decode header, returns `None` if there is no valid record header at the start of `buf`.
+    pub fn decode(buf: &[u8]) -> Option<RecordHeader> {
+        if buf.len() < RECORD_HEADER_SZ {
+            return None;
+        }
+
+        let tagged = match &buf[..2] {
+            m if m == RECORD_MAGIC => true,
+            m if m == PACKAGE_RECORD_MAGIC => false,
+            _ => return None,
+        };
+
+        let h = RecordHeader {
+            tagged,
+            len: read_u32(&buf[2..6]),
+            id: read_u32(&buf[6..10]),
+            crc: read_u32(&buf[10..14]),
+        };
+
+        (h.len as usize <= AXL_POSTCARD_SZ).then_some(h)
+    }
+
+    /// Size of record including header.
+    pub const fn size(&self) -> u32 {
+        RECORD_HEADER_SZ as u32 + self.len
+    }
+}
+
+/// Serialize `r` with `id` as a tagged record into `buf`, returns the record.
+pub fn encode_record<'a>(
+    id: u32,
+    r: &impl Tagged,
+    buf: &'a mut [u8; RECORD_MAX_SZ],
+) -> Result<&'a [u8], StorageErr> {
+    let len = r
+        .to_slice(&mut buf[RECORD_HEADER_SZ..])
+        .inspect_err(|e| defmt::error!("Serialization: {:?}", defmt::Debug2Format(e)))
+        .map_err(|_| StorageErr::SerializationError)?
+        .len();
+
+    let h = RecordHeader {
+        tagged: true,
+        len: len as u32,
+        id,
+        crc: crc32(&buf[RECORD_HEADER_SZ..(RECORD_HEADER_SZ + len)]),
+    };
+    buf[..RECORD_HEADER_SZ].copy_from_slice(&h.encode());
+
+    Ok(&buf[..h.size() as usize])
+}
+
+/// Verify the record at the start of `buf`, returns the header and the payload.
+pub fn verify_record(buf: &[u8]) -> Result<(RecordHeader, &[u8]), StorageErr> {
+    let h = RecordHeader::decode(buf).ok_or(StorageErr::ReadPackageError)?;
+
+    let payload = buf
+        .get(RECORD_HEADER_SZ..h.size() as usize)
+        .ok_or(StorageErr::ReadPackageError)?;
+
+    if crc32(payload) != h.crc {
+        defmt::warn!("Checksum mismatch in record: {}", h.id);
+        return Err(StorageErr::ReadPackageError);
+    }
+
+    Ok((h, payload))
+}
+
+fn decode_payload(h: &RecordHeader, payload: &[u8]) -> Result<Record, StorageErr> {
+    let r = if h.tagged {
+        postcard::from_bytes(payload)
+    } else {
+        postcard::from_bytes(payload).map(Record::Axl)
+    };
+
+    r.map_err(|_| StorageErr::ReadPackageError)
+}
+
+/// Decode and verify the record at the start of `buf`. A verified record with a tag that is not
+/// known (a record from newer firmware) fails like a corrupt record.
+pub fn decode_record(buf: &[u8]) -> Result<(RecordHeader, Record), StorageErr> {
+    let (h, payload) = verify_record(buf)?;
+
+    Ok((h, decode_payload(&h, payload)?))
+}
+
+/// Records in the body of a version 3 collection (after the header). Verified records with a tag
+/// that is not known are skipped, stops at the first record that can not be verified.
+pub fn records(buf: &[u8]) -> impl Iterator<Item = (RecordHeader, Record)> + '_ {
+    let mut offset = 0;
+
+    core::iter::from_fn(move || loop {
+        let (h, payload) = verify_record(buf.get(offset..)?).ok()?;
+        offset += h.size() as usize;
+
+        match decode_payload(&h, payload) {
+            Ok(r) => return Some((h, r)),
+            Err(_) => defmt::warn!("Skipping record with unknown tag: {}", h.id),
+        }
+    })
+}
+
+/// Entry in the index file of a collection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct IndexEntry {
+    /// Storage ID of record.
+    pub id: u32,
+
+    /// Byte offset of record in collection file.
+    pub offset: u32,
+}
+
+impl IndexEntry {
+    /// Byte offset of entry for `id` in the index file.
+    pub const fn position(id: u32) -> u32 {
+        (id % COLLECTION_SIZE) * INDEX_ENTRY_SZ as u32
+    }
+
+    pub fn encode(&self) -> [u8; INDEX_ENTRY_SZ] {
+        let mut buf = [0u8; INDEX_ENTRY_SZ];
+        buf[..4].copy_from_slice(&self.id.to_le_bytes());
+        buf[4..].copy_from_slice(&self.offset.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<IndexEntry> {
+        (buf.len() >= INDEX_ENTRY_SZ).then(|| IndexEntry {
+            id: read_u32(&buf[..4]),
+            offset: read_u32(&buf[4..8]),
+        })
+    }
+}
+
+/// Deserialize package from a slot in a version 1 or 2 collection.
+pub fn decode_legacy(version: u32, slot: &mut [u8]) -> Result<AxlPacket, StorageErr> {
+    match version {
+        1 => postcard::from_bytes_cobs::<AxlPacketV1>(slot).map(AxlPacket::from),
+        2 => postcard::from_bytes_cobs::<AxlPacketV2>(slot).map(AxlPacket::from),
+        _ => return Err(StorageErr::ReadPackageError),
+    }
+    .map_err(|_| StorageErr::ReadPackageError)
+}
+
+/// Package as stored in version 2.
+#[derive(Deserialize)]
+struct AxlPacketV2 {
+    timestamp: i64,
+    offset: u16,
+    storage_id: Option<u32>,
+    storage_version: Option<u32>,
+    position_time: u32,
+    lon: f64,
+    lat: f64,
+    freq: f32,
+    data: Vec<f16, { AXL_SZ }>,
+}
+
+impl From<AxlPacketV2> for AxlPacket {
+    fn from(p: AxlPacketV2) -> AxlPacket {
+        AxlPacket {
+            timestamp: p.timestamp,
+            offset: p.offset,
+            storage_id: p.storage_id,
+            storage_version: p.storage_version,
+            position_time: p.position_time,
+            lon: p.lon,
+            lat: p.lat,
+            fix: None,
+            burst: None,
+            freq: p.freq,
+            data: p.data,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct FixU16(#[serde(with = "postcard::fixint::le")] u16);
+
+#[derive(Deserialize)]
+struct FixU32(#[serde(with = "postcard::fixint::le")] u32);
+
+/// Package as stored in version 1, which had no storage version and fixed size integers.
+#[derive(Deserialize)]
+struct AxlPacketV1 {
+    #[serde(with = "postcard::fixint::le")]
+    timestamp: i64,
+    #[serde(with = "postcard::fixint::le")]
+    offset: u16,
+    storage_id: Option<FixU32>,
+    #[serde(with = "postcard::fixint::le")]
+    position_time: u32,
+    lon: f64,
+    lat: f64,
+    freq: f32,
+    data: Vec<FixU16, { AXL_SZ }>,
+}
+
+impl From<AxlPacketV1> for AxlPacket {
+    fn from(p: AxlPacketV1) -> AxlPacket {
+        AxlPacket {
+            timestamp: p.timestamp,
+            offset: p.offset,
+            storage_id: p.storage_id.map(|id| id.0),
+            storage_version: Some(1),
+            position_time: p.position_time,
+            lon: p.lon,
+            lat: p.lat,
+            fix: None,
+            burst: None,
+            freq: p.freq,
+            data: p.data.iter().map(|v| f16::from_bits(v.0)).collect(),
+        }
+    }
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+/// CRC32 (IEEE) of `buf`.
+pub fn crc32(buf: &[u8]) -> u32 {
+    !buf.iter().fold(!0u32, |c, b| {
+        CRC32_TABLE[((c ^ *b as u32) & 0xff) as usize] ^ (c >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pck(id: u32, n: usize) -> AxlPacket {
+        AxlPacket {
+            timestamp: 1_700_000_000_000 + id as i64 * 20_000,
+            position_time: 1_700_000_000,
+            lat: 60.1,
+            lon: 5.2,
+            freq: 52.0,
+            offset: 15,
+            storage_id: Some(id),
+            storage_version: Some(STORAGE_VERSION),
+            fix: None,
+            burst: Some(1_700_000_000),
+            data: (0..n).map(|v| f16::from_f32(v as f32)).collect(),
+        }
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn header() {
+        let h = CollectionHeader::new(12, "cain", "v0.1.0-123-g1234567-modified-and-long");
+        assert_eq!(h.firmware.len(), 32);
+
+        let mut b = h.encode().unwrap();
+        assert_eq!(&b[..4], HEADER_MAGIC);
+        assert_eq!(CollectionHeader::decode(&b).unwrap(), h);
+
+        b[10] ^= 1;
+        assert!(CollectionHeader::decode(&b).is_err());
+    }
+
+    #[test]
+    fn record_roundtrip() {
+        let mut buf = [0u8; RECORD_MAX_SZ];
+
+        let p = pck(1234, AXL_SZ);
+        let r = encode_record(1234, &p, &mut buf).unwrap();
+        println!("record size: {}", r.len());
+        assert!(r.len() < RECORD_MAX_SZ);
+
+        let (h, d) = decode_record(r).unwrap();
+        assert_eq!(h.id, 1234);
+        assert!(h.tagged);
+        assert_eq!(d, Record::Axl(p));
+
+        // Short package, no padding.
+        let p = pck(1235, 300);
+        let r = encode_record(1235, &p, &mut buf).unwrap();
+        assert!(r.len() < 1024);
+
+        let t = Record::Track(crate::gps::TrackPoint {
+            time: 1_700_000_000,
+            lat: 60.0,
+            lon: 5.0,
+            hdop: None,
+            sats: Some(7),
+        });
+        let r = encode_record(1236, &t, &mut buf).unwrap();
+        assert_eq!(
+            decode_record(r).unwrap(),
+            (RecordHeader::decode(r).unwrap(), t)
+        );
+    }
+
+    #[test]
+    fn read_package_record() {
+        let mut buf = [0u8; RECORD_MAX_SZ];
+
+        let p = pck(1234, 600);
+        let len = postcard::to_slice(&p, &mut buf[RECORD_HEADER_SZ..])
+            .unwrap()
+            .len();
+        let h = RecordHeader {
+            tagged: false,
+            len: len as u32,
+            id: 1234,
+            crc: crc32(&buf[RECORD_HEADER_SZ..(RECORD_HEADER_SZ + len)]),
+        };
+        buf[..RECORD_HEADER_SZ].copy_from_slice(&h.encode());
+        assert_eq!(&buf[..2], PACKAGE_RECORD_MAGIC);
+
+        let (d, r) = decode_record(&buf[..h.size() as usize]).unwrap();
+        assert_eq!(d, h);
+        assert_eq!(r, Record::Axl(p));
+    }
+
+    #[test]
+    fn torn_and_corrupt_records() {
+        let mut body = std::vec::Vec::new();
+        let mut buf = [0u8; RECORD_MAX_SZ];
+
+        for id in 100..103 {
+            body.extend_from_slice(encode_record(id, &pck(id, 600), &mut buf).unwrap());
+        }
+
+        let ids = records(&body)
+            .map(|(h, _)| h.id)
+            .collect::<std::vec::Vec<_>>();
+        assert_eq!(ids, [100, 101, 102]);
+
+        // Torn write of the last record.
+        let torn = &body[..(body.len() - 10)];
+        assert_eq!(records(torn).count(), 2);
+
+        // Flipped bit in the payload of the second record.
+        let second = RecordHeader::decode(&body).unwrap().size() as usize;
+        body[second + RECORD_HEADER_SZ + 20] ^= 0x4;
+        assert!(decode_record(&body[second..]).is_err());
+        assert_eq!(records(&body).count(), 1);
+    }
+
+    #[test]
+    fn unknown_tag() {
+        let mut body = std::vec::Vec::new();
+        let mut buf = [0u8; RECORD_MAX_SZ];
+
+        body.extend_from_slice(encode_record(100, &pck(100, 600), &mut buf).unwrap());
+
+        // A record from newer firmware.
+        let payload = [0xf0, 0x01, 1, 2, 3];
+        let h = RecordHeader {
+            tagged: true,
+            len: payload.len() as u32,
+            id: 101,
+            crc: crc32(&payload),
+        };
+        let unknown = body.len();
+        body.extend_from_slice(&h.encode());
+        body.extend_from_slice(&payload);
+
+        body.extend_from_slice(encode_record(102, &pck(102, 600), &mut buf).unwrap());
+
+        assert_eq!(verify_record(&body[unknown..]).unwrap().0, h);
+        assert!(decode_record(&body[unknown..]).is_err());
+
+        let ids = records(&body)
+            .map(|(h, _)| h.id)
+            .collect::<std::vec::Vec<_>>();
+        assert_eq!(ids, [100, 102]);
+    }
+
+    #[test]
+    fn index_entry() {
+        let e = IndexEntry {
+            id: 1231255,
+            offset: 4000,
+        };
+        assert_eq!(IndexEntry::decode(&e.encode()), Some(e));
+        assert_eq!(IndexEntry::position(1231255), 55 * INDEX_ENTRY_SZ as u32);
+        assert_eq!(IndexEntry::decode(&[0; 4]), None);
+    }
+
+    #[test]
+    fn read_legacy_v1() {
+        let mut c = std::fs::read("tests/data/0.1").unwrap();
+        assert_eq!(c.len(), slot_sz(1) * 4);
+
+        for (i, slot) in c.chunks_exact_mut(slot_sz(1)).enumerate() {
+            let p = decode_legacy(1, slot).unwrap();
+            assert_eq!(p.storage_id, Some(i as u32));
+            assert_eq!(p.storage_version, Some(1));
+            assert_eq!(p.position_time, 123123);
+            assert_eq!(p.offset, 15);
+            assert_eq!(p.freq, 53.0);
+            assert_eq!(p.data.len(), AXL_SZ);
+
+            if i == 0 {
+                assert_eq!(p.timestamp, 1002330);
+                assert_eq!(p.lat, 34.52341);
+                assert_eq!(p.data[0], f16::from_f32(6.0));
+            }
+        }
+    }
+
+    #[test]
+    fn read_legacy_v2() {
+        let mut c = std::fs::read("tests/data/2.2").unwrap();
+
+        for (i, slot) in c.chunks_exact_mut(slot_sz(2)).enumerate() {
+            let p = decode_legacy(2, slot).unwrap();
+            assert_eq!(p.storage_id, Some(200 + i as u32));
+            assert_eq!(p.storage_version, Some(2));
+        }
+
+        assert!(decode_legacy(3, &mut c[..slot_sz(2)]).is_err());
+    }
+}
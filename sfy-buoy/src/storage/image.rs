@@ -0,0 +1,314 @@
+//! FAT32 disk image on the host, so that [`Storage`](super::Storage) can be tested without an
+//! SD-card.
+//!
+//! Faults can be injected while the storage is in use: writes can be made to fail after a number
+//! of blocks (the blocks before the limit are written, leaving a torn write), and the card can be
+//! removed, also in the middle of a write.
+
+use std::cell::{Cell, RefCell};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx, SdMmcError};
+
+use super::Card;
+
+/// First block of the partition.
+const PARTITION_START: u32 = 64;
+
+/// Blocks in the partition, FAT32 needs at least 65525 clusters.
+const PARTITION_BLOCKS: u32 = 70_000;
+
+const RESERVED_BLOCKS: u32 = 32;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Faults {
+    /// Number of blocks that can be written before writes fail (`None` for no limit).
+    pub writes_left: Option<u32>,
+
+    /// The card is removed, it can not be initialized and all reads and writes fail.
+    pub removed: bool,
+
+    /// The card is removed when the writes run out.
+    pub eject: bool,
+}
+
+struct Disk {
+    file: File,
+    path: PathBuf,
+    temporary: bool,
+}
+
+impl Drop for Disk {
+    fn drop(&mut self) {
+        if self.temporary {
+            std::fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+/// Disk image, clones share the image and faults.
+#[derive(Clone)]
+pub struct Image {
+    disk: Rc<RefCell<Disk>>,
+    blocks: u32,
+    faults: Rc<Cell<Faults>>,
+}
+
+impl Image {
+    /// Open an existing disk image.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Image> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let blocks = (file.metadata()?.len() / Block::LEN as u64) as u32;
+
+        Ok(Image {
+            disk: Rc::new(RefCell::new(Disk {
+                file,
+                path,
+                temporary: false,
+            })),
+            blocks,
+            faults: Rc::new(Cell::new(Faults::default())),
+        })
+    }
+
+    /// Create an empty FAT32 formatted disk image in the temporary directory, the image is
+    /// removed when it is dropped.
+    pub fn temp(name: &str) -> std::io::Result<Image> {
+        let path = std::env::temp_dir().join(format!("sfy-{}-{}.img", name, std::process::id()));
+        format(&path)?;
+
+        let image = Image::open(&path)?;
+        image.disk.borrow_mut().temporary = true;
+
+        Ok(image)
+    }
+
+    pub fn set_faults(&self, faults: Faults) {
+        self.faults.set(faults);
+    }
+
+    fn check(&self) -> Result<(), SdMmcError> {
+        if self.faults.get().removed {
+            Err(SdMmcError::CardNotFound)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl BlockDevice for Image {
+    type Error = SdMmcError;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        self.check()?;
+
+        let mut disk = self.disk.borrow_mut();
+        disk.file
+            .seek(SeekFrom::Start(
+                start_block_idx.0 as u64 * Block::LEN as u64,
+            ))
+            .map_err(|_| SdMmcError::ReadError)?;
+
+        for b in blocks {
+            disk.file
+                .read_exact(&mut b.contents)
+                .map_err(|_| SdMmcError::ReadError)?;
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        self.check()?;
+
+        let mut disk = self.disk.borrow_mut();
+        disk.file
+            .seek(SeekFrom::Start(
+                start_block_idx.0 as u64 * Block::LEN as u64,
+            ))
+            .map_err(|_| SdMmcError::WriteError)?;
+
+        for b in blocks {
+            let mut faults = self.faults.get();
+
+            match faults.writes_left {
+                Some(0) => {
+                    faults.removed |= faults.eject;
+                    self.faults.set(faults);
+                    return Err(SdMmcError::WriteError);
+                }
+                Some(n) => faults.writes_left = Some(n - 1),
+                None => (),
+            }
+            self.faults.set(faults);
+
+            disk.file
+                .write_all(&b.contents)
+                .map_err(|_| SdMmcError::WriteError)?;
+        }
+
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        self.check()?;
+
+        Ok(BlockCount(self.blocks))
+    }
+}
+
+impl Card for Image {
+    type Device<'a>
+        = &'a Image
+    where
+        Self: 'a;
+
+    fn init(&mut self) -> Result<Self::Device<'_>, SdMmcError> {
+        self.check()?;
+
+        Ok(self)
+    }
+
+    fn acquire(&mut self) -> Result<Self::Device<'_>, SdMmcError> {
+        self.check()?;
+
+        Ok(self)
+    }
+
+    fn size_bytes(device: &Self::Device<'_>) -> Result<u64, SdMmcError> {
+        Ok(device.num_blocks()?.0 as u64 * Block::LEN as u64)
+    }
+}
+
+fn put_u16(b: &mut [u8], offset: usize, v: u16) {
+    b[offset..offset + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(b: &mut [u8], offset: usize, v: u32) {
+    b[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+/// Create a disk image with a partition table and a single empty FAT32 partition (one block per
+/// cluster). Blocks that are not written are sparse zeros.
+fn format(path: &Path) -> std::io::Result<()> {
+    const LEN: usize = Block::LEN;
+
+    // Blocks per FAT, the FAT must have room for an entry for every cluster.
+    let mut fat_blocks = 1;
+    while (fat_blocks * LEN as u32 / 4) < PARTITION_BLOCKS - RESERVED_BLOCKS - 2 * fat_blocks + 2 {
+        fat_blocks += 1;
+    }
+    let clusters = PARTITION_BLOCKS - RESERVED_BLOCKS - 2 * fat_blocks;
+
+    let mut f = File::create(path)?;
+    f.set_len((PARTITION_START + PARTITION_BLOCKS) as u64 * LEN as u64)?;
+
+    let mut write = |block: u32, b: &[u8]| -> std::io::Result<()> {
+        f.seek(SeekFrom::Start(block as u64 * LEN as u64))?;
+        f.write_all(b)
+    };
+
+    // Master boot record, partition type: FAT32 (LBA).
+    let mut mbr = [0u8; LEN];
+    mbr[446 + 4] = 0x0c;
+    put_u32(&mut mbr, 446 + 8, PARTITION_START);
+    put_u32(&mut mbr, 446 + 12, PARTITION_BLOCKS);
+    mbr[510..].copy_from_slice(&[0x55, 0xaa]);
+    write(0, &mbr)?;
+
+    // Boot sector with BIOS parameter block, and the backup copy.
+    let mut bpb = [0u8; LEN];
+    bpb[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    bpb[3..11].copy_from_slice(b"MSWIN4.1");
+    put_u16(&mut bpb, 11, LEN as u16);
+    bpb[13] = 1; // blocks per cluster
+    put_u16(&mut bpb, 14, RESERVED_BLOCKS as u16);
+    bpb[16] = 2; // number of FATs
+    bpb[21] = 0xf8; // media: fixed disk
+    put_u16(&mut bpb, 24, 32); // blocks per track
+    put_u16(&mut bpb, 26, 64); // heads
+    put_u32(&mut bpb, 28, PARTITION_START);
+    put_u32(&mut bpb, 32, PARTITION_BLOCKS);
+    put_u32(&mut bpb, 36, fat_blocks);
+    put_u32(&mut bpb, 44, 2); // root directory cluster
+    put_u16(&mut bpb, 48, 1); // info sector
+    put_u16(&mut bpb, 50, 6); // backup boot sector
+    bpb[64] = 0x80; // drive number
+    bpb[66] = 0x29; // extended boot signature
+    put_u32(&mut bpb, 67, 0x5f59_0001); // volume ID
+    bpb[71..82].copy_from_slice(b"SFY        ");
+    bpb[82..90].copy_from_slice(b"FAT32   ");
+    bpb[510..].copy_from_slice(&[0x55, 0xaa]);
+    write(PARTITION_START, &bpb)?;
+    write(PARTITION_START + 6, &bpb)?;
+
+    // Info sector, the root directory uses the first cluster.
+    let mut info = [0u8; LEN];
+    put_u32(&mut info, 0, 0x4161_5252);
+    put_u32(&mut info, 484, 0x6141_7272);
+    put_u32(&mut info, 488, clusters - 1);
+    put_u32(&mut info, 492, 3);
+    put_u32(&mut info, 508, 0xaa55_0000);
+    write(PARTITION_START + 1, &info)?;
+    write(PARTITION_START + 7, &info)?;
+
+    // FATs: media, reserved and end of chain for the (empty) root directory.
+    let mut fat = [0u8; LEN];
+    put_u32(&mut fat, 0, 0x0fff_fff8);
+    put_u32(&mut fat, 4, 0x0fff_ffff);
+    put_u32(&mut fat, 8, 0x0fff_ffff);
+    write(PARTITION_START + RESERVED_BLOCKS, &fat)?;
+    write(PARTITION_START + RESERVED_BLOCKS + fat_blocks, &fat)?;
+
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::clock::NullClock;
+    use embedded_sdmmc::{Controller, VolumeIdx};
+
+    #[test]
+    fn mount_image() {
+        let image = Image::temp("mount").unwrap();
+        assert_eq!(
+            Image::size_bytes(&&image).unwrap(),
+            (PARTITION_START + PARTITION_BLOCKS) as u64 * 512
+        );
+
+        let mut c = Controller::new(&image, NullClock);
+        let mut v = c.get_volume(VolumeIdx(0)).unwrap();
+        let root = c.open_root_dir(&v).unwrap();
+
+        let mut f = c
+            .open_file_in_dir(&mut v, &root, "0.3", embedded_sdmmc::Mode::ReadWriteCreate)
+            .unwrap();
+        c.write(&mut v, &mut f, b"hello").unwrap();
+        c.close_file(&v, f).unwrap();
+
+        assert_eq!(c.find_directory_entry(&v, &root, "0.3").unwrap().size, 5);
+        c.close_dir(&v, root);
+    }
+
+    #[test]
+    fn card_removed() {
+        let mut image = Image::temp("removed").unwrap();
+        image.set_faults(Faults {
+            removed: true,
+            ..Faults::default()
+        });
+
+        assert!(image.acquire().is_err());
+        assert!(image.clone().init().is_err());
+    }
+}
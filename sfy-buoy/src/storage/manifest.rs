@@ -0,0 +1,187 @@
+//! Deployment manifest on the SD-card.
+//!
+//! A [`Manifest`] is appended to [`MANIFEST_FNAME`] once per boot, when the first package of the
+//! session is stored (the buoy does not know the time when it boots). It records which buoy,
+//! firmware and sampling configuration produced the collections from the first storage ID of the
+//! session, and why the buoy was reset. The records are serialized using `postcard` and separated
+//! with `COBS`, like the track. The manifest can be printed and exported with `sfypack`.
+
+use heapless::{String, Vec};
+
+use super::{format::truncate, Card, Storage, StorageErr, STORAGE_VERSION};
+use crate::crash::BootReport;
+use crate::note::{BUOYPR, BUOYSN};
+use crate::{fir, waves};
+
+/// Manifest records, one per boot.
+pub const MANIFEST_FNAME: &str = "MANIFEST.M1";
+
+/// Max size of a `Manifest` serialized using postcard with COBS.
+pub const MANIFEST_POSTCARD_SZ: usize = 256;
+
+/// Sampling configuration of the IMU and the filter (see [`crate::waves`] and [`crate::fir`]).
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format, serde::Serialize, serde::Deserialize)]
+pub struct Sampling {
+    /// Sample rate of the IMU (Hz).
+    pub imu_freq: f32,
+
+    /// Sample rate of the packages (Hz).
+    pub output_freq: f32,
+
+    /// Cut-off frequency of the low-pass filter (Hz).
+    pub cutoff: f32,
+
+    /// Number of taps in the low-pass filter.
+    pub ntap: u16,
+
+    /// Decimation of the filtered samples.
+    pub decimate: u8,
+
+    /// Full-scale of the accelerometer (g).
+    pub accel_fs: u8,
+
+    /// Full-scale of the gyroscope (degrees per second).
+    pub gyro_fs: u16,
+}
+
+impl Sampling {
+    /// The sampling configuration of this firmware.
+    pub const fn current() -> Sampling {
+        Sampling {
+            imu_freq: waves::IMU_FREQ.value(),
+            output_freq: fir::OUT_FREQ,
+            cutoff: fir::CUTOFF,
+            ntap: fir::NTAP as u16,
+            decimate: fir::DECIMATE,
+            accel_fs: waves::ACCEL_FS_G,
+            gyro_fs: waves::GYRO_FS_DPS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, defmt::Format, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    /// Time of the first package stored after boot (UTC, seconds).
+    pub time: u32,
+
+    /// First storage ID of the session.
+    pub first_id: u32,
+
+    /// Serial number of the buoy (`BUOYSN`).
+    pub buoy: String<32>,
+
+    /// Notehub product (`BUOYPR`).
+    pub product: String<64>,
+
+    /// Firmware (git) version.
+    pub firmware: String<32>,
+
+    pub storage_version: u32,
+
+    pub sampling: Sampling,
+
+    /// Number of boots since the crash record was cleared (see [`crate::crash`]).
+    pub boot_count: u32,
+
+    /// Reason of the last reset if initiated by the firmware
+    /// ([`ResetReason`](crate::log::ResetReason), `0` otherwise).
+    pub reset_reason: u8,
+
+    /// Hardware reset cause (see [`ResetCause`](crate::crash::ResetCause)).
+    pub reset_cause: u32,
+}
+
+impl Manifest {
+    /// Manifest of this boot, the time and first storage ID are set when the first package is
+    /// stored.
+    pub fn new(boot: &BootReport, firmware: &str) -> Manifest {
+        Manifest {
+            time: 0,
+            first_id: 0,
+            buoy: truncate(BUOYSN),
+            product: truncate(BUOYPR),
+            firmware: truncate(firmware),
+            storage_version: STORAGE_VERSION,
+            sampling: Sampling::current(),
+            boot_count: boot.boot_count,
+            reset_reason: boot.reason.map(|r| r as u8).unwrap_or(0),
+            reset_cause: boot.reset_cause.0,
+        }
+    }
+}
+
+impl<C: Card> Storage<C> {
+    /// Write a manifest for this boot when the first package is stored.
+    pub fn set_boot(&mut self, boot: &BootReport) {
+        self.manifest = Some(Manifest::new(boot, self.firmware));
+    }
+
+    /// Append the manifest of this boot, if it has not been written. `id` and `time` (UTC,
+    /// seconds) are of the first stored package. If writing fails it is tried again with the next
+    /// package.
+    pub(super) fn write_manifest(&mut self, id: u32, time: u32) -> Result<(), StorageErr> {
+        let mut m = match self.manifest.take() {
+            Some(m) => m,
+            None => return Ok(()),
+        };
+
+        if m.time == 0 {
+            m.first_id = id;
+            m.time = time;
+        }
+
+        let buf: Vec<u8, MANIFEST_POSTCARD_SZ> = postcard::to_vec_cobs(&m)
+            .inspect_err(|e| defmt::error!("Serialization: {:?}", defmt::Debug2Format(e)))
+            .map_err(|_| StorageErr::SerializationError)?;
+
+        defmt::info!("Writing manifest: {}", m);
+        let r = self
+            .acquire()
+            .and_then(|mut b| b.write(MANIFEST_FNAME, &buf));
+
+        if r.is_err() {
+            self.manifest = Some(m);
+        }
+
+        r.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crash::ResetCause;
+    use crate::log::ResetReason;
+
+    #[test]
+    fn manifest_record() {
+        let boot = BootReport {
+            boot_count: 3,
+            reset_cause: ResetCause(0x40),
+            reason: Some(ResetReason::Watchdog),
+            pc: 0,
+            lr: 0,
+            task: None,
+            msg: String::new(),
+        };
+
+        let mut m = Manifest::new(&boot, "v0.1.0-123-gabcdef0-modified-and-a-very-long-suffix");
+        m.first_id = 12300;
+        m.time = 1_700_000_000;
+
+        assert_eq!(m.buoy, BUOYSN);
+        assert_eq!(m.firmware.len(), 32);
+        assert_eq!(m.reset_reason, ResetReason::Watchdog as u8);
+        assert_eq!(m.sampling.imu_freq, fir::FREQ);
+        assert_eq!(
+            m.sampling.output_freq,
+            m.sampling.imu_freq / m.sampling.decimate as f32
+        );
+
+        let mut b: Vec<u8, MANIFEST_POSTCARD_SZ> = postcard::to_vec_cobs(&m).unwrap();
+        assert_eq!(*b.last().unwrap(), 0);
+
+        let d: Manifest = postcard::from_bytes_cobs(&mut b).unwrap();
+        assert_eq!(d, m);
+    }
+}
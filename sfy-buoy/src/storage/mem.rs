@@ -0,0 +1,171 @@
+//! Stores in RAM for the host tests: [`MemStore`] keeps the records in a queue, and [`MemFlash`]
+//! is a NOR flash for the [`FlashStore`](super::flash::FlashStore).
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use super::flash::Flash;
+use super::format::{self, RECORD_MAX_SZ};
+use super::{Health, PacketStore, Record, StorageErr, Tagged};
+use crate::axl::AXL_POSTCARD_SZ;
+use crate::request::{self, Probe};
+
+/// The newest `capacity` records, kept encoded.
+pub struct MemStore {
+    records: VecDeque<Vec<u8>>,
+    capacity: usize,
+    next_id: u32,
+}
+
+impl MemStore {
+    pub fn new(capacity: usize) -> MemStore {
+        MemStore {
+            records: VecDeque::new(),
+            capacity,
+            next_id: 0,
+        }
+    }
+
+    fn oldest(&self) -> u32 {
+        self.next_id - self.records.len() as u32
+    }
+
+    fn record(&self, id: u32) -> Option<&[u8]> {
+        id.checked_sub(self.oldest())
+            .and_then(|i| self.records.get(i as usize))
+            .map(|r| r.as_slice())
+    }
+}
+
+impl PacketStore for MemStore {
+    fn store(&mut self, r: &mut impl Tagged) -> Result<u32, StorageErr> {
+        let id = self.next_id;
+        r.set_id(id);
+
+        let mut buf = [0u8; RECORD_MAX_SZ];
+        let b = format::encode_record(id, r, &mut buf)?;
+
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+
+        self.records.push_back(b.to_vec());
+        self.next_id += 1;
+
+        Ok(id)
+    }
+
+    fn get_record(&mut self, id: u32) -> Result<Record, StorageErr> {
+        let b = self.record(id).ok_or(StorageErr::NotFound)?;
+
+        format::decode_record(b).map(|(_, r)| r)
+    }
+
+    fn next_id(&self) -> Option<u32> {
+        Some(self.next_id)
+    }
+
+    fn find_by_time(&mut self, start: i64, end: i64) -> Result<Option<(u32, u32)>, StorageErr> {
+        let probe = |id: u32| match self.record(id).map(format::decode_record) {
+            Some(Ok((_, r))) => Ok(match r.timestamp() {
+                Some(t) => Probe::Found(t),
+                None => Probe::Missing { next: id + 1 },
+            }),
+            Some(Err(e)) => Err(e),
+            None => Ok(Probe::Missing { next: id + 1 }),
+        };
+
+        let (lo, hi) = (self.oldest(), self.next_id);
+        let first = request::lower_bound(lo, hi, start, probe)?;
+        let last = request::lower_bound(first, hi, end + 1, probe)?;
+
+        Ok((last > first).then(|| (first, last - 1)))
+    }
+
+    fn skip_missing(&self, id: u32) -> u32 {
+        (id + 1).max(self.oldest())
+    }
+
+    fn health(&self) -> Health {
+        Health {
+            ready: true,
+            capacity: (self.capacity * AXL_POSTCARD_SZ) as u64,
+            used: self.records.iter().map(|r| r.len() as u64).sum(),
+            full: self.next_id as usize > self.capacity,
+        }
+    }
+}
+
+/// NOR flash with 4 KiB sectors, clones share the memory. Programming can only clear bits, like
+/// on a real flash.
+#[derive(Clone)]
+pub struct MemFlash {
+    mem: Rc<RefCell<Vec<u8>>>,
+
+    /// Number of bytes that can be programmed before writes fail (`None` for no limit).
+    writes_left: Rc<Cell<Option<u32>>>,
+}
+
+impl MemFlash {
+    /// Erased flash of `capacity` bytes.
+    pub fn new(capacity: u32) -> MemFlash {
+        MemFlash {
+            mem: Rc::new(RefCell::new(vec![0xff; capacity as usize])),
+            writes_left: Rc::new(Cell::new(None)),
+        }
+    }
+
+    pub fn set_writes_left(&self, n: Option<u32>) {
+        self.writes_left.set(n);
+    }
+
+    fn range(&self, addr: u32, len: usize) -> Result<core::ops::Range<usize>, StorageErr> {
+        let r = addr as usize..(addr as usize + len);
+
+        if r.end <= self.mem.borrow().len() {
+            Ok(r)
+        } else {
+            Err(StorageErr::FlashError)
+        }
+    }
+}
+
+impl Flash for MemFlash {
+    const SECTOR_SZ: u32 = 4096;
+
+    fn capacity(&self) -> u32 {
+        self.mem.borrow().len() as u32
+    }
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), StorageErr> {
+        let r = self.range(addr, buf.len())?;
+        buf.copy_from_slice(&self.mem.borrow()[r]);
+
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u32, buf: &[u8]) -> Result<(), StorageErr> {
+        let r = self.range(addr, buf.len())?;
+        let mut mem = self.mem.borrow_mut();
+
+        for (m, b) in mem[r].iter_mut().zip(buf) {
+            match self.writes_left.get() {
+                Some(0) => return Err(StorageErr::FlashError),
+                Some(n) => self.writes_left.set(Some(n - 1)),
+                None => (),
+            }
+
+            *m &= *b;
+        }
+
+        Ok(())
+    }
+
+    fn erase(&mut self, addr: u32) -> Result<(), StorageErr> {
+        let r = self.range(addr, Self::SECTOR_SZ as usize)?;
+        self.mem.borrow_mut()[r].fill(0xff);
+
+        Ok(())
+    }
+}
@@ -3,37 +3,75 @@
 //! Every data-package is stored to the SD-card and queued for the Notecard. It should also be
 //! possible to request a range of old packages.
 //!
+//! The storage runs on any [`Card`] with a FAT32 file system: the SD-card on the SPI bus on the
+//! buoy ([`SdCard`]), or a disk image with injected faults in the host tests.
+//!
 //! The maximum number of files in a FAT32 directory is 65536. If a data package has ID
 //! `1234567` it is put in the file: `12345.X` where `X` is the version of the storage format
-//! starting with 1. The packages are serialized using the `postcard` format and separated with
-//! `COBS`es. The collection file is the full ID stripped of the last 2 digits. Each collection
-//! file holds 100 packages.
+//! starting with 1. The collection file is the full ID stripped of the last 2 digits. Each
+//! collection file holds 100 packages. A collection starts with a header identifying the buoy,
+//! firmware and layout, followed by variable length records with a CRC. Records are tagged with
+//! the type of data (see [`record`]), most are data-packages. The offset of every record
+//! is kept in an index file (`12345.I3`). See [`format`] for details and the layout of older
+//! versions, which can still be read. The time span of every collection is kept in a time index,
+//! see [`time_index`].
 //!
 //! At 52 Hz and 1024 length data-package, there is 4389 packages per day. That is about 44 collections per day. See tests for more details.
+//!
+//! When the card fills up the oldest collections are deleted, see [`crate::retention`]. The
+//! collection numbers keep increasing, deleted collections are not re-used.
+//!
+//! GPS fixes are stored as records among the packages, see [`crate::gps::TrackPoint`]. Log events
+//! and messages that can not be sent are also stored as records, and spooled for forwarding, see
+//! [`spool`]. The buoy, firmware and sampling configuration of every boot is appended to a
+//! manifest, see [`manifest`].
+//!
+//! The [`crate::StorageManager`] uses the storage through the [`PacketStore`] trait, which is
+//! also implemented by a log on a SPI NOR flash ([`flash::FlashStore`]).
 
 use core::fmt::Debug;
-use core::ops::DerefMut;
 use core::sync::atomic::Ordering;
+#[cfg(not(test))]
 use cortex_m::interrupt::free;
 use embedded_hal::{blocking::spi::Transfer, digital::v2::OutputPin};
-use embedded_sdmmc::{
-    BlockSpi, Controller, Error as GenericSdMmcError, Mode, SdMmcError, SdMmcSpi, VolumeIdx,
-};
+use embedded_sdmmc::{Controller, Error as GenericSdMmcError, Mode, SdMmcError, VolumeIdx};
 use heapless::{String, Vec};
 
 use crate::axl::{AxlPacket, AXL_POSTCARD_SZ};
+use crate::note::BUOYSN;
+use crate::request::{self, Probe};
+use crate::{log, power, retention};
 
+pub mod card;
 pub mod clock;
+pub mod flash;
+pub mod format;
 mod handles;
-
+#[cfg(test)]
+mod image;
+pub mod manifest;
+#[cfg(test)]
+pub mod mem;
+pub mod record;
+pub mod spool;
+pub mod store;
+pub mod time_index;
+pub mod writer;
+
+pub use card::{Card, SdCard, SdSpiSpeed};
 use clock::CountClock;
+use format::{CollectionHeader, IndexEntry, RecordHeader};
 use handles::*;
+pub use record::{Record, Tagged};
+pub use store::{Health, PacketStore};
+use time_index::{TimeEntry, Usage, Window, TIME_INDEX};
+use writer::{Batch, WriteStats, Writer, WRITE_BATCH};
 
 /// Writing to a file seems to take longer time when it has more packages, this can cause timeouts
 /// in the interrupt that drains the IMU FIFO. See <https://github.com/gauteh/sfy/issues/77>.
 pub const COLLECTION_SIZE: u32 = 100;
-pub const STORAGE_VERSION_STR: &'static str = "2";
-pub const STORAGE_VERSION: u32 = 2;
+pub const STORAGE_VERSION_STR: &'static str = "3";
+pub const STORAGE_VERSION: u32 = 3;
 
 #[derive(Debug, defmt::Format)]
 pub enum StorageErr {
@@ -46,6 +84,43 @@ pub enum StorageErr {
     SerializationError,
     DiskFull,
     Uninitialized,
+
+    /// There is no package with the ID in the store.
+    NotFound,
+    FlashError,
+
+    /// The store does not support the operation.
+    Unsupported,
+}
+
+impl StorageErr {
+    /// Numeric code of error, used in [`crate::log::Event::StorageError`].
+    pub const fn code(&self) -> u8 {
+        use StorageErr::*;
+
+        match self {
+            SdMmcErr(_) => 1,
+            GenericSdMmmcErr(_) => 2,
+            ParseIDFailure => 3,
+            WriteIDFailure => 4,
+            WriteError => 5,
+            ReadPackageError => 6,
+            SerializationError => 7,
+            DiskFull => 8,
+            Uninitialized => 9,
+            NotFound => 10,
+            FlashError => 11,
+            Unsupported => 12,
+        }
+    }
+
+    /// The package (or its collection) does not exist.
+    pub const fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            StorageErr::NotFound | StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)
+        )
+    }
 }
 
 impl From<SdMmcError> for StorageErr {
@@ -68,47 +143,93 @@ enum SdState {
     Initialized { next_id: u32 },
 }
 
-pub enum SdSpiSpeed {
-    Low,
-    High,
+/// The storage is tested on the host, where there are no interrupts to disable.
+#[cfg(test)]
+fn free<R>(f: impl FnOnce(&cortex_m::interrupt::CriticalSection) -> R) -> R {
+    f(&unsafe { cortex_m::interrupt::CriticalSection::new() })
 }
 
-pub struct Storage<Spi: Transfer<u8>, CS: OutputPin>
-where
-    <Spi as Transfer<u8>>::Error: Debug,
-{
-    sd: SdMmcSpi<Spi, CS>,
-
-    reclock_cb: fn(&mut Spi, SdSpiSpeed) -> (),
+pub struct Storage<C: Card> {
+    card: C,
     clock: CountClock,
     state: SdState,
+    spool: spool::SpoolState,
+
+    /// Firmware version, written to the header of new collections.
+    firmware: &'static str,
+
+    /// Time index entry of the collection being written.
+    current: Option<TimeEntry>,
+
+    /// Space used by the collections in the time index, read when the card is initialized.
+    usage: Usage,
+
+    /// Offset in the time index of the next collection to consider for deletion.
+    cursor: u32,
+
+    /// Size of the card (bytes).
+    card_size: u64,
+
+    /// Storage IDs (inclusive) in queued data-requests, these are not deleted.
+    protected: Vec<(u32, u32), { request::MAX_REQUESTS }>,
+
+    /// A write failed because the card is full.
+    full: bool,
+
+    /// Manifest of this boot, until it has been written.
+    manifest: Option<manifest::Manifest>,
+
+    /// Packages waiting to be written.
+    writer: Writer,
 }
 
-impl<Spi: Transfer<u8>, CS: OutputPin> Storage<Spi, CS>
+impl<Spi: Transfer<u8>, CS: OutputPin> Storage<SdCard<Spi, CS>>
 where
     <Spi as Transfer<u8>>::Error: Debug,
 {
-    /// Returns an un-initialized storage module.
+    /// Returns an un-initialized storage module on the SD-card, packages are batched in `batch`
+    /// (see [`writer::BATCH`]).
     pub fn open(
         spi: Spi,
         cs: CS,
         clock: CountClock,
         reclock_cb: fn(&mut Spi, SdSpiSpeed) -> (),
-    ) -> Storage<Spi, CS> {
+        firmware: &'static str,
+        batch: &'static mut Batch,
+    ) -> Storage<SdCard<Spi, CS>> {
         defmt::info!("Opening SD card..");
 
-        let sd = SdMmcSpi::new(spi, cs);
+        Storage::new(SdCard::new(spi, cs, reclock_cb), clock, firmware, batch)
+    }
+}
 
+impl<C: Card> Storage<C> {
+    /// Returns an un-initialized storage module on `card`.
+    pub fn new(
+        card: C,
+        clock: CountClock,
+        firmware: &'static str,
+        batch: &'static mut Batch,
+    ) -> Storage<C> {
         Storage {
-            sd,
-            reclock_cb,
+            card,
             clock,
             state: SdState::Uninitialized,
+            spool: spool::SpoolState::new(),
+            firmware,
+            current: None,
+            usage: Usage::default(),
+            cursor: 0,
+            card_size: 0,
+            protected: Vec::new(),
+            full: false,
+            manifest: None,
+            writer: Writer::new(batch),
         }
     }
 
-    pub fn acquire(&mut self) -> Result<BlockSpiHandle<'_, Spi, CS>, StorageErr> {
-        BlockSpiHandle::acquire(self)
+    pub fn acquire(&mut self) -> Result<BlockHandle<'_, C>, StorageErr> {
+        BlockHandle::acquire(self)
     }
 
     /// Returns the next free ID.
@@ -119,91 +240,422 @@ where
         }
     }
 
+    /// Set the millisecond timer used for the write statistics.
+    pub fn set_timer(&mut self, timer: fn() -> i64) {
+        self.writer.set_timer(timer);
+    }
+
+    /// Time used writing packages to the card.
+    pub fn write_stats(&self) -> WriteStats {
+        self.writer.stats
+    }
+
+    /// Write the batched packages to the card.
+    pub fn flush(&mut self) -> Result<(), StorageErr> {
+        if self.writer.is_empty() {
+            return Ok(());
+        }
+
+        let firmware = self.firmware;
+        self.acquire()?.flush(firmware)
+    }
+
     pub fn deinit(&mut self) {
         self.state = SdState::Uninitialized;
     }
 
-    /// Deserialize and return AxlPacket.
+    /// Set the ranges of storage IDs in queued data-requests.
+    pub fn set_protected(&mut self, ranges: Vec<(u32, u32), { request::MAX_REQUESTS }>) {
+        self.protected = ranges;
+    }
+
+    /// Deserialize and return the package with `id`.
     pub fn get(&mut self, id: u32) -> Result<AxlPacket, StorageErr> {
-        defmt::debug!("Reading file: {}", id);
-        let (collection, file, offset) = id_to_parts(id);
+        match self.get_record(id)? {
+            Record::Axl(pck) => Ok(pck),
+            _ => Err(StorageErr::NotFound),
+        }
+    }
 
-        let mut buf: Vec<u8, { AXL_POSTCARD_SZ }> = Vec::new();
-        buf.resize_default(AXL_POSTCARD_SZ).unwrap();
+    /// Deserialize and return the record with `id`.
+    pub fn get_record(&mut self, id: u32) -> Result<Record, StorageErr> {
+        let (collection, fid) = id_to_parts(id);
 
         defmt::debug!(
-            "Reading package id: {} from collection: {}, fileid: {}, offset: {}",
+            "Reading package id: {} from collection: {}, fileid: {}",
             id,
             collection,
-            file,
-            offset
+            fid
+        );
+
+        let firmware = self.firmware;
+        let mut block = self.acquire()?;
+        block.flush(firmware)?;
+
+        match block.read_record(id) {
+            Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)) => {
+                block.read_legacy(id).map(Record::Axl)
+            }
+            r => r,
+        }
+    }
+
+    /// Probe for record with `id`, used for finding records by time. Records without a timestamp
+    /// are skipped.
+    pub fn probe(&mut self, id: u32) -> Result<Probe, StorageErr> {
+        match self.get_record(id) {
+            Ok(r) => Ok(match r.timestamp() {
+                Some(t) => Probe::Found(t),
+                None => Probe::Missing { next: id + 1 },
+            }),
+            Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)) => {
+                Ok(Probe::Missing {
+                    next: (id / COLLECTION_SIZE + 1) * COLLECTION_SIZE,
+                })
+            }
+            Err(StorageErr::ReadPackageError) => Ok(Probe::Missing { next: id + 1 }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Find the range of storage IDs in the time window by bisecting IDs `lo` to `hi`
+    /// (exclusive). The range may include missing packages.
+    fn find_time_in(
+        &mut self,
+        lo: u32,
+        hi: u32,
+        start: i64,
+        end: i64,
+    ) -> Result<Option<(u32, u32)>, StorageErr> {
+        let first = request::lower_bound(lo, hi, start, |id| self.probe(id))?;
+        let last = request::lower_bound(first, hi, end + 1, |id| self.probe(id))?;
+
+        defmt::debug!(
+            "Packages in time window: {} -> {}: {} -> {}",
+            start,
+            end,
+            first,
+            last
+        );
+
+        if last > first {
+            Ok(Some((first, last - 1)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Find the range of storage IDs (inclusive) with packages in the time window `start` to `end`
+    /// (ms, inclusive) using the time index. Only the first and last collection in the window are
+    /// bisected. If the window is not in the index, collections older than the index (written
+    /// before it existed) are bisected.
+    pub fn find_by_time(&mut self, start: i64, end: i64) -> Result<Option<(u32, u32)>, StorageErr> {
+        let next_id = self.next_id().ok_or(StorageErr::Uninitialized)?;
+
+        let mut w = Window::new(start, end);
+        self.acquire()?.read_time_index(|b| w.push_entries(b))?;
+
+        if let Some(e) = &self.current {
+            w.push(e);
+        }
+
+        let (first, last) = match (w.first, w.last) {
+            (Some(first), Some(last)) => (first, last),
+            _ => {
+                let hi = w.oldest.map_or(next_id, |c| c * COLLECTION_SIZE);
+                defmt::debug!(
+                    "Time window not in index, searching packages before: {}",
+                    hi
+                );
+                return self.find_time_in(0, hi, start, end);
+            }
+        };
+
+        defmt::debug!(
+            "Time window in collections: {} -> {}",
+            first.collection,
+            last.collection
         );
 
-        let sz = self
-            .acquire()
-            .and_then(|mut block| block.read(&collection, offset, &mut buf))?;
+        let first =
+            request::lower_bound(first.first_id(), first.end_id(), start, |id| self.probe(id))?;
+        let last =
+            request::lower_bound(last.first_id(), last.end_id(), end + 1, |id| self.probe(id))?;
 
-        defmt::trace!("Read {:?} bytes.", sz);
+        if last > first {
+            Ok(Some((first, last - 1)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Add record to the time index entry of its collection, `sz` is the number of bytes written
+    /// for it. The entry is appended to the time index when the next collection is started.
+    fn index_time(&mut self, id: u32, r: &impl Tagged, sz: u32) -> Result<(), StorageErr> {
+        let c = id / COLLECTION_SIZE;
+
+        if let Some(e) = self.current.as_mut().filter(|e| e.collection == c) {
+            r.index(e);
+            e.size += sz;
+            return Ok(());
+        }
+
+        let mut e = TimeEntry::empty(c);
+        r.index(&mut e);
+        e.size = sz;
 
-        // De-serialize
-        let pck: AxlPacket =
-            postcard::from_bytes_cobs(&mut buf).map_err(|_| StorageErr::ReadPackageError)?;
+        match self.current.replace(e) {
+            Some(e) => {
+                self.acquire()?.append_time_entry(&e)?;
+                self.usage.push(&e);
+                Ok(())
+            }
 
-        Ok(pck)
+            // First collection since start-up, the buoy may have been reset before the previous
+            // collection was finished.
+            None if c > 0 => self.reindex(c - 1),
+            None => Ok(()),
+        }
     }
 
-    /// Store a new package.
-    pub fn store(&mut self, pck: &mut AxlPacket) -> Result<u32, StorageErr> {
+    /// Add collection `c` to the time index if it is missing.
+    fn reindex(&mut self, c: u32) -> Result<(), StorageErr> {
+        if self.usage.newest.filter(|n| *n >= c).is_some() {
+            return Ok(());
+        }
+
+        defmt::info!("Collection {} is not in time index, adding it.", c);
+
+        let mut entry: Option<TimeEntry> = None;
+
+        for id in (c * COLLECTION_SIZE)..((c + 1) * COLLECTION_SIZE) {
+            match self.get_record(id) {
+                Ok(r) => r.index(entry.get_or_insert(TimeEntry::empty(c))),
+                Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)) => break,
+                Err(StorageErr::ReadPackageError) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut e = match entry {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        let mut block = self.acquire()?;
+        for f in [collection_fname(c), index_fname(c)] {
+            e.size += match block.length(&f) {
+                Ok(len) => len,
+                Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)) => 0,
+                Err(e) => return Err(e),
+            };
+        }
+
+        block.append_time_entry(&e)?;
+        drop(block);
+
+        self.usage.push(&e);
+
+        Ok(())
+    }
+
+    /// Delete the oldest collections if they use more space than `policy` allows, or the card is
+    /// full. Collections that are protected by the policy are skipped, the collection being
+    /// written and the newest collection in the time index are never deleted. Returns the
+    /// [`CollectionsDeleted`](log::Event::CollectionsDeleted) event if any collections were
+    /// deleted (also when deleting fails after that).
+    fn retain(
+        &mut self,
+        policy: retention::Policy,
+    ) -> (Option<log::Event>, Result<(), StorageErr>) {
+        let limit = policy.limit(self.card_size);
+
+        if self.usage.used <= limit && !self.full {
+            return (None, Ok(()));
+        }
+
+        defmt::warn!(
+            "Collections use: {} of {} bytes (card full: {}), deleting oldest collections..",
+            self.usage.used,
+            limit,
+            self.full
+        );
+
+        let current = self.current.map(|e| e.collection);
+        let mut deleted: Option<(u32, u32)> = None;
+        let mut n = 0;
+        let mut buf = [0u8; 21 * time_index::ENTRY_SZ];
+
+        let result: Result<(), StorageErr> = 'scan: loop {
+            let cursor = self.cursor;
+            let read = self
+                .acquire()
+                .and_then(|mut b| b.read_at(TIME_INDEX, cursor, &mut buf));
+
+            let sz = match read {
+                Ok(sz) => sz / time_index::ENTRY_SZ * time_index::ENTRY_SZ,
+                Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)) => 0,
+                Err(e) => break Err(e),
+            };
+
+            if sz == 0 {
+                defmt::warn!("No more collections can be deleted.");
+                break Ok(());
+            }
+
+            for b in buf[..sz].chunks_exact(time_index::ENTRY_SZ) {
+                if n >= retention::MAX_DELETE || (self.usage.used <= limit && !self.full) {
+                    break 'scan Ok(());
+                }
+
+                let e = match TimeEntry::decode(b) {
+                    Some(e) if !e.is_deleted() && e.collection >= self.usage.retained => e,
+                    _ => {
+                        self.cursor += time_index::ENTRY_SZ as u32;
+                        continue;
+                    }
+                };
+
+                if Some(e.collection) == current || Some(e.collection) == self.usage.newest {
+                    defmt::warn!("Reached the newest collection, not deleting more.");
+                    break 'scan Ok(());
+                }
+
+                if policy.protects(e.flags, e.first_id(), e.end_id(), &self.protected) {
+                    defmt::debug!("Collection {} is protected, keeping it.", e.collection);
+                } else {
+                    let tombstone = e.deleted();
+                    let removed: Result<(), StorageErr> = try {
+                        let mut block = self.acquire()?;
+
+                        match block.remove_collection(e.collection) {
+                            Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)) => {
+                                defmt::warn!("Collection {} already removed.", e.collection)
+                            }
+                            r => r?,
+                        }
+
+                        block.append_time_entry(&tombstone)?;
+                    };
+
+                    if removed.is_err() {
+                        break 'scan removed;
+                    }
+
+                    self.usage.push(&tombstone);
+
+                    deleted = Some((deleted.map_or(e.collection, |(f, _)| f), e.collection));
+                    n += 1;
+                    self.full = false;
+                }
+
+                self.cursor += time_index::ENTRY_SZ as u32;
+            }
+        };
+
+        let event = deleted.map(|(first, last)| {
+            defmt::warn!(
+                "Deleted {} collections: {} -> {}, collections use: {} bytes.",
+                n,
+                first,
+                last,
+                self.usage.used
+            );
+
+            log::Event::CollectionsDeleted { first, last, n }
+        });
+
+        (event, result)
+    }
+
+    /// Store a new record, the storage ID is set on packages.
+    pub fn store(&mut self, r: &mut impl Tagged) -> Result<u32, StorageErr> {
+        // Make room before a new collection is started, or after a write has failed.
+        if self.full || matches!(self.next_id(), Some(id) if id % COLLECTION_SIZE == 0) {
+            let (deleted, r) = self.retain(retention::policy());
+
+            if let Some(e) = deleted {
+                log::event(e);
+            }
+
+            r.inspect_err(|e| defmt::error!("Failed to delete old collections: {:?}", e))
+                .ok();
+        }
+
+        let firmware = self.firmware;
         let mut block = self.acquire()?;
 
         // If writing fails we will always start a new collection, so ID's should not get out of
         // sync within one collection file.
         let id = block.advance_id()?;
-        let (collection, fid, offset) = id_to_parts(id);
+        let (collection, fid) = id_to_parts(id);
 
         // Package now has a storage ID.
-        pck.storage_id = Some(id);
-        pck.storage_version = Some(STORAGE_VERSION);
-
-        // Serialize
-        let mut buf: Vec<u8, { AXL_POSTCARD_SZ }> = postcard::to_vec_cobs(pck)
-            .inspect_err(|e| defmt::error!("Serialization: {:?}", defmt::Debug2Format(e)))
-            .map_err(|_| StorageErr::SerializationError)?;
-        buf.resize_default(buf.capacity()).unwrap();
-
-        // And write..
-        defmt::info!(
-            "Writing package to card id: {}, size: {}, timestamp: {}, collection: {}, fileid: {}, offset: {}",
-            id,
-            buf.len(),
-            pck.timestamp,
-            collection,
-            fid,
-            offset
-        );
+        r.set_id(id);
 
-        block.write(&collection, &buf)?;
+        let c = id / COLLECTION_SIZE;
+
+        let written: Result<u32, StorageErr> = try {
+            // Write the batch if it is for the previous collection, or a batch that failed.
+            if block.writer.needs_flush(c) {
+                block.flush(firmware)?;
+            }
+
+            let sz = block.writer.push(id, r)?;
+
+            defmt::info!(
+                "Storing record id: {}, size: {}, timestamp: {:?}, collection: {}, fileid: {}",
+                id,
+                sz,
+                r.timestamp(),
+                collection,
+                fid
+            );
+
+            if block.writer.is_full() {
+                block.flush(firmware)?;
+            }
+
+            // The collection header is written with the first record.
+            let header = if fid == 0 { format::HEADER_SZ } else { 0 };
+
+            (header + sz + format::INDEX_ENTRY_SZ) as u32
+        };
+        drop(block);
+
+        let sz = match written {
+            Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::NotEnoughSpace))
+            | Err(StorageErr::DiskFull) => {
+                defmt::error!("SD-card is full.");
+                self.full = true;
+                return written;
+            }
+            r => r?,
+        };
+
+        self.index_time(id, r, sz)
+            .inspect_err(|e| defmt::error!("Failed to update time index: {:?}", e))
+            .ok();
+
+        if let Some(t) = r.timestamp() {
+            self.write_manifest(id, (t / 1000) as u32)
+                .inspect_err(|e| defmt::error!("Failed to write manifest: {:?}", e))
+                .ok();
+        }
 
         Ok(id)
     }
 }
 
-pub struct BlockSpiHandle<'a, Spi: Transfer<u8>, CS: OutputPin>
-where
-    <Spi as Transfer<u8>>::Error: Debug,
-{
-    block: BlockSpi<'a, Spi, CS>,
+pub struct BlockHandle<'a, C: Card + 'a> {
+    block: C::Device<'a>,
     clock: &'a CountClock,
     state: &'a mut SdState,
+    writer: &'a mut Writer,
 }
 
-impl<'spi, Spi: Transfer<u8> + 'spi, CS: OutputPin + 'spi> BlockSpiHandle<'spi, Spi, CS>
-where
-    <Spi as Transfer<u8>>::Error: Debug,
-{
-    fn acquire<'a>(
-        storage: &'a mut Storage<Spi, CS>,
-    ) -> Result<BlockSpiHandle<'a, Spi, CS>, StorageErr> {
+impl<'c, C: Card + 'c> BlockHandle<'c, C> {
+    fn acquire<'a>(storage: &'a mut Storage<C>) -> Result<BlockHandle<'a, C>, StorageErr> {
         match storage.state {
             SdState::Retry { last_try } => {
                 let now = storage.clock.0.load(Ordering::Relaxed);
@@ -222,47 +674,70 @@ where
                 }
             }
             SdState::Uninitialized => {
-                defmt::info!("Initializing SD-card (low-speed)..");
                 storage.state = SdState::Retry {
                     last_try: storage.clock.0.load(Ordering::Relaxed),
                 };
-                (storage.reclock_cb)(storage.sd.spi().deref_mut(), SdSpiSpeed::Low);
 
-                // XXX: This is slow if it fails (time-out), hopefully not too slow, but if so
-                // needs to only be attempted seldomly.
-                let mut block = storage.sd.acquire()?;
+                let block = storage.card.init()?;
 
-                defmt::debug!("Increasing SPI speed.");
-                (storage.reclock_cb)(block.spi().deref_mut(), SdSpiSpeed::High);
+                storage.card_size = C::size_bytes(&block)?;
+                defmt::info!("SD card size: {} mb", storage.card_size / 1024_u64.pow(2));
 
-                let sz = block.card_size_bytes()? / 1024_u64.pow(2);
-                defmt::info!("SD card size: {} mb", sz);
+                // Read the space used by collections, and the newest collection so that deleted
+                // collections are not re-used.
+                let mut usage = Usage::default();
+                let mut h = BlockHandle {
+                    block,
+                    clock: &storage.clock,
+                    state: &mut storage.state,
+                    writer: &mut storage.writer,
+                };
+                h.read_time_index(|b| usage.push_entries(b))?;
+                let BlockHandle { block, .. } = h;
+
+                defmt::info!(
+                    "Collections use: {} bytes (newest: {:?})",
+                    usage.used,
+                    usage.newest
+                );
+                storage.usage = usage;
+                storage.cursor = 0;
+
+                let mut start = usage.newest.map(|c| c + 1);
+
+                // The collection of a batch that failed may not have been created, it is not
+                // re-used either.
+                if storage.writer.failed() {
+                    start = start.max(Some(storage.writer.collection() + 1));
+                }
 
                 // XXX: This is a slow operation which is likely to cause trouble if it is done on
                 // every send to notecard loop. Hopefully we will fail above (quickly
                 // enough), otherwise this can only be attempted seldomly.
-                let next_id = Self::find_first_free_collection(&mut block, &storage.clock, None)?
+                let next_id = Self::find_first_free_collection(&block, &storage.clock, start)?
                     * COLLECTION_SIZE;
                 defmt::info!("Next free ID: {}", next_id);
 
                 storage.state = SdState::Initialized { next_id };
 
-                Ok(BlockSpiHandle {
+                Ok(BlockHandle {
                     block,
                     clock: &storage.clock,
                     state: &mut storage.state,
+                    writer: &mut storage.writer,
                 })
             }
             SdState::Initialized { next_id: _ } => {
                 let block = storage
-                    .sd
+                    .card
                     .acquire()
                     .inspect_err(|_| storage.state = SdState::Uninitialized)?;
 
-                Ok(BlockSpiHandle {
+                Ok(BlockHandle {
                     block,
                     clock: &storage.clock,
                     state: &mut storage.state,
+                    writer: &mut storage.writer,
                 })
             }
         }
@@ -288,28 +763,240 @@ where
         sz
     }
 
-    pub fn read(
+    /// Write the batched records to their collection, the collection header is written first if
+    /// the collection is new. The offsets of the records are appended to the index of the
+    /// collection. The batch is kept if writing fails, and the card must be initialized again.
+    ///
+    /// A batch that failed is written again with the same IDs after the part of the collection
+    /// that may have been torn. If the last entry of the index was torn it is padded, so that
+    /// entries stay aligned.
+    pub fn flush(&mut self, firmware: &str) -> Result<(), StorageErr> {
+        if self.writer.is_empty() {
+            return Ok(());
+        }
+
+        if self.writer.failed() {
+            defmt::warn!(
+                "Writing failed batch to collection: {} again.",
+                self.writer.collection()
+            );
+        }
+
+        let start = self.writer.now();
+        let c = self.writer.collection();
+        let n = self.writer.len() as u32;
+
+        let written: Result<(), StorageErr> = try {
+            let offset = {
+                let mut ctrl = Controller::new(&self.block, self.clock);
+                let mut v = ctrl.get_volume(VolumeIdx(0))?;
+                let mut root = DirHandle::open_root(&mut ctrl, &mut v)?;
+                let mut f = root.open_file(&collection_fname(c), Mode::ReadWriteCreateOrAppend)?;
+                f.seek_from_end(0)
+                    .inspect_err(|e| defmt::error!("File seek error: {}", e))
+                    .map_err(|_| StorageErr::WriteError)?;
+
+                if f.length() == 0 {
+                    let header = CollectionHeader::new(c, BUOYSN, firmware).encode()?;
+                    free(|_| f.write(&header))?;
+                }
+
+                let offset = f.length();
+                free(|_| f.write(self.writer.records()))?;
+
+                offset
+            };
+
+            let pad = match self.length(&index_fname(c)) {
+                Ok(len) => {
+                    (format::INDEX_ENTRY_SZ - len as usize % format::INDEX_ENTRY_SZ)
+                        % format::INDEX_ENTRY_SZ
+                }
+                Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)) => 0,
+                Err(e) => Err(e)?,
+            };
+
+            let mut index: Vec<u8, { (WRITE_BATCH + 1) * format::INDEX_ENTRY_SZ }> = Vec::new();
+            index.resize_default(pad).unwrap();
+            for e in self.writer.index(offset) {
+                index.extend_from_slice(&e.encode()).unwrap();
+            }
+
+            self.write(&index_fname(c), &index)?;
+        };
+
+        if written.is_err() {
+            self.writer.fail();
+            *self.state = SdState::Uninitialized;
+            return written;
+        }
+
+        self.writer.clear();
+
+        let ms = start
+            .zip(self.writer.now())
+            .map(|(start, end)| (end - start) as u32);
+        self.writer.stats.push(n, ms);
+
+        defmt::debug!("Wrote {} packages to collection: {} in {:?} ms", n, c, ms);
+
+        power::ACTIVITY.flushes.fetch_add(1, Ordering::Relaxed);
+        if let Some(ms) = ms {
+            power::ACTIVITY.write_max.fetch_max(ms, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    /// Read record from a version 3 collection. The record is looked up in the index, if the
+    /// index is missing or does not match the collection is scanned.
+    fn read_record(&mut self, id: u32) -> Result<Record, StorageErr> {
+        let c = id / COLLECTION_SIZE;
+        let collection = collection_fname(c);
+
+        let mut e = [0u8; format::INDEX_ENTRY_SZ];
+        let offset = match self.read_at(&index_fname(c), IndexEntry::position(id), &mut e) {
+            Ok(format::INDEX_ENTRY_SZ) => IndexEntry::decode(&e)
+                .filter(|e| e.id == id)
+                .map(|e| e.offset),
+            Ok(_) | Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)) => None,
+            Err(e) => return Err(e),
+        };
+
+        let offset = match offset {
+            Some(offset) => offset,
+            None => {
+                defmt::debug!("Package {} not in index, scanning collection.", id);
+                self.scan_record(&collection, id)?
+            }
+        };
+
+        let mut buf = [0u8; format::RECORD_MAX_SZ];
+        let sz = self.read_at(&collection, offset, &mut buf)?;
+        let (_, r) = format::decode_record(&buf[..sz])?;
+
+        Ok(r)
+    }
+
+    /// Scan collection for record `id`, returns the offset of the record.
+    fn scan_record(&mut self, collection: &str, id: u32) -> Result<u32, StorageErr> {
+        let mut offset = format::HEADER_SZ as u32;
+        let mut h = [0u8; format::RECORD_HEADER_SZ];
+
+        loop {
+            let sz = self.read_at(collection, offset, &mut h)?;
+
+            if sz == 0 {
+                defmt::debug!("Collection is not long enough, no such package in it.");
+                return Err(GenericSdMmcError::FileNotFound.into());
+            }
+
+            match RecordHeader::decode(&h[..sz]) {
+                Some(r) if r.id == id => return Ok(offset),
+                Some(r) => offset += r.size(),
+                None => return Err(StorageErr::ReadPackageError),
+            }
+        }
+    }
+
+    /// Read package from a version 1 or 2 collection.
+    fn read_legacy(&mut self, id: u32) -> Result<AxlPacket, StorageErr> {
+        let (c, fid) = (id / COLLECTION_SIZE, id % COLLECTION_SIZE);
+        let mut buf = [0u8; AXL_POSTCARD_SZ];
+
+        for version in [2, 1] {
+            let sz = format::slot_sz(version);
+            let slot = &mut buf[..sz];
+
+            match self.read_at(&collection_fname_v(c, version), fid * sz as u32, slot) {
+                Ok(n) if n == sz => return format::decode_legacy(version, slot),
+                Ok(_) => {
+                    defmt::debug!("Collection is not long enough, no such package in it.");
+                    break;
+                }
+                Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(GenericSdMmcError::FileNotFound.into())
+    }
+
+    /// Append entry to the time index. If the last entry was torn it is padded, so that entries
+    /// stay aligned.
+    fn append_time_entry(&mut self, e: &TimeEntry) -> Result<(), StorageErr> {
+        let pad = match self.length(TIME_INDEX) {
+            Ok(len) => {
+                (time_index::ENTRY_SZ - len as usize % time_index::ENTRY_SZ) % time_index::ENTRY_SZ
+            }
+            Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)) => 0,
+            Err(e) => return Err(e),
+        };
+
+        let mut buf: Vec<u8, { 2 * time_index::ENTRY_SZ }> = Vec::new();
+        buf.resize_default(pad).unwrap();
+        buf.extend_from_slice(&e.encode()).unwrap();
+
+        defmt::debug!("Adding to time index: {}", e);
+        self.write(TIME_INDEX, &buf).map(|_| ())
+    }
+
+    /// Pass all entries in the time index to `f` (a number of whole entries at the time).
+    fn read_time_index(&mut self, mut f: impl FnMut(&[u8])) -> Result<(), StorageErr> {
+        let mut buf = [0u8; 21 * time_index::ENTRY_SZ];
+        let mut offset = 0;
+
+        loop {
+            let sz = match self.read_at(TIME_INDEX, offset, &mut buf) {
+                Ok(sz) => sz / time_index::ENTRY_SZ * time_index::ENTRY_SZ,
+                Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)) => 0,
+                Err(e) => return Err(e),
+            };
+
+            if sz == 0 {
+                return Ok(());
+            }
+
+            f(&buf[..sz]);
+            offset += sz as u32;
+        }
+    }
+
+    /// Length of file.
+    pub fn length(&mut self, fname: &str) -> Result<u32, StorageErr> {
+        let mut c = Controller::new(&self.block, self.clock);
+        let mut v = c.get_volume(VolumeIdx(0))?;
+        let mut root = DirHandle::open_root(&mut c, &mut v)?;
+
+        Ok(root.find_directory_entry(fname)?.size)
+    }
+
+    /// Read from `offset` in file, returns the number of bytes read (`0` at end of file).
+    pub fn read_at(
         &mut self,
-        collection: &str,
-        offset: usize,
+        fname: &str,
+        offset: u32,
         buf: &mut [u8],
     ) -> Result<usize, StorageErr> {
         let sz: Result<usize, StorageErr> = try {
             let mut c = Controller::new(&self.block, self.clock);
             let mut v = c.get_volume(VolumeIdx(0))?;
             let mut root = DirHandle::open_root(&mut c, &mut v)?;
-            let mut f = root.open_file(collection, Mode::ReadOnly)?;
+            let mut f = root.open_file(fname, Mode::ReadOnly)?;
 
-            if f.length() < (offset + AXL_POSTCARD_SZ) as u32 {
-                defmt::debug!("Collection is not long enough, no such file in it.");
-                return Err(GenericSdMmcError::FileNotFound.into());
+            if f.length() <= offset {
+                return Ok(0);
             }
 
-            f.seek_from_start(offset as u32)
+            f.seek_from_start(offset)
                 .map_err(|_| StorageErr::ReadPackageError)?;
             free(|_| f.read(buf))?
         };
 
+        if let Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)) = sz {
+            return sz;
+        }
+
         if sz.is_err() {
             *self.state = SdState::Uninitialized;
         }
@@ -317,6 +1004,42 @@ where
         sz
     }
 
+    /// Replace the contents of file.
+    pub fn overwrite(&mut self, fname: &str, buf: &[u8]) -> Result<usize, StorageErr> {
+        let sz: Result<usize, StorageErr> = try {
+            let mut c = Controller::new(&self.block, self.clock);
+            let mut v = c.get_volume(VolumeIdx(0))?;
+            let mut root = DirHandle::open_root(&mut c, &mut v)?;
+            let mut f = root.open_file(fname, Mode::ReadWriteCreateOrTruncate)?;
+            free(|_| f.write(buf))?
+        };
+
+        if sz.is_err() {
+            *self.state = SdState::Uninitialized;
+        }
+
+        sz
+    }
+
+    pub fn remove_file(&mut self, fname: &str) -> Result<(), StorageErr> {
+        let r: Result<(), StorageErr> = try {
+            let mut c = Controller::new(&self.block, self.clock);
+            let mut v = c.get_volume(VolumeIdx(0))?;
+            let mut root = DirHandle::open_root(&mut c, &mut v)?;
+            root.delete_file(fname)?;
+        };
+
+        if let Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)) = r {
+            return r;
+        }
+
+        if r.is_err() {
+            *self.state = SdState::Uninitialized;
+        }
+
+        r
+    }
+
     /// Get the next free ID (and advance to new collection if necessary).
     fn advance_id(&mut self) -> Result<u32, StorageErr> {
         if let SdState::Initialized { next_id: id } = &mut self.state {
@@ -326,7 +1049,7 @@ where
             // Check that the next collection is free, if rolling over.
             if next_id % COLLECTION_SIZE == 0 {
                 let c = next_id / COLLECTION_SIZE;
-                let nc = Self::find_first_free_collection(&mut self.block, self.clock, Some(c))?;
+                let nc = Self::find_first_free_collection(&self.block, self.clock, Some(c))?;
 
                 if nc > c {
                     defmt::info!("Starting new collection: {}", c);
@@ -344,24 +1067,29 @@ where
     /// Find the first free collection. Every time the buoy starts up a new collection will be used
     /// to prevent offset mismatch between file id. The ID will be set to the first entry in that
     /// collection.
-    pub fn find_first_free_collection<'a>(
-        block: &mut BlockSpi<'a, Spi, CS>,
+    pub fn find_first_free_collection(
+        block: &C::Device<'_>,
         clock: &CountClock,
         start: Option<u32>,
     ) -> Result<u32, StorageErr> {
-        let mut c = Controller::new(&block, clock);
+        let mut c = Controller::new(block, clock);
         let mut v = c.get_volume(VolumeIdx(0))?;
 
         let mut root = DirHandle::open_root(&mut c, &mut v)?;
 
-        for c in start.unwrap_or(0)..65536u32 {
-            let f = collection_fname(c);
-            defmt::debug!("Searching for free collection, testing: {}", f);
-            match root.find_directory_entry(&f) {
-                Ok(_) => continue,
-                Err(GenericSdMmcError::FileNotFound) => return Ok(c),
-                Err(e) => return Err(e.into()),
+        'collections: for c in start.unwrap_or(0)..(u32::MAX / COLLECTION_SIZE) {
+            // Collections written with an older storage version are not re-used either.
+            for version in [STORAGE_VERSION, 2, 1] {
+                let f = collection_fname_v(c, version);
+                defmt::debug!("Searching for free collection, testing: {}", f);
+                match root.find_directory_entry(&f) {
+                    Ok(_) => continue 'collections,
+                    Err(GenericSdMmcError::FileNotFound) => (),
+                    Err(e) => return Err(e.into()),
+                }
             }
+
+            return Ok(c);
         }
 
         Err(StorageErr::DiskFull)
@@ -370,53 +1098,62 @@ where
     pub fn remove_collection(&mut self, collection: u32) -> Result<(), StorageErr> {
         defmt::info!("Removing collection: {}", collection);
 
-        let f = collection_fname(collection);
+        self.remove_file(&collection_fname(collection))?;
 
-        let mut c = Controller::new(&self.block, self.clock);
-        let mut v = c.get_volume(VolumeIdx(0))?;
-        let mut root = DirHandle::open_root(&mut c, &mut v)?;
-        root.delete_file(&f)?;
-
-        Ok(())
+        match self.remove_file(&index_fname(collection)) {
+            Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)) => Ok(()),
+            r => r,
+        }
     }
 }
 
 pub fn collection_fname(c: u32) -> String<32> {
+    collection_fname_v(c, STORAGE_VERSION)
+}
+
+/// File name of collection `c` written with storage `version`.
+pub fn collection_fname_v(c: u32, version: u32) -> String<32> {
     let mut f: String<32> = String::from(c);
     f.push_str(".").unwrap();
+    f.push_str(&String::<10>::from(version)).unwrap();
+    f
+}
+
+/// File name of the index of collection `c`.
+pub fn index_fname(c: u32) -> String<32> {
+    let mut f: String<32> = String::from(c);
+    f.push_str(".I").unwrap();
     f.push_str(STORAGE_VERSION_STR).unwrap();
     f
 }
 
-/// Calculate collection file, file number in collection and byte offset of start of pacakge in
-/// collection file for a given ID.
-pub fn id_to_parts(id: u32) -> (String<32>, u32, usize) {
+/// Calculate collection file and file number in collection for a given ID.
+pub fn id_to_parts(id: u32) -> (String<32>, u32) {
     let collection = id / COLLECTION_SIZE;
     let fileid = id % COLLECTION_SIZE;
-    let offset = fileid as usize * AXL_POSTCARD_SZ;
 
-    let collection = collection_fname(collection);
-
-    (collection, fileid, offset)
+    (collection_fname(collection), fileid)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::axl::AXL_SZ;
+    use core::sync::atomic::AtomicI32;
     use half::f16;
 
     #[test]
     fn test_id_to_parts() {
-        let (c, file, o) = id_to_parts(0);
-        assert_eq!(c, "0.2");
+        let (c, file) = id_to_parts(0);
+        assert_eq!(c, "0.3");
         assert_eq!(file, 0);
-        assert_eq!(o, 0);
 
-        let (c, file, o) = id_to_parts(1231255);
-        assert_eq!(c, "12312.2");
+        let (c, file) = id_to_parts(1231255);
+        assert_eq!(c, "12312.3");
         assert_eq!(file, 55);
-        assert_eq!(o, 55 * AXL_POSTCARD_SZ);
+
+        assert_eq!(collection_fname_v(12312, 1), "12312.1");
+        assert_eq!(index_fname(12312), "12312.I3");
     }
 
     #[test]
@@ -450,12 +1187,11 @@ mod tests {
 
         let buf = c.as_mut_slice();
 
-        let p0: AxlPacket = postcard::from_bytes_cobs(&mut buf[..AXL_POSTCARD_SZ]).unwrap();
-        let p1: AxlPacket =
-            postcard::from_bytes_cobs(&mut buf[AXL_POSTCARD_SZ..(2 * AXL_POSTCARD_SZ)]).unwrap();
-        let p2: AxlPacket =
-            postcard::from_bytes_cobs(&mut buf[(AXL_POSTCARD_SZ * 2)..(AXL_POSTCARD_SZ * 3)])
-                .unwrap();
+        let p0 = format::decode_legacy(2, &mut buf[..AXL_POSTCARD_SZ]).unwrap();
+        let p1 =
+            format::decode_legacy(2, &mut buf[AXL_POSTCARD_SZ..(2 * AXL_POSTCARD_SZ)]).unwrap();
+        let p2 = format::decode_legacy(2, &mut buf[(AXL_POSTCARD_SZ * 2)..(AXL_POSTCARD_SZ * 3)])
+            .unwrap();
 
         assert_eq!(p0.storage_id, Some(0));
         assert_eq!(p1.storage_id, Some(1));
@@ -469,7 +1205,9 @@ mod tests {
             freq: 53.0,
             offset: 15,
             storage_id: Some(0),
-            storage_version: Some(STORAGE_VERSION),
+            storage_version: Some(2),
+            fix: None,
+            burst: None,
             data: (6..3078)
                 .map(|v| f16::from_f32(v as f32))
                 .collect::<Vec<_, { AXL_SZ }>>(),
@@ -482,7 +1220,9 @@ mod tests {
             freq: 53.0,
             offset: 15,
             storage_id: Some(1),
-            storage_version: Some(STORAGE_VERSION),
+            storage_version: Some(2),
+            fix: None,
+            burst: None,
             data: (6..3078)
                 .map(|v| f16::from_f32(v as f32))
                 .collect::<Vec<_, { AXL_SZ }>>(),
@@ -495,7 +1235,9 @@ mod tests {
             freq: 53.0,
             offset: 15,
             storage_id: Some(2),
-            storage_version: Some(STORAGE_VERSION),
+            storage_version: Some(2),
+            fix: None,
+            burst: None,
             data: (9..3081)
                 .map(|v| f16::from_f32(v as f32))
                 .collect::<Vec<_, { AXL_SZ }>>(),
@@ -515,10 +1257,282 @@ mod tests {
 
         for p in 0..12 {
             let slice = &mut buf[(AXL_POSTCARD_SZ * p)..(AXL_POSTCARD_SZ * (p + 1))];
-            let pck: AxlPacket = postcard::from_bytes_cobs(slice).unwrap();
+            let pck = format::decode_legacy(2, slice).unwrap();
             println!("Deserialized data package: {:?}", pck);
 
             assert_eq!(pck.storage_id, Some(200 + p as u32));
         }
     }
+
+    fn image_storage(card: &image::Image, clock: &'static AtomicI32) -> Storage<image::Image> {
+        Storage::new(card.clone(), CountClock(clock), "test", writer::batch())
+    }
+
+    fn pck(t: i64) -> AxlPacket {
+        AxlPacket {
+            timestamp: t * 1000,
+            position_time: t as u32,
+            lat: 60.0,
+            lon: 5.0,
+            freq: 52.0,
+            offset: 0,
+            storage_id: None,
+            storage_version: None,
+            fix: None,
+            burst: None,
+            data: (0..AXL_SZ)
+                .map(|v| f16::from_f32(v as f32))
+                .collect::<Vec<_, { AXL_SZ }>>(),
+        }
+    }
+
+    #[test]
+    fn image_rollover() {
+        static CLOCK: AtomicI32 = AtomicI32::new(1_700_000_000);
+        let card = image::Image::temp("rollover").unwrap();
+        let mut s = image_storage(&card, &CLOCK);
+
+        for i in 0..250 {
+            assert_eq!(s.store(&mut pck(1_700_000_000 + i)).unwrap(), i as u32);
+        }
+        assert_eq!(s.next_id(), Some(250));
+
+        for id in [0, 99, 100, 249] {
+            let p = s.get(id).unwrap();
+            assert_eq!(p.storage_id, Some(id));
+            assert_eq!(p.timestamp, (1_700_000_000 + id as i64) * 1000);
+        }
+
+        // Completed collections are in the time index.
+        assert_eq!(
+            s.find_by_time(1_700_000_150_000, 1_700_000_160_000)
+                .unwrap(),
+            Some((150, 160))
+        );
+
+        // Batched packages are written before the session ends.
+        s.store(&mut pck(1_700_000_250)).unwrap();
+        s.flush().unwrap();
+        assert_eq!(s.write_stats().packages, 251);
+
+        // A new session starts a new collection.
+        drop(s);
+        let mut s = image_storage(&card, &CLOCK);
+        assert_eq!(s.store(&mut pck(1_700_001_000)).unwrap(), 300);
+        assert_eq!(s.get(250).unwrap().storage_id, Some(250));
+    }
+
+    #[test]
+    fn image_remove_collection() {
+        static CLOCK: AtomicI32 = AtomicI32::new(1_700_000_000);
+        let card = image::Image::temp("remove").unwrap();
+        let mut s = image_storage(&card, &CLOCK);
+
+        for i in 0..150 {
+            s.store(&mut pck(1_700_000_000 + i)).unwrap();
+        }
+
+        s.acquire().unwrap().remove_collection(0).unwrap();
+
+        assert!(matches!(
+            s.get(10),
+            Err(StorageErr::GenericSdMmmcErr(
+                GenericSdMmcError::FileNotFound
+            ))
+        ));
+        assert_eq!(s.get(120).unwrap().storage_id, Some(120));
+        assert!(s.acquire().unwrap().remove_collection(0).is_err());
+    }
+
+    #[test]
+    fn image_retain() {
+        use retention::Policy;
+
+        static CLOCK: AtomicI32 = AtomicI32::new(1_700_000_000);
+        let card = image::Image::temp("retain").unwrap();
+        let mut s = image_storage(&card, &CLOCK);
+
+        // Collection 1 is recorded in bursts, and collection 3 is in a queued data-request.
+        for i in 0..600 {
+            let mut p = pck(1_700_000_000 + i);
+            if (100..200).contains(&i) {
+                p.burst = Some(1_700_000_100);
+            }
+            s.store(&mut p).unwrap();
+        }
+        s.flush().unwrap();
+        assert_eq!(s.usage.newest, Some(4));
+        s.set_protected(Vec::from_slice(&[(310, 320)]).unwrap());
+
+        // Within the policy nothing is deleted.
+        let policy = Policy {
+            max: 0,
+            protect: TimeEntry::BURST,
+        };
+        let (deleted, r) = s.retain(policy);
+        r.unwrap();
+        assert_eq!(deleted, None);
+
+        // On a card that is too small the oldest collections are deleted, skipping the protected
+        // collections, until the newest collection in the time index is reached.
+        let used = s.usage.used;
+        s.card_size = 0;
+        let (deleted, r) = s.retain(policy);
+        r.unwrap();
+        assert_eq!(
+            deleted,
+            Some(log::Event::CollectionsDeleted {
+                first: 0,
+                last: 2,
+                n: 2
+            })
+        );
+        assert!(s.usage.used < used);
+        assert_eq!(s.usage.retained, 3);
+        assert_eq!(s.cursor, 4 * time_index::ENTRY_SZ as u32);
+
+        for id in [0, 250] {
+            assert!(matches!(
+                s.get(id),
+                Err(StorageErr::GenericSdMmmcErr(
+                    GenericSdMmcError::FileNotFound
+                ))
+            ));
+        }
+        for id in [150, 315, 450, 550] {
+            assert_eq!(s.get(id).unwrap().storage_id, Some(id));
+        }
+
+        // Nothing more can be deleted.
+        let (deleted, r) = s.retain(policy);
+        r.unwrap();
+        assert_eq!(deleted, None);
+
+        // The tombstones are read back from the time index. Collection 1 was passed over and is
+        // kept, even though it is no longer protected. A full card deletes one collection.
+        let used = s.usage.used;
+        drop(s);
+        let mut s = image_storage(&card, &CLOCK);
+        s.acquire().unwrap();
+        assert_eq!(s.usage.used, used);
+        assert_eq!(s.usage.retained, 3);
+
+        let policy = Policy { max: 0, protect: 0 };
+        s.full = true;
+        let (deleted, r) = s.retain(policy);
+        r.unwrap();
+        assert_eq!(
+            deleted,
+            Some(log::Event::CollectionsDeleted {
+                first: 3,
+                last: 3,
+                n: 1
+            })
+        );
+        assert!(!s.full);
+        assert_eq!(s.get(150).unwrap().storage_id, Some(150));
+        assert!(s.get(315).is_err());
+
+        let (deleted, r) = s.retain(policy);
+        r.unwrap();
+        assert_eq!(deleted, None);
+    }
+
+    #[test]
+    fn image_write_failure() {
+        static CLOCK: AtomicI32 = AtomicI32::new(1_700_000_000);
+        let card = image::Image::temp("write-failure").unwrap();
+        let mut s = image_storage(&card, &CLOCK);
+
+        for i in 0..10 {
+            s.store(&mut pck(1_700_000_000 + i)).unwrap();
+        }
+
+        // Torn write: the first block of the batch is written.
+        card.set_faults(image::Faults {
+            writes_left: Some(1),
+            ..image::Faults::default()
+        });
+        assert!(s.flush().is_err());
+        assert_eq!(s.next_id(), None);
+
+        // The card is initialized again, and the failed batch is written again with the same IDs
+        // after the torn part of its collection, before the next package.
+        card.set_faults(image::Faults::default());
+        assert_eq!(s.store(&mut pck(1_700_000_010)).unwrap(), 100);
+
+        for id in [0, 7, 100] {
+            assert_eq!(s.get(id).unwrap().storage_id, Some(id));
+        }
+
+        for (id, t) in [(8, 1_700_000_008), (9, 1_700_000_009)] {
+            let p = s.get(id).unwrap();
+            assert_eq!(p.storage_id, Some(id));
+            assert_eq!(p.timestamp, t * 1000);
+        }
+    }
+
+    #[test]
+    fn image_write_failure_new_collection() {
+        static CLOCK: AtomicI32 = AtomicI32::new(1_700_000_000);
+        let card = image::Image::temp("write-failure-new").unwrap();
+        let mut s = image_storage(&card, &CLOCK);
+
+        // The collection of the batch is never created.
+        card.set_faults(image::Faults {
+            writes_left: Some(0),
+            ..image::Faults::default()
+        });
+        assert_eq!(s.store(&mut pck(1_700_000_000)).unwrap(), 0);
+        assert!(s.flush().is_err());
+
+        // The collection is not given out again when the card is initialized.
+        card.set_faults(image::Faults::default());
+        assert_eq!(s.store(&mut pck(1_700_000_001)).unwrap(), 100);
+        assert_eq!(s.get(0).unwrap().timestamp, 1_700_000_000_000);
+        assert_eq!(s.get(100).unwrap().timestamp, 1_700_000_001_000);
+    }
+
+    #[test]
+    fn image_card_removed() {
+        static CLOCK: AtomicI32 = AtomicI32::new(1_700_000_000);
+        let card = image::Image::temp("card-removed").unwrap();
+        let mut s = image_storage(&card, &CLOCK);
+
+        for i in 0..WRITE_BATCH as i64 {
+            s.store(&mut pck(1_700_000_000 + i)).unwrap();
+        }
+
+        // The card is removed in the middle of writing the next batch.
+        card.set_faults(image::Faults {
+            writes_left: Some(3),
+            eject: true,
+            ..image::Faults::default()
+        });
+        for i in 1..WRITE_BATCH as i64 {
+            s.store(&mut pck(1_700_000_010 + i)).unwrap();
+        }
+        assert!(s.store(&mut pck(1_700_000_020)).is_err());
+        assert!(matches!(s.state, SdState::Uninitialized));
+
+        // Initialization fails, and is not tried again until the retry delay has passed.
+        assert!(s.store(&mut pck(1_700_000_002)).is_err());
+        assert!(matches!(s.state, SdState::Retry { .. }));
+
+        card.set_faults(image::Faults::default());
+        assert!(matches!(
+            s.store(&mut pck(1_700_000_003)),
+            Err(StorageErr::Uninitialized)
+        ));
+
+        // The failed batch is written with the same IDs before the new collection is started.
+        CLOCK.fetch_add(SD_RETRY_DELAY + 1, Ordering::Relaxed);
+        assert_eq!(s.store(&mut pck(1_700_000_004)).unwrap(), 100);
+        assert_eq!(s.get(0).unwrap().storage_id, Some(0));
+        assert_eq!(s.get(4).unwrap().timestamp, 1_700_000_011_000);
+        assert_eq!(
+            s.get(WRITE_BATCH as u32 * 2 - 1).unwrap().timestamp,
+            1_700_000_020_000
+        );
+    }
 }
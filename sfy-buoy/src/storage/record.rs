@@ -0,0 +1,188 @@
+//! Tagged records of every type of data that is stored.
+//!
+//! Collections are a stream of [`Record`]s, serialized with postcard as the variant index (the
+//! tag) followed by the value. Everything that understands records (the stores, the data-requests
+//! and `sfypack`) handles a new type of data once it is added as a variant.
+//!
+//! The tag identifies both the type and the version of its layout. Variants are only ever added
+//! at the end: when the layout of a type changes, the variant of the old layout is kept with the
+//! old type (like [`AxlPacketV1`](super::format) for the legacy collections) and a new variant is
+//! added for the new layout.
+
+use embedded_hal::blocking::delay::DelayMs;
+
+use super::time_index::TimeEntry;
+use super::STORAGE_VERSION;
+use crate::axl::AxlPacket;
+use crate::gps::TrackPoint;
+use crate::log::LogRecord;
+use crate::uplink::Uplink;
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[allow(clippy::large_enum_variant)]
+pub enum Record {
+    /// Data-package (including packages recorded in bursts).
+    Axl(AxlPacket),
+
+    /// GPS fix.
+    Track(TrackPoint),
+
+    /// Log event or message.
+    Log(LogRecord),
+}
+
+impl Record {
+    pub const AXL: u8 = 0;
+    pub const TRACK: u8 = 1;
+    pub const LOG: u8 = 2;
+
+    pub const fn tag(&self) -> u8 {
+        match self {
+            Record::Axl(_) => Self::AXL,
+            Record::Track(_) => Self::TRACK,
+            Record::Log(_) => Self::LOG,
+        }
+    }
+
+    /// Send record over the uplink.
+    pub fn send<U: Uplink>(
+        &self,
+        uplink: &mut U,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), U::Error> {
+        match self {
+            Record::Axl(pck) => uplink.send_packet(pck, delay).map(|_| ()),
+            Record::Track(p) => uplink.send_track(p, delay),
+            Record::Log(r) => r.send(uplink, delay),
+        }
+    }
+}
+
+/// Values that are stored as a [`Record`]. Packages are stored directly, without moving them into
+/// a `Record`.
+pub trait Tagged {
+    /// Serialize as the payload of a record: the tag followed by the value.
+    fn to_slice<'a>(&self, buf: &'a mut [u8]) -> postcard::Result<&'a mut [u8]>;
+
+    /// Time of the record (ms), if it has one.
+    fn timestamp(&self) -> Option<i64>;
+
+    /// Set the storage ID, only packages carry it.
+    fn set_id(&mut self, _id: u32) {}
+
+    /// Add the record to the time index entry of its collection.
+    fn index(&self, e: &mut TimeEntry) {
+        e.push_record(self.timestamp());
+    }
+}
+
+impl Tagged for Record {
+    fn to_slice<'a>(&self, buf: &'a mut [u8]) -> postcard::Result<&'a mut [u8]> {
+        postcard::to_slice(self, buf)
+    }
+
+    fn timestamp(&self) -> Option<i64> {
+        match self {
+            Record::Axl(pck) => Some(pck.timestamp),
+            Record::Track(p) => Some(p.time as i64 * 1000),
+            Record::Log(LogRecord::Event(e)) => Some(e.time as i64 * 1000),
+            Record::Log(LogRecord::Message(_)) => None,
+        }
+    }
+
+    fn set_id(&mut self, id: u32) {
+        if let Record::Axl(pck) = self {
+            pck.set_id(id);
+        }
+    }
+
+    fn index(&self, e: &mut TimeEntry) {
+        match self {
+            Record::Axl(pck) => e.push(pck),
+            r => e.push_record(r.timestamp()),
+        }
+    }
+}
+
+impl Tagged for AxlPacket {
+    fn to_slice<'a>(&self, buf: &'a mut [u8]) -> postcard::Result<&'a mut [u8]> {
+        let (tag, rest) = buf
+            .split_first_mut()
+            .ok_or(postcard::Error::SerializeBufferFull)?;
+        *tag = Record::AXL;
+
+        let len = postcard::to_slice(self, rest)?.len();
+
+        Ok(&mut buf[..(1 + len)])
+    }
+
+    fn timestamp(&self) -> Option<i64> {
+        Some(self.timestamp)
+    }
+
+    fn set_id(&mut self, id: u32) {
+        self.storage_id = Some(id);
+        self.storage_version = Some(STORAGE_VERSION);
+    }
+
+    fn index(&self, e: &mut TimeEntry) {
+        e.push(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::{Event, LogEvent};
+    use half::f16;
+
+    fn pck() -> AxlPacket {
+        AxlPacket {
+            timestamp: 1_700_000_000_000,
+            position_time: 1_700_000_000,
+            lat: 60.0,
+            lon: 5.0,
+            freq: 52.0,
+            offset: 0,
+            storage_id: Some(12),
+            storage_version: None,
+            fix: None,
+            burst: None,
+            data: (0..300).map(|v| f16::from_f32(v as f32)).collect(),
+        }
+    }
+
+    #[test]
+    fn tags() {
+        let mut buf = [0u8; 1024];
+
+        let records = [
+            Record::Axl(pck()),
+            Record::Track(TrackPoint {
+                time: 1_700_000_000,
+                lat: 60.0,
+                lon: 5.0,
+                hdop: Some(1.2),
+                sats: None,
+            }),
+            Record::Log(LogRecord::Event(LogEvent {
+                time: 1_700_000_000,
+                event: Event::Startup,
+            })),
+        ];
+
+        for (tag, r) in records.iter().enumerate() {
+            let b = r.to_slice(&mut buf).unwrap();
+            assert_eq!(b[0], tag as u8);
+            assert_eq!(r.tag(), tag as u8);
+            assert_eq!(&postcard::from_bytes::<Record>(b).unwrap(), r);
+            assert_eq!(r.timestamp(), Some(1_700_000_000_000));
+        }
+
+        // A package is serialized the same without moving it into a record.
+        let a = records[0].to_slice(&mut buf).unwrap().to_vec();
+        assert_eq!(pck().to_slice(&mut buf).unwrap(), &a[..]);
+
+        assert!(pck().to_slice(&mut []).is_err());
+    }
+}
@@ -0,0 +1,252 @@
+//! Spool of log events and messages on the SD-card.
+//!
+//! The log queues in RAM are small and lost on reset, and the messages that matter most are the
+//! ones logged while the Notecard is failing. When the uplink is unavailable queued
+//! [`LogRecord`]s are stored among the packages instead, as tagged records (see
+//! [`super::record`]), and their storage IDs are appended to [`SPOOL_FNAME`] (`u32`, little
+//! endian). The offset of the first ID that has not been forwarded is kept in [`SPOOL_PTR_FNAME`]
+//! so that the spool survives a reset, and the records are forwarded, oldest first, when the
+//! uplink recovers. A record may be forwarded twice if the device is reset before the offset is
+//! updated. An ID torn by a reset or a failed write is padded before the next ID is appended, so
+//! that it is skipped as not found. Records that have been deleted, or that were lost before
+//! they were written, are skipped.
+//!
+//! The spool is removed when it is larger than [`SPOOL_MAX_SZ`] and everything has been
+//! forwarded, the records are kept in the collections.
+
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_sdmmc::Error as GenericSdMmcError;
+
+use super::{Card, Record, Storage, StorageErr};
+use crate::log::LogRecord;
+use crate::uplink::Uplink;
+
+/// Storage IDs of spooled log records.
+pub const SPOOL_FNAME: &str = "SPOOL.Q2";
+
+/// Offset of the first ID in the spool that has not been forwarded (`u32`, little endian).
+pub const SPOOL_PTR_FNAME: &str = "SPOOL.P2";
+
+/// Size of a storage ID in the spool.
+const ID_SZ: usize = 4;
+
+/// The spool is removed when it is larger than this and all records have been forwarded.
+pub const SPOOL_MAX_SZ: u32 = 64 * 1024;
+
+/// Maximum number of records forwarded per pass.
+pub const SPOOL_BATCH: usize = 16;
+
+pub(super) struct SpoolState {
+    /// Offset of the first record that has not been forwarded, read from the card on first use.
+    offset: Option<u32>,
+
+    /// There may be records that have not been forwarded (unknown at start-up).
+    pending: bool,
+
+    /// The spool ends with a complete ID (unknown at start-up, or after a failed write).
+    terminated: bool,
+}
+
+impl SpoolState {
+    pub(super) const fn new() -> SpoolState {
+        SpoolState {
+            offset: None,
+            pending: true,
+            terminated: false,
+        }
+    }
+}
+
+fn not_found<T: Default>(r: Result<T, StorageErr>) -> Result<T, StorageErr> {
+    match r {
+        Err(StorageErr::GenericSdMmmcErr(GenericSdMmcError::FileNotFound)) => Ok(T::default()),
+        r => r,
+    }
+}
+
+impl<C: Card> Storage<C> {
+    /// Store a record, and append its ID to the spool.
+    pub fn spool(&mut self, r: &LogRecord) -> Result<(), StorageErr> {
+        if !self.spool.terminated {
+            self.terminate_spool()?;
+        }
+
+        let id = self.store(&mut Record::Log(r.clone()))?;
+
+        defmt::debug!("Spooling log record: {}: {:?}", id, defmt::Debug2Format(r));
+        let w = self.acquire()?.write(SPOOL_FNAME, &id.to_le_bytes());
+        if w.is_err() {
+            self.spool.terminated = false;
+        }
+        w?;
+        self.spool.pending = true;
+
+        Ok(())
+    }
+
+    /// Pad an incomplete ID at the end of the spool, so that the next ID is not merged with it.
+    /// The missing high bytes are set, so that the padded ID is not found.
+    fn terminate_spool(&mut self) -> Result<(), StorageErr> {
+        let len = not_found(self.acquire()?.length(SPOOL_FNAME))?;
+        let torn = len as usize % ID_SZ;
+
+        if torn > 0 {
+            defmt::warn!("Padding incomplete ID in spool: {}", len);
+            self.acquire()?.write(SPOOL_FNAME, &[0xff; ID_SZ][torn..])?;
+        }
+
+        self.spool.terminated = true;
+
+        Ok(())
+    }
+
+    /// There may be spooled records that have not been forwarded.
+    pub fn spool_pending(&self) -> bool {
+        self.spool.pending
+    }
+
+    fn spool_offset(&mut self) -> Result<u32, StorageErr> {
+        if let Some(offset) = self.spool.offset {
+            return Ok(offset);
+        }
+
+        let mut b = [0u8; 4];
+        let n = not_found(self.acquire()?.read_at(SPOOL_PTR_FNAME, 0, &mut b))?;
+        let offset = if n == b.len() {
+            u32::from_le_bytes(b)
+        } else {
+            0
+        };
+
+        defmt::info!("Spool offset: {}", offset);
+        self.spool.offset = Some(offset);
+
+        Ok(offset)
+    }
+
+    /// Forward spooled records over the uplink, at most [`SPOOL_BATCH`] per pass. Forwarding
+    /// stops at the first record that fails to send. Returns `true` when all records have been
+    /// forwarded.
+    pub fn forward_spool<U: Uplink>(
+        &mut self,
+        uplink: &mut U,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<bool, StorageErr> {
+        if !self.spool.pending {
+            return Ok(true);
+        }
+
+        let start = self.spool_offset()?;
+        let mut offset = start;
+        let mut buf = [0u8; SPOOL_BATCH * ID_SZ];
+
+        let n = not_found(self.acquire()?.read_at(SPOOL_FNAME, offset, &mut buf))?;
+
+        // The end of the spool is reached if all the IDs that were read are forwarded, an
+        // incomplete ID at the end is padded by the next spooled record.
+        let mut done = n < buf.len();
+        let mut result = Ok(());
+
+        for b in buf[..n].chunks_exact(ID_SZ) {
+            let id = u32::from_le_bytes(b.try_into().unwrap());
+
+            let r = match self.next_id() {
+                Some(next) if id >= next => Err(StorageErr::NotFound),
+                _ => self.get_record(id),
+            };
+
+            match r {
+                Ok(Record::Log(r)) => {
+                    if let Err(e) = r.send(uplink, delay) {
+                        defmt::error!("Failed to forward spooled log record: {:?}", e);
+                        done = false;
+                        break;
+                    }
+                }
+                Ok(_) | Err(StorageErr::ReadPackageError) => {
+                    defmt::error!("Spooled record: {} is not a log record, skipping.", id);
+                }
+                Err(e) if e.is_not_found() => {
+                    defmt::warn!("Spooled log record: {} not found, skipping.", id);
+                }
+                Err(e) => {
+                    done = false;
+                    result = Err(e);
+                    break;
+                }
+            }
+
+            offset += ID_SZ as u32;
+        }
+
+        if offset != start {
+            defmt::info!("Forwarded spooled log records: {} -> {}", start, offset);
+            self.spool.offset = Some(offset);
+            self.acquire()?
+                .overwrite(SPOOL_PTR_FNAME, &offset.to_le_bytes())?;
+        }
+
+        if done {
+            self.spool.pending = false;
+
+            if offset > SPOOL_MAX_SZ {
+                defmt::info!("Spool forwarded, removing: {} bytes.", offset);
+                {
+                    let mut block = self.acquire()?;
+                    not_found(block.remove_file(SPOOL_FNAME))?;
+                    not_found(block.remove_file(SPOOL_PTR_FNAME))?;
+                }
+                self.spool.offset = Some(0);
+            }
+        }
+
+        result.map(|_| done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_torn_record() {
+        use super::super::{clock::CountClock, image, writer};
+        use crate::note::emulator::NoDelay;
+        use crate::uplink::tests::Recorder;
+        use core::sync::atomic::AtomicI32;
+
+        static CLOCK: AtomicI32 = AtomicI32::new(1_700_000_000);
+        let card = image::Image::temp("spool-torn").unwrap();
+        let mut s = Storage::new(card.clone(), CountClock(&CLOCK), "test", writer::batch());
+
+        s.spool(&LogRecord::Message("first".into())).unwrap();
+        s.store(&mut Record::Log(LogRecord::Message("not spooled".into())))
+            .unwrap();
+
+        // An ID that does not exist, a torn write, and reset.
+        {
+            let mut block = s.acquire().unwrap();
+            block.write(SPOOL_FNAME, &5000u32.to_le_bytes()).unwrap();
+            block.write(SPOOL_FNAME, &[1, 0]).unwrap();
+        }
+        s.flush().unwrap();
+        drop(s);
+        let mut s = Storage::new(card.clone(), CountClock(&CLOCK), "test", writer::batch());
+
+        s.spool(&LogRecord::Message("second".into())).unwrap();
+        s.spool(&LogRecord::Message("third".into())).unwrap();
+
+        let mut r = Recorder::default();
+        assert!(s.forward_spool(&mut r, &mut NoDelay).unwrap());
+        assert_eq!(r.logs, ["first", "second", "third"]);
+
+        // The forwarded records are kept among the packages.
+        assert_eq!(
+            s.get_record(0).unwrap(),
+            Record::Log(LogRecord::Message("first".into()))
+        );
+        assert!(!s.spool_pending());
+        assert!(s.forward_spool(&mut r, &mut NoDelay).unwrap());
+        assert_eq!(r.logs.len(), 3);
+    }
+}
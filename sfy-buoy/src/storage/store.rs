@@ -0,0 +1,248 @@
+//! Stores of data-packages.
+//!
+//! The [`StorageManager`](crate::StorageManager) stores packages, and reads them back for
+//! data-requests, through [`PacketStore`]. The stores hold [`Record`]s, so other types of data are
+//! stored and requested the same way. There are three stores:
+//!
+//! * [`Storage`]: collections on the SD-card (or a disk image in the host tests).
+//! * [`FlashStore`](super::flash::FlashStore): a log of records on a SPI NOR flash. A soldered
+//!   flash is more robust than an SD-card in cold water, but small.
+//! * [`MemStore`](super::mem::MemStore): packages in RAM, for tests.
+//!
+//! The log spool and protection of requested packages from retention are only supported by the
+//! SD-card, the other stores use the default implementations.
+
+use embedded_hal::blocking::delay::DelayMs;
+use heapless::Vec;
+
+use super::{Card, Record, Storage, StorageErr, Tagged, COLLECTION_SIZE};
+use crate::axl::AxlPacket;
+use crate::log::LogRecord;
+use crate::request;
+use crate::uplink::Uplink;
+
+/// State of a store.
+#[derive(Debug, Default, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Health {
+    /// The store is initialized and packages can be stored.
+    pub ready: bool,
+
+    /// Size of the store (bytes).
+    pub capacity: u64,
+
+    /// Bytes used by packages.
+    pub used: u64,
+
+    /// The store is full, the oldest packages are deleted (or overwritten) to make room.
+    pub full: bool,
+}
+
+pub trait PacketStore {
+    /// Store a new record (usually a package), the storage ID is set on packages and returned.
+    fn store(&mut self, r: &mut impl Tagged) -> Result<u32, StorageErr>;
+
+    /// Read record with `id`, fails with an error for which [`StorageErr::is_not_found`] is true
+    /// if the record does not exist.
+    fn get_record(&mut self, id: u32) -> Result<Record, StorageErr>;
+
+    /// Read package with `id`, a record of another type is not found.
+    fn get(&mut self, id: u32) -> Result<AxlPacket, StorageErr> {
+        match self.get_record(id)? {
+            Record::Axl(pck) => Ok(pck),
+            _ => Err(StorageErr::NotFound),
+        }
+    }
+
+    /// Returns the next free ID, or `None` if the store is not initialized.
+    fn next_id(&self) -> Option<u32>;
+
+    /// Find the range of storage IDs (inclusive) with records in the time window `start` to `end`
+    /// (ms, inclusive). The range may include missing records, and records without a timestamp.
+    fn find_by_time(&mut self, start: i64, end: i64) -> Result<Option<(u32, u32)>, StorageErr>;
+
+    /// The next ID that may exist when package `id` does not.
+    fn skip_missing(&self, id: u32) -> u32 {
+        id + 1
+    }
+
+    fn health(&self) -> Health;
+
+    /// Write batched records, only if they have waited long enough unless `all` is set.
+    fn flush(&mut self, _all: bool) -> Result<(), StorageErr> {
+        Ok(())
+    }
+
+    /// Set the ranges of storage IDs in queued data-requests, these are not deleted.
+    fn set_protected(&mut self, _ranges: Vec<(u32, u32), { request::MAX_REQUESTS }>) {}
+
+    /// Spool a log record that could not be sent.
+    fn spool(&mut self, _r: &LogRecord) -> Result<(), StorageErr> {
+        Err(StorageErr::Unsupported)
+    }
+
+    /// Forward spooled log records, returns `true` when all records have been forwarded.
+    fn forward_spool<U: Uplink>(
+        &mut self,
+        _uplink: &mut U,
+        _delay: &mut impl DelayMs<u16>,
+    ) -> Result<bool, StorageErr> {
+        Ok(true)
+    }
+}
+
+impl<C: Card> PacketStore for Storage<C> {
+    fn store(&mut self, r: &mut impl Tagged) -> Result<u32, StorageErr> {
+        Storage::store(self, r)
+    }
+
+    fn get_record(&mut self, id: u32) -> Result<Record, StorageErr> {
+        Storage::get_record(self, id)
+    }
+
+    fn next_id(&self) -> Option<u32> {
+        Storage::next_id(self)
+    }
+
+    fn find_by_time(&mut self, start: i64, end: i64) -> Result<Option<(u32, u32)>, StorageErr> {
+        Storage::find_by_time(self, start, end)
+    }
+
+    /// A missing collection file is skipped entirely.
+    fn skip_missing(&self, id: u32) -> u32 {
+        (id / COLLECTION_SIZE + 1) * COLLECTION_SIZE
+    }
+
+    fn health(&self) -> Health {
+        Health {
+            ready: Storage::next_id(self).is_some(),
+            capacity: self.card_size,
+            used: self.usage.used + self.current.map_or(0, |e| e.size as u64),
+            full: self.full,
+        }
+    }
+
+    fn flush(&mut self, all: bool) -> Result<(), StorageErr> {
+        if all || self.writer.is_due() {
+            Storage::flush(self)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn set_protected(&mut self, ranges: Vec<(u32, u32), { request::MAX_REQUESTS }>) {
+        Storage::set_protected(self, ranges)
+    }
+
+    fn spool(&mut self, r: &LogRecord) -> Result<(), StorageErr> {
+        Storage::spool(self, r)
+    }
+
+    fn forward_spool<U: Uplink>(
+        &mut self,
+        uplink: &mut U,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<bool, StorageErr> {
+        Storage::forward_spool(self, uplink, delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{clock::CountClock, flash::FlashStore, image::Image, mem, writer};
+    use super::*;
+    use crate::axl::AXL_SZ;
+    use crate::gps::TrackPoint;
+    use core::sync::atomic::AtomicI32;
+    use half::f16;
+
+    fn pck(t: i64) -> AxlPacket {
+        AxlPacket {
+            timestamp: t * 1000,
+            position_time: t as u32,
+            lat: 60.0,
+            lon: 5.0,
+            freq: 52.0,
+            offset: 0,
+            storage_id: None,
+            storage_version: None,
+            fix: None,
+            burst: None,
+            data: (0..AXL_SZ)
+                .map(|v| f16::from_f32(v as f32))
+                .collect::<Vec<_, { AXL_SZ }>>(),
+        }
+    }
+
+    /// The behaviour the `StorageManager` relies on, for an empty store.
+    fn check_store(s: &mut impl PacketStore) {
+        for i in 0..30 {
+            let mut p = pck(1_700_000_000 + i * 20);
+            let id = s.store(&mut p).unwrap();
+
+            assert_eq!(id, i as u32);
+            assert_eq!(p.storage_id, Some(id));
+        }
+
+        assert_eq!(s.next_id(), Some(30));
+        assert!(s.health().ready);
+
+        let p = s.get(12).unwrap();
+        assert_eq!(p.storage_id, Some(12));
+        assert_eq!(p.timestamp, 1_700_000_240_000);
+        assert_eq!(p.data, pck(0).data);
+
+        assert!(s.get(30).unwrap_err().is_not_found());
+        assert!(s.skip_missing(30) > 30);
+
+        assert_eq!(
+            s.find_by_time(1_700_000_100_000, 1_700_000_200_000)
+                .unwrap(),
+            Some((5, 10))
+        );
+        assert_eq!(
+            s.find_by_time(1_600_000_000_000, 1_600_000_001_000)
+                .unwrap(),
+            None
+        );
+
+        // Other records share the storage IDs, but are not packages.
+        let mut t = Record::Track(TrackPoint {
+            time: 1_700_000_700,
+            lat: 60.0,
+            lon: 5.0,
+            hdop: None,
+            sats: None,
+        });
+        assert_eq!(s.store(&mut t).unwrap(), 30);
+        assert_eq!(s.get_record(30).unwrap(), t);
+        assert!(s.get(30).unwrap_err().is_not_found());
+        assert_eq!(
+            s.find_by_time(1_700_000_700_000, 1_700_000_800_000)
+                .unwrap(),
+            Some((30, 30))
+        );
+    }
+
+    #[test]
+    fn sd_store() {
+        static CLOCK: AtomicI32 = AtomicI32::new(1_700_000_000);
+        let card = Image::temp("store").unwrap();
+        let mut s = Storage::new(card, CountClock(&CLOCK), "test", writer::batch());
+
+        check_store(&mut s);
+        assert!(s.health().used > 0);
+    }
+
+    #[test]
+    fn flash_store() {
+        let mut s = FlashStore::new(mem::MemFlash::new(256 * 4096));
+
+        check_store(&mut s);
+        assert_eq!(s.health().capacity, 256 * 4096);
+    }
+
+    #[test]
+    fn mem_store() {
+        check_store(&mut mem::MemStore::new(100));
+    }
+}
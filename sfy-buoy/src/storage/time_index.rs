@@ -0,0 +1,430 @@
+//! Index of the time span of collections.
+//!
+//! When a collection is finished an entry is appended to the time index file ([`TIME_INDEX`])
+//! with the first and last timestamp, the number of packages and quality [flags](TimeEntry) of
+//! the collection. Finding the packages in a time window only requires reading the index and
+//! bisecting the first and last collection of the window, rather than bisecting the whole card.
+//!
+//! Entries are [`ENTRY_SZ`] bytes:
+//!
+//! ```text
+//! | collection (u32 LE) | first (i64 LE) | last (i64 LE) | size (u32 LE) | count (u16 LE) | flags | CRC8 |
+//! ```
+//!
+//! where the last byte is the lowest byte of the CRC32 of the preceding bytes. Entries that do not
+//! match (torn writes) are skipped. When a collection is deleted (see [`crate::retention`]) an
+//! entry with the [`TimeEntry::DELETED`] flag is appended.
+
+use super::format::crc32;
+use super::COLLECTION_SIZE;
+use crate::axl::{AxlPacket, SAMPLE_SZ};
+
+/// Time index file.
+pub const TIME_INDEX: &str = "TIME.I3";
+
+/// Size of an entry in the time index.
+pub const ENTRY_SZ: usize = 28;
+
+/// Packages with a standard deviation of vertical acceleration above this (m/s^2) are flagged as
+/// events. At a 10 s period this corresponds to a significant wave height of about 5 m.
+pub const EVENT_STD: f32 = 0.5;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, defmt::Format, serde::Serialize, serde::Deserialize,
+)]
+pub struct TimeEntry {
+    pub collection: u32,
+
+    /// Timestamp of first package (ms).
+    pub first: i64,
+
+    /// Timestamp of last package (ms).
+    pub last: i64,
+
+    /// Size of the collection and its index (bytes).
+    pub size: u32,
+
+    /// Number of packages.
+    pub count: u16,
+
+    /// Quality flags, see [`TimeEntry::GAP`] etc.
+    pub flags: u8,
+}
+
+impl TimeEntry {
+    /// There is a gap between two consecutive packages.
+    pub const GAP: u8 = 1 << 0;
+
+    /// A package does not have a position.
+    pub const NO_POSITION: u8 = 1 << 1;
+
+    /// A package exceeded [`EVENT_STD`].
+    pub const EVENT: u8 = 1 << 2;
+
+    /// Packages were recorded in bursts (see [`crate::burst`]).
+    pub const BURST: u8 = 1 << 3;
+
+    /// The collection has been deleted.
+    pub const DELETED: u8 = 1 << 7;
+
+    pub fn new(collection: u32, pck: &AxlPacket) -> TimeEntry {
+        let mut e = TimeEntry::empty(collection);
+        e.push(pck);
+        e
+    }
+
+    /// Entry of a collection without records, the time span is empty until a record with a
+    /// timestamp is added.
+    pub const fn empty(collection: u32) -> TimeEntry {
+        TimeEntry {
+            collection,
+            first: i64::MAX,
+            last: i64::MIN,
+            size: 0,
+            count: 0,
+            flags: 0,
+        }
+    }
+
+    /// Add package to the collection.
+    pub fn push(&mut self, pck: &AxlPacket) {
+        if self.count > 0 && self.first <= self.last && pck.freq > 0. {
+            let duration = (pck.data.len() / SAMPLE_SZ) as f32 / pck.freq * 1000.;
+
+            if (pck.timestamp - self.last) as f32 > 1.5 * duration {
+                self.flags |= Self::GAP;
+            }
+        }
+
+        if pck.position_time == 0 {
+            self.flags |= Self::NO_POSITION;
+        }
+
+        if pck.burst.is_some() {
+            self.flags |= Self::BURST;
+        }
+
+        if pck.summary().std[2] > EVENT_STD {
+            self.flags |= Self::EVENT;
+        }
+
+        self.first = self.first.min(pck.timestamp);
+        self.last = self.last.max(pck.timestamp);
+        self.count = self.count.saturating_add(1);
+    }
+
+    /// Add a record that is not a package to the collection, `timestamp` in ms.
+    pub fn push_record(&mut self, timestamp: Option<i64>) {
+        if let Some(t) = timestamp {
+            self.first = self.first.min(t);
+            self.last = self.last.max(t);
+        }
+
+        self.count = self.count.saturating_add(1);
+    }
+
+    /// Entry marking this collection as deleted.
+    pub const fn deleted(&self) -> TimeEntry {
+        TimeEntry {
+            count: 0,
+            flags: self.flags | Self::DELETED,
+            ..*self
+        }
+    }
+
+    pub const fn is_deleted(&self) -> bool {
+        self.flags & Self::DELETED != 0
+    }
+
+    /// First storage ID in the collection.
+    pub const fn first_id(&self) -> u32 {
+        self.collection * COLLECTION_SIZE
+    }
+
+    /// One past the last storage ID in the collection.
+    pub const fn end_id(&self) -> u32 {
+        self.first_id() + self.count as u32
+    }
+
+    /// The collection has records in the time window (ms, inclusive).
+    pub const fn overlaps(&self, start: i64, end: i64) -> bool {
+        self.first <= self.last && self.last >= start && self.first <= end
+    }
+
+    pub fn encode(&self) -> [u8; ENTRY_SZ] {
+        let mut buf = [0u8; ENTRY_SZ];
+        buf[..4].copy_from_slice(&self.collection.to_le_bytes());
+        buf[4..12].copy_from_slice(&self.first.to_le_bytes());
+        buf[12..20].copy_from_slice(&self.last.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.size.to_le_bytes());
+        buf[24..26].copy_from_slice(&self.count.to_le_bytes());
+        buf[26] = self.flags;
+        buf[27] = crc32(&buf[..27]) as u8;
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<TimeEntry> {
+        if buf.len() < ENTRY_SZ || buf[27] != crc32(&buf[..27]) as u8 {
+            return None;
+        }
+
+        let mut b = [0u8; 8];
+        b.copy_from_slice(&buf[4..12]);
+        let first = i64::from_le_bytes(b);
+        b.copy_from_slice(&buf[12..20]);
+        let last = i64::from_le_bytes(b);
+
+        Some(TimeEntry {
+            collection: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            first,
+            last,
+            size: u32::from_le_bytes([buf[20], buf[21], buf[22], buf[23]]),
+            count: u16::from_le_bytes([buf[24], buf[25]]),
+            flags: buf[26],
+        })
+    }
+}
+
+/// The first and last collection with packages in a time window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    start: i64,
+    end: i64,
+
+    /// First collection in window.
+    pub first: Option<TimeEntry>,
+
+    /// Last collection in window.
+    pub last: Option<TimeEntry>,
+
+    /// Oldest collection in the index.
+    pub oldest: Option<u32>,
+}
+
+impl Window {
+    /// Window from `start` to `end` (ms, inclusive).
+    pub fn new(start: i64, end: i64) -> Window {
+        Window {
+            start,
+            end,
+            first: None,
+            last: None,
+            oldest: None,
+        }
+    }
+
+    pub fn push(&mut self, e: &TimeEntry) {
+        self.oldest = Some(self.oldest.map_or(e.collection, |c| c.min(e.collection)));
+
+        if e.count == 0 || !e.overlaps(self.start, self.end) {
+            return;
+        }
+
+        match self.first {
+            Some(f) if f.collection <= e.collection => (),
+            _ => self.first = Some(*e),
+        }
+
+        match self.last {
+            Some(l) if l.collection >= e.collection => (),
+            _ => self.last = Some(*e),
+        }
+    }
+
+    /// Add all entries in `buf` (a number of whole entries read from the index).
+    pub fn push_entries(&mut self, buf: &[u8]) {
+        for e in buf.chunks_exact(ENTRY_SZ).filter_map(TimeEntry::decode) {
+            self.push(&e);
+        }
+    }
+}
+
+/// Space used by the collections in the time index.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Usage {
+    /// Bytes used by collections that have not been deleted.
+    pub used: u64,
+
+    /// Newest collection in the index.
+    pub newest: Option<u32>,
+
+    /// Collections before this have been deleted or were protected when newer collections were
+    /// deleted.
+    pub retained: u32,
+}
+
+impl Usage {
+    pub fn push(&mut self, e: &TimeEntry) {
+        if e.is_deleted() {
+            self.used = self.used.saturating_sub(e.size as u64);
+            self.retained = self.retained.max(e.collection + 1);
+        } else {
+            self.used += e.size as u64;
+            self.newest = Some(self.newest.map_or(e.collection, |c| c.max(e.collection)));
+        }
+    }
+
+    /// Add all entries in `buf` (a number of whole entries read from the index).
+    pub fn push_entries(&mut self, buf: &[u8]) {
+        for e in buf.chunks_exact(ENTRY_SZ).filter_map(TimeEntry::decode) {
+            self.push(&e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use half::f16;
+
+    /// Package at `t` (s) with 1024 samples at 52 Hz (about 20 s).
+    fn pck(t: i64, amplitude: f32) -> AxlPacket {
+        AxlPacket {
+            timestamp: t * 1000,
+            position_time: t as u32,
+            lat: 60.0,
+            lon: 5.0,
+            freq: 52.0,
+            offset: 0,
+            storage_id: None,
+            storage_version: None,
+            fix: None,
+            burst: None,
+            data: (0..1024)
+                .flat_map(|i| {
+                    let z = if i % 2 == 0 { amplitude } else { -amplitude };
+                    [
+                        f16::from_f32(0.),
+                        f16::from_f32(0.),
+                        f16::from_f32(9.81 + z),
+                    ]
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn entry_flags() {
+        let mut e = TimeEntry::new(12, &pck(1000, 0.1));
+        e.push(&pck(1020, 0.1));
+        assert_eq!(
+            (e.first, e.last, e.count, e.flags),
+            (1_000_000, 1_020_000, 2, 0)
+        );
+        assert_eq!((e.first_id(), e.end_id()), (1200, 1202));
+
+        e.push(&pck(1100, 0.1));
+        assert_eq!(e.flags, TimeEntry::GAP);
+
+        let mut p = pck(1120, 1.0);
+        p.position_time = 0;
+        e.push(&p);
+        assert_eq!(
+            e.flags,
+            TimeEntry::GAP | TimeEntry::NO_POSITION | TimeEntry::EVENT
+        );
+    }
+
+    #[test]
+    fn entry_records() {
+        let mut e = TimeEntry::empty(12);
+        e.push_record(None);
+        assert_eq!(e.count, 1);
+        assert!(!e.overlaps(i64::MIN, i64::MAX));
+
+        // Records before the first package do not make a gap.
+        e.push(&pck(1000, 0.1));
+        e.push_record(Some(1_010_000));
+        e.push(&pck(1020, 0.1));
+        assert_eq!(
+            (e.first, e.last, e.count, e.flags),
+            (1_000_000, 1_020_000, 4, 0)
+        );
+        assert_eq!(e.end_id(), 1204);
+    }
+
+    #[test]
+    fn entry_encoding() {
+        let mut e = TimeEntry::new(65535, &pck(1_700_000_000, 0.1));
+        e.size = 620_000;
+
+        let mut b = e.encode();
+        assert_eq!(TimeEntry::decode(&b), Some(e));
+
+        b[5] ^= 1;
+        assert_eq!(TimeEntry::decode(&b), None);
+        assert_eq!(TimeEntry::decode(&b[..10]), None);
+    }
+
+    #[test]
+    fn window() {
+        // Collections 4..10 of 100 packages every 20 s, collection 7 is missing.
+        let mut buf = std::vec::Vec::new();
+        for c in (4..10).filter(|c| *c != 7) {
+            let t0 = c as i64 * 2000;
+            let mut e = TimeEntry::new(c, &pck(t0, 0.1));
+            for i in 1..100 {
+                e.push(&pck(t0 + i * 20, 0.1));
+            }
+            buf.extend_from_slice(&e.encode());
+        }
+
+        // Torn write of the last entry.
+        buf.extend_from_slice(&[1, 2, 3]);
+
+        let mut w = Window::new(11_000_000, 12_500_000);
+        w.push_entries(&buf);
+        assert_eq!(w.oldest, Some(4));
+        assert_eq!(w.first.unwrap().collection, 5);
+        assert_eq!(w.last.unwrap().collection, 6);
+
+        // In the missing collection.
+        let mut w = Window::new(14_500_000, 15_000_000);
+        w.push_entries(&buf);
+        assert_eq!((w.first, w.last), (None, None));
+
+        let mut w = Window::new(0, i64::MAX);
+        w.push_entries(&buf);
+        assert_eq!(w.first.unwrap().collection, 4);
+        assert_eq!(w.last.unwrap().collection, 9);
+        assert_eq!(w.last.unwrap().end_id(), 1000);
+    }
+
+    #[test]
+    fn usage() {
+        let mut buf = std::vec::Vec::new();
+        let mut entries = std::vec::Vec::new();
+
+        for c in 10..15 {
+            let mut e = TimeEntry::new(c, &pck(c as i64 * 2000, 0.1));
+            e.size = 1000 + c;
+            entries.push(e);
+            buf.extend_from_slice(&e.encode());
+        }
+
+        let mut u = Usage::default();
+        u.push_entries(&buf);
+        assert_eq!(u.used, 5060);
+        assert_eq!(u.newest, Some(14));
+        assert_eq!(u.retained, 0);
+
+        // Collection 10 is protected, 11 and 12 are deleted.
+        for e in &entries[1..3] {
+            let d = e.deleted();
+            assert!(d.is_deleted());
+            buf.extend_from_slice(&d.encode());
+        }
+
+        let mut u = Usage::default();
+        u.push_entries(&buf);
+        assert_eq!(u.used, 5060 - 1011 - 1012);
+        assert_eq!(u.newest, Some(14));
+        assert_eq!(u.retained, 13);
+
+        // Deleted collections are not in the window.
+        let mut w = Window::new(0, i64::MAX);
+        w.push_entries(&buf);
+        assert_eq!(w.first.unwrap().collection, 10);
+        assert_eq!(w.last.unwrap().collection, 14);
+    }
+}
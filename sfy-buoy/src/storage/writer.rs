@@ -0,0 +1,325 @@
+//! Batched writing of records to the collections.
+//!
+//! Appending a record opens the volume, the root directory and the collection, and seeks to the
+//! end of the collection through its cluster chain. This takes longer as the collection grows,
+//! and the SPI bus is busy meanwhile (see <https://github.com/gauteh/sfy/issues/77>). The records
+//! are therefore kept in the [`Writer`] and written [`WRITE_BATCH`] at the time: the collection is
+//! opened once, and the records and their index entries are written with one write each.
+//!
+//! The batch is written when it is full, before a record for the next collection is added,
+//! before packages are read from the card, when it has waited for [`WRITE_MAX_AGE`] and before
+//! the firmware resets the device. It is also written before the device is reset by the
+//! watchdog, a panic or a hard fault, unless the card was in use. The batch is kept in the static
+//! [`BATCH`] on the device, it is too big for the stack of the main loop.
+//!
+//! A batch that fails to be written is kept, and written again with the same IDs when the card
+//! has been initialized again. The packages have already been queued for the Notecard with these
+//! IDs. The collection may have been torn, so the records are appended after the torn part and
+//! the index entries are aligned before they are appended (see [`super::BlockHandle::flush`]).
+//!
+//! `embedded-sdmmc` 0.4 borrows the card while the volume is open and can not allocate clusters
+//! without writing to them, so the volume and collection are not kept open between batches and
+//! the cluster chain of a collection is not allocated in advance.
+
+use heapless::Vec;
+
+use super::format::{self, IndexEntry, RECORD_MAX_SZ};
+use super::record::Tagged;
+use super::{StorageErr, COLLECTION_SIZE};
+
+/// Number of packages written to the card at the time.
+pub const WRITE_BATCH: usize = 4;
+
+/// Time a batch waits for more records before it is written anyway (ms).
+pub const WRITE_MAX_AGE: i64 = 60_000;
+
+/// Encoded records of a batch.
+pub type Batch = Vec<u8, { WRITE_BATCH * RECORD_MAX_SZ }>;
+
+/// The batch of the storage on the device.
+pub static mut BATCH: Batch = Batch::new();
+
+/// Time used writing batches to the card.
+#[derive(Debug, Default, Clone, Copy, PartialEq, defmt::Format)]
+pub struct WriteStats {
+    /// Batches written.
+    pub flushes: u32,
+
+    /// Packages written.
+    pub packages: u32,
+
+    /// Time used writing the last batch (ms).
+    pub last: u32,
+
+    /// Longest time used writing a batch (ms).
+    pub max: u32,
+
+    /// Total time used writing batches (ms).
+    pub total: u64,
+}
+
+impl WriteStats {
+    /// Add a batch of `packages` which took `ms` to write, if timed.
+    pub fn push(&mut self, packages: u32, ms: Option<u32>) {
+        self.flushes += 1;
+        self.packages += packages;
+
+        if let Some(ms) = ms {
+            self.last = ms;
+            self.max = self.max.max(ms);
+            self.total += ms as u64;
+        }
+    }
+
+    /// Mean time used writing a package (ms).
+    pub fn per_package(&self) -> f32 {
+        if self.packages > 0 {
+            self.total as f32 / self.packages as f32
+        } else {
+            0.0
+        }
+    }
+}
+
+pub struct Writer {
+    /// Collection of the batched records.
+    collection: u32,
+
+    /// Encoded records.
+    records: &'static mut Batch,
+
+    /// Index entries of the records, offsets are from the start of the batch.
+    index: Vec<IndexEntry, WRITE_BATCH>,
+
+    /// Time the first record was added to the batch (ms), if timed.
+    since: Option<i64>,
+
+    /// Writing the batch failed, it is written again to the same collection.
+    failed: bool,
+
+    pub stats: WriteStats,
+
+    /// Millisecond timer for the statistics.
+    timer: Option<fn() -> i64>,
+}
+
+impl Writer {
+    pub fn new(records: &'static mut Batch) -> Writer {
+        records.clear();
+
+        Writer {
+            collection: 0,
+            records,
+            index: Vec::new(),
+            since: None,
+            failed: false,
+            stats: WriteStats {
+                flushes: 0,
+                packages: 0,
+                last: 0,
+                max: 0,
+                total: 0,
+            },
+            timer: None,
+        }
+    }
+
+    pub fn set_timer(&mut self, timer: fn() -> i64) {
+        self.timer = Some(timer);
+    }
+
+    /// Current time of the timer (ms).
+    pub fn now(&self) -> Option<i64> {
+        self.timer.map(|t| t())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.index.is_full()
+    }
+
+    /// The batch must be written before a record for `collection` can be added.
+    pub fn needs_flush(&self, collection: u32) -> bool {
+        self.is_full() || (!self.is_empty() && self.collection != collection)
+    }
+
+    /// The batch has waited for [`WRITE_MAX_AGE`], always when it is not timed.
+    pub fn is_due(&self) -> bool {
+        match (self.since, self.now()) {
+            _ if self.is_empty() => false,
+            (Some(since), Some(now)) => now - since >= WRITE_MAX_AGE,
+            _ => true,
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Keep the batch after writing it failed.
+    pub fn fail(&mut self) {
+        self.failed = true;
+    }
+
+    /// Collection of the batch.
+    pub fn collection(&self) -> u32 {
+        self.collection
+    }
+
+    pub fn records(&self) -> &[u8] {
+        &self.records
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Index entries of the records when the batch is written at `offset` in the collection.
+    pub fn index(&self, offset: u32) -> impl Iterator<Item = IndexEntry> + '_ {
+        self.index.iter().map(move |e| IndexEntry {
+            id: e.id,
+            offset: offset + e.offset,
+        })
+    }
+
+    /// Add record with `id` to the batch, returns the size of the record.
+    pub fn push(&mut self, id: u32, r: &impl Tagged) -> Result<usize, StorageErr> {
+        debug_assert!(!self.needs_flush(id / COLLECTION_SIZE));
+
+        let collection = id / COLLECTION_SIZE;
+
+        let start = self.records.len();
+        self.records
+            .resize_default(start + RECORD_MAX_SZ)
+            .map_err(|_| StorageErr::WriteError)?;
+
+        let buf: &mut [u8; RECORD_MAX_SZ] = (&mut self.records[start..]).try_into().unwrap();
+        let sz = match format::encode_record(id, r, buf) {
+            Ok(r) => r.len(),
+            Err(e) => {
+                self.records.truncate(start);
+                return Err(e);
+            }
+        };
+        self.records.truncate(start + sz);
+
+        if self.is_empty() {
+            self.since = self.now();
+        }
+
+        self.collection = collection;
+        self.index
+            .push(IndexEntry {
+                id,
+                offset: start as u32,
+            })
+            .map_err(|_| StorageErr::WriteError)?;
+
+        Ok(sz)
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.index.clear();
+        self.since = None;
+        self.failed = false;
+    }
+}
+
+/// A new batch for a writer in the tests.
+#[cfg(test)]
+pub fn batch() -> &'static mut Batch {
+    std::boxed::Box::leak(std::boxed::Box::new(Batch::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::record::Record;
+    use super::*;
+    use crate::axl::{AxlPacket, AXL_SZ};
+    use half::f16;
+
+    fn pck() -> AxlPacket {
+        AxlPacket {
+            timestamp: 1_700_000_000_000,
+            position_time: 0,
+            lat: 60.0,
+            lon: 5.0,
+            freq: 52.0,
+            offset: 0,
+            storage_id: None,
+            storage_version: None,
+            fix: None,
+            burst: None,
+            data: (0..AXL_SZ)
+                .map(|v| f16::from_f32(v as f32))
+                .collect::<Vec<_, { AXL_SZ }>>(),
+        }
+    }
+
+    #[test]
+    fn batch_records() {
+        let mut w = Writer::new(batch());
+        assert!(!w.needs_flush(1));
+
+        let mut sizes = [0; WRITE_BATCH];
+        for (i, sz) in sizes.iter_mut().enumerate() {
+            *sz = w.push(100 + i as u32, &pck()).unwrap();
+        }
+
+        assert!(w.is_full());
+        assert!(w.needs_flush(1));
+        assert_eq!(w.records().len(), sizes.iter().sum::<usize>());
+
+        // Records are decoded at the offsets in the index.
+        for (i, e) in w.index(format::HEADER_SZ as u32).enumerate() {
+            let offset = e.offset as usize - format::HEADER_SZ;
+            let (h, r) = format::decode_record(&w.records()[offset..]).unwrap();
+            assert_eq!(h.id, 100 + i as u32);
+            assert_eq!(e.id, h.id);
+            assert_eq!(r, Record::Axl(pck()));
+        }
+
+        w.clear();
+        w.push(199, &pck()).unwrap();
+        assert!(!w.needs_flush(1));
+        assert!(w.needs_flush(2));
+    }
+
+    #[test]
+    fn write_stats() {
+        let mut s = WriteStats::default();
+        s.push(4, Some(120));
+        s.push(2, Some(40));
+        s.push(4, None);
+
+        assert_eq!(s.flushes, 3);
+        assert_eq!(s.max, 120);
+        assert_eq!(s.last, 40);
+        assert_eq!(s.per_package(), 16.0);
+    }
+
+    #[test]
+    fn failed_batch() {
+        let mut w = Writer::new(batch());
+        for id in 8..10 {
+            w.push(id, &pck()).unwrap();
+        }
+
+        // The failed batch is kept with its IDs, and must be written before a record for the next
+        // collection is added.
+        w.fail();
+        assert!(w.failed());
+        assert!(w.needs_flush(1));
+        assert_eq!(w.collection(), 0);
+
+        let ids: std::vec::Vec<_> = format::records(w.records()).map(|(h, _)| h.id).collect();
+        assert_eq!(ids, [8, 9]);
+
+        w.clear();
+        assert!(!w.failed());
+    }
+}
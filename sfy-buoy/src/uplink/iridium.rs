@@ -0,0 +1,949 @@
+//! Iridium Short Burst Data (SBD) uplink through a RockBLOCK (Iridium 9602/9603) modem on a UART.
+//!
+//! A mobile originated (MO) message is at most [`MO_SZ`] bytes, so raw data-packages cannot be
+//! sent. Instead a summary of every package (see [`AxlPacket::summary`]) and log messages are
+//! packed as records into the MO buffer, which is transmitted in a session (`AT+SBDIX`) when it
+//! is full, when the oldest record is older than [`MAX_AGE`], or when [`Uplink::sync`] is called.
+//! A record that does not fit in the MO buffer triggers a session to make room for it. Failed
+//! sessions (e.g. no satellite in view) are retried with exponential back-off, starting at
+//! [`RETRY_DELAY`] and up to [`RETRY_MAX`], when a timer is set (see [`Iridium::set_timer`]).
+//!
+//! MO records (little endian):
+//!
+//! * `1`: summary: `timestamp: i64, storage_id: u32 (u32::MAX if none), position_time: u32,
+//!   lat: f32, lon: f32, freq: f32, std: [f16; 3], max: [f16; 3]`.
+//! * `2`: log: `len: u8, text: [u8; len]`.
+//! * `3`: event (see [`crate::log::Event`]): `code: u16, severity: u8, time: u32, args: [u32; 3]`.
+//!
+//! Mobile terminated (MT) messages received during a session carry data-requests:
+//!
+//! * `1`: storage IDs: `start: u32, end: u32`.
+//! * `2`: time window: `start_time: u32, end_time: u32`.
+//!
+//! The modem provides the Iridium system time (`AT-MSSTM`), but no position.
+
+use core::fmt::Write as _;
+use embedded_hal::{
+    blocking::{delay::DelayMs, serial::Write},
+    serial::Read,
+};
+use half::f16;
+use heapless::{Deque, String, Vec};
+
+use super::{Position, Uplink};
+use crate::axl::AxlPacket;
+use crate::log::LogEvent;
+use crate::request::DataRequest;
+
+/// Max size of a mobile originated message.
+pub const MO_SZ: usize = 340;
+
+/// Max size of a mobile terminated message.
+pub const MT_SZ: usize = 270;
+
+/// Size of a summary record.
+pub const SUMMARY_SZ: usize = 1 + 8 + 4 + 4 + 4 + 4 + 4 + 6 + 6;
+
+/// Transmit the MO buffer when the oldest record is older than this (ms).
+pub const MAX_AGE: i64 = 60 * 60_000;
+
+/// Delay before retrying a failed session (ms), doubled for every failure in a row.
+pub const RETRY_DELAY: i64 = 60_000;
+
+/// Max delay before retrying a failed session (ms).
+pub const RETRY_MAX: i64 = 30 * 60_000;
+
+/// Timeout for regular commands (ms).
+pub const COMMAND_TIMEOUT: u32 = 2_000;
+
+/// Timeout for an SBD session (ms).
+pub const SESSION_TIMEOUT: u32 = 60_000;
+
+/// Iridium system time epoch (2014-05-11T14:23:55Z) in UNIX time.
+pub const IRIDIUM_EPOCH: u32 = 1399818235;
+
+const SUMMARY: u8 = 1;
+const LOG: u8 = 2;
+const EVENT: u8 = 3;
+
+const REQUEST_IDS: u8 = 1;
+const REQUEST_TIME: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum SbdError {
+    /// Error reading or writing the serial port.
+    Serial,
+
+    /// No (complete) response within timeout.
+    Timeout,
+
+    /// The modem responded with `ERROR` or an unexpected response.
+    Response,
+
+    /// `AT+SBDWB` failed: 1 = timeout, 2 = bad checksum, 3 = wrong size.
+    Write(u8),
+
+    /// The MT message checksum did not match.
+    Checksum,
+
+    /// No room in MO buffer.
+    Overflow,
+}
+
+/// Result of `AT+SBDIX`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, defmt::Format)]
+pub struct SessionStatus {
+    /// MO status, 0 - 4 is success.
+    pub mo: u8,
+    pub momsn: u16,
+
+    /// MT status: 0 = no message, 1 = message received, 2 = error.
+    pub mt: u8,
+    pub mtmsn: u16,
+    pub mt_len: u16,
+
+    /// Messages waiting at the gateway.
+    pub mt_queued: u16,
+}
+
+impl SessionStatus {
+    pub fn parse(line: &str) -> Option<SessionStatus> {
+        let mut v = line.strip_prefix("+SBDIX:")?.split(',').map(|v| v.trim());
+
+        Some(SessionStatus {
+            mo: v.next()?.parse().ok()?,
+            momsn: v.next()?.parse().ok()?,
+            mt: v.next()?.parse().ok()?,
+            mtmsn: v.next()?.parse().ok()?,
+            mt_len: v.next()?.parse().ok()?,
+            mt_queued: v.next()?.parse().ok()?,
+        })
+    }
+
+    pub fn mo_ok(&self) -> bool {
+        self.mo <= 4
+    }
+}
+
+pub struct Iridium<S: Read<u8> + Write<u8>> {
+    serial: S,
+
+    /// Records waiting to be transmitted.
+    mo: Vec<u8, MO_SZ>,
+
+    /// Timestamp (ms) of the oldest summary in the MO buffer.
+    oldest: Option<i64>,
+
+    /// Transmit at next check.
+    flush: bool,
+
+    /// Received data-requests.
+    requests: Deque<DataRequest, 4>,
+
+    /// Status of last session.
+    pub session: Option<SessionStatus>,
+
+    /// Failed sessions in a row, and the time of the last (ms).
+    failures: u32,
+    last_try: Option<i64>,
+
+    /// Millisecond timer for the back-off of failed sessions.
+    timer: Option<fn() -> i64>,
+}
+
+impl<S: Read<u8> + Write<u8>> Iridium<S> {
+    pub fn new(serial: S, delay: &mut impl DelayMs<u16>) -> Result<Iridium<S>, SbdError> {
+        let mut i = Iridium {
+            serial,
+            mo: Vec::new(),
+            oldest: None,
+            flush: false,
+            requests: Deque::new(),
+            session: None,
+            failures: 0,
+            last_try: None,
+            timer: None,
+        };
+
+        i.command("AT", delay, COMMAND_TIMEOUT)?;
+        i.command("ATE0", delay, COMMAND_TIMEOUT)?; // no echo
+        i.command("AT&K0", delay, COMMAND_TIMEOUT)?; // no flow control
+        i.command("AT+SBDD0", delay, COMMAND_TIMEOUT)?; // clear MO buffer
+
+        Ok(i)
+    }
+
+    pub fn free(self) -> S {
+        self.serial
+    }
+
+    pub fn set_timer(&mut self, timer: fn() -> i64) {
+        self.timer = Some(timer);
+    }
+
+    /// A failed session is not retried before the back-off has passed.
+    fn backing_off(&self) -> bool {
+        match (self.timer.map(|t| t()), self.last_try) {
+            (Some(now), Some(last)) if self.failures > 0 => {
+                let delay = (RETRY_DELAY << (self.failures - 1).min(16)).min(RETRY_MAX);
+                now - last < delay
+            }
+            _ => false,
+        }
+    }
+
+    /// Bytes waiting to be transmitted.
+    pub fn pending(&self) -> usize {
+        self.mo.len()
+    }
+
+    /// Discard any unread input, e.g. the line ending after the last `OK`.
+    fn clear_input(&mut self) {
+        while self.serial.read().is_ok() {}
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), SbdError> {
+        self.serial
+            .bwrite_all(bytes)
+            .and_then(|_| self.serial.bflush())
+            .map_err(|_| SbdError::Serial)
+    }
+
+    fn read_byte(&mut self, delay: &mut impl DelayMs<u16>, timeout: u32) -> Result<u8, SbdError> {
+        for _ in 0..=timeout {
+            match self.serial.read() {
+                Ok(b) => return Ok(b),
+                Err(nb::Error::WouldBlock) => delay.delay_ms(1u16),
+                Err(nb::Error::Other(_)) => return Err(SbdError::Serial),
+            }
+        }
+
+        Err(SbdError::Timeout)
+    }
+
+    /// Read a non-empty line (without line ending).
+    fn read_line(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+        timeout: u32,
+    ) -> Result<String<64>, SbdError> {
+        let mut line = String::new();
+
+        loop {
+            match self.read_byte(delay, timeout)? {
+                b'\r' | b'\n' if line.is_empty() => (),
+                b'\r' | b'\n' => return Ok(line),
+                b => {
+                    // Overlong lines are truncated, they are not responses we are looking for.
+                    line.push(b as char).ok();
+                }
+            }
+        }
+    }
+
+    /// Send a command and wait for `OK`. Returns the last information line of the response (if
+    /// any). Echoed commands are skipped.
+    fn command(
+        &mut self,
+        cmd: &str,
+        delay: &mut impl DelayMs<u16>,
+        timeout: u32,
+    ) -> Result<String<64>, SbdError> {
+        defmt::trace!("sbd: {}", cmd);
+
+        self.clear_input();
+        self.write_all(cmd.as_bytes())?;
+        self.write_all(b"\r")?;
+
+        let mut info = String::new();
+
+        loop {
+            let line = self.read_line(delay, timeout)?;
+
+            match line.as_str() {
+                "OK" => return Ok(info),
+                "ERROR" => return Err(SbdError::Response),
+                l if l == cmd => (),
+                _ => info = line,
+            }
+        }
+    }
+
+    /// Write the MO buffer to the modem (`AT+SBDWB`).
+    fn write_mo(&mut self, delay: &mut impl DelayMs<u16>) -> Result<(), SbdError> {
+        let mut cmd = String::<16>::new();
+        write!(&mut cmd, "AT+SBDWB={}", self.mo.len()).ok();
+
+        self.clear_input();
+        self.write_all(cmd.as_bytes())?;
+        self.write_all(b"\r")?;
+
+        loop {
+            match self.read_line(delay, COMMAND_TIMEOUT)?.as_str() {
+                "READY" => break,
+                l if l == cmd.as_str() => (),
+                _ => return Err(SbdError::Response),
+            }
+        }
+
+        let checksum = checksum(&self.mo);
+        let mo = self.mo.clone();
+        self.write_all(&mo)?;
+        self.write_all(&checksum.to_be_bytes())?;
+
+        let status = self.read_line(delay, COMMAND_TIMEOUT)?;
+        let status: u8 = status.trim().parse().map_err(|_| SbdError::Response)?;
+
+        match self.read_line(delay, COMMAND_TIMEOUT)?.as_str() {
+            "OK" if status == 0 => Ok(()),
+            "OK" => Err(SbdError::Write(status)),
+            _ => Err(SbdError::Response),
+        }
+    }
+
+    /// Read the MT buffer (`AT+SBDRB`).
+    fn read_mt(&mut self, delay: &mut impl DelayMs<u16>) -> Result<Vec<u8, MT_SZ>, SbdError> {
+        self.clear_input();
+        self.write_all(b"AT+SBDRB\r")?;
+
+        let len = u16::from_be_bytes([
+            self.read_byte(delay, COMMAND_TIMEOUT)?,
+            self.read_byte(delay, COMMAND_TIMEOUT)?,
+        ]) as usize;
+
+        if len > MT_SZ {
+            return Err(SbdError::Response);
+        }
+
+        let mut mt = Vec::new();
+        for _ in 0..len {
+            let b = self.read_byte(delay, COMMAND_TIMEOUT)?;
+            mt.push(b).ok();
+        }
+
+        let sum = u16::from_be_bytes([
+            self.read_byte(delay, COMMAND_TIMEOUT)?,
+            self.read_byte(delay, COMMAND_TIMEOUT)?,
+        ]);
+
+        match self.read_line(delay, COMMAND_TIMEOUT)?.as_str() {
+            "OK" if sum == checksum(&mt) => Ok(mt),
+            "OK" => Err(SbdError::Checksum),
+            _ => Err(SbdError::Response),
+        }
+    }
+
+    /// Parse a data-request from an MT message.
+    fn receive(&mut self, mt: &[u8]) {
+        let request = match mt {
+            [REQUEST_IDS, a @ ..] if a.len() == 8 => {
+                DataRequest::ids(u32_le(&a[..4]), u32_le(&a[4..]))
+            }
+            [REQUEST_TIME, a @ ..] if a.len() == 8 => {
+                DataRequest::time(u32_le(&a[..4]), u32_le(&a[4..]))
+            }
+            _ => {
+                defmt::error!("sbd: unknown MT message: {:?}", mt);
+                return;
+            }
+        };
+
+        defmt::info!("sbd: received data-request: {:?}", request);
+
+        if self.requests.push_back(request).is_err() {
+            defmt::error!("sbd: too many data-requests, discarding.");
+        }
+    }
+
+    /// Run an SBD session: transmit the MO buffer (if any) and receive an MT message (if any).
+    pub fn session(&mut self, delay: &mut impl DelayMs<u16>) -> Result<SessionStatus, SbdError> {
+        self.last_try = self.timer.map(|t| t());
+
+        let status = self.try_session(delay);
+
+        match status {
+            Ok(s) if s.mo_ok() => self.failures = 0,
+            _ => self.failures = self.failures.saturating_add(1),
+        }
+
+        status
+    }
+
+    fn try_session(&mut self, delay: &mut impl DelayMs<u16>) -> Result<SessionStatus, SbdError> {
+        if !self.mo.is_empty() {
+            self.write_mo(delay)?;
+        }
+
+        let status = self.command("AT+SBDIX", delay, SESSION_TIMEOUT)?;
+        let status = SessionStatus::parse(&status).ok_or(SbdError::Response)?;
+        defmt::info!("sbd: session: {:?}", status);
+
+        self.session = Some(status);
+
+        if status.mo_ok() {
+            self.mo.clear();
+            self.oldest = None;
+            self.flush = false;
+            self.command("AT+SBDD0", delay, COMMAND_TIMEOUT)?;
+        }
+
+        if status.mt == 1 {
+            let mt = self.read_mt(delay)?;
+            self.receive(&mt);
+        }
+
+        Ok(status)
+    }
+
+    /// Add a record to the MO buffer. The caller has already taken the record from its queue, so
+    /// if it does not fit the buffer is transmitted to make room for it (unless a failed session
+    /// is backing off).
+    fn push_record(
+        &mut self,
+        record: &[u8],
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), SbdError> {
+        if self.mo.len() + record.len() > MO_SZ {
+            self.flush = true;
+
+            if !self.backing_off() {
+                self.session(delay)?;
+            }
+        }
+
+        if self.mo.len() + record.len() > MO_SZ {
+            return Err(SbdError::Overflow);
+        }
+
+        self.mo.extend_from_slice(record).unwrap();
+
+        if self.mo.len() + SUMMARY_SZ > MO_SZ {
+            self.flush = true;
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: Read<u8> + Write<u8>> Uplink for Iridium<S> {
+    type Error = SbdError;
+
+    fn ready(&mut self, _delay: &mut impl DelayMs<u16>) -> Result<bool, SbdError> {
+        Ok(!self.flush)
+    }
+
+    fn send_packet(
+        &mut self,
+        pck: &AxlPacket,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<usize, SbdError> {
+        let s = pck.summary();
+
+        let mut r = Vec::<u8, SUMMARY_SZ>::new();
+        r.push(SUMMARY).unwrap();
+        r.extend_from_slice(&s.timestamp.to_le_bytes()).unwrap();
+        r.extend_from_slice(&s.storage_id.unwrap_or(u32::MAX).to_le_bytes())
+            .unwrap();
+        r.extend_from_slice(&s.position_time.to_le_bytes()).unwrap();
+        r.extend_from_slice(&(s.lat as f32).to_le_bytes()).unwrap();
+        r.extend_from_slice(&(s.lon as f32).to_le_bytes()).unwrap();
+        r.extend_from_slice(&s.freq.to_le_bytes()).unwrap();
+        for v in s.std.iter().chain(s.max.iter()) {
+            r.extend_from_slice(&f16::from_f32(*v).to_le_bytes())
+                .unwrap();
+        }
+
+        self.push_record(&r, delay)?;
+
+        let oldest = *self.oldest.get_or_insert(pck.timestamp);
+        if pck.timestamp - oldest > MAX_AGE {
+            self.flush = true;
+        }
+
+        Ok(r.len())
+    }
+
+    fn send_log(&mut self, msg: &str, delay: &mut impl DelayMs<u16>) -> Result<(), SbdError> {
+        let msg = &msg.as_bytes()[..msg.len().min(u8::MAX as usize)];
+
+        let mut r = Vec::<u8, { 2 + u8::MAX as usize }>::new();
+        r.push(LOG).unwrap();
+        r.push(msg.len() as u8).unwrap();
+        r.extend_from_slice(msg).unwrap();
+
+        self.push_record(&r, delay)
+    }
+
+    fn send_event(
+        &mut self,
+        event: &LogEvent,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), SbdError> {
+        let mut r = Vec::<u8, 20>::new();
+        r.push(EVENT).unwrap();
+        r.extend_from_slice(&event.event.code().to_le_bytes())
+            .unwrap();
+        r.push(event.event.severity() as u8).unwrap();
+        r.extend_from_slice(&event.time.to_le_bytes()).unwrap();
+        for a in event.event.args() {
+            r.extend_from_slice(&a.to_le_bytes()).unwrap();
+        }
+
+        self.push_record(&r, delay)
+    }
+
+    fn time(&mut self, delay: &mut impl DelayMs<u16>) -> Result<Option<u32>, SbdError> {
+        let r = self.command("AT-MSSTM", delay, COMMAND_TIMEOUT)?;
+
+        // `-MSSTM: <hex ticks of 90 ms>` or `-MSSTM: no network service`.
+        Ok(r.strip_prefix("-MSSTM:")
+            .and_then(|t| u32::from_str_radix(t.trim(), 16).ok())
+            .map(|ticks| IRIDIUM_EPOCH + (ticks as u64 * 90 / 1000) as u32))
+    }
+
+    fn location(&mut self, _delay: &mut impl DelayMs<u16>) -> Result<Option<Position>, SbdError> {
+        Ok(None)
+    }
+
+    fn take_request(
+        &mut self,
+        _delay: &mut impl DelayMs<u16>,
+    ) -> Result<Option<DataRequest>, SbdError> {
+        Ok(self.requests.pop_front())
+    }
+
+    fn sync(&mut self, delay: &mut impl DelayMs<u16>) -> Result<(), SbdError> {
+        self.session(delay).map(|_| ())
+    }
+
+    fn check_and_sync(&mut self, delay: &mut impl DelayMs<u16>) -> Result<(), SbdError> {
+        let queued = self.session.map(|s| s.mt_queued > 0).unwrap_or(false);
+
+        if (self.flush || queued) && !self.backing_off() {
+            let status = self.session(delay)?;
+
+            // No satellite in view is normal, keep the buffer and try again later.
+            if !status.mo_ok() {
+                defmt::warn!(
+                    "sbd: session failed (mo status: {}), retrying later.",
+                    status.mo
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn u32_le(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+/// SBD checksum: least significant 2 bytes of the sum of the message.
+pub fn checksum(b: &[u8]) -> u16 {
+    b.iter().fold(0u16, |s, v| s.wrapping_add(*v as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::axl::AXL_SZ;
+    use crate::note::emulator::NoDelay;
+    use std::collections::VecDeque;
+
+    /// A fake RockBLOCK on the other end of the serial port.
+    #[derive(Default)]
+    struct FakeModem {
+        rx: VecDeque<u8>,
+        line: std::vec::Vec<u8>,
+
+        /// Receiving binary MO message of this length (+ checksum).
+        binary: Option<usize>,
+
+        /// MO messages received by the gateway.
+        sent: std::vec::Vec<std::vec::Vec<u8>>,
+        mo: std::vec::Vec<u8>,
+
+        /// MT messages waiting at the gateway.
+        mt: VecDeque<std::vec::Vec<u8>>,
+        mt_buf: std::vec::Vec<u8>,
+
+        signal: bool,
+        echo: bool,
+        momsn: u16,
+    }
+
+    impl FakeModem {
+        fn new() -> FakeModem {
+            FakeModem {
+                signal: true,
+                echo: true,
+                ..Default::default()
+            }
+        }
+
+        fn respond(&mut self, s: &str) {
+            self.rx.extend(s.as_bytes());
+        }
+
+        fn command(&mut self, cmd: &str) {
+            if self.echo {
+                self.respond(&format!("{}\r", cmd));
+            }
+
+            match cmd {
+                "AT" | "AT&K0" => self.respond("\r\nOK\r\n"),
+                "ATE0" => {
+                    self.echo = false;
+                    self.respond("\r\nOK\r\n")
+                }
+                "AT+SBDD0" => {
+                    self.mo.clear();
+                    self.respond("\r\n0\r\n\r\nOK\r\n")
+                }
+                "AT-MSSTM" if self.signal => self.respond("\r\n-MSSTM: 5f3b1a20\r\n\r\nOK\r\n"),
+                "AT-MSSTM" => self.respond("\r\n-MSSTM: no network service\r\n\r\nOK\r\n"),
+                "AT+SBDIX" => {
+                    self.momsn += 1;
+
+                    if self.signal {
+                        if !self.mo.is_empty() {
+                            self.sent.push(self.mo.clone());
+                        }
+
+                        let (mt, len) = match self.mt.pop_front() {
+                            Some(m) => {
+                                let len = m.len();
+                                self.mt_buf = m;
+                                (1, len)
+                            }
+                            None => (0, 0),
+                        };
+
+                        let r = format!(
+                            "\r\n+SBDIX: 0, {}, {}, 1, {}, {}\r\n\r\nOK\r\n",
+                            self.momsn,
+                            mt,
+                            len,
+                            self.mt.len()
+                        );
+                        self.respond(&r);
+                    } else {
+                        let r = format!("\r\n+SBDIX: 32, {}, 2, 0, 0, 0\r\n\r\nOK\r\n", self.momsn);
+                        self.respond(&r);
+                    }
+                }
+                "AT+SBDRB" => {
+                    let mt = self.mt_buf.clone();
+                    self.rx.extend((mt.len() as u16).to_be_bytes());
+                    self.rx.extend(&mt);
+                    self.rx.extend(checksum(&mt).to_be_bytes());
+                    self.respond("\r\nOK\r\n");
+                }
+                c if c.starts_with("AT+SBDWB=") => {
+                    self.binary = Some(c[9..].parse().unwrap());
+                    self.respond("READY\r\n");
+                }
+                _ => self.respond("\r\nERROR\r\n"),
+            }
+        }
+    }
+
+    impl Read<u8> for FakeModem {
+        type Error = ();
+
+        fn read(&mut self) -> nb::Result<u8, ()> {
+            self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    impl Write<u8> for FakeModem {
+        type Error = ();
+
+        fn bwrite_all(&mut self, buffer: &[u8]) -> Result<(), ()> {
+            for b in buffer {
+                self.line.push(*b);
+
+                if let Some(n) = self.binary {
+                    if self.line.len() == n + 2 {
+                        let msg = self.line[..n].to_vec();
+                        let sum = u16::from_be_bytes([self.line[n], self.line[n + 1]]);
+                        self.line.clear();
+                        self.binary = None;
+
+                        if sum == checksum(&msg) {
+                            self.mo = msg;
+                            self.respond("0\r\n\r\nOK\r\n");
+                        } else {
+                            self.respond("2\r\n\r\nOK\r\n");
+                        }
+                    }
+                } else if *b == b'\r' {
+                    let cmd = std::str::from_utf8(&self.line[..self.line.len() - 1])
+                        .unwrap()
+                        .to_owned();
+                    self.line.clear();
+                    self.command(&cmd);
+                }
+            }
+
+            Ok(())
+        }
+
+        fn bflush(&mut self) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    fn package(timestamp: i64, storage_id: u32) -> AxlPacket {
+        AxlPacket {
+            timestamp,
+            position_time: 0,
+            lat: 60.0,
+            lon: 5.0,
+            freq: 52.0,
+            offset: 0,
+            storage_id: Some(storage_id),
+            storage_version: None,
+            fix: None,
+            burst: None,
+            data: (0..AXL_SZ).map(|v| f16::from_f32((v % 3) as f32)).collect(),
+        }
+    }
+
+    #[test]
+    fn parse_session_status() {
+        assert_eq!(
+            SessionStatus::parse("+SBDIX: 0, 12, 1, 5, 9, 2"),
+            Some(SessionStatus {
+                mo: 0,
+                momsn: 12,
+                mt: 1,
+                mtmsn: 5,
+                mt_len: 9,
+                mt_queued: 2
+            })
+        );
+        assert_eq!(SessionStatus::parse("+SBDIX: 0, 12"), None);
+    }
+
+    #[test]
+    fn fill_and_transmit() {
+        let mut sbd = Iridium::new(FakeModem::new(), &mut NoDelay).unwrap();
+
+        let mut n = 0;
+        while sbd.ready(&mut NoDelay).unwrap() {
+            sbd.send_packet(&package(n * 20_000, n as u32), &mut NoDelay)
+                .unwrap();
+            n += 1;
+        }
+        assert_eq!(n as usize, MO_SZ / SUMMARY_SZ);
+
+        sbd.check_and_sync(&mut NoDelay).unwrap();
+        assert_eq!(sbd.pending(), 0);
+        assert!(sbd.ready(&mut NoDelay).unwrap());
+
+        let modem = sbd.free();
+        assert_eq!(modem.sent.len(), 1);
+        let mo = &modem.sent[0];
+        assert_eq!(mo.len(), n as usize * SUMMARY_SZ);
+        assert_eq!(mo[0], SUMMARY);
+        assert_eq!(
+            i64::from_le_bytes(mo[1 + SUMMARY_SZ..9 + SUMMARY_SZ].try_into().unwrap()),
+            20_000
+        );
+        assert_eq!(u32_le(&mo[9 + SUMMARY_SZ..]), 1);
+    }
+
+    #[test]
+    fn event_record() {
+        use crate::log::{Event, QueueId};
+
+        let mut sbd = Iridium::new(FakeModem::new(), &mut NoDelay).unwrap();
+
+        let e = LogEvent {
+            time: 1000,
+            event: Event::QueueFull {
+                queue: QueueId::Uplink,
+            },
+        };
+        sbd.send_event(&e, &mut NoDelay).unwrap();
+        assert_eq!(sbd.pending(), 20);
+
+        sbd.sync(&mut NoDelay).unwrap();
+        let modem = sbd.free();
+        let mo = &modem.sent[0];
+        assert_eq!(mo[0], EVENT);
+        assert_eq!(&mo[1..4], &[4, 0, 3]);
+        assert_eq!(u32_le(&mo[4..]), 1000);
+        assert_eq!(u32_le(&mo[8..]), 2);
+    }
+
+    #[test]
+    fn no_signal_keeps_buffer() {
+        let mut modem = FakeModem::new();
+        modem.signal = false;
+        let mut sbd = Iridium::new(modem, &mut NoDelay).unwrap();
+
+        sbd.send_log("hello", &mut NoDelay).unwrap();
+        sbd.send_packet(&package(0, 0), &mut NoDelay).unwrap();
+        sbd.send_packet(&package(MAX_AGE + 1, 1), &mut NoDelay)
+            .unwrap();
+        assert!(!sbd.ready(&mut NoDelay).unwrap());
+
+        sbd.check_and_sync(&mut NoDelay).unwrap();
+        assert_eq!(sbd.pending(), 7 + 2 * SUMMARY_SZ);
+        assert_eq!(sbd.time(&mut NoDelay).unwrap(), None);
+
+        sbd.serial.signal = true;
+        sbd.check_and_sync(&mut NoDelay).unwrap();
+        assert_eq!(sbd.pending(), 0);
+        assert_eq!(&sbd.serial.sent[0][..7], b"\x02\x05hello");
+    }
+
+    #[test]
+    fn receive_requests() {
+        let mut modem = FakeModem::new();
+        let mut ids = vec![REQUEST_IDS];
+        ids.extend(10u32.to_le_bytes());
+        ids.extend(20u32.to_le_bytes());
+        let mut time = vec![REQUEST_TIME];
+        time.extend(1665360000u32.to_le_bytes());
+        time.extend(1665370800u32.to_le_bytes());
+        modem.mt.push_back(ids);
+        modem.mt.push_back(time);
+
+        let mut sbd = Iridium::new(modem, &mut NoDelay).unwrap();
+        assert_eq!(sbd.take_request(&mut NoDelay).unwrap(), None);
+
+        sbd.sync(&mut NoDelay).unwrap();
+        assert_eq!(sbd.session.unwrap().mt_queued, 1);
+
+        // More messages queued at gateway: next check runs a session.
+        sbd.check_and_sync(&mut NoDelay).unwrap();
+
+        assert_eq!(
+            sbd.take_request(&mut NoDelay).unwrap(),
+            Some(DataRequest::ids(10, 20))
+        );
+        assert_eq!(
+            sbd.take_request(&mut NoDelay).unwrap(),
+            Some(DataRequest::time(1665360000, 1665370800))
+        );
+    }
+
+    #[test]
+    fn system_time() {
+        let mut sbd = Iridium::new(FakeModem::new(), &mut NoDelay).unwrap();
+        let t = sbd.time(&mut NoDelay).unwrap().unwrap();
+        assert_eq!(t, IRIDIUM_EPOCH + (0x5f3b1a20u64 * 90 / 1000) as u32);
+    }
+
+    #[test]
+    fn timeout() {
+        struct Mute;
+
+        impl Read<u8> for Mute {
+            type Error = ();
+
+            fn read(&mut self) -> nb::Result<u8, ()> {
+                Err(nb::Error::WouldBlock)
+            }
+        }
+
+        impl Write<u8> for Mute {
+            type Error = ();
+
+            fn bwrite_all(&mut self, _buffer: &[u8]) -> Result<(), ()> {
+                Ok(())
+            }
+
+            fn bflush(&mut self) -> Result<(), ()> {
+                Ok(())
+            }
+        }
+
+        assert_eq!(
+            Iridium::new(Mute, &mut NoDelay).err(),
+            Some(SbdError::Timeout)
+        );
+    }
+
+    #[test]
+    fn overflow_transmits() {
+        let mut sbd = Iridium::new(FakeModem::new(), &mut NoDelay).unwrap();
+
+        let mut n = 0;
+        while sbd.ready(&mut NoDelay).unwrap() {
+            sbd.send_packet(&package(n * 20_000, n as u32), &mut NoDelay)
+                .unwrap();
+            n += 1;
+        }
+
+        // Does not fit, the buffer is transmitted first.
+        let msg = "x".repeat(100);
+        sbd.send_log(&msg, &mut NoDelay).unwrap();
+        assert_eq!(sbd.pending(), 102);
+        assert_eq!(sbd.serial.sent.len(), 1);
+        assert_eq!(sbd.serial.sent[0].len(), n as usize * SUMMARY_SZ);
+
+        // No signal: the record is not lost silently.
+        sbd.serial.signal = false;
+        sbd.send_log(&msg, &mut NoDelay).unwrap();
+        sbd.send_log(&msg, &mut NoDelay).unwrap();
+        assert_eq!(sbd.send_log(&msg, &mut NoDelay), Err(SbdError::Overflow));
+        assert_eq!(sbd.pending(), 3 * 102);
+    }
+
+    #[test]
+    fn back_off() {
+        use std::sync::atomic::{AtomicI64, Ordering};
+
+        static NOW: AtomicI64 = AtomicI64::new(0);
+
+        let mut modem = FakeModem::new();
+        modem.signal = false;
+        let mut sbd = Iridium::new(modem, &mut NoDelay).unwrap();
+        sbd.set_timer(|| NOW.load(Ordering::Relaxed));
+
+        sbd.send_packet(&package(0, 0), &mut NoDelay).unwrap();
+        sbd.send_packet(&package(MAX_AGE + 1, 1), &mut NoDelay)
+            .unwrap();
+
+        let sessions = |sbd: &Iridium<FakeModem>| sbd.serial.momsn;
+
+        sbd.check_and_sync(&mut NoDelay).unwrap();
+        assert_eq!(sessions(&sbd), 1);
+
+        // Retried after 1, 2, 4, .. minutes.
+        let mut t = 0;
+        for i in 0..5 {
+            NOW.store(t + (RETRY_DELAY << i) - 1, Ordering::Relaxed);
+            sbd.check_and_sync(&mut NoDelay).unwrap();
+            assert_eq!(sessions(&sbd), 1 + i as u16);
+
+            t += RETRY_DELAY << i;
+            NOW.store(t, Ordering::Relaxed);
+            sbd.check_and_sync(&mut NoDelay).unwrap();
+            assert_eq!(sessions(&sbd), 2 + i as u16);
+        }
+
+        // Up to the max delay.
+        NOW.store(t + RETRY_MAX - 1, Ordering::Relaxed);
+        sbd.check_and_sync(&mut NoDelay).unwrap();
+        assert_eq!(sessions(&sbd), 6);
+
+        t += RETRY_MAX;
+        NOW.store(t, Ordering::Relaxed);
+        sbd.check_and_sync(&mut NoDelay).unwrap();
+        assert_eq!(sessions(&sbd), 7);
+
+        // A successful session resets the back-off.
+        sbd.serial.signal = true;
+        t += RETRY_MAX;
+        NOW.store(t, Ordering::Relaxed);
+        sbd.check_and_sync(&mut NoDelay).unwrap();
+        assert_eq!(sbd.pending(), 0);
+        assert_eq!(sbd.failures, 0);
+    }
+}
@@ -0,0 +1,229 @@
+//! Uplinks carry data-packages, log messages and data-requests between the buoy and shore.
+//!
+//! The [`Notecarrier`](crate::note::Notecarrier) (cellular) is the main uplink, and
+//! [`iridium::Iridium`] (Iridium SBD over UART) is used outside cellular coverage. Uplinks
+//! that have no persistent storage for the data-request queue or storage progress can rely on the
+//! default (no-op) implementations: the request queue is then only kept in memory.
+
+use core::fmt::{Debug, Write as _};
+use core::sync::atomic::Ordering;
+use embedded_hal::blocking::delay::DelayMs;
+
+use crate::axl::AxlPacket;
+use crate::config::LOCATION_INTERVAL;
+use crate::gps::TrackPoint;
+use crate::log::LogEvent;
+use crate::note::StorageIdInfo;
+use crate::power;
+use crate::request::{DataRequest, RequestQueue};
+
+pub mod iridium;
+
+/// A position fix.
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Position {
+    pub lat: f64,
+    pub lon: f64,
+
+    /// Time of fix (UTC, seconds).
+    pub time: u32,
+
+    /// Horizontal dilution of precision, if provided by the GPS.
+    pub hdop: Option<f32>,
+
+    /// Number of satellites, if provided by the GPS.
+    pub sats: Option<u8>,
+}
+
+pub trait Uplink {
+    type Error: Debug + defmt::Format;
+
+    /// The uplink can accept another package.
+    fn ready(&mut self, delay: &mut impl DelayMs<u16>) -> Result<bool, Self::Error>;
+
+    /// Send (or queue for sending) a data-package. Returns the number of bytes queued.
+    fn send_packet(
+        &mut self,
+        pck: &AxlPacket,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<usize, Self::Error>;
+
+    /// Send a log message.
+    fn send_log(&mut self, msg: &str, delay: &mut impl DelayMs<u16>) -> Result<(), Self::Error>;
+
+    /// Send a log event. Sent as a text message by default.
+    fn send_event(
+        &mut self,
+        event: &LogEvent,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), Self::Error> {
+        let mut msg = heapless::String::<128>::new();
+        write!(&mut msg, "{}: {:?}", event.time, event.event).ok();
+        self.send_log(&msg, delay)
+    }
+
+    /// Send a stored GPS fix. Sent as a text message by default.
+    fn send_track(
+        &mut self,
+        p: &TrackPoint,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), Self::Error> {
+        let mut msg = heapless::String::<128>::new();
+        write!(&mut msg, "{}: fix: {:.5}, {:.5}", p.time, p.lat, p.lon).ok();
+        self.send_log(&msg, delay)
+    }
+
+    /// Current time (UTC, seconds), if known.
+    fn time(&mut self, delay: &mut impl DelayMs<u16>) -> Result<Option<u32>, Self::Error>;
+
+    /// Last position fix, if any.
+    fn location(&mut self, delay: &mut impl DelayMs<u16>) -> Result<Option<Position>, Self::Error>;
+
+    /// Interval between retrieving time and location (ms).
+    fn location_interval_ms(&self) -> i64 {
+        LOCATION_INTERVAL as i64 * 1000
+    }
+
+    /// Take the next received data-request.
+    fn take_request(
+        &mut self,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<Option<DataRequest>, Self::Error>;
+
+    /// Read the persisted queue of data-requests.
+    fn read_request_queue(
+        &mut self,
+        _delay: &mut impl DelayMs<u16>,
+    ) -> Result<Option<RequestQueue>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Persist the queue of data-requests.
+    fn write_request_queue(
+        &mut self,
+        _delay: &mut impl DelayMs<u16>,
+        _queue: &RequestQueue,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Report a completed data-request.
+    fn report_request(
+        &mut self,
+        _delay: &mut impl DelayMs<u16>,
+        _request: &DataRequest,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Update progress of data-requests.
+    fn write_storage_info(
+        &mut self,
+        _delay: &mut impl DelayMs<u16>,
+        _info: StorageIdInfo,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Start a sync (transmit queued data) now.
+    fn sync(&mut self, delay: &mut impl DelayMs<u16>) -> Result<(), Self::Error>;
+
+    /// Check whether a sync is necessary, and start it in that case.
+    fn check_and_sync(&mut self, delay: &mut impl DelayMs<u16>) -> Result<(), Self::Error>;
+}
+
+/// Send one package from the queue, if the uplink is ready. Only one package is sent at a time
+/// so that the main loop can run other tasks in between.
+pub fn drain_queue<U: Uplink, const N: usize>(
+    uplink: &mut U,
+    queue: &mut heapless::spsc::Consumer<'static, AxlPacket, N>,
+    delay: &mut impl DelayMs<u16>,
+) -> Result<usize, U::Error> {
+    if let Some(pck) = queue.peek() {
+        if !uplink.ready(delay)? {
+            defmt::debug!(
+                "uplink not ready, keeping packages: queue sz: {}",
+                queue.len()
+            );
+            return Ok(0);
+        }
+
+        defmt::debug!("sending package: queue sz: {}", queue.len());
+
+        let sz = uplink
+            .send_packet(pck, delay)
+            .inspect_err(|e| defmt::error!("Error while sending package: {:?}", e))?;
+
+        queue.dequeue(); // dequeue package after successfully sent.
+        power::ACTIVITY.sent.fetch_add(1, Ordering::Relaxed);
+
+        Ok(sz)
+    } else {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// An uplink that records log messages and events.
+    #[derive(Default)]
+    pub struct Recorder {
+        pub logs: std::vec::Vec<std::string::String>,
+        pub events: std::vec::Vec<LogEvent>,
+    }
+
+    impl Uplink for Recorder {
+        type Error = ();
+
+        fn ready(&mut self, _delay: &mut impl DelayMs<u16>) -> Result<bool, ()> {
+            Ok(true)
+        }
+
+        fn send_packet(
+            &mut self,
+            _pck: &AxlPacket,
+            _delay: &mut impl DelayMs<u16>,
+        ) -> Result<usize, ()> {
+            Ok(0)
+        }
+
+        fn send_log(&mut self, msg: &str, _delay: &mut impl DelayMs<u16>) -> Result<(), ()> {
+            self.logs.push(msg.into());
+            Ok(())
+        }
+
+        fn send_event(
+            &mut self,
+            event: &LogEvent,
+            _delay: &mut impl DelayMs<u16>,
+        ) -> Result<(), ()> {
+            self.events.push(*event);
+            Ok(())
+        }
+
+        fn time(&mut self, _delay: &mut impl DelayMs<u16>) -> Result<Option<u32>, ()> {
+            Ok(None)
+        }
+
+        fn location(&mut self, _delay: &mut impl DelayMs<u16>) -> Result<Option<Position>, ()> {
+            Ok(None)
+        }
+
+        fn take_request(
+            &mut self,
+            _delay: &mut impl DelayMs<u16>,
+        ) -> Result<Option<DataRequest>, ()> {
+            Ok(None)
+        }
+
+        fn sync(&mut self, _delay: &mut impl DelayMs<u16>) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn check_and_sync(&mut self, _delay: &mut impl DelayMs<u16>) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+}
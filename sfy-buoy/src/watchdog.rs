@@ -0,0 +1,185 @@
+//! Task liveness supervision for the hardware watchdog.
+//!
+//! Each supervised [`Task`] is registered and then checks in with the [`WATCHDOG`] every time it
+//! has completed a pass (successfully or not). The watchdog interrupt calls [`Watchdog::tick`]
+//! every period, and the hardware watchdog is only fed when every registered task has checked in
+//! within its [`Task::timeout`]. Otherwise the late task (the culprit) should be recorded in the
+//! [crash record](crate::crash::CrashRecord), and the hardware watchdog resets the device.
+//!
+//! The supervisor keeps its own time (advanced by `tick`), so that it does not depend on the RTC
+//! interrupt which is itself supervised. If interrupts are disabled (e.g. while hung in a critical
+//! section) the watchdog interrupt does not run either, and the device is reset without a culprit.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Supervised tasks.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Task {
+    /// The IMU interrupt.
+    Imu = 1,
+
+    /// Draining the storage queue to the SD-card.
+    Storage = 2,
+
+    /// Draining the package queue to the Notecard and syncing.
+    Notecard = 3,
+
+    /// Retrieving time and location.
+    Location = 4,
+}
+
+pub const TASKS: usize = 4;
+
+impl Task {
+    pub const ALL: [Task; TASKS] = [Task::Imu, Task::Storage, Task::Notecard, Task::Location];
+
+    /// Maximum time between check-ins (seconds).
+    pub const fn timeout(&self) -> u32 {
+        match self {
+            Task::Imu => 30,
+            Task::Storage | Task::Notecard | Task::Location => 5 * 60,
+        }
+    }
+
+    pub fn from_code(code: u32) -> Option<Task> {
+        Task::ALL.into_iter().find(|t| *t as u32 == code)
+    }
+
+    const fn idx(&self) -> usize {
+        *self as usize - 1
+    }
+}
+
+pub struct Watchdog {
+    /// Supervisor time (seconds).
+    now: AtomicU32,
+
+    /// Bit mask of registered tasks.
+    registered: AtomicU32,
+
+    /// Time of last check-in of each task.
+    last: [AtomicU32; TASKS],
+}
+
+/// The watchdog supervisor.
+pub static WATCHDOG: Watchdog = Watchdog::new();
+
+impl Watchdog {
+    pub const fn new() -> Watchdog {
+        Watchdog {
+            now: AtomicU32::new(0),
+            registered: AtomicU32::new(0),
+            last: [
+                AtomicU32::new(0),
+                AtomicU32::new(0),
+                AtomicU32::new(0),
+                AtomicU32::new(0),
+            ],
+        }
+    }
+
+    /// Start supervising task.
+    pub fn register(&self, task: Task) {
+        self.checkin(task);
+        self.registered.fetch_or(1 << task.idx(), Ordering::AcqRel);
+    }
+
+    pub fn is_registered(&self, task: Task) -> bool {
+        self.registered.load(Ordering::Acquire) & (1 << task.idx()) != 0
+    }
+
+    /// The task is alive.
+    pub fn checkin(&self, task: Task) {
+        self.last[task.idx()].store(self.now.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    /// Time since last check-in of task (seconds).
+    pub fn age(&self, task: Task) -> u32 {
+        self.now
+            .load(Ordering::Relaxed)
+            .wrapping_sub(self.last[task.idx()].load(Ordering::Relaxed))
+    }
+
+    /// Advance the supervisor time by `dt` seconds and check that all registered tasks are alive.
+    /// If not, the task that is most overdue is returned.
+    pub fn tick(&self, dt: u32) -> Result<(), Task> {
+        self.now.fetch_add(dt, Ordering::Relaxed);
+
+        Task::ALL
+            .into_iter()
+            .filter(|t| self.is_registered(*t))
+            .filter(|t| self.age(*t) > t.timeout())
+            .max_by_key(|t| self.age(*t) - t.timeout())
+            .map_or(Ok(()), Err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supervise() {
+        let w = Watchdog::new();
+
+        // Nothing registered.
+        assert_eq!(w.tick(1000), Ok(()));
+
+        for t in Task::ALL {
+            w.register(t);
+        }
+
+        for _ in 0..100 {
+            assert_eq!(w.tick(10), Ok(()));
+            w.checkin(Task::Imu);
+            w.checkin(Task::Location);
+            w.checkin(Task::Storage);
+            w.checkin(Task::Notecard);
+        }
+
+        // Hung in a Notecard transaction: the main loop stops checking in, the other main-loop
+        // tasks checked in after the Notecard the last time.
+        w.checkin(Task::Location);
+        w.checkin(Task::Storage);
+        w.tick(5).unwrap();
+        w.checkin(Task::Location);
+        w.checkin(Task::Storage);
+
+        let mut r = Ok(());
+        for _ in 0..40 {
+            r = w.tick(10);
+            w.checkin(Task::Imu);
+
+            if r.is_err() {
+                break;
+            }
+        }
+
+        assert_eq!(r, Err(Task::Notecard));
+        assert_eq!(w.age(Task::Notecard), 5 * 60 + 5);
+        assert!(w.age(Task::Imu) < Task::Imu.timeout());
+    }
+
+    #[test]
+    fn imu_stopped() {
+        let w = Watchdog::new();
+        w.register(Task::Imu);
+        w.register(Task::Notecard);
+
+        assert_eq!(w.tick(20), Ok(()));
+        w.checkin(Task::Notecard);
+        assert_eq!(w.tick(20), Err(Task::Imu));
+
+        // Unregistered tasks are not supervised.
+        assert!(!w.is_registered(Task::Storage));
+    }
+
+    #[test]
+    fn task_codes() {
+        for t in Task::ALL {
+            assert_eq!(Task::from_code(t as u32), Some(t));
+        }
+        assert_eq!(Task::from_code(0), None);
+    }
+}
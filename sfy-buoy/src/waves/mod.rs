@@ -89,6 +89,21 @@ impl Freq {
 /// The installed IMU.
 pub type IMU = Ism330Dhcx;
 
+/// Sample rate of the IMU.
+pub const IMU_FREQ: Freq = Freq::Hz208;
+
+/// Full-scale of the accelerometer.
+pub const ACCEL_FS: ctrl1xl::Fs_Xl = ctrl1xl::Fs_Xl::G4;
+
+/// Full-scale of the accelerometer (g), must match [`ACCEL_FS`].
+pub const ACCEL_FS_G: u8 = 4;
+
+/// Full-scale of the gyroscope.
+pub const GYRO_FS: ctrl2g::Fs = ctrl2g::Fs::Dps500;
+
+/// Full-scale of the gyroscope (degrees per second), must match [`GYRO_FS`].
+pub const GYRO_FS_DPS: u16 = 500;
+
 pub struct Waves<I2C: WriteRead + Write> {
     pub i2c: I2C,
     pub imu: IMU,
@@ -100,9 +115,6 @@ pub struct Waves<I2C: WriteRead + Write> {
 
     /// Timestamp at `fifo_offset` sample in buffer.
     pub timestamp: i64,
-    pub position_time: u32,
-    pub lon: f64,
-    pub lat: f64,
 
     /// Offset in FIFO _in samples_ (that is one gyro and one accel sample) when timestamp
     /// was set.
@@ -123,6 +135,18 @@ pub enum ImuError<E: Debug> {
     TooFewSamples(i64),
 }
 
+impl<E: Debug> ImuError<E> {
+    /// Numeric code of error, used in [`crate::log::Event::ImuFailure`].
+    pub const fn code(&self) -> u8 {
+        match self {
+            ImuError::I2C(_) => 1,
+            ImuError::FifoOverrun { .. } => 2,
+            ImuError::FifoBadSequence(..) => 3,
+            ImuError::TooFewSamples(_) => 4,
+        }
+    }
+}
+
 impl<E: Debug> From<E> for ImuError<E> {
     fn from(e: E) -> ImuError<E> {
         ImuError::I2C(e)
@@ -134,7 +158,7 @@ impl<E: Debug, I2C: WriteRead<Error = E> + Write<Error = E>> Waves<I2C> {
         defmt::debug!("setting up imu driver..");
         let imu = Ism330Dhcx::new_with_address(&mut i2c, 0x6a)?;
 
-        const FREQ: Freq = Freq::Hz208;
+        const FREQ: Freq = IMU_FREQ;
         let output_freq = fir::OUT_FREQ;
 
         sa::const_assert_eq!(FREQ.value(), fir::FREQ);
@@ -149,9 +173,6 @@ impl<E: Debug, I2C: WriteRead<Error = E> + Write<Error = E>> Waves<I2C> {
             output_freq,
             buf: ImuBuf::new(FREQ.value()),
             timestamp: 0,
-            position_time: 0,
-            lon: 0.0,
-            lat: 0.0,
             fifo_offset: 0,
         };
 
@@ -219,9 +240,7 @@ impl<E: Debug, I2C: WriteRead<Error = E> + Write<Error = E>> Waves<I2C> {
             .ctrl1xl
             .set_accelerometer_data_rate(i2c, self.freq.accel_odr())?;
 
-        sensor
-            .ctrl1xl
-            .set_chain_full_scale(i2c, ctrl1xl::Fs_Xl::G4)?;
+        sensor.ctrl1xl.set_chain_full_scale(i2c, ACCEL_FS)?;
         sensor.ctrl1xl.set_lpf2_xl_en(i2c, true)?;
 
         // CTRL2_G
@@ -229,9 +248,7 @@ impl<E: Debug, I2C: WriteRead<Error = E> + Write<Error = E>> Waves<I2C> {
             .ctrl2g
             .set_gyroscope_data_rate(i2c, self.freq.gyro_odr())?;
 
-        sensor
-            .ctrl2g
-            .set_chain_full_scale(i2c, ctrl2g::Fs::Dps500)?;
+        sensor.ctrl2g.set_chain_full_scale(i2c, GYRO_FS)?;
 
         // CTRL7_G
         sensor.ctrl7g.set_g_hm_mode(i2c, true)?;
@@ -281,6 +298,35 @@ impl<E: Debug, I2C: WriteRead<Error = E> + Write<Error = E>> Waves<I2C> {
         Ok(())
     }
 
+    /// Stop the FIFO and put the accelerometer and gyroscope in power-down mode.
+    pub fn power_down(&mut self) -> Result<(), E> {
+        defmt::debug!("powering down imu..");
+        self.disable_fifo()?;
+
+        let i2c = &mut self.i2c;
+
+        self.imu
+            .ctrl1xl
+            .set_accelerometer_data_rate(i2c, ctrl1xl::Odr_Xl::PowerDown)?;
+        self.imu
+            .ctrl2g
+            .set_gyroscope_data_rate(i2c, ctrl2g::Odr::PowerDown)?;
+
+        Ok(())
+    }
+
+    /// Power up the IMU after [`Self::power_down`] and start the FIFO. The filters are reset and
+    /// need to warm up, and the buffer is started at `now`.
+    pub fn power_up(&mut self, now: i64, delay: &mut impl DelayMs<u16>) -> Result<(), E> {
+        defmt::debug!("powering up imu..");
+        self.boot_imu()?;
+        self.buf.reset();
+        self.enable_fifo(delay)?;
+        self.take_buf(now)?;
+
+        Ok(())
+    }
+
     /// Returns iterator with all the currently available samples in the FIFO.
     pub fn consume_fifo(
         &mut self,
@@ -291,13 +337,9 @@ impl<E: Debug, I2C: WriteRead<Error = E> + Write<Error = E>> Waves<I2C> {
     }
 
     /// Take buf and reset timestamp.
-    pub fn take_buf(
-        &mut self,
-        now: i64,
-        position_time: u32,
-        lon: f64,
-        lat: f64,
-    ) -> Result<AxlPacket, E> {
+    /// Take the buffer as a package and start a new one at `now`. The position of the package is
+    /// not set, see [`crate::gps`].
+    pub fn take_buf(&mut self, now: i64) -> Result<AxlPacket, E> {
         defmt::trace!("axl: taking buffer");
         let pck = AxlPacket {
             timestamp: self.timestamp,
@@ -305,17 +347,16 @@ impl<E: Debug, I2C: WriteRead<Error = E> + Write<Error = E>> Waves<I2C> {
             data: self.buf.take_buf(),
             storage_id: None,
             storage_version: Some(STORAGE_VERSION),
-            position_time: self.position_time,
-            lon: self.lon,
-            lat: self.lat,
+            position_time: 0,
+            lon: 0.0,
+            lat: 0.0,
+            fix: None,
+            burst: None,
             freq: self.output_freq,
         };
         defmt::trace!("axl: buffer taken: {:?}", pck);
 
-        self.lon = lon;
-        self.lat = lat;
         self.timestamp = now;
-        self.position_time = position_time;
         self.fifo_offset = self.imu.fifostatus.diff_fifo(&mut self.i2c)? / 2;
 
         defmt::debug!(
@@ -57,6 +57,8 @@ mod tests {
             timestamp: 1000,
             storage_id: None,
             storage_version: None,
+            fix: None,
+            burst: None,
             position_time: 0,
             offset: 1,
             freq: 100.,
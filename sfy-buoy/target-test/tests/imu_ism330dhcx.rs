@@ -168,7 +168,7 @@ mod tests {
         let mut samples = s.waves.imu.fifostatus.diff_fifo(&mut s.waves.i2c).unwrap();
         assert_eq!(samples, 0);
 
-        let _p = s.waves.take_buf(100031231, 1231231, 34.0, 23.2).unwrap();
+        let _p = s.waves.take_buf(100031231).unwrap();
 
         s.waves.enable_fifo(&mut s.delay).unwrap();
 
@@ -189,7 +189,7 @@ mod tests {
         defmt::debug!("time series len: {}", s.waves.len());
 
         defmt::debug!("taking buf..");
-        let p = s.waves.take_buf(100031231, 1231231, 34.0, 23.2).unwrap();
+        let p = s.waves.take_buf(100031231).unwrap();
         defmt::debug!("pck: {:?}", p);
     }
 }
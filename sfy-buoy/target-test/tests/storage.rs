@@ -7,14 +7,14 @@ use core::sync::atomic::AtomicI32;
 use defmt_rtt as _;
 use panic_probe as _; // memory layout + panic handler
 
-use sfy::storage::{self, Storage};
+use sfy::storage::{self, SdCard, Storage};
 
 pub static COUNT: AtomicI32 = AtomicI32::new(0);
 
 type Spi0 = hal::spi::Spi0;
 type CS = hal::gpio::pin::P35<{ hal::gpio::Mode::Output }>;
 
-fn clean_up_collection(s: &mut Storage<Spi0, CS>) {
+fn clean_up_collection(s: &mut Storage<SdCard<Spi0, CS>>) {
     defmt::info!("cleaning up test collection");
     s.acquire().unwrap().remove_collection(0).ok();
     s.acquire().unwrap().remove_collection(1).ok();
@@ -31,7 +31,7 @@ mod tests {
     use half::f16;
     use heapless::Vec;
 
-    use sfy::axl::{AxlPacket, AXL_POSTCARD_SZ, AXL_SZ};
+    use sfy::axl::{AxlPacket, AXL_SZ};
     use sfy::storage::{SdSpiSpeed, Storage};
 
     struct State {
@@ -41,7 +41,7 @@ mod tests {
         #[allow(unused)]
         rtc: hal::rtc::Rtc,
 
-        storage: Storage<Spi0, CS>,
+        storage: Storage<SdCard<Spi0, CS>>,
     }
 
     #[init]
@@ -81,6 +81,8 @@ mod tests {
                 SdSpiSpeed::Low => spi.set_freq(Freq::F100kHz),
                 SdSpiSpeed::High => spi.set_freq(Freq::F12mHz),
             },
+            "target-test",
+            unsafe { &mut storage::writer::BATCH },
         );
 
         State {
@@ -108,6 +110,8 @@ mod tests {
             offset: 15,
             storage_id: None,
             storage_version: None,
+            fix: None,
+            burst: None,
             data: (6..3078)
                 .map(|v| f16::from_f32(v as f32))
                 .collect::<Vec<_, { AXL_SZ }>>(),
@@ -125,6 +129,8 @@ mod tests {
             offset: 15,
             storage_id: None,
             storage_version: None,
+            fix: None,
+            burst: None,
             data: (6..3078)
                 .map(|v| f16::from_f32(v as f32))
                 .collect::<Vec<_, { AXL_SZ }>>(),
@@ -147,6 +153,8 @@ mod tests {
             offset: 15,
             storage_id: None,
             storage_version: None,
+            fix: None,
+            burst: None,
             data: (6..3078)
                 .map(|v| f16::from_f32(v as f32))
                 .collect::<Vec<_, { AXL_SZ }>>(),
@@ -154,7 +162,7 @@ mod tests {
 
         s.storage.store(&mut p).unwrap();
         assert_eq!(p.storage_id, Some(0));
-        assert_eq!(p.storage_version, Some(2));
+        assert_eq!(p.storage_version, Some(3));
 
         let p_read = s.storage.get(0).unwrap();
         assert_eq!(p, p_read);
@@ -168,6 +176,8 @@ mod tests {
             offset: 15,
             storage_id: None,
             storage_version: None,
+            fix: None,
+            burst: None,
             data: (6..3078)
                 .map(|v| f16::from_f32(v as f32))
                 .collect::<Vec<_, { AXL_SZ }>>(),
@@ -185,6 +195,8 @@ mod tests {
             offset: 15,
             storage_id: None,
             storage_version: None,
+            fix: None,
+            burst: None,
             data: (9..3081)
                 .map(|v| f16::from_f32(v as f32))
                 .collect::<Vec<_, { AXL_SZ }>>(),
@@ -221,6 +233,8 @@ mod tests {
                 offset: 15,
                 storage_id: None,
                 storage_version: None,
+                fix: None,
+                burst: None,
                 data: (6..3078)
                     .map(|v| f16::from_f32(v as f32))
                     .collect::<Vec<_, { AXL_SZ }>>(),
@@ -229,16 +243,14 @@ mod tests {
             s.storage.store(&mut p).unwrap();
             assert_eq!(p.storage_id, Some(i));
 
-            let (c, fid, offset) = storage::id_to_parts(p.storage_id.unwrap());
+            let (c, fid) = storage::id_to_parts(p.storage_id.unwrap());
 
             if i < 100 {
-                assert_eq!(c, "0.2");
+                assert_eq!(c, "0.3");
                 assert_eq!(fid, i);
-                assert_eq!(offset as u32, (AXL_POSTCARD_SZ as u32) * i);
             } else {
-                assert_eq!(c, "1.2");
+                assert_eq!(c, "1.3");
                 assert_eq!(fid, i - 100);
-                assert_eq!(offset as u32, (AXL_POSTCARD_SZ as u32) * (i - 100));
             }
         }
 
@@ -251,7 +263,9 @@ mod tests {
                 freq: 53.0,
                 offset: 15,
                 storage_id: Some(i),
-                storage_version: Some(2),
+                storage_version: Some(3),
+                fix: None,
+                burst: None,
                 data: (6..3078)
                     .map(|v| f16::from_f32(v as f32))
                     .collect::<Vec<_, { AXL_SZ }>>(),
@@ -260,16 +274,14 @@ mod tests {
             let p_read = s.storage.get(i).unwrap();
             assert_eq!(p, p_read);
 
-            let (c, fid, offset) = storage::id_to_parts(p.storage_id.unwrap());
+            let (c, fid) = storage::id_to_parts(p.storage_id.unwrap());
 
             if i < 100 {
-                assert_eq!(c, "0.2");
+                assert_eq!(c, "0.3");
                 assert_eq!(fid, i);
-                assert_eq!(offset as u32, (AXL_POSTCARD_SZ as u32) * i);
             } else {
-                assert_eq!(c, "1.2");
+                assert_eq!(c, "1.3");
                 assert_eq!(fid, i - 100);
-                assert_eq!(offset as u32, (AXL_POSTCARD_SZ as u32) * (i - 100));
             }
         }
         clean_up_collection(&mut s.storage);
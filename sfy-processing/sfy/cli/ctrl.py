@@ -2,7 +2,7 @@ import click
 import logging
 import os
 import requests
-from datetime import datetime
+from datetime import datetime, timezone
 from sfy.hub import Hub
 
 logger = logging.getLogger(__name__)
@@ -64,6 +64,52 @@ def get_pcks(dev, start, end):
     r.raise_for_status()
 
 
+@ctrl.command()
+@click.argument('dev')
+@click.argument('start', type=click.DateTime())
+@click.argument('end', type=click.DateTime())
+@click.option('--priority',
+              type=int,
+              default=0,
+              help='Requests with higher priority are served first')
+def get_time(dev, start, end, priority):
+    """
+    Request all stored packages between two times (UTC). Several requests
+    can be queued.
+
+    dev:    Device
+    start:  Start time (UTC)
+    end:    End time (UTC)
+    """
+    hub = Hub.from_env()
+    b = hub.buoy(dev)
+
+    start = start.replace(tzinfo=timezone.utc)
+    end = end.replace(tzinfo=timezone.utc)
+
+    logger.info(f"Requesting packages from {b} between {start} and {end}")
+
+    token = hub.login()
+
+    product = os.getenv('SFY_PRODUCT')
+    assert product is not None, "SFY_PRODUCT env not set."
+
+    r = requests.post(
+        f'https://api.notefile.net/req?product={product}&device=dev:{b.dev[3:]}',
+        json={
+            'req': 'note.add',
+            'file': 'request.qi',
+            'body': {
+                'start_time': int(start.timestamp()),
+                'end_time': int(end.timestamp()),
+                'priority': priority
+            },
+        },
+        headers={'X-SESSION-TOKEN': token})
+    logger.debug(f"Response: {r}: {r.text}")
+    r.raise_for_status()
+
+
 @ctrl.command()
 @click.argument('dev')
 def clear_get(dev):
@@ -136,3 +182,52 @@ def status(dev):
     print("request_start ....: %s" % request_start)
     print("request_end ......: %s" % request_end)
     print("time .............: %s" % request_time)
+
+
+@ctrl.command()
+@click.argument('dev')
+@click.argument('command',
+                type=click.Choice([
+                    'reboot', 'reset-imu', 'resend', 'hub-mode', 'gps-period',
+                    'flush', 'start', 'stop'
+                ]))
+@click.option('--start', type=int, help='Start storage ID (resend)')
+@click.option('--end', type=int, help='End storage ID (resend)')
+@click.option('--value', type=int, help='Value (gps-period)')
+@click.option('--mode',
+              type=click.Choice(['periodic', 'continuous']),
+              help='Hub mode (hub-mode)')
+@click.option('--id', 'cid', type=int, help='Command ID returned in cmd.qo')
+def cmd(dev, command, start, end, value, mode, cid):
+    """
+    Send a command to the buoy through cmd.qi. The result is acknowledged in cmd.qo.
+
+    dev:        Device
+    command:    Command
+    """
+    hub = Hub.from_env()
+    b = hub.buoy(dev)
+    logger.info(f"Sending command {command} to buoy: {b}")
+
+    token = hub.login()
+
+    product = os.getenv('SFY_PRODUCT')
+    assert product is not None, "SFY_PRODUCT env not set."
+
+    body = {'cmd': command}
+    for k, v in [('id', cid), ('start', start), ('end', end),
+                 ('value', value), ('mode', mode)]:
+        if v is not None:
+            body[k] = v
+
+    logger.debug(f"Adding command note: {body}")
+    r = requests.post(
+        f'https://api.notefile.net/req?product={product}&device=dev:{b.dev[3:]}',
+        json={
+            'req': 'note.add',
+            'file': 'cmd.qi',
+            'body': body,
+        },
+        headers={'X-SESSION-TOKEN': token})
+    logger.debug(f"Response: {r}: {r.text}")
+    r.raise_for_status()
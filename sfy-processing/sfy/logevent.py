@@ -0,0 +1,137 @@
+"""
+Decode typed log events (`event.qo`) from the buoy, see `sfy-buoy/src/log.rs`.
+"""
+from dataclasses import dataclass
+from datetime import datetime, timezone
+
+SEVERITY = ['debug', 'info', 'warn', 'error']
+
+EVENTS = {
+    1: 'startup',
+    2: 'reset',
+    3: 'imu_failure',
+    4: 'queue_full',
+    5: 'storage_error',
+    6: 'main_loop_error',
+    7: 'power_mode',
+    8: 'request_rejected',
+    9: 'config_rejected',
+    10: 'events_dropped',
+    11: 'boot',
+    12: 'watchdog_reset',
+    13: 'collections_deleted',
+}
+
+RESET_REASON = {
+    1: 'command',
+    2: 'main_loop',
+    3: 'imu',
+    4: 'panic',
+    5: 'hard_fault',
+    6: 'watchdog',
+}
+TASK = {1: 'imu', 2: 'storage', 3: 'notecard', 4: 'location'}
+QUEUE = {1: 'imu', 2: 'uplink'}
+POWER_MODE = {0: 'continuous', 1: 'periodic', 2: 'summary', 3: 'survival'}
+IMU_ERROR = {1: 'i2c', 2: 'fifo_overrun', 3: 'fifo_bad_sequence', 4: 'too_few_samples'}
+STORAGE_ERROR = {
+    1: 'sdmmc',
+    2: 'generic_sdmmc',
+    3: 'parse_id',
+    4: 'write_id',
+    5: 'write',
+    6: 'read_package',
+    7: 'serialization',
+    8: 'disk_full',
+    9: 'uninitialized',
+    10: 'not_found',
+    11: 'flash',
+    12: 'unsupported',
+}
+REQUEST_ERROR = {1: 'invalid', 2: 'queue_full'}
+
+# Bits of the hardware reset cause (Apollo3 RSTGEN STAT).
+RESET_CAUSE = ['EXT', 'POR', 'BOD', 'SWPOR', 'SWPOI', 'DBG', 'WDT']
+
+
+@dataclass(frozen=True)
+class LogEvent:
+    code: int
+    severity: int
+    time: int
+    a: int = 0
+    b: int = 0
+    c: int = 0
+
+    @staticmethod
+    def from_body(body):
+        """
+        Parse the body of an `event.qo` note. Fields that are zero are left out of the body by
+        the Notecard.
+        """
+        return LogEvent(**{
+            k: int(body.get(k, 0))
+            for k in ['code', 'severity', 'time', 'a', 'b', 'c']
+        })
+
+    @property
+    def name(self):
+        return EVENTS.get(self.code, f'unknown({self.code})')
+
+    @property
+    def severity_name(self):
+        return SEVERITY[self.severity] if self.severity < len(
+            SEVERITY) else f'unknown({self.severity})'
+
+    @property
+    def datetime(self):
+        return datetime.fromtimestamp(self.time, timezone.utc)
+
+    @property
+    def args(self):
+        """
+        Named arguments of the event.
+        """
+        a, b, c = self.a, self.b, self.c
+
+        if self.code == 2:
+            return {'reason': RESET_REASON.get(a, a)}
+        elif self.code == 3:
+            return {'error': IMU_ERROR.get(a, a), 'tries': b}
+        elif self.code == 4:
+            return {'queue': QUEUE.get(a, a)}
+        elif self.code == 5:
+            return {'error': STORAGE_ERROR.get(a, a)}
+        elif self.code == 6:
+            return {
+                'location': bool(a & 1),
+                'drain': bool(a & 2),
+                'sync': bool(a & 4),
+                'tries': b
+            }
+        elif self.code == 7:
+            return {
+                'from': POWER_MODE.get(a, a),
+                'to': POWER_MODE.get(b, b),
+                'mv': c
+            }
+        elif self.code == 8:
+            return {'error': REQUEST_ERROR.get(a, a)}
+        elif self.code == 10:
+            return {'n': a}
+        elif self.code == 11:
+            return {
+                'count': a,
+                'reason': RESET_REASON.get(b, 'unknown'),
+                'cause': '|'.join(n for i, n in enumerate(RESET_CAUSE) if c & (1 << i))
+            }
+        elif self.code == 12:
+            return {'task': TASK.get(a, a)}
+        elif self.code == 13:
+            return {'first': a, 'last': b, 'n': c}
+        else:
+            return {}
+
+    def __str__(self):
+        args = ', '.join(f'{k}={v}' for k, v in self.args.items())
+        return f'{self.datetime.isoformat()} [{self.severity_name}] {self.name}({args})'
@@ -0,0 +1,48 @@
+from sfy.logevent import LogEvent
+
+
+def test_decode_power_mode():
+    # Same event as `log::tests::event_args` in sfy-buoy.
+    e = LogEvent.from_body({'code': 7, 'severity': 1, 'time': 1000, 'a': 1, 'b': 2, 'c': 3390})
+    assert e.name == 'power_mode'
+    assert e.severity_name == 'info'
+    assert e.args == {'from': 'periodic', 'to': 'summary', 'mv': 3390}
+    print(e)
+
+
+def test_decode_zero_fields():
+    # The Notecard leaves out fields that are zero.
+    e = LogEvent.from_body({'code': 6, 'severity': 3, 'time': 1000, 'a': 5, 'b': 2})
+    assert e.args == {'location': True, 'drain': False, 'sync': True, 'tries': 2}
+
+    e = LogEvent.from_body({'code': 1, 'severity': 1, 'time': 1000})
+    assert e.name == 'startup'
+    assert e.args == {}
+
+
+def test_decode_boot():
+    e = LogEvent.from_body({'code': 11, 'severity': 1, 'time': 1000, 'a': 3, 'b': 4, 'c': 0x11})
+    assert e.args == {'count': 3, 'reason': 'panic', 'cause': 'EXT|SWPOI'}
+
+    e = LogEvent.from_body({'code': 11, 'severity': 1, 'time': 1000, 'a': 1, 'c': 0x40})
+    assert e.args == {'count': 1, 'reason': 'unknown', 'cause': 'WDT'}
+
+    e = LogEvent.from_body({'code': 12, 'severity': 3, 'time': 1000, 'a': 3})
+    assert e.name == 'watchdog_reset'
+    assert e.args == {'task': 'notecard'}
+
+
+def test_decode_collections_deleted():
+    e = LogEvent.from_body({'code': 13, 'severity': 2, 'time': 1000, 'a': 120, 'b': 169, 'c': 48})
+    assert e.name == 'collections_deleted'
+    assert e.severity_name == 'warn'
+    assert e.args == {'first': 120, 'last': 169, 'n': 48}
+
+
+def test_decode_storage_error():
+    e = LogEvent.from_body({'code': 5, 'severity': 3, 'time': 1000, 'a': 11})
+    assert e.name == 'storage_error'
+    assert e.args == {'error': 'flash'}
+
+    e = LogEvent.from_body({'code': 5, 'severity': 3, 'time': 1000, 'a': 12})
+    assert e.args == {'error': 'unsupported'}